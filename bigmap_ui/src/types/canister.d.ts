@@ -5,18 +5,23 @@ declare module 'ic:idl/bigmap_data' {
 }
 
 declare module 'ic:canisters/bigmap' {
+  /**
+   * All endpoints return either the value or a BigMapError variant, e.g. { NotFound: null }
+   */
+  type BigMapResult<T> = { Ok: T } | { Err: { [name: string]: any } };
+
   interface BigMap {
     /**
      * Store a value in the Big Map
      * Both `key` and `value` must be [Word8] (arrays of utf8 charcodes)
      */
-    put: (key: number[], value: number[]) => Promise<any>;
+    put: (key: number[], value: number[]) => Promise<BigMapResult<any>>;
     /**
      * Fetch a value from the Big Map
      */
-    get: (arr: number[]) => Promise<number[][]>;
-    status: () => Promise<string>;
-    search: (query: string) => Promise<[BigNumber, [[number[], number[]]]]>;
+    get: (arr: number[]) => Promise<BigMapResult<number[]>>;
+    status: () => Promise<BigMapResult<string>>;
+    search: (query: string) => Promise<BigMapResult<[BigNumber, [[number[], number[]]]]>>;
   }
   const BigMap: BigMap;
   export default BigMap;
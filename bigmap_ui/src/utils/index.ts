@@ -46,10 +46,13 @@ export async function bigMapGet(key: string): Promise<number[]> {
   const res = await BigMap.get(encodedKey);
   console.timeEnd(`GET "${key}"`);
 
-  if (res) {
-    return res && res[0];
+  // All BigMap endpoints return a variant { Ok: T; Err: BigMapError }
+  if ('Ok' in res) {
+    return res.Ok;
   } else {
-    console.error(`Error getting key "${key}"`);
+    if (!('NotFound' in res.Err)) {
+      console.error(`Error getting key "${key}"`, res.Err);
+    }
     return [];
   }
 }
@@ -61,10 +64,10 @@ export async function bigMapPut(key: string, encodedValue: number[]): Promise<nu
   const res = await BigMap.put(encodedKey, encodedValue);
   console.time(`PUT "${key}"`);
 
-  if (res) {
-    return res && res[0];
+  if ('Ok' in res) {
+    return res.Ok;
   } else {
-    console.error(`Error putting key "${key}"`);
+    console.error(`Error putting key "${key}"`, res.Err);
     return [];
   }
 }
@@ -75,11 +78,11 @@ export async function getBigMapStatus(): Promise<string> {
   const res = await BigMap.status();
   console.timeEnd("BigMap status");
 
-  if (res) {
-    console.log("status", res);
-    return res;
+  if ('Ok' in res) {
+    console.log("status", res.Ok);
+    return res.Ok;
   } else {
-    console.error("BigMap status get failed");
+    console.error("BigMap status get failed", res.Err);
     return "ERROR retrieving";
   }
 }
@@ -87,9 +90,15 @@ export async function getBigMapStatus(): Promise<string> {
 export async function bigMapSearch(query: string): Promise<SearchResults | null> {
 
   console.time("BigMap search");
-  const search_raw = await BigMap.search(query);
+  const res = await BigMap.search(query);
   console.timeEnd("BigMap search");
 
+  if (!('Ok' in res)) {
+    console.error("BigMap Search failed", res.Err);
+    return null;
+  }
+  const search_raw = res.Ok;
+
   let results: SearchResults = {
     entries_count: search_raw[0].toNumber(),
     entries: search_raw[1].map(e => { return <SearchResultItem>{ key: arrToStr(e[0]), value: arrToStr(e[1]) } })
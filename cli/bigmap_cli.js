@@ -5,13 +5,14 @@ const bigmap_fn = require('./bigmap_functions');
 
 const strToArr = bigmap_fn.strToArr;
 const arrToStr = bigmap_fn.arrToStr;
+const unwrapResult = bigmap_fn.unwrapResult;
 async function sleep(millis) {
   return new Promise(resolve => setTimeout(resolve, millis));
 }
 
 async function get(key) {
   console.time(`BigMap get ${key}`);
-  let [value] = await bigmap_fn.bigMapGet(strToArr(key));
+  let value = await bigmap_fn.bigMapGet(strToArr(key));
   console.timeEnd(`BigMap get ${key}`);
   if (value === undefined) {
     console.log(`BigMap key ${key} does not exist`);
@@ -23,7 +24,7 @@ async function get(key) {
 
 async function getToFile(key, fileName) {
   console.time(`BigMap get ${key}`);
-  let [value] = await bigmap_fn.bigMapGet(strToArr(key));
+  let value = await bigmap_fn.bigMapGet(strToArr(key));
   console.timeEnd(`BigMap get ${key}`);
   if (value === undefined) {
     console.log(`BigMap key ${key} does not exist`);
@@ -81,7 +82,7 @@ async function setDataBucketWasmBinary(file_name_wasm_binary) {
   console.time(`BigMap set Data Canister wasm binary from file ${file_name_wasm_binary}`);
   let wasm_binary = fs.readFileSync(file_name_wasm_binary);
   let wasm_binary_array = Array.from(wasm_binary);
-  await bigmap_fn.getBigMapActor().set_data_bucket_canister_wasm_binary(wasm_binary_array);
+  unwrapResult(await bigmap_fn.getBigMapActor().set_data_bucket_canister_wasm_binary(wasm_binary_array), 'set_data_bucket_canister_wasm_binary');
   console.timeEnd(`BigMap set Data Canister wasm binary from file ${file_name_wasm_binary}`);
 }
 
@@ -89,19 +90,19 @@ async function setSearchWasmBinary(file_name_wasm_binary) {
   console.time(`BigMap set Search Canister wasm binary from file ${file_name_wasm_binary}`);
   let wasm_binary = fs.readFileSync(file_name_wasm_binary);
   let wasm_binary_array = Array.from(wasm_binary);
-  await bigmap_fn.getBigMapActor().set_search_canister_wasm_binary(wasm_binary_array);
+  unwrapResult(await bigmap_fn.getBigMapActor().set_search_canister_wasm_binary(wasm_binary_array), 'set_search_canister_wasm_binary');
   console.timeEnd(`BigMap set Search Canister wasm binary from file ${file_name_wasm_binary}`);
 }
 
 async function maintenance() {
   console.time(`BigMap maintenance`);
-  let res = (await bigmap_fn.getBigMapActor().maintenance());
+  let res = unwrapResult(await bigmap_fn.getBigMapActor().maintenance(), 'maintenance');
   console.log(JSON.stringify(JSON.parse(res), null, 2));
   console.timeEnd(`BigMap maintenance`);
 }
 
 async function status() {
-  let res = (await bigmap_fn.getBigMapActor().status());
+  let res = unwrapResult(await bigmap_fn.getBigMapActor().status(), 'status');
   console.log(JSON.stringify(JSON.parse(res), null, 2));
 }
 
@@ -123,7 +124,7 @@ async function callData(canisterId, functionName, ...args) {
 
 async function put_and_fts_index(key, value) {
   console.time(`BigMap put_and_fts_index ${key}`);
-  await bigmap_fn.getBigMapActor().put_and_fts_index(strToArr(key), value);
+  unwrapResult(await bigmap_fn.getBigMapActor().put_and_fts_index(strToArr(key), value), 'put_and_fts_index');
   console.timeEnd(`BigMap put_and_fts_index ${key}`);
 }
 
@@ -132,7 +133,7 @@ async function remove_from_fts_index(key) {
 }
 
 async function search(search_query) {
-  let results = await bigmap_fn.getBigMapActor().search(search_query.join(' '));
+  let results = unwrapResult(await bigmap_fn.getBigMapActor().search(search_query.join(' ')), 'search');
   let results_count = results[0];
   let results_str = results[1].map(e => { return { key: arrToStr(e[0]), value: arrToStr(e[1]) } });
   console.log(`Found ${results_count} hits`);
@@ -115,15 +115,30 @@ const getBigMapDataActor = (canisterId) => {
   return makeActorFactory(candid)({ canisterId, agent });
 };
 
+// All BigMap endpoints return a variant { Ok: T; Err: BigMapError }
+const formatError = (err) => {
+  const [name] = Object.keys(err);
+  const details = err[name];
+  return details === null ? name : `${name} ${JSON.stringify(details)}`;
+}
+
+const unwrapResult = (res, what) => {
+  if ('Err' in res) {
+    throw new Error(`BigMap: ${what} failed: ${formatError(res.Err)}`);
+  }
+  return res.Ok;
+}
+
 async function bigMapPut(encodedKey, encodedValue) {
 
-  let res = bigMap.put(encodedKey, encodedValue);
+  let res = await bigMap.put(encodedKey, encodedValue);
 
-  if (!res) {
+  if ('Err' in res) {
     const key = arrToStr(encodedKey).substr(0, 100);
-    console.error(`BigMap: Error putting key "${key}"`);
+    console.error(`BigMap: Error putting key "${key}": ${formatError(res.Err)}`);
+    return undefined;
   }
-  return res;
+  return res.Ok;
 }
 
 async function bigMapPutSync(encodedKey) {
@@ -132,33 +147,39 @@ async function bigMapPutSync(encodedKey) {
 
 async function bigMapAppend(encodedKey, encodedValue) {
 
-  let res = bigMap.append(encodedKey, encodedValue);
+  let res = await bigMap.append(encodedKey, encodedValue);
 
-  if (!res) {
+  if ('Err' in res) {
     const key = arrToStr(encodedKey).substr(0, 100);
-    console.error(`BigMap: Error appending key "${key}"`);
+    console.error(`BigMap: Error appending key "${key}": ${formatError(res.Err)}`);
+    return undefined;
   }
-  return res;
+  return res.Ok;
 }
 
 async function bigMapDelete(encodedKey) {
-  let res = bigMap.delete(encodedKey);
+  let res = await bigMap.delete(encodedKey);
 
-  if (!res) {
+  if ('Err' in res) {
     const key = arrToStr(encodedKey).substr(0, 100);
-    console.error(`BigMap: Error deleting key "${key}"`);
+    console.error(`BigMap: Error deleting key "${key}": ${formatError(res.Err)}`);
+    return undefined;
   }
-  return res;
+  return res.Ok;
 }
 
 async function bigMapGet(encodedKey) {
-  let res = bigMap.get(encodedKey);
-
-  if (!res) {
-    const key = arrToStr(encodedKey).substr(0, 100);
-    console.error(`BigMap: Error getting key "${key}"`);
+  let res = await bigMap.get(encodedKey);
+
+  if ('Err' in res) {
+    // A missing key is not an error for the caller
+    if (!('NotFound' in res.Err)) {
+      const key = arrToStr(encodedKey).substr(0, 100);
+      console.error(`BigMap: Error getting key "${key}": ${formatError(res.Err)}`);
+    }
+    return undefined;
   }
-  return res;
+  return res.Ok;
 }
 
 async function bigMapGetSync(encodedKey) {
@@ -166,13 +187,14 @@ async function bigMapGetSync(encodedKey) {
 }
 
 async function bigMapList(encodedKeyPrefix) {
-  let res = bigMap.list(encodedKeyPrefix);
+  let res = await bigMap.list(encodedKeyPrefix);
 
-  if (!res) {
+  if ('Err' in res) {
     const key = arrToStr(encodedKeyPrefix).substr(0, 100);
-    console.error(`BigMap: Error listing with key_prefix "${key}"`);
+    console.error(`BigMap: Error listing with key_prefix "${key}": ${formatError(res.Err)}`);
+    return [];
   }
-  return res;
+  return res.Ok;
 }
 
 async function bigMapInit() {
@@ -183,7 +205,7 @@ async function bigMapInit() {
   )
   let wasm_binary = fs.readFileSync(data_wasm);
   let wasm_binary_array = Array.from(wasm_binary);
-  await bigMap.set_data_bucket_canister_wasm_binary(wasm_binary_array);
+  unwrapResult(await bigMap.set_data_bucket_canister_wasm_binary(wasm_binary_array), 'set_data_bucket_canister_wasm_binary');
 }
 
 async function bigMapInitWithSearch() {
@@ -199,14 +221,15 @@ async function bigMapInitWithSearch() {
   )
   let wasm_binary1 = fs.readFileSync(data_wasm);
   let wasm_binary_array1 = Array.from(wasm_binary1);
-  await bigMap.set_data_bucket_canister_wasm_binary(wasm_binary_array1);
+  unwrapResult(await bigMap.set_data_bucket_canister_wasm_binary(wasm_binary_array1), 'set_data_bucket_canister_wasm_binary');
   let wasm_binary2 = fs.readFileSync(search_wasm);
   let wasm_binary_array2 = Array.from(wasm_binary2);
-  await bigMap.set_search_canister_wasm_binary(wasm_binary_array2);
+  unwrapResult(await bigMap.set_search_canister_wasm_binary(wasm_binary_array2), 'set_search_canister_wasm_binary');
 }
 
 module.exports = {
   getCanister, getCanisterId, getBigMapActor, bigMapPut, bigMapPutSync,
   bigMapAppend, bigMapDelete, bigMapGet, bigMapGetSync, bigMapList,
-  bigMapInit, bigMapInitWithSearch, getBigMapDataActor, strToArr, arrToStr
+  bigMapInit, bigMapInitWithSearch, getBigMapDataActor, strToArr, arrToStr,
+  formatError, unwrapResult
 };
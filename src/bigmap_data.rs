@@ -1,35 +1,106 @@
-use ::bigmap::data::DataBucket;
-use ::bigmap::{Key, Sha2Vec, Val};
+use ::bigmap::data::snapshot::{SnapshotCursor, SnapshotPage};
+use ::bigmap::data::{
+    self, Access, AccessPolicy, CasResult, CertifiedValue, Codec, DataBucket, KeyRange,
+    RelocationEntry,
+};
+use ::bigmap::memory::MemoryUsage;
+use ::bigmap::upgrade::CodeVersion;
+use ::bigmap::{upgrade, BigMapError, CanisterId, Key, Sha2Vec, Val};
 #[cfg(target_arch = "wasm32")]
 use ic_cdk::println;
 use ic_cdk::storage;
 use ic_cdk_macros::*;
+use std::borrow::Cow;
 // use std::sync::Mutex;
 
+// The (range_start, range_end) pairs of the ranges, as sent by the BigMap Index
+type KeyRangesArg = Vec<(Sha2Vec, Sha2Vec)>;
+
+// Rejects the call unless the caller has the access, according to the access policy
+fn authorize(bm_data: &DataBucket, access: Access) -> Result<(), BigMapError> {
+    let caller = CanisterId::from(ic_cdk::reflection::caller());
+    let res = bm_data.authorize(&caller, access);
+    if let Err(err) = &res {
+        println!(
+            "BigMap Data: caller {} {:?} access error: {}",
+            caller, access, err
+        );
+    }
+    res
+}
+
+// As authorize, for an access to the key, which must be in the caller's own namespace
+fn authorize_key(bm_data: &DataBucket, access: Access, key: &[u8]) -> Result<(), BigMapError> {
+    let caller = CanisterId::from(ic_cdk::reflection::caller());
+    let res = bm_data.authorize_key(&caller, access, key);
+    if let Err(err) = &res {
+        println!(
+            "BigMap Data: caller {} {:?} access to key {} error: {}",
+            caller,
+            access,
+            String::from_utf8_lossy(key),
+            err
+        );
+    }
+    res
+}
+
+fn authorize_upload(bm_data: &DataBucket, upload_id: u64) -> Result<(), BigMapError> {
+    let caller = CanisterId::from(ic_cdk::reflection::caller());
+    let res = bm_data.authorize_upload(&caller, upload_id);
+    if let Err(err) = &res {
+        println!(
+            "BigMap Data: caller {} upload {} access error: {}",
+            caller, upload_id, err
+        );
+    }
+    res
+}
+
 #[query]
-fn get(key: Key) -> Option<Val> {
+fn get(key: Key) -> Result<Val, BigMapError> {
     let bm_data = storage::get::<DataBucket>();
+    authorize_key(bm_data, Access::Read, &key)?;
 
-    let res = bm_data.get(key.clone()).ok().cloned();
+    let res = bm_data.get(key.clone()).map(Cow::into_owned);
     match &res {
-        Some(value) => println!(
+        Ok(value) => println!(
             "BigMap Data: get key {} ({} bytes) => value ({} bytes)",
             String::from_utf8_lossy(&key),
             key.len(),
             value.len()
         ),
-        None => println!(
-            "BigMap Data: get key {} ({} bytes) => None",
+        Err(err) => println!(
+            "BigMap Data: get key {} ({} bytes) => {}",
             String::from_utf8_lossy(&key),
-            key.len()
+            key.len(),
+            err
         ),
     };
     res
 }
 
+#[query]
+// The value with a witness, which the client verifies against the certified root hash
+fn get_certified(key: Key) -> Result<CertifiedValue, BigMapError> {
+    let bm_data = storage::get::<DataBucket>();
+    authorize_key(bm_data, Access::Read, &key)?;
+
+    let res = bm_data.get_certified(&key);
+    if let Err(err) = &res {
+        println!(
+            "BigMap Data: get_certified key {} error: {}",
+            String::from_utf8_lossy(&key),
+            err
+        );
+    }
+    res
+}
+
 #[update]
-fn put(key: Key, value: Val) -> u64 {
+fn put(key: Key, value: Val) -> Result<u64, BigMapError> {
     let bm_data = storage::get_mut::<DataBucket>();
+    authorize_key(bm_data, Access::Write, &key)?;
 
     let key_str = String::from_utf8_lossy(&key);
     println!(
@@ -38,70 +109,133 @@ fn put(key: Key, value: Val) -> u64 {
         key.len(),
         value.len()
     );
-    match bm_data.put(&key, &value, false) {
-        Ok(value_len) => value_len,
-        Err(err) => {
-            println!("BigMap Data: put key {} error: {}", key_str, err);
-            0
-        }
+    let res = bm_data.put(&key, &value, false);
+    if let Err(err) = &res {
+        println!("BigMap Data: put key {} error: {}", key_str, err);
     }
+    res
 }
 
 #[update]
-// Returns the number of successful puts
-fn batch_put(batch: Vec<(Key, Val)>) -> u64 {
+// Returns the result of the put for each entry, in the order of the batch
+fn batch_put(batch: Vec<(Key, Val)>) -> Result<Vec<Result<u64, BigMapError>>, BigMapError> {
     let bm_data = storage::get_mut::<DataBucket>();
+    for (key, _) in batch.iter() {
+        authorize_key(bm_data, Access::Write, key)?;
+    }
 
-    if batch.len() == 1 {
-        let (key, value) = batch.get(0).unwrap();
-        let key_str = String::from_utf8_lossy(&key);
-        println!(
-            "BigMap Data: put key {} ({} bytes) value ({} bytes)",
+    println!("BigMap Data: put batch of {} entries", batch.len());
+
+    Ok(bm_data.batch_put(&batch))
+}
+
+#[update]
+fn append(key: Key, value: Val) -> Result<u64, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize_key(bm_data, Access::Write, &key)?;
+
+    let key_str = String::from_utf8_lossy(&key);
+    let appended_value_len = value.len();
+    let res = bm_data.put(&key, &value, true);
+    match &res {
+        Ok(total_value_len) => println!(
+            "BigMap Data: put_append key {} ({} bytes) value ({} bytes appended, {} bytes total)",
             key_str,
             key.len(),
-            value.len()
-        );
+            appended_value_len,
+            total_value_len
+        ),
+        Err(err) => println!("BigMap Data: put key {} error: {}", key_str, err),
+    }
+    res
+}
 
-        match bm_data.put(&key, &value, false) {
-            Ok(_) => 1,
-            Err(err) => {
-                println!("BigMap Data: put key {} error: {}", key_str, err);
-                0
-            }
-        }
-    } else {
-        println!("BigMap Data: put batch of {} entries", batch.len());
+#[update]
+// Store the value with the provided codec, instead of the default codec of this DataBucket
+fn put_with_codec(key: Key, value: Val, codec: Codec) -> Result<u64, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize_key(bm_data, Access::Write, &key)?;
 
-        bm_data.batch_put(&batch)
+    let key_str = String::from_utf8_lossy(&key);
+    println!(
+        "BigMap Data: put_with_codec key {} ({} bytes) value ({} bytes) codec {:?}",
+        key_str,
+        key.len(),
+        value.len(),
+        codec
+    );
+    let res = bm_data.put_with_codec(&key, &value, false, codec);
+    if let Err(err) = &res {
+        println!("BigMap Data: put_with_codec key {} error: {}", key_str, err);
     }
+    res
+}
+
+#[update]
+fn put_with_codec_from_index(args: (Key, Val, Codec)) -> Result<u64, BigMapError> {
+    let (key, value, codec) = args;
+    put_with_codec(key, value, codec)
+}
+
+#[update]
+fn set_default_codec(codec: Codec) -> Result<(), BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    println!("BigMap Data: set_default_codec {:?}", codec);
+    bm_data.set_default_codec(codec);
+    Ok(())
+}
+
+#[update]
+// Writes which would grow the used bytes over the capacity are rejected with BucketFull
+fn set_capacity_bytes(capacity_bytes: u64) -> Result<(), BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    println!("BigMap Data: set_capacity_bytes {}", capacity_bytes);
+    bm_data.set_capacity_bytes(capacity_bytes)
+}
+
+#[query]
+fn capacity_bytes() -> Result<u64, BigMapError> {
+    let bm_data = storage::get::<DataBucket>();
+
+    Ok(bm_data.capacity_bytes())
 }
 
 #[update]
-fn append(key: Key, value: Val) -> u64 {
+// expires_at is in nanoseconds since 1970-01-01, as returned by ic_cdk::time()
+fn put_with_expiry(key: Key, value: Val, expires_at: u64) -> Result<u64, BigMapError> {
     let bm_data = storage::get_mut::<DataBucket>();
+    authorize_key(bm_data, Access::Write, &key)?;
 
     let key_str = String::from_utf8_lossy(&key);
-    let appended_value_len = value.len();
-    match bm_data.put(&key, &value, true) {
-        Ok(total_value_len) => {
-            println!(
-                "BigMap Data: put_append key {} ({} bytes) value ({} bytes appended, {} bytes total)",
-                key_str,
-                key.len(),
-                appended_value_len,
-                total_value_len
-            );
-            total_value_len
-        }
-        Err(err) => {
-            println!("BigMap Data: put key {} error: {}", key_str, err);
-            0
-        }
+    println!(
+        "BigMap Data: put_with_expiry key {} ({} bytes) value ({} bytes) expires at {}",
+        key_str,
+        key.len(),
+        value.len(),
+        expires_at
+    );
+    let res = bm_data.put_with_expiry(&key, &value, expires_at);
+    if let Err(err) = &res {
+        println!(
+            "BigMap Data: put_with_expiry key {} error: {}",
+            key_str, err
+        );
     }
+    res
 }
 
 #[update]
-fn put_from_index(key_value: (Key, Val)) -> u64 {
+fn put_with_expiry_from_index(args: (Key, Val, u64)) -> Result<u64, BigMapError> {
+    let (key, value, expires_at) = args;
+    put_with_expiry(key, value, expires_at)
+}
+
+#[update]
+fn put_from_index(key_value: (Key, Val)) -> Result<u64, BigMapError> {
     // There is an ugly bug at the moment, where arguments in
     // a function call function(arg1, arg2) from
     // a Canister A to Canister B get converted into function((arg1, arg2))
@@ -112,97 +246,462 @@ fn put_from_index(key_value: (Key, Val)) -> u64 {
 }
 
 #[update]
-fn append_from_index(key_value: (Key, Val)) -> u64 {
+fn append_from_index(key_value: (Key, Val)) -> Result<u64, BigMapError> {
     let (key, value) = key_value;
     append(key, value)
 }
 
 #[update]
-fn delete(key: Key) -> u64 {
+fn begin_upload(key: Key, total_len: u64) -> Result<u64, BigMapError> {
     let bm_data = storage::get_mut::<DataBucket>();
+    authorize_key(bm_data, Access::Write, &key)?;
 
     let key_str = String::from_utf8_lossy(&key);
-    match bm_data.delete(key.clone()) {
-        Ok(deleted_value_len) => {
-            println!(
-                "BigMap Data: delete key {} ({} bytes)",
-                key_str, deleted_value_len
-            );
-            deleted_value_len
-        }
-        Err(err) => {
-            println!("BigMap Data: delete key {} error: {}", key_str, err);
-            0
-        }
+    let res = bm_data.begin_upload(&key, total_len);
+    match &res {
+        Ok(upload_id) => println!(
+            "BigMap Data: begin_upload key {} ({} bytes) upload id {}",
+            key_str, total_len, upload_id
+        ),
+        Err(err) => println!("BigMap Data: begin_upload key {} error: {}", key_str, err),
+    }
+    res
+}
+
+#[update]
+fn begin_upload_from_index(key_total_len: (Key, u64)) -> Result<u64, BigMapError> {
+    let (key, total_len) = key_total_len;
+    begin_upload(key, total_len)
+}
+
+#[update]
+fn put_chunk(upload_id: u64, offset: u64, bytes: Vec<u8>) -> Result<u64, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize_upload(bm_data, upload_id)?;
+
+    let res = bm_data.put_chunk(upload_id, offset, &bytes);
+    if let Err(err) = &res {
+        println!("BigMap Data: put_chunk error: {}", err);
+    }
+    res
+}
+
+#[update]
+fn put_chunk_from_index(args: (u64, u64, Vec<u8>)) -> Result<u64, BigMapError> {
+    let (upload_id, offset, bytes) = args;
+    put_chunk(upload_id, offset, bytes)
+}
+
+#[update]
+fn commit_upload(upload_id: u64) -> Result<u64, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize_upload(bm_data, upload_id)?;
+
+    let res = bm_data.commit_upload(upload_id);
+    match &res {
+        Ok(value_len) => println!(
+            "BigMap Data: commit_upload id {} ({} bytes)",
+            upload_id, value_len
+        ),
+        Err(err) => println!("BigMap Data: commit_upload error: {}", err),
     }
+    res
+}
+
+#[update]
+fn abort_upload(upload_id: u64) -> Result<(), BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize_upload(bm_data, upload_id)?;
+
+    let res = bm_data.abort_upload(upload_id);
+    if let Err(err) = &res {
+        println!("BigMap Data: abort_upload error: {}", err);
+    }
+    res
 }
 
 #[query]
-fn list(key_prefix: Key) -> Vec<Key> {
+fn get_chunk(key: Key, offset: u64, len: u64) -> Result<(u64, Val), BigMapError> {
     let bm_data = storage::get::<DataBucket>();
+    authorize_key(bm_data, Access::Read, &key)?;
 
-    bm_data.list(&key_prefix)
+    let res = bm_data.get_chunk(&key, offset, len);
+    if let Err(err) = &res {
+        println!(
+            "BigMap Data: get_chunk key {} error: {}",
+            String::from_utf8_lossy(&key),
+            err
+        );
+    }
+    res
 }
 
 #[query]
-fn holds_key(key: Key) -> bool {
+fn get_chunk_from_index(args: (Key, u64, u64)) -> Result<(u64, Val), BigMapError> {
+    let (key, offset, len) = args;
+    get_chunk(key, offset, len)
+}
+
+#[update]
+fn delete(key: Key) -> Result<u64, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize_key(bm_data, Access::Write, &key)?;
+
+    let key_str = String::from_utf8_lossy(&key);
+    let res = bm_data.delete(key.clone());
+    match &res {
+        Ok(deleted_value_len) => println!(
+            "BigMap Data: delete key {} ({} bytes)",
+            key_str, deleted_value_len
+        ),
+        Err(err) => println!("BigMap Data: delete key {} error: {}", key_str, err),
+    }
+    res
+}
+
+#[update]
+// Keeps a tombstone, which the BigMap Index copies to the other replicas of the key
+fn delete_from_index(key: Key) -> Result<u64, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    let key_str = String::from_utf8_lossy(&key);
+    let res = bm_data.delete_with_tombstone(&key);
+    match &res {
+        Ok(deleted_value_len) => println!(
+            "BigMap Data: delete key {} ({} bytes)",
+            key_str, deleted_value_len
+        ),
+        Err(err) => println!("BigMap Data: delete key {} error: {}", key_str, err),
+    }
+    res
+}
+
+// The operations tagged with the epoch of the routing table of the client, which are
+// rejected with StaleEpoch if the range of this DataBucket changed since
+#[query]
+fn get_at_epoch(key: Key, epoch: u64) -> Result<Val, BigMapError> {
+    storage::get::<DataBucket>().check_epoch(epoch)?;
+    get(key)
+}
+
+#[update]
+fn put_at_epoch(key: Key, value: Val, epoch: u64) -> Result<u64, BigMapError> {
+    storage::get::<DataBucket>().check_epoch(epoch)?;
+    put(key, value)
+}
+
+#[update]
+fn delete_at_epoch(key: Key, epoch: u64) -> Result<u64, BigMapError> {
+    storage::get::<DataBucket>().check_epoch(epoch)?;
+    delete(key)
+}
+
+#[query]
+fn get_with_version(key: Key) -> Result<(Val, u64), BigMapError> {
+    let bm_data = storage::get::<DataBucket>();
+    authorize_key(bm_data, Access::Read, &key)?;
+
+    bm_data
+        .get_with_version(&key)
+        .map(|(value, version)| (value.into_owned(), version))
+}
+
+#[query]
+fn get_with_version_from_index(key: Key) -> Result<(Val, u64), BigMapError> {
+    get_with_version(key)
+}
+
+#[update]
+// Fails with KeyOutOfRange if the key doesn't belong to this DataBucket
+fn put_if_version(key: Key, value: Val, expected_version: u64) -> Result<CasResult, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize_key(bm_data, Access::Write, &key)?;
+
+    let key_str = String::from_utf8_lossy(&key);
+    let res = bm_data.put_if_version(&key, &value, expected_version);
+    match &res {
+        Ok(result) => println!(
+            "BigMap Data: put_if_version key {} expected version {} => {:?}",
+            key_str, expected_version, result
+        ),
+        Err(err) => println!("BigMap Data: put_if_version key {} error: {}", key_str, err),
+    }
+    res
+}
+
+#[update]
+fn put_if_version_from_index(args: (Key, Val, u64)) -> Result<CasResult, BigMapError> {
+    let (key, value, expected_version) = args;
+    put_if_version(key, value, expected_version)
+}
+
+#[update]
+// Fails with KeyOutOfRange if the key doesn't belong to this DataBucket
+fn delete_if_version(key: Key, expected_version: u64) -> Result<CasResult, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize_key(bm_data, Access::Write, &key)?;
+
+    let key_str = String::from_utf8_lossy(&key);
+    let res = bm_data.delete_if_version(&key, expected_version);
+    match &res {
+        Ok(result) => println!(
+            "BigMap Data: delete_if_version key {} expected version {} => {:?}",
+            key_str, expected_version, result
+        ),
+        Err(err) => println!(
+            "BigMap Data: delete_if_version key {} error: {}",
+            key_str, err
+        ),
+    }
+    res
+}
+
+#[update]
+// Keeps a tombstone, which the BigMap Index copies to the other replicas of the key
+fn delete_if_version_from_index(args: (Key, u64)) -> Result<CasResult, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    let (key, expected_version) = args;
+    let res = bm_data.delete_if_version_with_tombstone(&key, expected_version);
+    if let Err(err) = &res {
+        println!(
+            "BigMap Data: delete_if_version key {} error: {}",
+            String::from_utf8_lossy(&key),
+            err
+        );
+    }
+    res
+}
+
+#[query]
+fn list(key_prefix: Key) -> Result<Vec<Key>, BigMapError> {
+    let bm_data = storage::get::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    Ok(bm_data.list(&key_prefix))
+}
+
+#[query]
+fn list_page(args: (Key, Option<Key>, u32)) -> Result<Vec<Key>, BigMapError> {
     let bm_data = storage::get::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
 
-    bm_data.holds_key(&key)
+    let (key_prefix, start_after, limit) = args;
+    Ok(bm_data.list_page(&key_prefix, &start_after, limit))
 }
 
 #[query]
-fn used_bytes(_: ()) -> u64 {
+fn holds_key(key: Key) -> Result<bool, BigMapError> {
     let bm_data = storage::get::<DataBucket>();
+    authorize_key(bm_data, Access::Read, &key)?;
 
-    bm_data.used_bytes() as u64
+    Ok(bm_data.holds_key(&key))
+}
+
+#[query]
+fn used_bytes(_: ()) -> Result<u64, BigMapError> {
+    let bm_data = storage::get::<DataBucket>();
+
+    Ok(bm_data.used_bytes() as u64)
+}
+
+#[query]
+fn memory_usage() -> Result<MemoryUsage, BigMapError> {
+    let bm_data = storage::get::<DataBucket>();
+
+    Ok(bm_data.memory_usage())
+}
+
+#[query]
+fn version() -> Result<CodeVersion, BigMapError> {
+    Ok(CodeVersion::new(data::STABLE_SCHEMA_VERSION))
 }
 
 #[update]
-fn set_range(range: (Vec<u8>, Vec<u8>)) {
+// The ranges, one for each virtual node in the hash ring, and the epoch of the routing
+// table of the BigMap Index which assigned them
+fn set_range(args: (KeyRangesArg, u64)) -> Result<(), BigMapError> {
     let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
 
-    let (mut range_start, mut range_end) = range;
-    range_start.resize_with(32, Default::default);
-    range_end.resize_with(32, Default::default);
-    let range_start = generic_array::GenericArray::from_slice(&range_start);
-    let range_end = generic_array::GenericArray::from_slice(&range_end);
-    bm_data.set_range(range_start, range_end);
+    let (ranges, epoch) = args;
+    bm_data.set_ranges(&key_ranges(ranges), epoch);
+    Ok(())
+}
+
+fn key_ranges(ranges: KeyRangesArg) -> Vec<KeyRange> {
+    ranges
+        .into_iter()
+        .map(|(mut range_start, mut range_end)| {
+            range_start.resize_with(32, Default::default);
+            range_end.resize_with(32, Default::default);
+            (
+                *generic_array::GenericArray::from_slice(&range_start),
+                *generic_array::GenericArray::from_slice(&range_end),
+            )
+        })
+        .collect()
 }
 
 #[query]
-fn get_relocation_batch(batch_limit_bytes: u64) -> Vec<(Sha2Vec, Key, Val)> {
+fn range_epoch() -> Result<u64, BigMapError> {
     let bm_data = storage::get::<DataBucket>();
 
-    bm_data.get_relocation_batch(batch_limit_bytes)
+    Ok(bm_data.range_epoch())
+}
+
+#[query]
+// Returns the entries to relocate after the key_sha2 start_after, up to batch_limit_bytes
+fn get_relocation_batch(args: (Option<Sha2Vec>, u64)) -> Result<Vec<RelocationEntry>, BigMapError> {
+    let bm_data = storage::get::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    let (start_after, batch_limit_bytes) = args;
+    Ok(bm_data.get_relocation_batch(&start_after, batch_limit_bytes))
+}
+
+#[update]
+fn put_relocation_batch(batch: Vec<RelocationEntry>) -> Result<u64, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    Ok(bm_data.put_relocation_batch(&batch))
+}
+
+#[query]
+// Returns the entries in the ranges after the key_sha2 start_after, up to
+// batch_limit_bytes, to be copied to a replica of the ranges
+fn get_range_batch(
+    args: (KeyRangesArg, Option<Sha2Vec>, u64),
+) -> Result<Vec<RelocationEntry>, BigMapError> {
+    let bm_data = storage::get::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    let (ranges, start_after, batch_limit_bytes) = args;
+    Ok(bm_data.get_range_batch(&key_ranges(ranges), &start_after, batch_limit_bytes))
+}
+
+#[query]
+fn get_replica_entries(keys: Vec<Key>) -> Result<Vec<RelocationEntry>, BigMapError> {
+    let bm_data = storage::get::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    Ok(bm_data.get_replica_entries(&keys))
+}
+
+#[query]
+// Returns the part of the entry from offset, of an entry which is moved in parts
+fn get_entry_part(args: (Key, u64)) -> Result<Option<RelocationEntry>, BigMapError> {
+    let bm_data = storage::get::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    let (key, offset) = args;
+    Ok(bm_data.get_entry_part(&key, offset))
+}
+
+#[update]
+// Returns the version stored for each entry, in the order of the batch
+fn put_replica_batch(
+    batch: Vec<RelocationEntry>,
+) -> Result<Vec<Result<u64, BigMapError>>, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    Ok(bm_data.put_replica_batch(&batch))
+}
+
+#[update]
+fn delete_entries(keys_sha2: Vec<Vec<u8>>) -> Result<(), BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    bm_data.delete_entries(&keys_sha2);
+    Ok(())
+}
+
+#[update]
+// Called by the BigMap Index before the DataBucket takes over keys from another DataBucket
+fn raise_version_counter(version: u64) -> Result<u64, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    Ok(bm_data.raise_version_counter(version))
 }
 
 #[update]
-fn put_relocation_batch(batch: Vec<(Sha2Vec, Key, Val)>) -> u64 {
+// Called by the BigMap Index before the DataBucket is used again for another split
+fn reset(_: ()) -> Result<(), BigMapError> {
     let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
 
-    bm_data.put_relocation_batch(&batch)
+    bm_data.reset()
 }
 
 #[update]
-fn delete_entries(keys_sha2: Vec<Vec<u8>>) {
+// Returns the number of reclaimed expired entries
+fn sweep_expired(limit: u32) -> Result<u64, BigMapError> {
     let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
 
-    bm_data.delete_entries(&keys_sha2)
+    Ok(bm_data.sweep_expired(limit as usize))
 }
 
 #[query]
-fn get_random_key() -> String {
+// Export the snapshot in pages, starting with cursor None and continuing from next_cursor
+fn export_snapshot(args: (Option<SnapshotCursor>, u64)) -> Result<SnapshotPage, BigMapError> {
     let bm_data = storage::get::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
 
-    bm_data.get_random_key(None)
+    let (cursor, limit_bytes) = args;
+    Ok(bm_data.export_snapshot(&cursor, limit_bytes))
 }
 
 #[update]
-fn seed_random_data(num_entries: u32, entry_size_bytes: u32) -> Vec<String> {
+// Import the exported pages in order, returns the number of entries imported so far
+fn import_snapshot(bytes: Vec<u8>) -> Result<u64, BigMapError> {
     let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
 
-    bm_data.seed_random_data(num_entries, entry_size_bytes)
+    let res = bm_data.import_snapshot(&bytes);
+    if let Err(err) = &res {
+        println!("BigMap Data: import_snapshot error: {}", err);
+    }
+    res
+}
+
+#[update]
+// Only the index canister and the admins may change the access policy
+fn set_access_policy(policy: AccessPolicy) -> Result<(), BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    println!("BigMap Data: set_access_policy {:?}", policy);
+    bm_data.set_access_policy(policy)
+}
+
+#[query]
+fn access_policy() -> Result<AccessPolicy, BigMapError> {
+    let bm_data = storage::get::<DataBucket>();
+
+    Ok(bm_data.access_policy().clone())
+}
+
+#[query]
+fn get_random_key() -> Result<String, BigMapError> {
+    let bm_data = storage::get::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    Ok(bm_data.get_random_key(None))
+}
+
+#[update]
+fn seed_random_data(num_entries: u32, entry_size_bytes: u32) -> Result<Vec<String>, BigMapError> {
+    let bm_data = storage::get_mut::<DataBucket>();
+    authorize(bm_data, Access::Admin)?;
+
+    Ok(bm_data.seed_random_data(num_entries, entry_size_bytes))
 }
 
 #[init]
@@ -212,6 +711,48 @@ fn initialize() {
     println!("BigMap Data: initialize");
     let can_id = ic_cdk::reflection::id().into();
     bm_data.set_canister_id(can_id);
+    // The caller of init is the canister which installed the code, normally the BigMap Index
+    bm_data.record_index_canister(ic_cdk::reflection::caller().into());
+    bm_data.certify();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let bm_data = storage::get::<DataBucket>();
+
+    println!(
+        "BigMap Data: pre_upgrade saving {} entries",
+        bm_data.entries.len()
+    );
+    match upgrade::stable_save(&data::STABLE_MAGIC, data::STABLE_SCHEMA_VERSION, |writer| {
+        bm_data.write_stable_payload(writer)
+    }) {
+        Ok(payload_len) => println!("BigMap Data: pre_upgrade saved {} bytes", payload_len),
+        // Trapping aborts the upgrade, so the canister keeps running the old code and state
+        Err(err) => ic_cdk::trap(&format!("BigMap Data: pre_upgrade failed: {}", err)),
+    }
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let bm_data = storage::get_mut::<DataBucket>();
+
+    match upgrade::stable_restore(&data::STABLE_MAGIC, |schema_version, reader| {
+        DataBucket::read_stable_payload(schema_version, reader)
+    }) {
+        Ok(Some(restored)) => *bm_data = restored,
+        Ok(None) => println!("BigMap Data: post_upgrade found no saved state"),
+        Err(err) => ic_cdk::trap(&format!("BigMap Data: post_upgrade failed: {}", err)),
+    }
+    // canister_init is not invoked on upgrade
+    bm_data.set_canister_id(ic_cdk::reflection::id().into());
+    // State saved before the access policy was persisted has no index canister recorded
+    bm_data.record_index_canister(ic_cdk::reflection::caller().into());
+    bm_data.certify();
+    println!(
+        "BigMap Data: post_upgrade restored {} entries",
+        bm_data.entries.len()
+    );
 }
 
 fn main() {}
@@ -1,151 +1,400 @@
-use ::bigmap::{index::BigmapIdx, CanisterId, Key, Val};
+use ::bigmap::data::{CasResult, Codec};
+use ::bigmap::index::call::CallPolicy;
+use ::bigmap::index::code_upgrade::UpgradeJob;
+use ::bigmap::index::namespace::{self, Namespace, NamespaceAcl, Permission};
+use ::bigmap::index::schedule::{MaintenanceSchedule, MaintenanceStatus};
+use ::bigmap::index::{self, BigmapIdx};
+use ::bigmap::{upgrade, BigMapError, CanisterId, Key, Val};
 #[cfg(target_arch = "wasm32")]
 use ic_cdk::println;
 use ic_cdk::storage;
 use ic_cdk_macros::*;
 
+fn caller() -> CanisterId {
+    ic_cdk::reflection::caller().into()
+}
+
+// The current namespace of the caller, if the caller has the permission there
+// The keys are scoped to the namespace before they are passed to the BigmapIdx
+fn namespace_for(bigmap_idx: &BigmapIdx, permission: Permission) -> Result<Namespace, BigMapError> {
+    bigmap_idx.namespaces().authorize(&caller(), permission)
+}
+
+// The current namespace of the caller, if the caller has all of the permissions there
+fn namespace_for_all(
+    bigmap_idx: &BigmapIdx,
+    permissions: &[Permission],
+) -> Result<Namespace, BigMapError> {
+    bigmap_idx
+        .namespaces()
+        .authorize_all(&caller(), permissions)
+}
+
+// Returns Unauthorized unless the caller is one of the admins of the BigmapIdx
+fn authorize_admin(bigmap_idx: &BigmapIdx) -> Result<(), BigMapError> {
+    let caller = caller();
+    bigmap_idx.authorize_admin(&caller).map_err(|err| {
+        println!("BigMap Index: caller {} is not an admin", caller);
+        err
+    })
+}
+
 #[query]
-async fn get(key: Key) -> Option<Val> {
+async fn get(key: Key) -> Result<Val, BigMapError> {
     let bigmap_idx = storage::get::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Read)?;
+    let key = namespace::scoped_key(&ns, &key);
 
     bigmap_idx.get(&key).await
 }
 
 #[update]
-async fn put(key: Key, value: Val) -> u64 {
+async fn put(key: Key, value: Val) -> Result<u64, BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
 
-    println!("BigMap Index: put key {}", String::from_utf8_lossy(&key));
+    println!(
+        "BigMap Index: put key {} in namespace {}",
+        String::from_utf8_lossy(&key),
+        ns
+    );
+    let key = namespace::scoped_key(&ns, &key);
 
     bigmap_idx.put(&key, &value).await
 }
 
 #[update]
-// Returns the number of successful puts
-async fn batch_put(batch: Vec<(Key, Val)>) -> u64 {
+// Returns the result of the put for each entry, in the order of the batch
+async fn batch_put(batch: Vec<(Key, Val)>) -> Result<Vec<Result<u64, BigMapError>>, BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
-    if batch.len() == 1 {
-        let (key, value) = batch.get(0).unwrap();
-        println!("BigMap Index: put key {}", String::from_utf8_lossy(key));
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
 
-        bigmap_idx.put(key, value).await;
-        1
-    } else {
-        println!("BigMap Data: put batch of {} entries", batch.len());
+    println!("BigMap Index: put batch of {} entries", batch.len());
+    let batch: Vec<(Key, Val)> = batch
+        .into_iter()
+        .map(|(key, value)| (namespace::scoped_key(&ns, &key), value))
+        .collect();
 
-        bigmap_idx.batch_put(&batch).await
-    }
+    Ok(bigmap_idx.batch_put(&batch).await)
 }
 
 #[update]
-async fn append(key: Key, value: Val) -> u64 {
+async fn append(key: Key, value: Val) -> Result<u64, BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
+    let key = namespace::scoped_key(&ns, &key);
 
     bigmap_idx.append(&key, &value).await
 }
 
 #[update]
-async fn delete(key: Key) -> u64 {
+async fn put_with_codec(key: Key, value: Val, codec: Codec) -> Result<u64, BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
+    let key = namespace::scoped_key(&ns, &key);
+
+    bigmap_idx.put_with_codec(&key, &value, codec).await
+}
+
+#[update]
+async fn put_with_expiry(key: Key, value: Val, expires_at: u64) -> Result<u64, BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
+    let key = namespace::scoped_key(&ns, &key);
+
+    bigmap_idx.put_with_expiry(&key, &value, expires_at).await
+}
+
+#[query]
+async fn get_with_version(key: Key) -> Result<(Val, u64), BigMapError> {
     let bigmap_idx = storage::get::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Read)?;
+    let key = namespace::scoped_key(&ns, &key);
 
-    match bigmap_idx.lookup_put(&key) {
-        Some(can_id) => {
-            let can_id: ic_cdk::CanisterId = can_id.0.into();
-            println!(
-                "BigMap Index: delete key {} @CanisterId {}",
-                String::from_utf8_lossy(&key),
-                can_id
-            );
-            ic_cdk::call(can_id.clone(), "delete", Some(key))
-                .await
-                .expect(&format!(
-                    "BigMap index: delete call to CanisterId {} failed",
-                    can_id
-                ))
-        }
-        None => {
-            println!(
-                "BigMap Index: no data canister suitable for key {}",
-                String::from_utf8_lossy(&key)
-            );
-            0
-        }
-    }
+    bigmap_idx.get_with_version(&key).await
+}
+
+#[update]
+async fn put_if_version(
+    key: Key,
+    value: Val,
+    expected_version: u64,
+) -> Result<CasResult, BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
+    let key = namespace::scoped_key(&ns, &key);
+
+    bigmap_idx
+        .put_if_version(&key, &value, expected_version)
+        .await
+}
+
+#[update]
+async fn delete_if_version(key: Key, expected_version: u64) -> Result<CasResult, BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
+    let key = namespace::scoped_key(&ns, &key);
+
+    bigmap_idx.delete_if_version(&key, expected_version).await
+}
+
+#[update]
+async fn begin_upload(key: Key, total_len: u64) -> Result<u64, BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
+    let key = namespace::scoped_key(&ns, &key);
+
+    bigmap_idx.begin_upload(&ns, &key, total_len).await
+}
+
+#[update]
+async fn put_chunk(upload_id: u64, offset: u64, bytes: Vec<u8>) -> Result<u64, BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
+
+    bigmap_idx.put_chunk(&ns, upload_id, offset, &bytes).await
+}
+
+#[update]
+async fn commit_upload(upload_id: u64) -> Result<u64, BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
+
+    bigmap_idx.commit_upload(&ns, upload_id).await
+}
+
+#[update]
+async fn abort_upload(upload_id: u64) -> Result<(), BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
+
+    bigmap_idx.abort_upload(&ns, upload_id).await
+}
+
+#[query]
+async fn get_chunk(key: Key, offset: u64, len: u64) -> Result<(u64, Val), BigMapError> {
+    let bigmap_idx = storage::get::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Read)?;
+    let key = namespace::scoped_key(&ns, &key);
+
+    bigmap_idx.get_chunk(&key, offset, len).await
+}
+
+#[update]
+async fn delete(key: Key) -> Result<u64, BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
+    let key = namespace::scoped_key(&ns, &key);
+
+    bigmap_idx.delete(&key).await
 }
 
 #[query]
-async fn list(key_prefix: Key) -> Vec<Key> {
+async fn list(key_prefix: Key) -> Result<Vec<Key>, BigMapError> {
     let bigmap_idx = storage::get::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::List)?;
+
+    let keys = bigmap_idx
+        .list(&namespace::scoped_key(&ns, &key_prefix))
+        .await?;
+    Ok(keys
+        .iter()
+        .filter_map(|key| namespace::unscoped_key(&ns, key))
+        .collect())
+}
 
-    bigmap_idx.list(&key_prefix).await
+#[query]
+async fn list_page(
+    key_prefix: Key,
+    cursor: Option<Vec<u8>>,
+    limit: u32,
+) -> Result<(Vec<Key>, Option<Vec<u8>>), BigMapError> {
+    let bigmap_idx = storage::get::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::List)?;
+
+    // The cursor holds the last scoped key, so it only continues within the namespace
+    let (keys, cursor) = bigmap_idx
+        .list_page(&namespace::scoped_key(&ns, &key_prefix), &cursor, limit)
+        .await?;
+    let keys = keys
+        .iter()
+        .filter_map(|key| namespace::unscoped_key(&ns, key))
+        .collect();
+    Ok((keys, cursor))
 }
 
 #[update]
-async fn add_data_buckets(can_vec: Vec<String>) {
+async fn add_data_buckets(can_vec: Vec<String>) -> Result<(), BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    authorize_admin(bigmap_idx)?;
 
     let mut cans: Vec<CanisterId> = Vec::new();
     for can_text in can_vec {
-        let can_id = ic_cdk::CanisterId::from_str(&can_text).unwrap();
-        cans.push(can_id.into());
+        match ic_cdk::CanisterId::from_str(&can_text) {
+            Ok(can_id) => cans.push(can_id.into()),
+            Err(err) => return Err(BigMapError::InvalidArgument(err.to_string())),
+        }
     }
-    bigmap_idx.add_canisters(cans).await;
+    bigmap_idx.add_canisters(cans).await
 }
 
 #[query]
-async fn lookup_data_bucket_for_put(key: Key) -> Option<String> {
+async fn lookup_data_bucket_for_put(key: Key) -> Result<String, BigMapError> {
     let bigmap_idx = storage::get::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
 
-    match bigmap_idx.lookup_put(&key) {
-        Some(can_id) => Some(format!("{}", can_id)),
-        None => None,
-    }
+    let can_id = bigmap_idx.lookup_put(&namespace::scoped_key(&ns, &key))?;
+    Ok(format!("{}", can_id))
+}
+
+#[query]
+// The key as held by the data buckets, for accessing them directly
+fn scoped_key(key: Key) -> Result<Key, BigMapError> {
+    let bigmap_idx = storage::get::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Read)?;
+
+    Ok(namespace::scoped_key(&ns, &key))
+}
+
+#[query]
+// The hash ring with the certificate, for verifying the routing of the keys to the data buckets
+fn get_ring_certified() -> Result<index::CertifiedRing, BigMapError> {
+    let bigmap_idx = storage::get::<BigmapIdx>();
+
+    Ok(bigmap_idx.get_ring_certified())
+}
+
+#[query]
+// The key ranges of the data buckets with the epoch, for sending the operations to the data
+// buckets directly, tagged with the epoch
+fn get_routing_table() -> Result<index::RoutingTable, BigMapError> {
+    let bigmap_idx = storage::get::<BigmapIdx>();
+
+    Ok(bigmap_idx.get_routing_table())
 }
 
 #[query]
-async fn lookup_data_bucket_for_get(key: Key) -> Option<String> {
+async fn lookup_data_bucket_for_get(key: Key) -> Result<String, BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Read)?;
 
-    match bigmap_idx.lookup_get(&key).await {
-        Some(can_id) => {
-            let can_id = format!("{}", can_id);
-            println!(
-                "BigMap Index: lookup_data_bucket_for_get key {} => {}",
-                String::from_utf8_lossy(&key),
-                can_id
-            );
-            Some(can_id)
-        }
-        None => None,
-    }
+    let can_id = format!(
+        "{}",
+        bigmap_idx
+            .lookup_get(&namespace::scoped_key(&ns, &key))
+            .await?
+    );
+    println!(
+        "BigMap Index: lookup_data_bucket_for_get key {} => {}",
+        String::from_utf8_lossy(&key),
+        can_id
+    );
+    Ok(can_id)
 }
 
 #[query]
-async fn get_random_key() -> String {
+// A key which is not used yet in the current namespace of the caller, to put a new entry
+async fn get_random_key() -> Result<String, BigMapError> {
     let bigmap_idx = storage::get::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
 
-    bigmap_idx.get_random_key().await
+    bigmap_idx.get_random_key(&ns).await
 }
 
 #[update]
-fn set_used_bytes_threshold(threshold: u32) {
+fn set_used_bytes_threshold(threshold: u32) -> Result<(), BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    authorize_admin(bigmap_idx)?;
 
     bigmap_idx.set_used_bytes_threshold(threshold);
+    Ok(())
+}
+
+#[update]
+// Set the number of data buckets which hold each key, while there is one data bucket
+fn set_replication_factor(replication_factor: u32) -> Result<(), BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    authorize_admin(bigmap_idx)?;
+
+    bigmap_idx.set_replication_factor(replication_factor)
+}
+
+#[update]
+// Set the number of nodes of each data bucket in the hash ring, while there is one data bucket
+fn set_virtual_nodes(virtual_nodes: u32) -> Result<(), BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    authorize_admin(bigmap_idx)?;
+
+    bigmap_idx.set_virtual_nodes(virtual_nodes)
+}
+
+#[update]
+// Set the share of the keys of a data bucket relative to the others, used from its next split
+fn set_data_bucket_weight(can_text: String, weight: u32) -> Result<(), BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    authorize_admin(bigmap_idx)?;
+
+    let can_id = match ic_cdk::CanisterId::from_str(&can_text) {
+        Ok(can_id) => can_id.into(),
+        Err(err) => return Err(BigMapError::InvalidArgument(err.to_string())),
+    };
+    bigmap_idx.set_data_bucket_weight(&can_id, weight)
 }
 
 #[update]
-async fn maintenance() -> String {
+// Set the retries and the backoff of the calls to the data buckets
+fn set_call_policy(call_policy: CallPolicy) -> Result<(), BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    authorize_admin(bigmap_idx)?;
+
+    bigmap_idx.set_call_policy(call_policy)
+}
+
+#[update]
+async fn maintenance() -> Result<String, BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
 
     bigmap_idx.maintenance().await
 }
 
+#[update]
+// Set the schedule of the maintenance run by the heartbeat
+fn set_maintenance_schedule(schedule: MaintenanceSchedule) -> Result<(), BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    authorize_admin(bigmap_idx)?;
+
+    bigmap_idx.set_maintenance_schedule(schedule)
+}
+
+#[query]
+// The schedule of the maintenance, and the result of its last tick
+fn maintenance_schedule() -> MaintenanceStatus {
+    let bigmap_idx = storage::get::<BigmapIdx>();
+
+    bigmap_idx.maintenance_status()
+}
+
+#[heartbeat]
+async fn heartbeat() {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+
+    bigmap_idx.maintenance_tick().await;
+}
+
+#[update]
+// Abandon the data bucket split in progress, the next maintenance moves the entries back
+async fn relocation_rollback() -> Result<(), BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    authorize_admin(bigmap_idx)?;
+
+    bigmap_idx.relocation_rollback().await
+}
+
 #[query]
-async fn status() -> String {
+async fn status() -> Result<String, BigMapError> {
     let bigmap_idx = storage::get::<BigmapIdx>();
 
-    bigmap_idx.status().await
+    bigmap_idx.status(&caller()).await
 }
 
 #[init]
@@ -156,12 +405,55 @@ fn initialize() {
     println!("BigMap Index: initialize");
     bigmap_idx.reset();
     bigmap_idx.set_canister_id(can_id);
+    bigmap_idx.record_admin(caller());
+    bigmap_idx.certify_ring();
+    ic_cdk::setup();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let bigmap_idx = storage::get::<BigmapIdx>();
+
+    match upgrade::stable_save(
+        &index::STABLE_MAGIC,
+        index::STABLE_SCHEMA_VERSION,
+        |writer| bigmap_idx.write_stable_payload(writer),
+    ) {
+        Ok(payload_len) => println!(
+            "BigMap Index: pre_upgrade saved state ({} bytes)",
+            payload_len
+        ),
+        // Trapping aborts the upgrade, so the canister keeps running the old code and state
+        Err(err) => ic_cdk::trap(&format!("BigMap Index: pre_upgrade failed: {}", err)),
+    }
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+
+    match upgrade::stable_restore(&index::STABLE_MAGIC, |schema_version, reader| {
+        BigmapIdx::read_stable_payload(schema_version, reader)
+    }) {
+        Ok(Some(restored)) => *bigmap_idx = restored,
+        Ok(None) => {
+            println!("BigMap Index: post_upgrade found no saved state");
+            bigmap_idx.reset();
+        }
+        Err(err) => ic_cdk::trap(&format!("BigMap Index: post_upgrade failed: {}", err)),
+    }
+    // canister_init is not invoked on upgrade
+    bigmap_idx.set_canister_id(ic_cdk::reflection::id().into());
+    // The admins saved before version 2 are unknown, the upgrading controller becomes one
+    bigmap_idx.record_admin(caller());
+    bigmap_idx.certify_ring();
     ic_cdk::setup();
 }
 
 #[update]
-async fn set_data_bucket_canister_wasm_binary(wasm_binary: Vec<u8>) {
+async fn set_data_bucket_canister_wasm_binary(wasm_binary: Vec<u8>) -> Result<(), BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    authorize_admin(bigmap_idx)?;
     println!(
         "BigMap Index: set_data_bucket_canister_wasm_binary ({} bytes)",
         wasm_binary.len()
@@ -173,8 +465,9 @@ async fn set_data_bucket_canister_wasm_binary(wasm_binary: Vec<u8>) {
 }
 
 #[update]
-async fn set_search_canister_wasm_binary(wasm_binary: Vec<u8>) {
+async fn set_search_canister_wasm_binary(wasm_binary: Vec<u8>) -> Result<(), BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    authorize_admin(bigmap_idx)?;
     println!(
         "BigMap Index: set_search_canister_wasm_binary ({} bytes)",
         wasm_binary.len()
@@ -186,8 +479,53 @@ async fn set_search_canister_wasm_binary(wasm_binary: Vec<u8>) {
 }
 
 #[update]
-async fn put_and_fts_index(key: Key, document: String) -> u64 {
+// Install the stored wasm binaries in the existing canisters, one after the other
+async fn upgrade_canisters(expected_version: Option<String>) -> Result<UpgradeJob, BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    authorize_admin(bigmap_idx)?;
+
+    bigmap_idx.upgrade_canisters(expected_version).await
+}
+
+#[update]
+// Replace the admins, which may call the admin operations
+fn set_admins(admins: Vec<String>) -> Result<(), BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    authorize_admin(bigmap_idx)?;
+
+    let mut can_ids: Vec<CanisterId> = Vec::new();
+    for can_text in admins {
+        match ic_cdk::CanisterId::from_str(&can_text) {
+            Ok(can_id) => can_ids.push(can_id.into()),
+            Err(err) => return Err(BigMapError::InvalidArgument(err.to_string())),
+        }
+    }
+    println!("BigMap Index: set_admins {:?}", can_ids);
+    bigmap_idx.set_admins(can_ids)
+}
+
+#[query]
+fn admins() -> Vec<String> {
+    let bigmap_idx = storage::get::<BigmapIdx>();
+
+    bigmap_idx
+        .admins()
+        .iter()
+        .map(|can_id| can_id.to_string())
+        .collect()
+}
+
+#[query]
+fn upgrade_status() -> Option<UpgradeJob> {
+    let bigmap_idx = storage::get::<BigmapIdx>();
+
+    bigmap_idx.upgrade_status().cloned()
+}
+
+#[update]
+async fn put_and_fts_index(key: Key, document: String) -> Result<u64, BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
 
     if document.len() > 100 {
         println!(
@@ -203,33 +541,91 @@ async fn put_and_fts_index(key: Key, document: String) -> u64 {
         );
     }
 
+    let key = namespace::scoped_key(&ns, &key);
     bigmap_idx.put_and_fts_index(&key, &document).await
 }
 
 #[update]
-async fn batch_put_and_fts_index(doc_vec: Vec<(Key, String)>) -> u64 {
+async fn batch_put_and_fts_index(doc_vec: Vec<(Key, String)>) -> Result<u64, BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
 
+    let doc_vec: Vec<(Key, String)> = doc_vec
+        .into_iter()
+        .map(|(key, document)| (namespace::scoped_key(&ns, &key), document))
+        .collect();
     bigmap_idx.batch_put_and_fts_index(&doc_vec).await
 }
 
 #[update]
-async fn remove_from_fts_index(key: Key) {
+async fn remove_from_fts_index(key: Key) -> Result<(), BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    let ns = namespace_for(bigmap_idx, Permission::Write)?;
 
     println!(
         "BigMap Search Index: remove key {}",
         String::from_utf8_lossy(&key)
     );
+    let key = namespace::scoped_key(&ns, &key);
 
     bigmap_idx.remove_from_fts_index(&key).await
 }
 
 #[query]
-async fn search(query: String) -> (u64, Vec<(Key, Val)>) {
+async fn search(query: String) -> Result<(u64, Vec<(Key, Val)>), BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+    // The search reveals the keys and the values
+    let ns = namespace_for_all(bigmap_idx, &[Permission::Read, Permission::List])?;
+
+    let (results_len, results) = bigmap_idx.search(&query, &namespace::scope(&ns)).await?;
+    let results = results
+        .into_iter()
+        .filter_map(|(key, value)| Some((namespace::unscoped_key(&ns, &key)?, value)))
+        .collect();
+    Ok((results_len, results))
+}
+
+#[update]
+// Create a namespace owned by the caller
+fn create_namespace(name: Namespace) -> Result<(), BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+
+    println!("BigMap Index: create_namespace {}", name);
+    bigmap_idx.namespaces_mut().create(&caller(), &name)
+}
+
+#[update]
+// Only the owner of the namespace may replace its ACL
+fn set_namespace_acl(name: Namespace, acl: NamespaceAcl) -> Result<(), BigMapError> {
+    let bigmap_idx = storage::get_mut::<BigmapIdx>();
+
+    println!("BigMap Index: set_namespace_acl {}", name);
+    bigmap_idx.namespaces_mut().set_acl(&caller(), &name, acl)
+}
+
+#[query]
+fn get_namespace_acl(name: Namespace) -> Result<NamespaceAcl, BigMapError> {
+    let bigmap_idx = storage::get::<BigmapIdx>();
+
+    bigmap_idx.namespaces().acl(&caller(), &name).cloned()
+}
+
+#[update]
+// Use the namespace for the following calls of the caller, or its default namespace if None
+fn use_namespace(name: Option<Namespace>) -> Result<(), BigMapError> {
     let bigmap_idx = storage::get_mut::<BigmapIdx>();
 
-    bigmap_idx.search(&query).await
+    bigmap_idx
+        .namespaces_mut()
+        .select(&caller(), name.as_deref())
+}
+
+#[query]
+// The namespaces the caller has access to
+fn namespaces() -> Result<Vec<Namespace>, BigMapError> {
+    let bigmap_idx = storage::get::<BigmapIdx>();
+
+    Ok(bigmap_idx.namespaces().visible(&caller()))
 }
 
 fn main() {}
@@ -1,11 +1,13 @@
-use bigmap::{search::SearchIndexer, Key};
+use bigmap::memory::MemoryUsage;
+use bigmap::upgrade::{self, CodeVersion};
+use bigmap::{search, search::SearchIndexer, BigMapError, Key};
 #[cfg(target_arch = "wasm32")]
 use ic_cdk::println;
 use ic_cdk::storage;
 use ic_cdk_macros::*;
 
 #[update]
-fn add_to_search_index(key_doc: (Key, String)) {
+fn add_to_search_index(key_doc: (Key, String)) -> Result<(), BigMapError> {
     let search = storage::get_mut::<SearchIndexer>();
 
     let (key, document) = key_doc;
@@ -23,21 +25,22 @@ fn add_to_search_index(key_doc: (Key, String)) {
         );
     }
     search.add_to_index(&key, &document);
+    Ok(())
 }
 
 #[update]
-fn batch_add_to_search_index(doc_vec: Vec<(Key, String)>) -> u64 {
+fn batch_add_to_search_index(doc_vec: Vec<(Key, String)>) -> Result<u64, BigMapError> {
     let search = storage::get_mut::<SearchIndexer>();
 
     println!(
         "BigMap Search Index: batch_put_and_fts_index {} entries",
         doc_vec.len()
     );
-    search.batch_add_to_index(&doc_vec)
+    Ok(search.batch_add_to_index(&doc_vec))
 }
 
 #[update]
-fn remove_from_search_index(key: Key) {
+fn remove_from_search_index(key: Key) -> Result<(), BigMapError> {
     let search = storage::get_mut::<SearchIndexer>();
 
     println!(
@@ -45,20 +48,68 @@ fn remove_from_search_index(key: Key) {
         String::from_utf8_lossy(&key)
     );
     search.remove_key(&key);
+    Ok(())
 }
 
 #[query]
-fn search_keys_by_query(query: String) -> Vec<Key> {
+fn search_keys_by_query(query: String) -> Result<Vec<Key>, BigMapError> {
     let search = storage::get::<SearchIndexer>();
 
-    search.search_keys_by_query(&query)
+    Ok(search.search_keys_by_query(&query))
 }
 
 #[query]
-fn used_bytes() -> u64 {
+fn used_bytes() -> Result<u64, BigMapError> {
     let search = storage::get::<SearchIndexer>();
 
-    search.used_bytes() as u64
+    Ok(search.used_bytes() as u64)
+}
+
+#[query]
+fn memory_usage() -> Result<MemoryUsage, BigMapError> {
+    let search = storage::get::<SearchIndexer>();
+
+    Ok(search.memory_usage())
+}
+
+#[query]
+fn version() -> Result<CodeVersion, BigMapError> {
+    Ok(CodeVersion::new(search::STABLE_SCHEMA_VERSION))
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let search = storage::get::<SearchIndexer>();
+
+    match upgrade::stable_save(
+        &search::STABLE_MAGIC,
+        search::STABLE_SCHEMA_VERSION,
+        |writer| search.write_stable_payload(writer),
+    ) {
+        Ok(payload_len) => println!(
+            "BigMap Search Index: pre_upgrade saved state ({} bytes)",
+            payload_len
+        ),
+        // Trapping aborts the upgrade, so the canister keeps running the old code and state
+        Err(err) => ic_cdk::trap(&format!("BigMap Search Index: pre_upgrade failed: {}", err)),
+    }
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let search = storage::get_mut::<SearchIndexer>();
+
+    match upgrade::stable_restore(&search::STABLE_MAGIC, |schema_version, reader| {
+        SearchIndexer::read_stable_payload(schema_version, reader)
+    }) {
+        Ok(Some(restored)) => *search = restored,
+        // Released before the state was persisted, the documents have to be indexed again
+        Ok(None) => println!("BigMap Search Index: post_upgrade found no saved state"),
+        Err(err) => ic_cdk::trap(&format!(
+            "BigMap Search Index: post_upgrade failed: {}",
+            err
+        )),
+    }
 }
 
 fn main() {}
@@ -50,6 +50,22 @@ use std::convert::TryFrom;
 pub async fn subnet_install_canister_code(
     canister_id: CanisterId,
     wasm_module: Vec<u8>,
+) -> Result<(), String> {
+    subnet_install_code(canister_id, wasm_module, CanisterInstallMode::Install).await
+}
+
+// Replace the code of the canister, which keeps its state through pre_upgrade and post_upgrade
+pub async fn subnet_upgrade_canister_code(
+    canister_id: CanisterId,
+    wasm_module: Vec<u8>,
+) -> Result<(), String> {
+    subnet_install_code(canister_id, wasm_module, CanisterInstallMode::Upgrade).await
+}
+
+async fn subnet_install_code(
+    canister_id: CanisterId,
+    wasm_module: Vec<u8>,
+    mode: CanisterInstallMode,
 ) -> Result<(), String> {
     if wasm_module.is_empty() {
         return Err("Empty wasm module provided for canister installation".to_string());
@@ -58,7 +74,7 @@ pub async fn subnet_install_canister_code(
     let management_canister = ic_cdk::CanisterId::from(Vec::new());
 
     let install_code_args = InstallCodeArgs {
-        mode: CanisterInstallMode::Install,
+        mode,
         canister_id: candid::Principal::try_from(canister_id.0)
             .expect("Failed to make principal from canister_id"),
         wasm_module,
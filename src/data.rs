@@ -1,20 +1,440 @@
-use crate::{calc_sha256, sha256_digest_from_vec, CanisterId, Key, Sha256Digest, Sha2Vec, Val};
+use crate::memory::{btree_element_bytes, MemoryUsage};
+use crate::merkle::{self, MerkleTree, Witness};
+use crate::namespace;
+use crate::upgrade::StableMagic;
+use crate::{
+    calc_sha256, data_certificate, set_certified_data, sha256_digest_from_vec, time_now,
+    BigMapError, CanisterId, Key, Sha256Digest, Sha2Vec, Val,
+};
+use candid::CandidType;
 #[cfg(target_arch = "wasm32")]
 use ic_cdk::println;
-use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use snapshot::{SnapshotCursor, SnapshotDecoder, SnapshotHeader, SnapshotPage};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::{TryFrom, TryInto};
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::ops::Bound::{Excluded, Included, Unbounded};
 // use std::hash::{BuildHasherDefault, Hash, Hasher};
 // use wyhash::WyHash;
 
 // pub type DetHashMap<K, V> = HashMap<K, V, BuildHasherDefault<WyHash>>;
 
+pub mod snapshot;
+
+// The keys whose Sha256 is in [range_start..range_end), wrapping around the end of the key
+// space if range_start > range_end
+pub type KeyRange = (Sha256Digest, Sha256Digest);
+
 #[derive(Clone, Debug, Default)]
 pub struct DataBucket {
-    pub entries: BTreeMap<Sha256Digest, (Key, Val)>, // Can be DetHashMap
-    range_start: Sha256Digest,                       // This DataBucket holds entries
-    range_end: Sha256Digest,                         // in [range_start..range_end]
-    used_bytes: usize,
+    pub entries: BTreeMap<Sha256Digest, Entry>, // Can be DetHashMap
+    keys: BTreeMap<Key, Sha256Digest>,          // Secondary index, ordered by key
+    expiries: BTreeSet<(u64, Sha256Digest)>,    // Entries with a TTL, ordered by expiry time
+    tombstones: BTreeMap<Sha256Digest, Tombstone>, // Entries deleted by the BigMap Index
+    merkle: MerkleTree,                         // Over all entries, the root hash is certified
+    ranges: Vec<KeyRange>,                      // This DataBucket holds the entries in these
+    range_epoch: u64, // Epoch of the routing table of the BigMap Index which set the ranges
+    usage: MemoryUsage, // Breakdown of the memory used by the structures above
+    entries_bytes: u64, // Keys and values of the entries as stored, and the Sha256 of the keys
+    capacity_bytes: u64, // Writes which would grow the memory usage over this are rejected
+    access: AccessPolicy, // Who may call the mutating endpoints
+    version_counter: u64, // The highest entry version ever assigned or received
+    default_codec: Codec, // Used to store the values, unless a put requests a different codec
     bytes_to_send: usize,
     id: CanisterId,
+    uploads: BTreeMap<u64, Upload>, // Chunked uploads in progress, not kept across upgrades
+    next_upload_id: u64,
+    // Entries being received in parts, with the time of the last part, see ValuePart
+    // Not kept across upgrades, the sender starts over with the first part
+    relocation_parts: BTreeMap<Sha256Digest, (RelocationEntry, u64)>,
+    snapshot_import: Option<SnapshotDecoder>, // Import in progress, not kept across upgrades
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Entry {
+    pub key: Key,
+    pub value: Val, // As stored, encoded with the codec
+    pub codec: Codec,
+    // Increases on every write of the entry, 0 is reserved for entries which don't exist
+    pub version: u64,
+    // Time in nanoseconds since 1970-01-01 (as from ic_cdk::time) after which the
+    // entry is treated as absent, and can be reclaimed by sweep_expired
+    pub expires_at: Option<u64>,
+}
+
+// An entry moved between DataBuckets during rebalancing
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RelocationEntry {
+    pub key_sha2: Sha2Vec,
+    pub key: Key,
+    pub value: Val, // Encoded with the codec, relocation doesn't decode the values
+    pub codec: Codec,
+    pub version: u64,
+    pub expires_at: Option<u64>,
+    // Set if the value is only a part of the encoded value, since the entry is too large
+    // to be moved in one message
+    pub part: Option<ValuePart>,
+    pub deleted: bool, // A tombstone, which has no value, see Tombstone
+}
+
+// An entry deleted through the BigMap Index, with the version of the delete
+// With replicas, the delete is copied to the other replicas as the tombstone, and a
+// replica which missed it is repaired with the tombstone, instead of the read repair
+// restoring the entry from that replica. Tombstones are reclaimed after TOMBSTONE_TTL_NS.
+#[derive(Clone, Debug)]
+struct Tombstone {
+    key: Key,
+    version: u64,
+    deleted_at: u64, // Time of the delete, in nanoseconds since 1970-01-01
+}
+
+// An entry larger than RELOCATION_PART_BYTES is moved in parts of up to that size, in order
+// of the offset. The receiving DataBucket stores the entry once it received the last part.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ValuePart {
+    pub offset: u64,    // Of the part in the encoded value
+    pub total_len: u64, // Of the encoded value
+}
+
+// Encoding of the stored values, tagged on every entry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum Codec {
+    #[default]
+    Raw,
+    Deflate,
+}
+
+// Compression level used for the Deflate codec, from 0 (fastest) to 10 (smallest)
+const DEFLATE_LEVEL: u8 = 6;
+
+impl Codec {
+    // Returns the codec actually used and the encoded value
+    // Values which don't get smaller with compression are stored Raw, and so are the
+    // values larger than a put, so that they can be read in chunks without decompressing
+    pub fn encode(self, value: &[u8]) -> (Codec, Val) {
+        match self {
+            Codec::Raw => (Codec::Raw, value.to_vec()),
+            Codec::Deflate if value.len() as u64 > PUT_VALUE_SIZE_MAX => {
+                (Codec::Raw, value.to_vec())
+            }
+            Codec::Deflate => {
+                let compressed = miniz_oxide::deflate::compress_to_vec(value, DEFLATE_LEVEL);
+                if compressed.len() < value.len() {
+                    (Codec::Deflate, compressed)
+                } else {
+                    (Codec::Raw, value.to_vec())
+                }
+            }
+        }
+    }
+
+    // A compressed value is at most PUT_VALUE_SIZE_MAX bytes, larger ones are rejected
+    pub fn decode(self, encoded: &[u8]) -> Result<Val, String> {
+        match self {
+            Codec::Raw => Ok(encoded.to_vec()),
+            Codec::Deflate => miniz_oxide::inflate::decompress_to_vec_with_limit(
+                encoded,
+                PUT_VALUE_SIZE_MAX as usize,
+            )
+            .map_err(|err| format!("Failed to decompress the value: {:?}", err)),
+        }
+    }
+}
+
+// The bytes of the entry counted in used_bytes
+fn entry_bytes(entry: &Entry) -> u64 {
+    (entry.key.len() + entry.value.len() + size_of::<Sha256Digest>()) as u64
+}
+
+impl Entry {
+    pub fn new(key: Key, value: Val, version: u64) -> Self {
+        Self {
+            key,
+            value,
+            codec: Codec::Raw,
+            version,
+            expires_at: None,
+        }
+    }
+
+    // Returns the original value, without a copy if the value is stored Raw
+    pub fn decoded_value(&self) -> Result<Cow<'_, Val>, BigMapError> {
+        match self.codec {
+            Codec::Raw => Ok(Cow::Borrowed(&self.value)),
+            codec => match codec.decode(&self.value) {
+                Ok(value) => Ok(Cow::Owned(value)),
+                Err(err) => Err(BigMapError::DataCorrupted(err)),
+            },
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
+}
+
+impl RelocationEntry {
+    // Returns the original value
+    pub fn into_decoded_value(self) -> Result<Val, BigMapError> {
+        if self.part.is_some() {
+            // Read with get_chunk instead
+            return Err(BigMapError::ValueTooLarge {
+                limit_bytes: RELOCATION_PART_BYTES,
+            });
+        }
+        match self.codec {
+            Codec::Raw => Ok(self.value),
+            codec => codec
+                .decode(&self.value)
+                .map_err(BigMapError::DataCorrupted),
+        }
+    }
+
+    // True for a whole entry, and for the last part of an entry
+    pub fn is_complete(&self) -> bool {
+        match &self.part {
+            Some(part) => part.offset + self.value.len() as u64 >= part.total_len,
+            None => true,
+        }
+    }
+
+    // The offset of the next part of the value
+    pub fn next_offset(&self) -> u64 {
+        match &self.part {
+            Some(part) => part.offset + self.value.len() as u64,
+            None => self.value.len() as u64,
+        }
+    }
+
+    // The cursor to continue a batch after this entry, the key_sha2, followed by the offset
+    // of the next part if the batch ended within the entry
+    pub fn cursor(&self) -> Sha2Vec {
+        let mut cursor = self.key_sha2.clone();
+        if !self.is_complete() {
+            cursor.extend_from_slice(&self.next_offset().to_be_bytes());
+        }
+        cursor
+    }
+}
+
+// Result of a conditional (compare-and-swap) write
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum CasResult {
+    // The write was performed, the entry now has the provided version (0 if deleted)
+    Applied { version: u64 },
+    // The entry has a different version than expected (0 if it doesn't exist)
+    Conflict { current_version: u64 },
+}
+
+// A value with the proof that it is held in the DataBucket
+// The client verifies that the witness leads from the key and value to the root hash
+// certified in the certificate, which is signed by the IC for this canister
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CertifiedValue {
+    pub value: Val,
+    pub witness: Witness,
+    pub certificate: Option<Vec<u8>>, // Only available in query calls
+}
+
+// Who may call the endpoints of the DataBucket
+// The index canister and the admins may call all of them. Other callers may only read
+// entries by key, and write them only if allow_direct_writes, since the writes are checked
+// against the range of the DataBucket, while e.g. set_range or delete_entries are not.
+// Other callers may access only the keys of their default namespace.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct AccessPolicy {
+    // The canister which installed the DataBucket, normally the BigMap Index
+    pub index_canister: Option<CanisterId>,
+    pub admins: Vec<CanisterId>,
+    pub allow_direct_writes: bool,
+}
+
+impl Default for AccessPolicy {
+    fn default() -> Self {
+        Self {
+            index_canister: None,
+            admins: Vec::new(),
+            allow_direct_writes: true,
+        }
+    }
+}
+
+// The access required by an endpoint of the DataBucket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,  // Reads of entries by key, e.g. get and get_chunk
+    Write, // Writes of entries by key, e.g. put, delete and the chunked uploads
+    Admin, // Configuration, ranges, relocation, listing and bulk reads and changes of entries
+}
+
+// A value being uploaded in chunks, stored into the entries on commit
+#[derive(Clone, Debug, Default)]
+struct Upload {
+    key: Key,
+    total_len: u64,
+    value: Val,
+    chunks: BTreeMap<u64, u64>, // offset => length of the received chunks
+    last_active: u64,           // Time of begin_upload or of the last chunk
+}
+
+// Upper limit for the size of a single chunk, keeps the messages well below the size limit
+pub const CHUNK_SIZE_MAX: u64 = 1024 * 1024;
+
+// Upper limit for the size of a value sent with put, larger values are uploaded in chunks
+pub const PUT_VALUE_SIZE_MAX: u64 = 2 * CHUNK_SIZE_MAX;
+
+// Upper limit for the total length of a value uploaded in chunks
+pub const UPLOAD_SIZE_MAX: u64 = 512 * 1024 * 1024;
+
+// Uploads, and entries received in parts, are dropped by sweep_expired if no chunk or part
+// arrived for this long, in nanoseconds
+pub const UPLOAD_IDLE_NS: u64 = 60 * 60 * 1_000_000_000;
+
+// Tombstones are reclaimed by sweep_expired after this long, in nanoseconds. The replicas
+// which missed a delete must be repaired before, see BigmapIdx::repair_step
+pub const TOMBSTONE_TTL_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// Entries larger than this, key and value, are moved between DataBuckets in parts
+pub const RELOCATION_PART_BYTES: u64 = CHUNK_SIZE_MAX;
+
+// Upper limit for the number of keys returned by list_page
+pub const LIST_PAGE_LIMIT_MAX: u32 = 10000;
+
+// Default capacity of a DataBucket, so that a burst of writes between two maintenance runs
+// is rejected instead of trapping the canister, see capacity_bytes_for_heap
+pub const CAPACITY_BYTES_DEFAULT: u64 = capacity_bytes_for_heap(crate::HEAP_BYTES_MAX);
+
+// Heap kept free in addition to the state for upgrades: the buffers of the stable memory,
+// the decoding of a value and the memory of the canister which is not in the breakdown
+const UPGRADE_HEADROOM_BYTES: u64 = 256 * 1024 * 1024;
+
+// Identifies the DataBucket state in the stable memory
+pub const STABLE_MAGIC: StableMagic = *b"BMDB";
+// Bump when the layout of DataBucketStable changes, and add a migration in read_stable_payload
+// Version 2: the ranges with their epoch, the capacity, the access policy, the default codec,
+// the version counter and the tombstones are persisted, and the entries have a codec, a
+// version and an optional expiry time
+pub const STABLE_SCHEMA_VERSION: u32 = 2;
+
+// Memory used by an entry in the entries map, in addition to the key and value
+const ENTRY_INDEX_BYTES: usize =
+    btree_element_bytes(size_of::<Sha256Digest>() + size_of::<Entry>());
+
+// Memory used by an entry in the secondary key index, in addition to the key itself
+const KEY_INDEX_BYTES: usize = btree_element_bytes(size_of::<Key>() + size_of::<Sha256Digest>());
+
+// Memory used by a tombstone, in addition to the key
+const TOMBSTONE_INDEX_BYTES: usize =
+    btree_element_bytes(size_of::<Sha256Digest>() + size_of::<Tombstone>());
+
+// Memory used on restore by an entry decoded from the stable memory, in addition to the
+// restored entry: the decoded entries are held in a Vec, which grows by doubling, until
+// they are moved into the maps, and the key hash is decoded into a Vec
+const RESTORE_ENTRY_BYTES: usize = 2 * size_of::<StableEntry>() + size_of::<Sha256Digest>();
+
+// Memory accounted for an entry with an empty key and value
+const ENTRY_BYTES_MIN: usize = ENTRY_INDEX_BYTES + KEY_INDEX_BYTES + MERKLE_OVERHEAD_BYTES;
+
+// The capacity of a DataBucket which can still be upgraded in a heap of heap_bytes. The state
+// is streamed to and from the stable memory, so the upgrade doesn't hold a copy of the values,
+// but the restore needs RESTORE_ENTRY_BYTES for each entry, at most for each ENTRY_BYTES_MIN
+// of the capacity.
+pub const fn capacity_bytes_for_heap(heap_bytes: u64) -> u64 {
+    (heap_bytes - UPGRADE_HEADROOM_BYTES) / (ENTRY_BYTES_MIN + RESTORE_ENTRY_BYTES) as u64
+        * ENTRY_BYTES_MIN as u64
+}
+
+// Memory used by an entry in the expiry index
+const EXPIRY_INDEX_BYTES: usize = btree_element_bytes(size_of::<(u64, Sha256Digest)>());
+
+// Memory used by an entry in the Merkle tree: a leaf (Sha256 of the key + hash)
+// and a fork (hash + 2 pointers + bit index)
+const MERKLE_OVERHEAD_BYTES: usize = 32 + 32 + 32 + 2 * 8 + 8;
+
+// Entry as persisted across upgrades: (key_sha2, key, value, codec, version, expires_at)
+type StableEntryRef<'a> = (&'a [u8], &'a Key, &'a Val, Codec, u64, Option<u64>);
+type StableEntry = (Sha2Vec, Key, Val, Codec, u64, Option<u64>);
+
+// Tombstone as persisted across upgrades: (key_sha2, key, version, deleted_at)
+type StableTombstoneRef<'a> = (&'a [u8], &'a Key, u64, u64);
+type StableTombstone = (Sha2Vec, Key, u64, u64);
+
+// The entries as a sequence of StableEntryRef, serialized straight from the map
+struct StableEntriesRef<'a>(&'a BTreeMap<Sha256Digest, Entry>);
+
+impl Serialize for StableEntriesRef<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|(key_sha2, e)| -> StableEntryRef {
+            (
+                key_sha2.as_slice(),
+                &e.key,
+                &e.value,
+                e.codec,
+                e.version,
+                e.expires_at,
+            )
+        }))
+    }
+}
+
+// The tombstones as a sequence of StableTombstoneRef, serialized straight from the map
+struct StableTombstonesRef<'a>(&'a BTreeMap<Sha256Digest, Tombstone>);
+
+impl Serialize for StableTombstonesRef<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|(key_sha2, t)| -> StableTombstoneRef {
+            (key_sha2.as_slice(), &t.key, t.version, t.deleted_at)
+        }))
+    }
+}
+
+// DataBucket state as persisted across upgrades
+// The entries are borrowed when saving, and streamed to the stable memory, to avoid
+// holding a copy of all values in the heap right before the upgrade
+#[derive(Serialize)]
+struct DataBucketStableRef<'a> {
+    id: &'a CanisterId,
+    ranges: Vec<(&'a [u8], &'a [u8])>,
+    range_epoch: u64,
+    used_bytes: u64,
+    capacity_bytes: u64,
+    access: &'a AccessPolicy,
+    version_counter: u64,
+    default_codec: Codec,
+    entries: StableEntriesRef<'a>,
+    tombstones: StableTombstonesRef<'a>,
+}
+
+// Owned counterpart of DataBucketStableRef, with the same serialized layout
+#[derive(Deserialize)]
+struct DataBucketStable {
+    id: CanisterId,
+    ranges: Vec<(Sha2Vec, Sha2Vec)>,
+    range_epoch: u64,
+    #[allow(dead_code)] // The memory usage is recalculated on restore
+    used_bytes: u64,
+    capacity_bytes: u64,
+    access: AccessPolicy,
+    version_counter: u64,
+    default_codec: Codec,
+    entries: Vec<StableEntry>,
+    tombstones: Vec<StableTombstone>,
+}
+
+// DataBucket state persisted by schema version 1
+#[derive(Deserialize)]
+struct DataBucketStableV1 {
+    id: CanisterId,
+    range_start: Sha2Vec,
+    range_end: Sha2Vec,
+    used_bytes: u64,
+    entries: Vec<(Sha2Vec, Key, Val)>,
 }
 
 #[allow(dead_code)]
@@ -23,127 +443,735 @@ impl DataBucket {
         // println!("BigMap Data {}: new", id);
         Self {
             id,
+            capacity_bytes: CAPACITY_BYTES_DEFAULT,
             ..Default::default()
         }
     }
 
-    pub fn set_range(&mut self, range_start: &Sha256Digest, range_end: &Sha256Digest) {
-        println!(
-            "BigMap Data: set_range {} .. {}",
-            hex::encode(range_start),
-            hex::encode(range_end)
-        );
-        self.range_start = range_start.clone();
-        self.range_end = range_end.clone();
+    // The epoch is the one of the routing table of the BigMap Index with the new ranges
+    pub fn set_ranges(&mut self, ranges: &[KeyRange], epoch: u64) {
+        for (range_start, range_end) in ranges.iter() {
+            println!(
+                "BigMap Data: set_range {} .. {} at epoch {}",
+                hex::encode(range_start),
+                hex::encode(range_end),
+                epoch
+            );
+        }
+        self.ranges = ranges.to_vec();
+        self.range_epoch = epoch;
     }
 
+    // Drop the state of a DataBucket which the BigMap Index took out of the hash ring, so
+    // that it starts like a new one when it's used for the next split. The canister id, the
+    // access policy and the configuration are kept. Refused while any entry is left.
+    pub fn reset(&mut self) -> Result<(), BigMapError> {
+        if !self.entries.is_empty() {
+            return Err(BigMapError::InvalidArgument(format!(
+                "DataBucket {} still holds {} entries",
+                self.id,
+                self.entries.len()
+            )));
+        }
+        println!("BigMap Data: reset");
+        *self = Self {
+            id: self.id.clone(),
+            access: self.access.clone(),
+            capacity_bytes: self.capacity_bytes,
+            default_codec: self.default_codec,
+            ..Default::default()
+        };
+        self.certify();
+        Ok(())
+    }
+
+    pub fn set_range(&mut self, range_start: &Sha256Digest, range_end: &Sha256Digest, epoch: u64) {
+        self.set_ranges(&[(*range_start, *range_end)], epoch);
+    }
+
+    pub fn ranges(&self) -> &[KeyRange] {
+        &self.ranges
+    }
+
+    pub fn range_epoch(&self) -> u64 {
+        self.range_epoch
+    }
+
+    // Rejects an operation routed with a routing table older than the range of this
+    // DataBucket. A newer routing table is accepted, since the range didn't change with it.
+    pub fn check_epoch(&self, epoch: u64) -> Result<(), BigMapError> {
+        if epoch < self.range_epoch {
+            return Err(BigMapError::StaleEpoch {
+                current_epoch: self.range_epoch,
+            });
+        }
+        Ok(())
+    }
+
+    // With replicas, a range of a DataBucket may wrap around the end of the key space
     pub fn is_in_range(&self, key_sha2: &Sha256Digest) -> bool {
-        key_sha2 >= &self.range_start && key_sha2 < &self.range_end
+        self.ranges.iter().any(|(range_start, range_end)| {
+            if range_start <= range_end {
+                key_sha2 >= range_start && key_sha2 < range_end
+            } else {
+                key_sha2 >= range_start || key_sha2 < range_end
+            }
+        })
+    }
+
+    pub fn put(&mut self, key: &Key, value: &Val, append: bool) -> Result<u64, BigMapError> {
+        self.put_with_codec(key, value, append, self.default_codec)
     }
 
-    pub fn put(&mut self, key: &Key, value: &Val, append: bool) -> Result<u64, String> {
+    // Put the value, stored with the provided codec instead of the default codec of the DataBucket
+    // Returns the length of the value before encoding
+    pub fn put_with_codec(
+        &mut self,
+        key: &Key,
+        value: &Val,
+        append: bool,
+        codec: Codec,
+    ) -> Result<u64, BigMapError> {
         // println!("BigMap Data: put {}", String::from_utf8_lossy(&key));
-        let key_sha2 = calc_sha256(&key);
+        let key_sha2 = calc_sha256(key);
         if !self.is_in_range(&key_sha2) {
-            return Err(format!(
-                "Provided key {} with sha256 {} is not in the range assigned to this DataBucket",
-                String::from_utf8_lossy(&key),
-                hex::encode(&key_sha2)
+            return Err(BigMapError::KeyOutOfRange);
+        }
+        if value.len() as u64 > PUT_VALUE_SIZE_MAX {
+            return Err(BigMapError::ValueTooLarge {
+                limit_bytes: PUT_VALUE_SIZE_MAX,
+            });
+        }
+        // A replaced value is freed only after the put, and may be smaller than the new one
+        self.check_capacity(key.len() + value.len())?;
+
+        let version = self.next_version(&key_sha2);
+        let now = time_now();
+        let mut expires_at = None;
+        let value_new = match self.entries.get(&key_sha2) {
+            // An expired entry is treated as absent, and replaced
+            Some(entry) if append && !entry.is_expired(now) => {
+                // Appending keeps the expiry time of the entry
+                expires_at = entry.expires_at;
+                let mut value_new = entry.decoded_value()?.into_owned();
+                value_new.extend_from_slice(value);
+                Cow::Owned(value_new)
+            }
+            _ => Cow::Borrowed(value),
+        };
+        let value_len = value_new.len();
+        let (codec, value_encoded) = codec.encode(&value_new);
+
+        let mut entry = Entry::new(key.clone(), value_encoded, version);
+        entry.codec = codec;
+        entry.expires_at = expires_at;
+        if let Some(entry_prev) = self.entry_insert(key_sha2, entry) {
+            // The previous value is overwritten, it can't be in the expiry index anymore
+            self.expiry_index_remove(key_sha2, entry_prev.expires_at);
+        }
+        self.key_index_insert(key, key_sha2);
+        self.expiry_index_insert(key_sha2, expires_at);
+        self.merkle_insert(key_sha2, merkle::leaf_hash(&key_sha2, &value_new));
+        Ok(value_len as u64)
+    }
+
+    // Rejects a write of additional_bytes which would grow the memory usage breakdown of the
+    // DataBucket over its capacity. Deletes and relocations are never rejected, they are
+    // needed to make room.
+    fn check_capacity(&self, additional_bytes: usize) -> Result<(), BigMapError> {
+        if self.usage.total() + additional_bytes as u64 > self.capacity_bytes {
+            return Err(BigMapError::BucketFull {
+                capacity_bytes: self.capacity_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn set_capacity_bytes(&mut self, capacity_bytes: u64) -> Result<(), BigMapError> {
+        if capacity_bytes == 0 {
+            return Err(BigMapError::InvalidArgument(
+                "The capacity must be larger than 0".to_string(),
             ));
         }
+        self.capacity_bytes = capacity_bytes;
+        Ok(())
+    }
 
-        self.used_bytes += key.len();
-        self.used_bytes += value.len();
-        self.used_bytes += 32; // for the Sha256 of the key (=32 bytes)
-        let value_len;
+    pub fn capacity_bytes(&self) -> u64 {
+        self.capacity_bytes
+    }
 
-        if append {
-            match &self.entries.get_key_value(&key_sha2) {
-                Some((_, (_, val_old))) => {
-                    let value_new = [&val_old[..], &value[..]].concat();
-                    value_len = value_new.len();
-                    self.entries.insert(key_sha2, (key.clone(), value_new));
-                }
-                None => {
-                    value_len = value.len();
-                    self.entries.insert(key_sha2, (key.clone(), value.clone()));
-                }
-            }
+    // Records the canister which installed the DataBucket as the index canister, unless
+    // one is recorded already. The BigMap Index installs the DataBuckets it creates.
+    pub fn record_index_canister(&mut self, can_id: CanisterId) {
+        if self.access.index_canister.is_none() {
+            println!("BigMap Data: index canister {}", can_id);
+            self.access.index_canister = Some(can_id);
+        }
+    }
+
+    // Returns Unauthorized unless the caller has the access, according to the access policy
+    pub fn authorize(&self, caller: &CanisterId, access: Access) -> Result<(), BigMapError> {
+        if self.is_index_or_admin(caller) {
+            return Ok(());
+        }
+        match access {
+            Access::Read => Ok(()),
+            Access::Write if self.access.allow_direct_writes => Ok(()),
+            _ => Err(BigMapError::Unauthorized),
+        }
+    }
+
+    // As authorize, for an access to the key. The ACLs of the shared namespaces are held
+    // by the BigMap Index, so other callers may only access their default namespace here.
+    pub fn authorize_key(
+        &self,
+        caller: &CanisterId,
+        access: Access,
+        key: &[u8],
+    ) -> Result<(), BigMapError> {
+        self.authorize(caller, access)?;
+        let scope = namespace::scope(&namespace::default_namespace(caller));
+        if self.is_index_or_admin(caller) || key.starts_with(&scope) {
+            Ok(())
         } else {
-            if let Some((_, (k, v))) = self.entries.get_key_value(&key_sha2) {
-                // previous value is getting overwritten, update the accounting
-                self.used_bytes -= k.len() + v.len() + 32;
+            Err(BigMapError::Unauthorized)
+        }
+    }
+
+    // As authorize_key, for the key of the upload
+    pub fn authorize_upload(&self, caller: &CanisterId, upload_id: u64) -> Result<(), BigMapError> {
+        match self.uploads.get(&upload_id) {
+            Some(upload) => self.authorize_key(caller, Access::Write, &upload.key),
+            None => self.authorize(caller, Access::Write),
+        }
+    }
+
+    fn is_index_or_admin(&self, caller: &CanisterId) -> bool {
+        self.access.index_canister.as_ref() == Some(caller) || self.access.admins.contains(caller)
+    }
+
+    pub fn access_policy(&self) -> &AccessPolicy {
+        &self.access
+    }
+
+    pub fn set_access_policy(&mut self, policy: AccessPolicy) -> Result<(), BigMapError> {
+        if policy.index_canister.is_none() && policy.admins.is_empty() {
+            return Err(BigMapError::InvalidArgument(
+                "The access policy needs an index canister or an admin".to_string(),
+            ));
+        }
+        self.access = policy;
+        Ok(())
+    }
+
+    // The codec used by put, the chunked uploads are stored Raw
+    pub fn set_default_codec(&mut self, codec: Codec) {
+        self.default_codec = codec;
+    }
+
+    pub fn default_codec(&self) -> Codec {
+        self.default_codec
+    }
+
+    // Put the value, which will be treated as absent from the expires_at time on
+    // A plain put of the same key afterwards removes the expiry time
+    pub fn put_with_expiry(
+        &mut self,
+        key: &Key,
+        value: &Val,
+        expires_at: u64,
+    ) -> Result<u64, BigMapError> {
+        let value_len = self.put(key, value, false)?;
+        let key_sha2 = calc_sha256(key);
+        if let Some(entry) = self.entries.get_mut(&key_sha2) {
+            entry.expires_at = Some(expires_at);
+        }
+        self.expiry_index_insert(key_sha2, Some(expires_at));
+        Ok(value_len)
+    }
+
+    // Remove up to `limit` expired entries, returns the number of removed entries
+    // Bounded, so that a single call doesn't run out of instructions
+    pub fn sweep_expired(&mut self, limit: usize) -> u64 {
+        let now = time_now();
+        let expired: Vec<(u64, Sha256Digest)> = self
+            .expiries
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .take(limit)
+            .cloned()
+            .collect();
+
+        for (expires_at, key_sha2) in expired.iter() {
+            self.expiry_index_remove(*key_sha2, Some(*expires_at));
+            if let Some(entry) = self.entry_remove(key_sha2) {
+                self.key_index_remove(&entry.key);
+                self.merkle_remove(key_sha2);
+            }
+        }
+        self.sweep_idle_uploads(now);
+        self.sweep_tombstones(now, limit);
+        expired.len() as u64
+    }
+
+    // Drop up to limit tombstones older than TOMBSTONE_TTL_NS
+    fn sweep_tombstones(&mut self, now: u64, limit: usize) {
+        let stale: Vec<Sha256Digest> = self
+            .tombstones
+            .iter()
+            .filter(|(_, tombstone)| now.saturating_sub(tombstone.deleted_at) >= TOMBSTONE_TTL_NS)
+            .map(|(key_sha2, _)| *key_sha2)
+            .take(limit)
+            .collect();
+        for key_sha2 in stale.iter() {
+            self.tombstone_remove(key_sha2);
+        }
+    }
+
+    // Drop the uploads, and the entries received in parts, which made no progress for
+    // UPLOAD_IDLE_NS, e.g. since the client or the BigMap Index gave up on them
+    fn sweep_idle_uploads(&mut self, now: u64) {
+        let is_idle = |last_active: u64| now.saturating_sub(last_active) >= UPLOAD_IDLE_NS;
+        let mut freed_bytes = 0;
+        self.uploads.retain(|upload_id, upload| {
+            if !is_idle(upload.last_active) {
+                return true;
+            }
+            println!("BigMap Data: dropping the idle upload id {}", upload_id);
+            freed_bytes += upload.value.len() as u64;
+            false
+        });
+        self.relocation_parts.retain(|_, (received, last_active)| {
+            if !is_idle(*last_active) {
+                return true;
+            }
+            println!(
+                "BigMap Data: dropping the parts received of key {}",
+                String::from_utf8_lossy(&received.key)
+            );
+            freed_bytes += received.value.len() as u64;
+            false
+        });
+        self.usage.uploads = self.usage.uploads.saturating_sub(freed_bytes);
+    }
+
+    // Put the value only if the entry currently has the expected version
+    // Use expected_version 0 to put only if the entry doesn't exist yet
+    pub fn put_if_version(
+        &mut self,
+        key: &Key,
+        value: &Val,
+        expected_version: u64,
+    ) -> Result<CasResult, BigMapError> {
+        let key_sha2 = calc_sha256(key);
+        if !self.is_in_range(&key_sha2) {
+            return Err(BigMapError::KeyOutOfRange);
+        }
+        let current_version = self.get_version(&key_sha2);
+        if current_version != expected_version {
+            return Ok(CasResult::Conflict { current_version });
+        }
+
+        self.put(key, value, false)?;
+        Ok(CasResult::Applied {
+            version: self.get_version(&key_sha2),
+        })
+    }
+
+    // Delete the entry only if it currently has the expected version
+    pub fn delete_if_version(
+        &mut self,
+        key: &Key,
+        expected_version: u64,
+    ) -> Result<CasResult, BigMapError> {
+        self.delete_if_version_entry(key, expected_version, false)
+    }
+
+    // As delete_if_version, and keeps a tombstone, see delete_with_tombstone
+    pub fn delete_if_version_with_tombstone(
+        &mut self,
+        key: &Key,
+        expected_version: u64,
+    ) -> Result<CasResult, BigMapError> {
+        self.delete_if_version_entry(key, expected_version, true)
+    }
+
+    fn delete_if_version_entry(
+        &mut self,
+        key: &Key,
+        expected_version: u64,
+        tombstone: bool,
+    ) -> Result<CasResult, BigMapError> {
+        let key_sha2 = calc_sha256(key);
+        if !self.is_in_range(&key_sha2) {
+            return Err(BigMapError::KeyOutOfRange);
+        }
+        let current_version = self.get_version(&key_sha2);
+        if current_version != expected_version {
+            return Ok(CasResult::Conflict { current_version });
+        }
+
+        self.delete_entry(key, tombstone)?;
+        Ok(CasResult::Applied { version: 0 })
+    }
+
+    fn get_version(&self, key_sha2: &Sha256Digest) -> u64 {
+        match self.get_entry(key_sha2) {
+            Some(entry) => entry.version,
+            None => 0,
+        }
+    }
+
+    // Returns the entry, unless it doesn't exist or it expired
+    fn get_entry(&self, key_sha2: &Sha256Digest) -> Option<&Entry> {
+        match self.entries.get(key_sha2) {
+            Some(entry) if !entry.is_expired(time_now()) => Some(entry),
+            _ => None,
+        }
+    }
+
+    // The new version of an entry is higher than both its current version and any
+    // version previously assigned in this DataBucket, so a version is never reused
+    // for a key, even after the key is deleted and written again
+    fn next_version(&mut self, key_sha2: &Sha256Digest) -> u64 {
+        self.version_counter = self.version_counter.max(self.get_version(key_sha2)) + 1;
+        self.version_counter
+    }
+
+    // Start a chunked upload of a value of total_len bytes, returns the upload id
+    // The value is sent with put_chunk and becomes visible only after commit_upload
+    pub fn begin_upload(&mut self, key: &Key, total_len: u64) -> Result<u64, BigMapError> {
+        let key_sha2 = calc_sha256(key);
+        if !self.is_in_range(&key_sha2) {
+            return Err(BigMapError::KeyOutOfRange);
+        }
+        if total_len > UPLOAD_SIZE_MAX {
+            return Err(BigMapError::ValueTooLarge {
+                limit_bytes: UPLOAD_SIZE_MAX,
+            });
+        }
+
+        self.next_upload_id += 1;
+        let upload_id = self.next_upload_id;
+        self.uploads.insert(
+            upload_id,
+            Upload {
+                key: key.clone(),
+                total_len,
+                last_active: time_now(),
+                ..Default::default()
+            },
+        );
+        Ok(upload_id)
+    }
+
+    // Store a chunk of an upload at the given offset, returns the chunk length
+    // Chunks may arrive in any order and may be re-sent
+    pub fn put_chunk(
+        &mut self,
+        upload_id: u64,
+        offset: u64,
+        bytes: &[u8],
+    ) -> Result<u64, BigMapError> {
+        let upload = match self.uploads.get(&upload_id) {
+            Some(upload) => upload,
+            None => return Err(BigMapError::UploadNotFound { upload_id }),
+        };
+        let chunk_len = bytes.len() as u64;
+        if chunk_len > CHUNK_SIZE_MAX {
+            return Err(BigMapError::InvalidArgument(format!(
+                "Chunk of {} bytes is over the limit of {} bytes",
+                chunk_len, CHUNK_SIZE_MAX
+            )));
+        }
+        let chunk_end = offset
+            .checked_add(chunk_len)
+            .filter(|chunk_end| *chunk_end <= upload.total_len)
+            .and_then(|chunk_end| usize::try_from(chunk_end).ok());
+        let chunk_end = match chunk_end {
+            Some(chunk_end) => chunk_end,
+            None => {
+                return Err(BigMapError::InvalidArgument(format!(
+                    "Chunk at offset {} with {} bytes is beyond the upload length {}",
+                    offset, chunk_len, upload.total_len
+                )))
+            }
+        };
+        let chunk_start = chunk_end - bytes.len();
+
+        let grow_bytes = chunk_end.saturating_sub(upload.value.len());
+        self.check_capacity(grow_bytes)?;
+        let upload = self.uploads.get_mut(&upload_id).unwrap();
+        if upload.value.len() < chunk_end {
+            // The partially uploaded value is accounted for, since it occupies memory
+            self.usage.uploads += (chunk_end - upload.value.len()) as u64;
+            upload.value.resize(chunk_end, 0);
+        }
+        upload.value[chunk_start..chunk_end].copy_from_slice(bytes);
+        upload.last_active = time_now();
+        let chunk_len_prev = upload.chunks.entry(offset).or_insert(0);
+        *chunk_len_prev = (*chunk_len_prev).max(chunk_len);
+        Ok(chunk_len)
+    }
+
+    // Store the uploaded value into the entries, once all chunks have been received
+    // Returns the length of the stored value
+    pub fn commit_upload(&mut self, upload_id: u64) -> Result<u64, BigMapError> {
+        let upload = match self.uploads.get(&upload_id) {
+            Some(upload) => upload,
+            None => return Err(BigMapError::UploadNotFound { upload_id }),
+        };
+
+        let mut received_end = 0;
+        for (offset, chunk_len) in upload.chunks.iter() {
+            if *offset > received_end {
+                return Err(BigMapError::InvalidArgument(format!(
+                    "Upload id {} is missing bytes {}..{}",
+                    upload_id, received_end, offset
+                )));
             }
-            value_len = value.len();
-            self.entries.insert(key_sha2, (key.clone(), value.clone()));
+            received_end = received_end.max(offset + chunk_len);
+        }
+        if received_end < upload.total_len {
+            return Err(BigMapError::InvalidArgument(format!(
+                "Upload id {} is missing bytes {}..{}",
+                upload_id, received_end, upload.total_len
+            )));
+        }
+
+        let key_sha2 = calc_sha256(&upload.key);
+        if !self.is_in_range(&key_sha2) {
+            // The range may have changed due to rebalancing since the upload started
+            // The upload is kept, until it's aborted or dropped by the sweep
+            return Err(BigMapError::KeyOutOfRange);
+        }
+
+        let upload = self.uploads.remove(&upload_id).unwrap();
+        self.usage.uploads = self.usage.uploads.saturating_sub(upload.value.len() as u64);
+
+        // The value is stored Raw, so that get_chunk doesn't decompress the whole value
+        let value_len = upload.value.len();
+        self.key_index_insert(&upload.key, key_sha2);
+        let version = self.next_version(&key_sha2);
+        self.merkle_insert(key_sha2, merkle::leaf_hash(&key_sha2, &upload.value));
+        let entry = Entry::new(upload.key, upload.value, version);
+        if let Some(entry_prev) = self.entry_insert(key_sha2, entry) {
+            self.expiry_index_remove(key_sha2, entry_prev.expires_at);
         }
         Ok(value_len as u64)
     }
 
-    pub fn batch_put(&mut self, batch: &Vec<(Key, Val)>) -> u64 {
-        let mut result = 0;
+    // Drop an upload in progress and free the memory used by the received chunks
+    pub fn abort_upload(&mut self, upload_id: u64) -> Result<(), BigMapError> {
+        match self.uploads.remove(&upload_id) {
+            Some(upload) => {
+                self.usage.uploads = self.usage.uploads.saturating_sub(upload.value.len() as u64);
+                Ok(())
+            }
+            None => Err(BigMapError::UploadNotFound { upload_id }),
+        }
+    }
+
+    // Returns the total length of the value and up to len bytes of the value, starting at offset
+    pub fn get_chunk(&self, key: &Key, offset: u64, len: u64) -> Result<(u64, Val), BigMapError> {
+        let key_sha2 = calc_sha256(key);
+        // Only the values up to the size of a put may be compressed, the larger values are
+        // stored Raw and not copied
+        let value = match self.get_entry(&key_sha2) {
+            Some(entry) => entry.decoded_value()?,
+            None => return Err(BigMapError::NotFound),
+        };
+        let value_len = value.len() as u64;
+        if offset > value_len {
+            return Err(BigMapError::InvalidArgument(format!(
+                "Offset {} is beyond the value length {}",
+                offset, value_len
+            )));
+        }
+        let chunk_end = value_len.min(offset + len.min(CHUNK_SIZE_MAX));
+        Ok((
+            value_len,
+            value[offset as usize..chunk_end as usize].to_vec(),
+        ))
+    }
+
+    // Returns the result of the put for each entry, in the order of the batch
+    pub fn batch_put(&mut self, batch: &[(Key, Val)]) -> Vec<Result<u64, BigMapError>> {
+        let mut results = Vec::with_capacity(batch.len());
 
         for (key, value) in batch {
-            match self.put(key, value, false) {
-                Ok(_) => result += 1,
-                Err(err) => {
-                    let key_str = String::from_utf8_lossy(&key);
-                    println!("BigMap Data: put key {} error: {}", key_str, err);
-                }
+            let res = self.put(key, value, false);
+            if let Err(err) = &res {
+                let key_str = String::from_utf8_lossy(key);
+                println!("BigMap Data: put key {} error: {}", key_str, err);
             }
+            results.push(res);
         }
-        result
+        results
     }
 
-    pub fn delete(&mut self, key: Key) -> Result<u64, String> {
-        let key_sha2 = calc_sha256(&key);
+    pub fn delete(&mut self, key: Key) -> Result<u64, BigMapError> {
+        self.delete_entry(&key, false)
+    }
+
+    // As delete, and keeps a tombstone with a new version of the entry, also if the entry
+    // doesn't exist here, since the other replicas may hold it. See Tombstone.
+    pub fn delete_with_tombstone(&mut self, key: &Key) -> Result<u64, BigMapError> {
+        self.delete_entry(key, true)
+    }
+
+    fn delete_entry(&mut self, key: &Key, tombstone: bool) -> Result<u64, BigMapError> {
+        let key_sha2 = calc_sha256(key);
         if !self.is_in_range(&key_sha2) {
-            return Err("Provided key is not in the range assigned to this DataBucket".to_string());
+            return Err(BigMapError::KeyOutOfRange);
         }
 
-        Ok(match &self.entries.remove(&key_sha2) {
-            Some((_, value)) => {
-                let value_bytes = value.len();
-                let bytes_freed = key.len() + value.len() + 32;
-                self.used_bytes = self.used_bytes.saturating_sub(bytes_freed);
-                value_bytes as u64
-            }
-            None => 0,
+        let now = time_now();
+        if tombstone {
+            let version = self.next_version(&key_sha2);
+            self.tombstone_insert(key_sha2, key, version);
+        }
+        Ok(match self.remove_entry_and_indexes(&key_sha2) {
+            // The length of the value as stored, which is not decoded on the delete path
+            Some(entry) if !entry.is_expired(now) => entry.value.len() as u64,
+            // An expired entry is already treated as absent
+            _ => 0,
         })
     }
 
-    pub fn get_relocation_batch(&self, batch_limit_bytes: u64) -> Vec<(Sha2Vec, Key, Val)> {
+    // Remove the entry from the entries, the key and expiry indexes, and the Merkle tree
+    fn remove_entry_and_indexes(&mut self, key_sha2: &Sha256Digest) -> Option<Entry> {
+        let entry = self.entry_remove(key_sha2)?;
+        self.key_index_remove(&entry.key);
+        self.expiry_index_remove(*key_sha2, entry.expires_at);
+        self.merkle_remove(key_sha2);
+        Some(entry)
+    }
+
+    // Entries outside of the ranges of this DataBucket, to be moved to another DataBucket
+    // The batch continues after the key_sha2 start_after, the last entry of the previous
+    // batch, so the entries which are in the ranges are never scanned
+    // If the DataBucket is not in the hash ring, all of its entries are relocated
+    pub fn get_relocation_batch(
+        &self,
+        start_after: &Option<Sha2Vec>,
+        batch_limit_bytes: u64,
+    ) -> Vec<RelocationEntry> {
+        let segments = segments_complement(&ranges_segments(&self.ranges));
+        self.batch_from_segments(&segments, start_after, batch_limit_bytes)
+    }
+
+    // Entries in the ranges, e.g. for another DataBucket which now holds a replica of the
+    // ranges. The batch continues after the key_sha2 start_after.
+    pub fn get_range_batch(
+        &self,
+        ranges: &[KeyRange],
+        start_after: &Option<Sha2Vec>,
+        batch_limit_bytes: u64,
+    ) -> Vec<RelocationEntry> {
+        let segments = ranges_segments(ranges);
+        self.batch_from_segments(&segments, start_after, batch_limit_bytes)
+    }
+
+    // Entries in the segments of the key space after start_after, in the order of key_sha2
+    fn batch_from_segments(
+        &self,
+        segments: &[Segment],
+        start_after: &Option<Sha2Vec>,
+        batch_limit_bytes: u64,
+    ) -> Vec<RelocationEntry> {
         let mut batch = Vec::new();
         let mut batch_size_bytes = 0;
+        let now = time_now();
 
-        for (key_sha2, (key, value)) in self.entries.iter() {
-            if !self.is_in_range(key_sha2) {
-                if batch_size_bytes + (key.len() + value.len()) as u64 >= batch_limit_bytes {
-                    break;
+        let (start_after, part_offset) = match start_after {
+            Some(cursor) => {
+                let (key_sha2, offset) = parse_cursor(cursor);
+                (Some(key_sha2), offset)
+            }
+            None => (None, None),
+        };
+        if let (Some(key_sha2), Some(offset)) = (&start_after, part_offset) {
+            // The previous batch ended within an entry, continue with its next part, unless
+            // the entry was deleted or written meanwhile and became smaller
+            match self.get_entry(key_sha2) {
+                Some(entry) if offset < entry.value.len() as u64 => {
+                    return vec![relocation_entry(key_sha2, entry, offset)];
                 }
-                batch.push((key_sha2.to_vec(), key.clone(), value.clone()));
-                batch_size_bytes += (key.len() + value.len()) as u64;
+                _ => {}
             }
         }
+        let ranges = segments.iter().filter_map(|(lower, upper)| {
+            let lower = match start_after {
+                Some(start_after) if start_after >= *lower => Excluded(start_after),
+                _ => Included(*lower),
+            };
+            let upper = upper.map_or(Unbounded, Excluded);
+            match (lower, upper) {
+                // The segment is before start_after
+                (Excluded(lower), Excluded(upper)) if lower >= upper => None,
+                (lower, upper) => Some(self.entries.range((lower, upper))),
+            }
+        });
+
+        for (key_sha2, entry) in ranges.flatten() {
+            // Expired entries are not moved, sweep_expired reclaims them in this DataBucket
+            if entry.is_expired(now) {
+                continue;
+            }
+            let entry_size_bytes = (entry.key.len() + entry.value.len()) as u64;
+            // A batch has at least one entry, even if the entry is above the limit
+            if !batch.is_empty() && batch_size_bytes + entry_size_bytes >= batch_limit_bytes {
+                break;
+            }
+            if entry_size_bytes > RELOCATION_PART_BYTES {
+                // The entry is moved in parts, the batch ends with the first one
+                if batch.is_empty() {
+                    batch.push(relocation_entry(key_sha2, entry, 0));
+                }
+                break;
+            }
+            batch.push(relocation_entry(key_sha2, entry, 0));
+            batch_size_bytes += entry_size_bytes;
+        }
 
         batch
     }
 
-    pub fn put_relocation_batch(&mut self, batch: &Vec<(Sha2Vec, Key, Val)>) -> u64 {
+    // Raise the version counter to at least version, and return it. The BigMap Index raises
+    // the counter of the DataBucket which takes over keys to the one of the DataBucket which
+    // held them, so the writes here get newer versions than the entries relocated from there.
+    pub fn raise_version_counter(&mut self, version: u64) -> u64 {
+        self.version_counter = self.version_counter.max(version);
+        self.version_counter
+    }
+
+    pub fn put_relocation_batch(&mut self, batch: &[RelocationEntry]) -> u64 {
         let mut put_count = 0;
 
-        for (key_sha2, key, value) in batch.iter() {
-            let key_sha2 = sha256_digest_from_vec(key_sha2);
-            if self.is_in_range(&key_sha2) {
-                self.used_bytes += key.len();
-                self.used_bytes += value.len();
-                self.used_bytes += 32; // for the Sha256 of the key (=32 bytes)
-                self.entries.insert(key_sha2, (key.clone(), value.clone()));
+        for e in batch.iter() {
+            let key_sha2 = sha256_digest_from_vec(&e.key_sha2);
+            if self.get_version(&key_sha2) >= e.version {
+                // Either the batch is sent again after an interrupted relocation, or the
+                // entry was written here after the split, and is newer than the relocated one,
+                // since the version counter here was raised to the one of the source
                 put_count += 1;
+            } else if self.is_in_range(&key_sha2) {
+                // An older entry is replaced, e.g. on a replica which missed a write, or in
+                // the source of a rolled back split, which may still hold an old copy
+                match self.put_relocated(key_sha2, e) {
+                    Ok(()) => put_count += 1,
+                    Err(err) => println!(
+                        "BigMap Data: relocated key {} error: {}",
+                        String::from_utf8_lossy(&e.key),
+                        err
+                    ),
+                }
             } else {
                 println!(
                     "BigMap Data: key is not in the assigned data bucket range {}",
-                    String::from_utf8_lossy(&key)
+                    String::from_utf8_lossy(&e.key)
                 );
             }
         }
@@ -151,59 +1179,424 @@ impl DataBucket {
         put_count
     }
 
+    // Store the entry as it was in the source DataBucket, with its codec, version and expiry time
+    // A part of an entry is kept aside until the last part arrives
+    fn put_relocated(
+        &mut self,
+        key_sha2: Sha256Digest,
+        e: &RelocationEntry,
+    ) -> Result<(), BigMapError> {
+        let assembled;
+        let e = match &e.part {
+            Some(part) => match self.receive_part(key_sha2, e, part)? {
+                Some(received) => {
+                    assembled = received;
+                    &assembled
+                }
+                None => return Ok(()),
+            },
+            None => e,
+        };
+        let mut entry = Entry::new(e.key.clone(), e.value.clone(), e.version);
+        entry.codec = e.codec;
+        entry.expires_at = e.expires_at;
+        // The Merkle tree is over the original values
+        let leaf_hash = merkle::leaf_hash(&key_sha2, &entry.decoded_value()?);
+        // Relocated entries keep their version, and later writes continue from it
+        self.version_counter = self.version_counter.max(e.version);
+        if let Some(entry_prev) = self.entry_insert(key_sha2, entry) {
+            // The entry is already here, e.g. if the batch is sent again
+            self.expiry_index_remove(key_sha2, entry_prev.expires_at);
+        }
+        self.key_index_insert(&e.key, key_sha2);
+        self.expiry_index_insert(key_sha2, e.expires_at);
+        self.merkle_insert(key_sha2, leaf_hash);
+        Ok(())
+    }
+
+    // Append the part to the parts of the entry received so far, returns the entry once the
+    // last part is received. A part sent again replaces the bytes from its offset on, and
+    // a first part of another version of the entry starts over.
+    fn receive_part(
+        &mut self,
+        key_sha2: Sha256Digest,
+        e: &RelocationEntry,
+        part: &ValuePart,
+    ) -> Result<Option<RelocationEntry>, BigMapError> {
+        if self.get_version(&key_sha2) == e.version {
+            // The last part was sent again, and the entry is already stored
+            return Ok(None);
+        }
+        let received = self
+            .relocation_parts
+            .remove(&key_sha2)
+            .map(|(received, _)| {
+                self.usage.uploads = self
+                    .usage
+                    .uploads
+                    .saturating_sub(received.value.len() as u64);
+                received
+            });
+        let mut received = match received {
+            Some(received)
+                if received.version == e.version && part.offset <= received.value.len() as u64 =>
+            {
+                received
+            }
+            _ if part.offset == 0 => RelocationEntry {
+                value: Vec::new(),
+                ..e.clone()
+            },
+            _ => {
+                return Err(BigMapError::InvalidArgument(format!(
+                    "Part at offset {} of key {} doesn't continue the received parts",
+                    part.offset,
+                    String::from_utf8_lossy(&e.key)
+                )))
+            }
+        };
+        received.value.truncate(part.offset as usize);
+        received.value.extend_from_slice(&e.value);
+        if received.value.len() as u64 >= part.total_len {
+            received.part = None;
+            return Ok(Some(received));
+        }
+        self.usage.uploads += received.value.len() as u64;
+        self.relocation_parts
+            .insert(key_sha2, (received, time_now()));
+        Ok(None)
+    }
+
+    // The entries of the keys, as held by this DataBucket, to compare them with the
+    // other replicas. The tombstones of deleted keys are returned as entries with
+    // `deleted` set, and the keys which are not here are skipped.
+    // An entry which is too large for one message is returned as its first part, and
+    // get_entry_part returns the next parts.
+    pub fn get_replica_entries(&self, keys: &[Key]) -> Vec<RelocationEntry> {
+        keys.iter()
+            .filter_map(|key| {
+                let key_sha2 = calc_sha256(key);
+                match self.get_entry(&key_sha2) {
+                    Some(entry) => Some(relocation_entry(&key_sha2, entry, 0)),
+                    None => self
+                        .tombstones
+                        .get(&key_sha2)
+                        .map(|tombstone| RelocationEntry {
+                            key_sha2: key_sha2.to_vec(),
+                            key: tombstone.key.clone(),
+                            value: Vec::new(),
+                            codec: Codec::Raw,
+                            version: tombstone.version,
+                            expires_at: None,
+                            part: None,
+                            deleted: true,
+                        }),
+                }
+            })
+            .collect()
+    }
+
+    // The part of the entry from offset, None if the entry doesn't exist or is smaller
+    pub fn get_entry_part(&self, key: &Key, offset: u64) -> Option<RelocationEntry> {
+        let key_sha2 = calc_sha256(key);
+        self.get_entry(&key_sha2)
+            .filter(|entry| offset < entry.value.len() as u64)
+            .map(|entry| relocation_entry(&key_sha2, entry, offset))
+    }
+
+    // Store the entries as sent by the BigMap Index from the primary replica, replacing
+    // the entries held here. A tombstone deletes the entry held here and is kept.
+    // Returns the version stored for each entry.
+    pub fn put_replica_batch(
+        &mut self,
+        batch: &[RelocationEntry],
+    ) -> Vec<Result<u64, BigMapError>> {
+        batch
+            .iter()
+            .map(|e| {
+                let key_sha2 = sha256_digest_from_vec(&e.key_sha2);
+                if !self.is_in_range(&key_sha2) {
+                    return Err(BigMapError::KeyOutOfRange);
+                }
+                if e.deleted {
+                    self.version_counter = self.version_counter.max(e.version);
+                    self.tombstone_insert(key_sha2, &e.key, e.version);
+                    self.remove_entry_and_indexes(&key_sha2);
+                    return Ok(e.version);
+                }
+                self.check_capacity(e.key.len() + e.value.len())?;
+                self.put_relocated(key_sha2, e)?;
+                Ok(e.version)
+            })
+            .collect()
+    }
+
+    // Delete the relocated entries, the entries in the range of this DataBucket are kept
+    // so that a repeated or late delete after a relocation can't remove live entries
     pub fn delete_entries(&mut self, keys_sha2: &Vec<Vec<u8>>) {
         for key_sha2 in keys_sha2 {
             let key_sha2 = sha256_digest_from_vec(key_sha2);
-            match self.entries.remove(&key_sha2) {
-                Some((key, value)) => {
-                    self.used_bytes -= key.len();
-                    self.used_bytes -= value.len();
-                    self.used_bytes -= 32; // for the Sha256 of the key (=32 bytes)
-                }
-                None => {}
+            if self.is_in_range(&key_sha2) {
+                continue;
+            }
+            if let Some(entry) = self.entry_remove(&key_sha2) {
+                self.key_index_remove(&entry.key);
+                self.expiry_index_remove(key_sha2, entry.expires_at);
+                self.merkle_remove(&key_sha2);
             }
         }
     }
 
-    pub fn get(&self, key: Key) -> Result<&Val, String> {
+    pub fn get(&self, key: Key) -> Result<Cow<'_, Val>, BigMapError> {
         // println!(
         //     "BigMap Data: get {}",
         //     String::from_utf8_lossy(&key)
         // );
         let key_sha2 = calc_sha256(&key);
-        match self.entries.get(&key_sha2) {
-            Some((_, v)) => Ok(v),
-            None => Err("Entry not found".to_string()),
+        match self.get_entry(&key_sha2) {
+            Some(entry) => entry.decoded_value(),
+            None => Err(BigMapError::NotFound),
+        }
+    }
+
+    // Returns the value and its current version
+    pub fn get_with_version(&self, key: &Key) -> Result<(Cow<'_, Val>, u64), BigMapError> {
+        let key_sha2 = calc_sha256(key);
+        match self.get_entry(&key_sha2) {
+            Some(entry) => Ok((entry.decoded_value()?, entry.version)),
+            None => Err(BigMapError::NotFound),
         }
     }
 
+    // Returns the value with the witness for the certified root hash
+    pub fn get_certified(&self, key: &Key) -> Result<CertifiedValue, BigMapError> {
+        let key_sha2 = calc_sha256(key);
+        let entry = match self.get_entry(&key_sha2) {
+            Some(entry) => entry,
+            None => return Err(BigMapError::NotFound),
+        };
+        let witness = match self.merkle.witness(&key_sha2) {
+            Some(witness) => witness,
+            None => {
+                return Err(BigMapError::DataCorrupted(
+                    "Entry not found in the Merkle tree".to_string(),
+                ))
+            }
+        };
+        Ok(CertifiedValue {
+            value: entry.decoded_value()?.into_owned(),
+            witness,
+            certificate: data_certificate(),
+        })
+    }
+
     pub fn list(&self, key_prefix: &Key) -> Vec<Key> {
-        let mut result = Vec::new();
+        // Safety brake, don't return too many entries
+        self.list_range(key_prefix, &None, 10001)
+    }
 
-        for (key, _) in self.entries.values() {
-            if key.len() >= key_prefix.len() && &key[0..key_prefix.len()] == key_prefix.as_slice() {
-                result.push(key.clone());
-                if result.len() > 10000 {
-                    // Safety brake, don't return too many entries
-                    break;
-                }
+    // List up to `limit` keys starting with key_prefix, in key order
+    // If start_after is provided, only keys strictly greater than it are returned,
+    // which allows iterating all keys by passing the last key of the previous page
+    pub fn list_page(&self, key_prefix: &Key, start_after: &Option<Key>, limit: u32) -> Vec<Key> {
+        let limit = limit.clamp(1, LIST_PAGE_LIMIT_MAX) as usize;
+        self.list_range(key_prefix, start_after, limit)
+    }
+
+    // Range scan over the secondary key index, in O(log n + limit)
+    fn list_range(&self, key_prefix: &Key, start_after: &Option<Key>, limit: usize) -> Vec<Key> {
+        let range_start = match start_after {
+            Some(start_after) if start_after >= key_prefix => Excluded(start_after),
+            _ => Included(key_prefix),
+        };
+
+        let now = time_now();
+        self.keys
+            .range::<Key, _>((range_start, Unbounded))
+            .take_while(|(key, _)| key.starts_with(key_prefix))
+            .filter(|(_, key_sha2)| match self.entries.get(*key_sha2) {
+                Some(entry) => !entry.is_expired(now),
+                None => false,
+            })
+            .map(|(key, _)| key)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    // Insert the entry and account for its memory, returns the entry it replaced
+    fn entry_insert(&mut self, key_sha2: Sha256Digest, entry: Entry) -> Option<Entry> {
+        // A write after the delete supersedes the tombstone
+        self.tombstone_remove(&key_sha2);
+        self.usage.keys += entry.key.len() as u64;
+        self.usage.values += entry.value.len() as u64;
+        self.usage.index_overhead += ENTRY_INDEX_BYTES as u64;
+        self.entries_bytes += entry_bytes(&entry);
+        let entry_prev = self.entries.insert(key_sha2, entry);
+        if let Some(entry_prev) = &entry_prev {
+            self.usage_sub_entry(entry_prev);
+        }
+        entry_prev
+    }
+
+    fn entry_remove(&mut self, key_sha2: &Sha256Digest) -> Option<Entry> {
+        let entry = self.entries.remove(key_sha2);
+        if let Some(entry) = &entry {
+            self.usage_sub_entry(entry);
+        }
+        entry
+    }
+
+    fn tombstone_insert(&mut self, key_sha2: Sha256Digest, key: &Key, version: u64) {
+        let tombstone = Tombstone {
+            key: key.clone(),
+            version,
+            deleted_at: time_now(),
+        };
+        self.usage.keys += key.len() as u64;
+        self.usage.index_overhead += TOMBSTONE_INDEX_BYTES as u64;
+        if let Some(tombstone_prev) = self.tombstones.insert(key_sha2, tombstone) {
+            self.usage_sub_tombstone(&tombstone_prev);
+        }
+    }
+
+    fn tombstone_remove(&mut self, key_sha2: &Sha256Digest) {
+        if let Some(tombstone) = self.tombstones.remove(key_sha2) {
+            self.usage_sub_tombstone(&tombstone);
+        }
+    }
+
+    fn usage_sub_tombstone(&mut self, tombstone: &Tombstone) {
+        self.usage.keys = self.usage.keys.saturating_sub(tombstone.key.len() as u64);
+        self.usage.index_overhead = self
+            .usage
+            .index_overhead
+            .saturating_sub(TOMBSTONE_INDEX_BYTES as u64);
+    }
+
+    fn usage_sub_entry(&mut self, entry: &Entry) {
+        self.entries_bytes = self.entries_bytes.saturating_sub(entry_bytes(entry));
+        self.usage.keys = self.usage.keys.saturating_sub(entry.key.len() as u64);
+        self.usage.values = self.usage.values.saturating_sub(entry.value.len() as u64);
+        self.usage.index_overhead = self
+            .usage
+            .index_overhead
+            .saturating_sub(ENTRY_INDEX_BYTES as u64);
+    }
+
+    fn key_index_insert(&mut self, key: &Key, key_sha2: Sha256Digest) {
+        if self.keys.insert(key.clone(), key_sha2).is_none() {
+            self.usage.keys += key.len() as u64;
+            self.usage.index_overhead += KEY_INDEX_BYTES as u64;
+        }
+    }
+
+    fn key_index_remove(&mut self, key: &Key) {
+        if self.keys.remove(key).is_some() {
+            self.usage.keys = self.usage.keys.saturating_sub(key.len() as u64);
+            self.usage.index_overhead = self
+                .usage
+                .index_overhead
+                .saturating_sub(KEY_INDEX_BYTES as u64);
+        }
+    }
+
+    fn expiry_index_insert(&mut self, key_sha2: Sha256Digest, expires_at: Option<u64>) {
+        if let Some(expires_at) = expires_at {
+            if self.expiries.insert((expires_at, key_sha2)) {
+                self.usage.index_overhead += EXPIRY_INDEX_BYTES as u64;
             }
         }
+    }
 
-        result
+    fn expiry_index_remove(&mut self, key_sha2: Sha256Digest, expires_at: Option<u64>) {
+        if let Some(expires_at) = expires_at {
+            if self.expiries.remove(&(expires_at, key_sha2)) {
+                self.usage.index_overhead = self
+                    .usage
+                    .index_overhead
+                    .saturating_sub(EXPIRY_INDEX_BYTES as u64);
+            }
+        }
+    }
+
+    // Every change of the Merkle tree is certified right away, so that the certified
+    // root hash always matches the entries in the following query calls
+    fn merkle_insert(&mut self, key_sha2: Sha256Digest, leaf_hash: Sha256Digest) {
+        if self.merkle.insert(key_sha2, leaf_hash) {
+            self.usage.merkle += MERKLE_OVERHEAD_BYTES as u64;
+        }
+        self.certify();
+    }
+
+    fn merkle_remove(&mut self, key_sha2: &Sha256Digest) {
+        if self.merkle.remove(key_sha2) {
+            self.usage.merkle = self
+                .usage
+                .merkle
+                .saturating_sub(MERKLE_OVERHEAD_BYTES as u64);
+        }
+        self.certify();
+    }
+
+    // Set the Merkle root hash of the entries as the certified data of the canister
+    pub fn certify(&self) {
+        set_certified_data(&self.merkle.root_hash());
+    }
+
+    pub fn root_hash(&self) -> Sha256Digest {
+        self.merkle.root_hash()
     }
 
     pub fn holds_key(&self, key: &Key) -> bool {
         let key_sha2 = calc_sha256(&key);
-        self.entries.get(&key_sha2).is_some()
+        self.get_entry(&key_sha2).is_some()
     }
 
+    // The bytes of the entries as stored: the keys, the values with their codec and the
+    // Sha256 of the keys. The maintenance splits the DataBuckets by this, which doesn't depend
+    // on the allocator, so it's the same in the canisters and in the native tests.
     pub fn used_bytes(&self) -> usize {
-        // We use DataBucket with BTreeMap, and the size is precalculated
-        // For DataBucket configuration with HashMap, usage can be calculated as:
-        // (key_size + value_size + 8) bytes * 1.1
-        // https://github.com/servo/servo/issues/6908
-        self.used_bytes
+        self.entries_bytes as usize
+    }
+
+    // Breakdown of the memory used by the entries, the indexes and the uploads
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            heap: crate::heap_allocated_bytes(),
+            ..self.usage.clone()
+        }
+    }
+
+    // The memory usage breakdown calculated from scratch, from the current structures
+    fn memory_usage_calc(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage::default();
+        for entry in self.entries.values() {
+            usage.keys += entry.key.len() as u64;
+            usage.values += entry.value.len() as u64;
+            usage.index_overhead += ENTRY_INDEX_BYTES as u64;
+        }
+        for key in self.keys.keys() {
+            usage.keys += key.len() as u64;
+            usage.index_overhead += KEY_INDEX_BYTES as u64;
+        }
+        for tombstone in self.tombstones.values() {
+            usage.keys += tombstone.key.len() as u64;
+            usage.index_overhead += TOMBSTONE_INDEX_BYTES as u64;
+        }
+        usage.index_overhead += (self.expiries.len() * EXPIRY_INDEX_BYTES) as u64;
+        usage.merkle = (self.merkle.len() * MERKLE_OVERHEAD_BYTES) as u64;
+        usage.uploads = self
+            .uploads
+            .values()
+            .map(|upload| upload.value.len() as u64)
+            .chain(
+                self.relocation_parts
+                    .values()
+                    .map(|(received, _)| received.value.len() as u64),
+            )
+            .sum();
+        usage
     }
 
     pub fn canister_id(&self) -> CanisterId {
@@ -214,6 +1607,277 @@ impl DataBucket {
         self.id = can_id
     }
 
+    // Serialize the complete DataBucket state, to be saved in the stable memory before an upgrade
+    pub fn write_stable_payload<W: Write>(&self, writer: W) -> Result<(), String> {
+        let state = DataBucketStableRef {
+            id: &self.id,
+            ranges: self
+                .ranges
+                .iter()
+                .map(|(range_start, range_end)| (range_start.as_slice(), range_end.as_slice()))
+                .collect(),
+            range_epoch: self.range_epoch,
+            used_bytes: self.entries_bytes,
+            capacity_bytes: self.capacity_bytes,
+            access: &self.access,
+            version_counter: self.version_counter,
+            default_codec: self.default_codec,
+            entries: StableEntriesRef(&self.entries),
+            tombstones: StableTombstonesRef(&self.tombstones),
+        };
+        bincode::serialize_into(writer, &state)
+            .map_err(|err| format!("DataBucket serialization failed: {}", err))
+    }
+
+    // As write_stable_payload, into a Vec
+    pub fn to_stable_payload(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        self.write_stable_payload(&mut result)
+            .expect("DataBucket serialization failed");
+        result
+    }
+
+    // As read_stable_payload, from a slice
+    pub fn from_stable_payload(schema_version: u32, payload: &[u8]) -> Result<Self, String> {
+        Self::read_stable_payload(schema_version, payload)
+    }
+
+    // Restore the DataBucket state saved with write_stable_payload
+    pub fn read_stable_payload<R: Read>(schema_version: u32, reader: R) -> Result<Self, String> {
+        let state: DataBucketStable = match schema_version {
+            2 => bincode::deserialize_from(reader)
+                .map_err(|err| format!("DataBucket deserialization failed: {}", err))?,
+            1 => {
+                let state: DataBucketStableV1 = bincode::deserialize_from(reader)
+                    .map_err(|err| format!("DataBucket deserialization failed: {}", err))?;
+                // Version 1 had a single range without an epoch, which the BigMap Index sets
+                // again with the next change of the range. The entries start at version 1
+                // and are stored Raw. The index canister is recorded again in post_upgrade.
+                DataBucketStable {
+                    id: state.id,
+                    ranges: vec![(state.range_start, state.range_end)],
+                    range_epoch: 0,
+                    used_bytes: state.used_bytes,
+                    capacity_bytes: CAPACITY_BYTES_DEFAULT,
+                    access: AccessPolicy::default(),
+                    version_counter: 1,
+                    default_codec: Codec::Raw,
+                    entries: state
+                        .entries
+                        .into_iter()
+                        .map(|(key_sha2, key, value)| (key_sha2, key, value, Codec::Raw, 1, None))
+                        .collect(),
+                    tombstones: Vec::new(),
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "Unsupported DataBucket schema version {}",
+                    schema_version
+                ))
+            }
+        };
+
+        let mut result = Self {
+            entries: state
+                .entries
+                .into_iter()
+                .map(|(key_sha2, key, value, codec, version, expires_at)| {
+                    let mut entry = Entry::new(key, value, version);
+                    entry.codec = codec;
+                    entry.expires_at = expires_at;
+                    (sha256_digest_from_vec(&key_sha2), entry)
+                })
+                .collect(),
+            ranges: state
+                .ranges
+                .iter()
+                .map(|(range_start, range_end)| {
+                    (
+                        sha256_digest_from_vec(range_start),
+                        sha256_digest_from_vec(range_end),
+                    )
+                })
+                .collect(),
+            range_epoch: state.range_epoch,
+            capacity_bytes: state.capacity_bytes,
+            access: state.access,
+            tombstones: state
+                .tombstones
+                .into_iter()
+                .map(|(key_sha2, key, version, deleted_at)| {
+                    let tombstone = Tombstone {
+                        key,
+                        version,
+                        deleted_at,
+                    };
+                    (sha256_digest_from_vec(&key_sha2), tombstone)
+                })
+                .collect(),
+            version_counter: state.version_counter,
+            default_codec: state.default_codec,
+            id: state.id,
+            ..Default::default()
+        };
+        // The secondary key index is not persisted, rebuild it from the entries
+        result.keys = result
+            .entries
+            .iter()
+            .map(|(key_sha2, entry)| (entry.key.clone(), *key_sha2))
+            .collect();
+        // The expiry index is not persisted either
+        result.expiries = result
+            .entries
+            .iter()
+            .filter_map(|(key_sha2, entry)| Some((entry.expires_at?, *key_sha2)))
+            .collect();
+        // The Merkle tree is not persisted, rebuild it from the original values
+        let mut leaves = Vec::with_capacity(result.entries.len());
+        for (key_sha2, entry) in result.entries.iter() {
+            leaves.push((
+                *key_sha2,
+                merkle::leaf_hash(
+                    key_sha2,
+                    &entry.decoded_value().map_err(|err| err.to_string())?,
+                ),
+            ));
+        }
+        result.merkle = MerkleTree::from_sorted_leaves(&leaves);
+        // The persisted used_bytes is only informative, the memory usage is recalculated
+        // since the accounting of earlier versions didn't match the actual structures
+        result.usage = result.memory_usage_calc();
+        result.entries_bytes = result.entries.values().map(entry_bytes).sum();
+        Ok(result)
+    }
+
+    // Returns up to limit_bytes of the snapshot, starting at the cursor returned with
+    // the previous page, or at the beginning of the snapshot if the cursor is None
+    pub fn export_snapshot(
+        &self,
+        cursor: &Option<SnapshotCursor>,
+        limit_bytes: u64,
+    ) -> SnapshotPage {
+        let limit_bytes = limit_bytes.clamp(1, snapshot::SNAPSHOT_PAGE_LIMIT_MAX) as usize;
+        let mut bytes = Vec::new();
+        let (range_from, mut skip_bytes) = match cursor {
+            Some(cursor) => (
+                Included(sha256_digest_from_vec(&cursor.key_sha2)),
+                cursor.offset as usize,
+            ),
+            None => {
+                let header = SnapshotHeader {
+                    ranges: self.ranges.clone(),
+                    id: self.id.clone(),
+                    entry_count: self.entries.len() as u64,
+                    checksum: self.merkle.root_hash(),
+                };
+                header.encode(&mut bytes);
+                (Unbounded, 0)
+            }
+        };
+
+        for (key_sha2, entry) in self.entries.range((range_from, Unbounded)) {
+            if bytes.len() >= limit_bytes {
+                return SnapshotPage {
+                    bytes,
+                    next_cursor: Some(SnapshotCursor {
+                        key_sha2: key_sha2.to_vec(),
+                        offset: 0,
+                    }),
+                };
+            }
+            let mut entry_bytes = Vec::new();
+            snapshot::encode_entry(key_sha2, entry, &mut entry_bytes);
+            let offset = skip_bytes.min(entry_bytes.len());
+            skip_bytes = 0;
+            let room = limit_bytes - bytes.len();
+            if entry_bytes.len() - offset > room {
+                // The rest of the entry goes into the next page(s)
+                bytes.extend_from_slice(&entry_bytes[offset..offset + room]);
+                return SnapshotPage {
+                    bytes,
+                    next_cursor: Some(SnapshotCursor {
+                        key_sha2: key_sha2.to_vec(),
+                        offset: (offset + room) as u64,
+                    }),
+                };
+            }
+            bytes.extend_from_slice(&entry_bytes[offset..]);
+        }
+        SnapshotPage {
+            bytes,
+            next_cursor: None,
+        }
+    }
+
+    // Import the snapshot pages, in order, into an empty DataBucket, which takes over
+    // the ranges of the snapshot. Returns the number of entries imported so far.
+    // After the last page, the entries are verified against the snapshot checksum, and only
+    // then the ranges are set. On any error, the DataBucket is empty again, and the
+    // import has to start over with the first page.
+    pub fn import_snapshot(&mut self, bytes: &[u8]) -> Result<u64, BigMapError> {
+        let decoder = match self.snapshot_import.take() {
+            Some(decoder) => decoder,
+            None if self.entries.is_empty() => SnapshotDecoder::new(),
+            None => {
+                return Err(BigMapError::InvalidArgument(
+                    "Snapshots can only be imported into an empty DataBucket".to_string(),
+                ))
+            }
+        };
+        let result = self.import_snapshot_page(decoder, bytes);
+        if result.is_err() {
+            self.clear_entries();
+        }
+        result
+    }
+
+    fn import_snapshot_page(
+        &mut self,
+        mut decoder: SnapshotDecoder,
+        bytes: &[u8],
+    ) -> Result<u64, BigMapError> {
+        let entries = decoder.push(bytes).map_err(BigMapError::DataCorrupted)?;
+        let header = match decoder.header() {
+            Some(header) => header.clone(),
+            None => {
+                self.snapshot_import = Some(decoder);
+                return Ok(0);
+            }
+        };
+
+        // Entries outside of the range are kept as well, e.g. if the snapshot was
+        // exported during rebalancing, and relocated again by the next maintenance
+        for e in entries.iter() {
+            self.put_relocated(sha256_digest_from_vec(&e.key_sha2), e)?;
+        }
+        if !decoder.is_complete() {
+            let entries_decoded = decoder.entries_decoded();
+            self.snapshot_import = Some(decoder);
+            return Ok(entries_decoded);
+        }
+        if self.merkle.root_hash() != header.checksum {
+            return Err(BigMapError::DataCorrupted(
+                "Snapshot checksum mismatch".to_string(),
+            ));
+        }
+        // The snapshot doesn't have an epoch, the BigMap Index sets it with the next range
+        self.set_ranges(&header.ranges, self.range_epoch);
+        Ok(header.entry_count)
+    }
+
+    // Remove all entries and the import in progress, e.g. after a failed import
+    fn clear_entries(&mut self) {
+        self.entries.clear();
+        self.keys.clear();
+        self.expiries.clear();
+        self.merkle = MerkleTree::default();
+        self.snapshot_import = None;
+        self.usage = self.memory_usage_calc();
+        self.entries_bytes = 0;
+        self.certify();
+    }
+
     pub fn get_key_hash_range(&self) -> Option<(Sha256Digest, Sha256Digest)> {
         match (self.entries.keys().min(), self.entries.keys().max()) {
             (Some(min), Some(max)) => Some((*min, *max)),
@@ -286,5 +1950,95 @@ impl DataBucket {
     }
 }
 
+// A part of the key space: the key_sha2 from the first, up to the second if any
+// (excluded), or up to the end of the key space
+type Segment = (Sha256Digest, Option<Sha256Digest>);
+
+// The parts of the key space in the ranges, in the order of the key_sha2 and without
+// overlaps, as needed for continuing a batch after the last key_sha2 of the previous one
+fn ranges_segments(ranges: &[KeyRange]) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    for (range_start, range_end) in ranges.iter() {
+        if range_start <= range_end {
+            segments.push((*range_start, Some(*range_end)));
+        } else {
+            segments.push((Sha256Digest::default(), Some(*range_end)));
+            segments.push((*range_start, None));
+        }
+    }
+    segments.retain(|(lower, upper)| Some(*lower) != *upper);
+    segments.sort();
+
+    let mut result: Vec<Segment> = Vec::new();
+    for (lower, upper) in segments {
+        match result.last_mut() {
+            Some(last) if last.1.is_none_or(|last_upper| lower <= last_upper) => {
+                if last.1.is_some() && (upper.is_none() || upper > last.1) {
+                    last.1 = upper;
+                }
+            }
+            _ => result.push((lower, upper)),
+        }
+    }
+    result
+}
+
+// The parts of the key space outside of the segments, which are ordered and without overlaps
+fn segments_complement(segments: &[Segment]) -> Vec<Segment> {
+    let mut result = Vec::new();
+    let mut lower = Some(Sha256Digest::default());
+    for (segment_lower, segment_upper) in segments.iter() {
+        if let Some(lower) = lower.filter(|lower| lower < segment_lower) {
+            result.push((lower, Some(*segment_lower)));
+        }
+        lower = *segment_upper;
+    }
+    if let Some(lower) = lower {
+        result.push((lower, None));
+    }
+    result
+}
+
+// The entry as moved to another DataBucket, or its part from offset if the entry is
+// larger than RELOCATION_PART_BYTES
+fn relocation_entry(key_sha2: &Sha256Digest, entry: &Entry, offset: u64) -> RelocationEntry {
+    let total_len = entry.value.len() as u64;
+    let (value, part) = if entry.key.len() as u64 + total_len <= RELOCATION_PART_BYTES {
+        (entry.value.clone(), None)
+    } else {
+        let part_start = offset.min(total_len);
+        let part_end = part_start
+            .saturating_add(RELOCATION_PART_BYTES)
+            .min(total_len);
+        (
+            entry.value[part_start as usize..part_end as usize].to_vec(),
+            Some(ValuePart {
+                offset: part_start,
+                total_len,
+            }),
+        )
+    };
+    RelocationEntry {
+        key_sha2: key_sha2.to_vec(),
+        key: entry.key.clone(),
+        value,
+        codec: entry.codec,
+        version: entry.version,
+        expires_at: entry.expires_at,
+        part,
+        deleted: false,
+    }
+}
+
+// The key_sha2 of a batch cursor, and the offset of the next part if the cursor is within
+// an entry, see RelocationEntry::cursor
+fn parse_cursor(cursor: &Sha2Vec) -> (Sha256Digest, Option<u64>) {
+    let key_sha2 = sha256_digest_from_vec(cursor);
+    let offset = cursor
+        .get(32..40)
+        .map(|offset| u64::from_be_bytes(offset.try_into().unwrap()));
+    (key_sha2, offset)
+}
+
 #[cfg(test)]
 mod tests;
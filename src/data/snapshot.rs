@@ -0,0 +1,317 @@
+// Snapshot of a DataBucket, for backups and offline inspection
+//
+// A snapshot is a header followed by the entries, ordered by the Sha256 of the key.
+// All integers are little endian.
+//
+// Header:
+//   +-------+---------+-------------+--------------+--------+--------+-------------+----------+
+//   | magic | version | range count | ranges       | id len | id     | entry count | checksum |
+//   | 4 B   | u32     | u32         | count × 64 B | u32    | id len | u64         | 32 B     |
+//   +-------+---------+-------------+--------------+--------+--------+-------------+----------+
+//
+// Each range is range_start (32 B) followed by range_end (32 B). Version 1 had a single
+// range, without the range count.
+//
+// Entry:
+//   +----------+---------+-----+-------+-----------+-------+---------+------------+
+//   | key_sha2 | key len | key | codec | value len | value | version | expires_at |
+//   | 32 B     | u32     |     | u8    | u64       |       | u64     | u8 (+ u64) |
+//   +----------+---------+-----+-------+-----------+-------+---------+------------+
+//
+// The value is stored as in the DataBucket, encoded with the codec (0: Raw, 1: Deflate).
+// expires_at is a flag byte, followed by the expiry time only if the flag is 1.
+// The checksum is the Merkle root hash of the entries (see merkle.rs), so a reader
+// verifies the entries by rebuilding the Merkle tree. The snapshot is exported in
+// pages, and a checksum mismatch also reveals writes to the DataBucket during the export.
+use super::{Codec, Entry, RelocationEntry};
+use crate::merkle::{self, MerkleTree};
+use crate::{sha256_digest_from_vec, CanisterId, Sha256Digest, Sha2Vec};
+use candid::CandidType;
+use serde::Deserialize;
+
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"BMSS";
+// Bump when the layout changes, and keep decoding the older versions
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 2;
+
+// Upper limit for the size of an exported page, keeps the messages well below the size limit
+pub const SNAPSHOT_PAGE_LIMIT_MAX: u64 = 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotHeader {
+    pub ranges: Vec<(Sha256Digest, Sha256Digest)>,
+    pub id: CanisterId,
+    pub entry_count: u64,
+    pub checksum: Sha256Digest,
+}
+
+// Position of the next page: the entry and the offset within its encoding,
+// since a large entry can be split over several pages
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct SnapshotCursor {
+    pub key_sha2: Sha2Vec,
+    pub offset: u64,
+}
+
+// The concatenated bytes of all pages are the snapshot
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SnapshotPage {
+    pub bytes: Vec<u8>,
+    pub next_cursor: Option<SnapshotCursor>, // None on the last page
+}
+
+impl SnapshotHeader {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&SNAPSHOT_MAGIC);
+        out.extend_from_slice(&SNAPSHOT_FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.ranges.len() as u32).to_le_bytes());
+        for (range_start, range_end) in self.ranges.iter() {
+            out.extend_from_slice(range_start);
+            out.extend_from_slice(range_end);
+        }
+        out.extend_from_slice(&(self.id.0.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.id.0);
+        out.extend_from_slice(&self.entry_count.to_le_bytes());
+        out.extend_from_slice(&self.checksum);
+    }
+}
+
+pub fn encode_entry(key_sha2: &Sha256Digest, entry: &Entry, out: &mut Vec<u8>) {
+    out.extend_from_slice(key_sha2);
+    out.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+    out.extend_from_slice(&entry.key);
+    out.push(codec_to_u8(entry.codec));
+    out.extend_from_slice(&(entry.value.len() as u64).to_le_bytes());
+    out.extend_from_slice(&entry.value);
+    out.extend_from_slice(&entry.version.to_le_bytes());
+    match entry.expires_at {
+        Some(expires_at) => {
+            out.push(1);
+            out.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        None => out.push(0),
+    }
+}
+
+fn codec_to_u8(codec: Codec) -> u8 {
+    match codec {
+        Codec::Raw => 0,
+        Codec::Deflate => 1,
+    }
+}
+
+fn codec_from_u8(codec: u8) -> Result<Codec, String> {
+    match codec {
+        0 => Ok(Codec::Raw),
+        1 => Ok(Codec::Deflate),
+        _ => Err(format!("Unknown codec {} in the snapshot", codec)),
+    }
+}
+
+// Reads from the decoder buffer, returns None if there are not enough bytes yet
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() - self.pos < len {
+            return None;
+        }
+        self.pos += len;
+        Some(&self.bytes[self.pos - len..self.pos])
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let mut result = [0u8; 4];
+        result.copy_from_slice(self.take(4)?);
+        Some(u32::from_le_bytes(result))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut result = [0u8; 8];
+        result.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(result))
+    }
+
+    fn digest(&mut self) -> Option<Sha256Digest> {
+        Some(*Sha256Digest::from_slice(self.take(32)?))
+    }
+}
+
+fn read_header(r: &mut Reader) -> Result<Option<SnapshotHeader>, String> {
+    let magic = match r.take(4) {
+        Some(magic) => magic,
+        None => return Ok(None),
+    };
+    if magic != SNAPSHOT_MAGIC {
+        return Err(format!(
+            "Snapshot magic mismatch: expected {} found {}",
+            hex::encode(SNAPSHOT_MAGIC),
+            hex::encode(magic)
+        ));
+    }
+    match r.u32() {
+        Some(1) => Ok(read_header_v1(r)),
+        Some(SNAPSHOT_FORMAT_VERSION) => Ok(read_header_v2(r)),
+        Some(version) => Err(format!("Unsupported snapshot format version {}", version)),
+        None => Ok(None),
+    }
+}
+
+fn read_header_v1(r: &mut Reader) -> Option<SnapshotHeader> {
+    let range_start = r.digest()?;
+    let range_end = r.digest()?;
+    read_header_rest(r, vec![(range_start, range_end)])
+}
+
+fn read_header_v2(r: &mut Reader) -> Option<SnapshotHeader> {
+    let range_count = r.u32()?;
+    let mut ranges = Vec::new();
+    for _ in 0..range_count {
+        ranges.push((r.digest()?, r.digest()?));
+    }
+    read_header_rest(r, ranges)
+}
+
+// The fields after the ranges, which are the same in all versions
+fn read_header_rest(
+    r: &mut Reader,
+    ranges: Vec<(Sha256Digest, Sha256Digest)>,
+) -> Option<SnapshotHeader> {
+    let id_len = r.u32()? as usize;
+    let id = CanisterId::from(r.take(id_len)?);
+    Some(SnapshotHeader {
+        ranges,
+        id,
+        entry_count: r.u64()?,
+        checksum: r.digest()?,
+    })
+}
+
+fn read_entry(r: &mut Reader) -> Option<Result<RelocationEntry, String>> {
+    let key_sha2 = r.take(32)?.to_vec();
+    let key_len = r.u32()? as usize;
+    let key = r.take(key_len)?.to_vec();
+    let codec = r.u8()?;
+    let value_len = r.u64()? as usize;
+    let value = r.take(value_len)?.to_vec();
+    let version = r.u64()?;
+    let expires_at = match r.u8()? {
+        0 => None,
+        1 => Some(r.u64()?),
+        flag => return Some(Err(format!("Invalid expiry flag {} in the snapshot", flag))),
+    };
+    Some(codec_from_u8(codec).map(|codec| RelocationEntry {
+        key_sha2,
+        key,
+        value,
+        codec,
+        version,
+        expires_at,
+        part: None,
+        deleted: false,
+    }))
+}
+
+// Decodes a snapshot which arrives in pieces of any size, e.g. the exported pages
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotDecoder {
+    buffer: Vec<u8>, // Received bytes which don't form a complete entry yet
+    header: Option<SnapshotHeader>,
+    entries_decoded: u64,
+}
+
+impl SnapshotDecoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Returns the entries completed by the provided bytes
+    pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<RelocationEntry>, String> {
+        self.buffer.extend_from_slice(bytes);
+        let mut r = Reader {
+            bytes: &self.buffer,
+            pos: 0,
+        };
+        if self.header.is_none() {
+            match read_header(&mut r)? {
+                Some(header) => self.header = Some(header),
+                None => return Ok(Vec::new()),
+            }
+        }
+
+        let entry_count = self.header.as_ref().unwrap().entry_count;
+        let mut entries = Vec::new();
+        while self.entries_decoded < entry_count {
+            let pos = r.pos;
+            match read_entry(&mut r) {
+                Some(entry) => {
+                    entries.push(entry?);
+                    self.entries_decoded += 1;
+                }
+                None => {
+                    r.pos = pos;
+                    break;
+                }
+            }
+        }
+        let pos = r.pos;
+        self.buffer.drain(..pos);
+        if self.is_complete() && !self.buffer.is_empty() {
+            return Err(format!(
+                "Snapshot has {} bytes after the last entry",
+                self.buffer.len()
+            ));
+        }
+        Ok(entries)
+    }
+
+    pub fn header(&self) -> Option<&SnapshotHeader> {
+        self.header.as_ref()
+    }
+
+    pub fn entries_decoded(&self) -> u64 {
+        self.entries_decoded
+    }
+
+    // True once the header and all entries have been decoded
+    pub fn is_complete(&self) -> bool {
+        match &self.header {
+            Some(header) => self.entries_decoded == header.entry_count,
+            None => false,
+        }
+    }
+}
+
+// The Merkle root hash of the entries, to compare with the checksum in the header
+pub fn checksum(entries: &[RelocationEntry]) -> Result<Sha256Digest, String> {
+    let mut leaves = Vec::with_capacity(entries.len());
+    for e in entries {
+        let key_sha2 = sha256_digest_from_vec(&e.key_sha2);
+        leaves.push((
+            key_sha2,
+            merkle::leaf_hash(&key_sha2, &e.codec.decode(&e.value)?),
+        ));
+    }
+    leaves.sort();
+    Ok(MerkleTree::from_sorted_leaves(&leaves).root_hash())
+}
+
+// Decode and verify a complete snapshot, e.g. read from a backup file
+pub fn decode_snapshot(bytes: &[u8]) -> Result<(SnapshotHeader, Vec<RelocationEntry>), String> {
+    let mut decoder = SnapshotDecoder::new();
+    let entries = decoder.push(bytes)?;
+    let header = match decoder.header() {
+        Some(header) if decoder.is_complete() => header.clone(),
+        _ => return Err("Snapshot is truncated".to_string()),
+    };
+    if checksum(&entries)? != header.checksum {
+        return Err("Snapshot checksum mismatch".to_string());
+    }
+    Ok((header, entries))
+}
@@ -1,11 +1,21 @@
-use super::{calc_sha256, CanisterId, DataBucket};
-use crate::hashring_sha256::{SHA256_DIGEST_MAX, SHA256_DIGEST_MIN};
+use super::{
+    calc_sha256, capacity_bytes_for_heap, sha256_digest_from_vec, snapshot, Access, AccessPolicy,
+    CanisterId, CasResult, Codec, DataBucket, RelocationEntry, CAPACITY_BYTES_DEFAULT,
+    CHUNK_SIZE_MAX, PUT_VALUE_SIZE_MAX, RELOCATION_PART_BYTES, RESTORE_ENTRY_BYTES, STABLE_MAGIC,
+    STABLE_SCHEMA_VERSION, TOMBSTONE_TTL_NS, UPGRADE_HEADROOM_BYTES, UPLOAD_IDLE_NS,
+    UPLOAD_SIZE_MAX,
+};
+use crate::error::BigMapError;
+use crate::hashring_sha256::{sha256_range_half, SHA256_DIGEST_MAX, SHA256_DIGEST_MIN};
+use crate::memory::{MemoryUsage, WASM_HEAP_BYTES};
+use crate::namespace::scoped_key;
+use crate::upgrade::{decode_stable_blob, encode_stable_blob};
 
 #[actix_rt::test]
 async fn bm_data_put_get() {
     // Insert key&value pairs and then get the value, and verify the correctness
     let mut d = DataBucket::new(CanisterId::from(42));
-    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX);
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
     for i in 0..100 as u8 {
         let key = format!("key-{}", i).into_bytes();
         let value = vec![i; 200_000];
@@ -26,7 +36,7 @@ async fn bm_data_put_get() {
 async fn bm_data_append_get() {
     // Insert key&value pairs and then get the value, and verify the correctness
     let mut d = DataBucket::new(CanisterId::from(42));
-    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX);
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
     for i in 0..100 as u8 {
         let key = b"key-0".to_vec();
         let value = vec![i; 200_000];
@@ -49,7 +59,7 @@ async fn bm_data_append_get() {
 fn bm_data_hash_range_get() {
     // Insert key&value pairs and then get the value, and verify the correctness
     let mut d = DataBucket::new(CanisterId::from(42));
-    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX);
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
 
     let mut key_hashes = Vec::new();
 
@@ -66,3 +76,1293 @@ fn bm_data_hash_range_get() {
     assert_eq!(key_hashes[0], d_key_hashes.0);
     assert_eq!(key_hashes[key_hashes.len() - 1], d_key_hashes.1);
 }
+
+#[test]
+fn bm_data_batch_put_results() {
+    // Every entry of a batch gets its own result, in the order of the batch
+    let mut d = DataBucket::new(CanisterId::from(42));
+    let range_end = sha256_range_half(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX);
+    d.set_range(&SHA256_DIGEST_MIN, &range_end, 0);
+
+    let mut batch = Vec::new();
+    for i in 0..20u32 {
+        batch.push((format!("key-{}", i).into_bytes(), vec![1u8; 100]));
+    }
+    let too_large = vec![2u8; PUT_VALUE_SIZE_MAX as usize + 1];
+    batch.push((b"key-too-large".to_vec(), too_large));
+
+    let results = d.batch_put(&batch);
+    assert_eq!(results.len(), batch.len());
+    for ((key, value), res) in batch.iter().zip(results) {
+        if !d.is_in_range(&calc_sha256(key)) {
+            assert_eq!(res, Err(BigMapError::KeyOutOfRange));
+        } else if value.len() as u64 > PUT_VALUE_SIZE_MAX {
+            assert_eq!(
+                res,
+                Err(BigMapError::ValueTooLarge {
+                    limit_bytes: PUT_VALUE_SIZE_MAX
+                })
+            );
+            assert_eq!(d.get(key.clone()), Err(BigMapError::NotFound));
+        } else {
+            assert_eq!(res, Ok(value.len() as u64));
+            assert_eq!(*d.get(key.clone()).unwrap(), *value);
+        }
+    }
+}
+
+#[test]
+fn bm_data_stable_save_restore() {
+    // Serialize the DataBucket as before an upgrade, restore it, and verify the state is preserved
+    let mut d = DataBucket::new(CanisterId::from(42));
+    let range_end = sha256_range_half(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX);
+    d.set_range(&SHA256_DIGEST_MIN, &range_end, 3);
+
+    let mut keys = Vec::new();
+    let mut keys_out_of_range = Vec::new();
+    for i in 0..1000u32 {
+        let key = format!("key-{}", i).into_bytes();
+        match d.put(&key, &vec![(i % 256) as u8; 100], false) {
+            Ok(_) => keys.push(key),
+            Err(err) => {
+                assert_eq!(err, BigMapError::KeyOutOfRange);
+                keys_out_of_range.push(key)
+            }
+        }
+    }
+
+    let blob = encode_stable_blob(&STABLE_MAGIC, STABLE_SCHEMA_VERSION, &d.to_stable_payload());
+    let (schema_version, payload) = decode_stable_blob(&STABLE_MAGIC, &blob).unwrap();
+    let r = DataBucket::from_stable_payload(schema_version, payload).unwrap();
+
+    assert_eq!(r.canister_id(), d.canister_id());
+    assert_eq!(r.used_bytes(), d.used_bytes());
+    assert_eq!(r.entries.len(), keys.len());
+    for key in keys {
+        assert_eq!(r.get(key.clone()).unwrap(), d.get(key).unwrap());
+    }
+    // The assigned range is restored as well
+    assert!(!keys_out_of_range.is_empty());
+    for key in keys_out_of_range {
+        assert!(!r.is_in_range(&calc_sha256(&key)));
+    }
+    assert_eq!(r.range_epoch(), 3);
+
+    // State saved by a different canister type is rejected
+    assert!(decode_stable_blob(b"XXXX", &blob).is_err());
+    assert!(DataBucket::from_stable_payload(STABLE_SCHEMA_VERSION + 1, payload).is_err());
+}
+
+#[test]
+fn bm_data_stable_streamed() {
+    // The state is streamed to and from the stable memory in small pieces
+    struct Chunked<T>(T);
+
+    impl<W: std::io::Write> std::io::Write for Chunked<W> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(&buf[..buf.len().min(7)])
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl<R: std::io::Read> std::io::Read for Chunked<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(5);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    let mut d = DataBucket::new(CanisterId::from(42));
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    for i in 0..100u32 {
+        let key = format!("key-{}", i).into_bytes();
+        d.put(&key, &vec![(i % 256) as u8; 100], false).unwrap();
+    }
+    d.delete_with_tombstone(&b"key-0".to_vec()).unwrap();
+
+    let mut payload = Vec::new();
+    d.write_stable_payload(Chunked(&mut payload)).unwrap();
+    assert_eq!(payload, d.to_stable_payload());
+    let r = DataBucket::read_stable_payload(STABLE_SCHEMA_VERSION, Chunked(payload.as_slice()))
+        .unwrap();
+    assert_eq!(r.to_stable_payload(), payload);
+    assert_eq!(r.entries.len(), 99);
+    // A truncated payload is rejected
+    let truncated = &payload[..payload.len() - 1];
+    assert!(DataBucket::read_stable_payload(STABLE_SCHEMA_VERSION, Chunked(truncated)).is_err());
+}
+
+#[test]
+fn bm_data_range_epoch() {
+    // Operations routed with a routing table older than the range are rejected
+    let mut d = DataBucket::new(CanisterId::from(42));
+    assert_eq!(d.range_epoch(), 0);
+    assert_eq!(d.check_epoch(0), Ok(()));
+
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 5);
+    assert_eq!(d.range_epoch(), 5);
+    assert_eq!(
+        d.check_epoch(4),
+        Err(BigMapError::StaleEpoch { current_epoch: 5 })
+    );
+    assert_eq!(d.check_epoch(5), Ok(()));
+    // A newer routing table didn't change the range of this DataBucket
+    assert_eq!(d.check_epoch(6), Ok(()));
+
+    // Importing a snapshot keeps the epoch, which comes with the next range
+    let mut d2 = DataBucket::new(CanisterId::from(43));
+    d2.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MIN, 7);
+    let page = d.export_snapshot(&None, u64::MAX);
+    d2.import_snapshot(&page.bytes).unwrap();
+    assert_eq!(d2.range_epoch(), 7);
+}
+
+#[test]
+fn bm_data_key_index_list_page() {
+    // The secondary key index is kept in sync with the entries and is accounted in the memory usage
+    let mut d = DataBucket::new(CanisterId::from(42));
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+
+    for i in 0..1000u32 {
+        let key = format!("key-{:04}", i).into_bytes();
+        d.put(&key, &vec![(i % 256) as u8; 10], false)
+            .expect("DataBucket put failed");
+    }
+    for i in (0..1000u32).step_by(2) {
+        d.delete(format!("key-{:04}", i).into_bytes())
+            .expect("DataBucket delete failed");
+    }
+
+    let page = d.list_page(&b"key-01".to_vec(), &None, 10);
+    let expected: Vec<_> = (101..120)
+        .step_by(2)
+        .map(|i| format!("key-{:04}", i).into_bytes())
+        .collect();
+    assert_eq!(page, expected);
+
+    let page = d.list_page(&b"key-01".to_vec(), &Some(b"key-0191".to_vec()), 10);
+    assert_eq!(
+        page,
+        vec![
+            b"key-0193".to_vec(),
+            b"key-0195".to_vec(),
+            b"key-0197".to_vec(),
+            b"key-0199".to_vec()
+        ]
+    );
+    assert_eq!(d.list(&b"key-".to_vec()).len(), 500);
+
+    // Moving all entries out of the range also removes them from the key index
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MIN, 0);
+    let batch = d.get_relocation_batch(&None, u64::MAX);
+    let batch_sha2 = batch.iter().map(|e| e.key_sha2.clone()).collect();
+    d.delete_entries(&batch_sha2);
+    assert!(d.list(&Vec::new()).is_empty());
+    assert_eq!(d.used_bytes(), 0);
+    assert_eq!(d.memory_usage().keys, 0);
+}
+
+#[test]
+fn bm_data_chunked_upload_download() {
+    // Upload a value in out-of-order chunks, commit it, and read it back in chunks
+    let mut d = DataBucket::new(CanisterId::from(42));
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+
+    let key = b"big-object".to_vec();
+    let value: Vec<u8> = (0..5_000_000u32).map(|i| (i % 251) as u8).collect();
+    let chunk_size = CHUNK_SIZE_MAX as usize;
+
+    let upload_id = d.begin_upload(&key, value.len() as u64).unwrap();
+    let chunks: Vec<_> = value.chunks(chunk_size).enumerate().collect();
+    for (i, chunk) in chunks.iter().rev() {
+        let offset = (i * chunk_size) as u64;
+        assert_eq!(
+            d.put_chunk(upload_id, offset, chunk).unwrap(),
+            chunk.len() as u64
+        );
+        if *i > 0 {
+            // Not visible and not committable until all chunks are received
+            assert_eq!(d.get(key.clone()), Err(BigMapError::NotFound));
+            assert!(matches!(
+                d.commit_upload(upload_id),
+                Err(BigMapError::InvalidArgument(_))
+            ));
+        }
+    }
+    assert!(d.put_chunk(upload_id, value.len() as u64, &[1u8]).is_err());
+
+    // A commit while the key is out of range keeps the upload, for a commit after the range is back
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MIN, 1);
+    assert_eq!(d.commit_upload(upload_id), Err(BigMapError::KeyOutOfRange));
+    assert_eq!(d.memory_usage().uploads, value.len() as u64);
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 2);
+    assert_eq!(d.commit_upload(upload_id).unwrap(), value.len() as u64);
+    assert_eq!(
+        d.commit_upload(upload_id),
+        Err(BigMapError::UploadNotFound { upload_id })
+    );
+    assert_eq!(*d.get(key.clone()).unwrap(), value);
+
+    let mut downloaded = Vec::new();
+    loop {
+        let (total_len, chunk) = d
+            .get_chunk(&key, downloaded.len() as u64, CHUNK_SIZE_MAX)
+            .unwrap();
+        assert_eq!(total_len, value.len() as u64);
+        if chunk.is_empty() {
+            break;
+        }
+        downloaded.extend(chunk);
+    }
+    assert_eq!(downloaded, value);
+
+    // Aborted uploads release the memory of the received chunks
+    let usage_total = d.memory_usage().total();
+    let upload_id = d.begin_upload(&b"aborted".to_vec(), 100).unwrap();
+    d.put_chunk(upload_id, 0, &[0u8; 50]).unwrap();
+    assert_eq!(d.memory_usage().total(), usage_total + 50);
+    d.abort_upload(upload_id).unwrap();
+    assert_eq!(d.memory_usage().total(), usage_total);
+
+    // The length of an upload is limited, and chunks beyond it are rejected
+    assert_eq!(
+        d.begin_upload(&key, UPLOAD_SIZE_MAX + 1),
+        Err(BigMapError::ValueTooLarge {
+            limit_bytes: UPLOAD_SIZE_MAX
+        })
+    );
+    let upload_id = d.begin_upload(&b"idle".to_vec(), 100).unwrap();
+    assert!(matches!(
+        d.put_chunk(upload_id, u64::MAX, &[0u8; 50]),
+        Err(BigMapError::InvalidArgument(_))
+    ));
+
+    // Uploads which got no chunk for a while are dropped by the sweep
+    d.put_chunk(upload_id, 0, &[0u8; 50]).unwrap();
+    d.sweep_expired(100);
+    assert_eq!(d.memory_usage().total(), usage_total + 50);
+    d.uploads.get_mut(&upload_id).unwrap().last_active -= UPLOAD_IDLE_NS;
+    d.sweep_expired(100);
+    assert_eq!(
+        d.put_chunk(upload_id, 50, &[0u8; 50]),
+        Err(BigMapError::UploadNotFound { upload_id })
+    );
+    assert_eq!(d.memory_usage().total(), usage_total);
+}
+
+#[test]
+fn bm_data_relocation_in_parts() {
+    // An entry larger than a message is relocated and replicated in parts
+    let mut src = DataBucket::new(CanisterId::from(42));
+    src.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    let mut dst = DataBucket::new(CanisterId::from(43));
+    dst.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+
+    let key_big = b"big-object".to_vec();
+    let value_big: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+    let upload_id = src.begin_upload(&key_big, value_big.len() as u64).unwrap();
+    for (i, chunk) in value_big.chunks(CHUNK_SIZE_MAX as usize).enumerate() {
+        let offset = i as u64 * CHUNK_SIZE_MAX;
+        src.put_chunk(upload_id, offset, chunk).unwrap();
+    }
+    src.commit_upload(upload_id).unwrap();
+    for i in 0..20u32 {
+        let key = format!("key-{}", i).into_bytes();
+        src.put(&key, &vec![7u8; 1000], false).unwrap();
+    }
+    let used_bytes = src.used_bytes();
+
+    // The batches end with the part of an entry, and continue with its next part
+    let ranges = src.ranges().to_vec();
+    let mut cursor = None;
+    let mut parts = 0;
+    loop {
+        let batch = src.get_range_batch(&ranges, &cursor, 10_000);
+        if batch.is_empty() {
+            break;
+        }
+        for e in batch.iter() {
+            assert!((e.key.len() + e.value.len()) as u64 <= RELOCATION_PART_BYTES + 100);
+            if e.part.is_some() {
+                parts += 1;
+                assert!(e.is_complete() || std::ptr::eq(e, batch.last().unwrap()));
+            }
+        }
+        assert_eq!(dst.put_relocation_batch(&batch), batch.len() as u64);
+        // The entry isn't visible until its last part is received
+        if !batch.last().unwrap().is_complete() {
+            assert_eq!(dst.get(key_big.clone()), Err(BigMapError::NotFound));
+        }
+        cursor = batch.last().map(|e| e.cursor());
+    }
+    assert_eq!(parts, 3);
+    assert_eq!(*dst.get(key_big.clone()).unwrap(), value_big);
+    assert_eq!(dst.root_hash(), src.root_hash());
+    assert_eq!(dst.used_bytes(), used_bytes);
+
+    // The replica entries of a large entry start with its first part, and a part which
+    // doesn't continue the received parts is rejected
+    let mut replica = DataBucket::new(CanisterId::from(44));
+    replica.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    let first = src.get_replica_entries(&[key_big.clone()]).pop().unwrap();
+    assert!(!first.is_complete());
+    let second = src.get_entry_part(&key_big, first.next_offset()).unwrap();
+    assert!(replica.put_replica_batch(&[second.clone()])[0].is_err());
+    assert!(replica.put_replica_batch(&[first.clone()])[0].is_ok());
+    let mut part = second;
+    loop {
+        // A part sent again is accepted
+        assert!(replica.put_replica_batch(&[part.clone()])[0].is_ok());
+        assert!(replica.put_replica_batch(&[part.clone()])[0].is_ok());
+        if part.is_complete() {
+            break;
+        }
+        part = src.get_entry_part(&key_big, part.next_offset()).unwrap();
+    }
+    assert_eq!(*replica.get(key_big.clone()).unwrap(), value_big);
+    assert!(src
+        .get_entry_part(&key_big, value_big.len() as u64)
+        .is_none());
+    assert_eq!(
+        first.into_decoded_value(),
+        Err(BigMapError::ValueTooLarge {
+            limit_bytes: RELOCATION_PART_BYTES
+        })
+    );
+
+    // The parts of an entry which is never completed are dropped by the sweep
+    let mut partial = DataBucket::new(CanisterId::from(45));
+    partial.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    let first = src.get_replica_entries(&[key_big.clone()]).pop().unwrap();
+    assert_eq!(partial.put_relocation_batch(&vec![first.clone()]), 1);
+    assert_eq!(partial.used_bytes(), 0);
+    assert_eq!(partial.memory_usage().total(), first.value.len() as u64);
+    let key_sha2 = calc_sha256(&key_big);
+    partial.relocation_parts.get_mut(&key_sha2).unwrap().1 -= UPLOAD_IDLE_NS;
+    partial.sweep_expired(100);
+    assert!(partial.relocation_parts.is_empty());
+    assert_eq!(partial.memory_usage().total(), 0);
+}
+
+#[test]
+fn bm_data_versioned_cas() {
+    // Conditional writes succeed only with the current version, versions survive relocation
+    let mut d = DataBucket::new(CanisterId::from(42));
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    let key = b"key-cas".to_vec();
+
+    // Version 0 means the entry must not exist yet
+    assert_eq!(
+        d.put_if_version(&key, &b"v1".to_vec(), 5).unwrap(),
+        CasResult::Conflict { current_version: 0 }
+    );
+    let v1 = match d.put_if_version(&key, &b"v1".to_vec(), 0).unwrap() {
+        CasResult::Applied { version } => version,
+        res => panic!("unexpected {:?}", res),
+    };
+    assert_eq!(
+        d.put_if_version(&key, &b"v1".to_vec(), 0).unwrap(),
+        CasResult::Conflict {
+            current_version: v1
+        }
+    );
+
+    // Every write, including unconditional ones and appends, bumps the version
+    d.put(&key, &b"v2".to_vec(), true).unwrap();
+    let (value, v2) = d.get_with_version(&key).unwrap();
+    assert_eq!(*value, b"v1v2".to_vec());
+    assert!(v2 > v1);
+    assert_eq!(
+        d.put_if_version(&key, &b"v3".to_vec(), v1).unwrap(),
+        CasResult::Conflict {
+            current_version: v2
+        }
+    );
+    assert_eq!(
+        d.delete_if_version(&key, v1).unwrap(),
+        CasResult::Conflict {
+            current_version: v2
+        }
+    );
+    assert_eq!(
+        d.delete_if_version(&key, v2).unwrap(),
+        CasResult::Applied { version: 0 }
+    );
+    assert!(d.get_with_version(&key).is_err());
+
+    // A recreated key never gets a version it had before
+    d.put(&key, &b"v4".to_vec(), false).unwrap();
+    let (_, v4) = d.get_with_version(&key).unwrap();
+    assert!(v4 > v2);
+
+    // Relocated entries keep their version
+    let mut d2 = DataBucket::new(CanisterId::from(43));
+    d2.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MIN, 0);
+    let batch = d.get_relocation_batch(&None, u64::MAX);
+    assert_eq!(d2.put_relocation_batch(&batch), 1);
+    assert_eq!(d2.get_with_version(&key).unwrap().1, v4);
+    d2.put(&key, &b"v5".to_vec(), false).unwrap();
+    assert!(d2.get_with_version(&key).unwrap().1 > v4);
+    // A relocated entry replaces only an older entry
+    assert_eq!(d2.put_relocation_batch(&batch), 1);
+    assert_eq!(*d2.get(key.clone()).unwrap(), b"v5".to_vec());
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    d2.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MIN, 0);
+    let newer = d2.get_relocation_batch(&None, u64::MAX);
+    assert_eq!(d.put_relocation_batch(&newer), 1);
+    assert_eq!(*d.get(key.clone()).unwrap(), b"v5".to_vec());
+    d2.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MIN, 0);
+
+    // Keys outside of the range are rejected
+    assert!(d.put_if_version(&key, &b"v6".to_vec(), 0).is_err());
+
+    // Versions are preserved across upgrades
+    let r =
+        DataBucket::from_stable_payload(STABLE_SCHEMA_VERSION, &d2.to_stable_payload()).unwrap();
+    assert_eq!(r.get_with_version(&key), d2.get_with_version(&key));
+}
+
+#[test]
+fn bm_data_entry_expiry() {
+    // Expired entries are treated as absent, and sweep_expired reclaims their memory
+    let mut d = DataBucket::new(CanisterId::from(42));
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    let now = crate::time_now();
+    let hour = 3_600_000_000_000;
+
+    d.put(&b"key-keep".to_vec(), &b"value".to_vec(), false)
+        .unwrap();
+    d.put_with_expiry(&b"key-live".to_vec(), &b"value".to_vec(), now + hour)
+        .unwrap();
+    d.put(&b"key-live".to_vec(), &b"-appended".to_vec(), true)
+        .unwrap();
+    let used_bytes_base = d.used_bytes();
+    for i in 0..10u32 {
+        let key = format!("key-expired-{}", i).into_bytes();
+        d.put_with_expiry(&key, &b"value".to_vec(), now - 1)
+            .unwrap();
+    }
+    assert_eq!(d.entries.len(), 12);
+
+    // Appending keeps the expiry time
+    assert_eq!(
+        *d.get(b"key-live".to_vec()).unwrap(),
+        b"value-appended".to_vec()
+    );
+    assert_eq!(
+        d.entries[&calc_sha256(b"key-live")].expires_at,
+        Some(now + hour)
+    );
+    let key = b"key-expired-0".to_vec();
+    assert!(d.get(key.clone()).is_err());
+    assert!(d.get_with_version(&key).is_err());
+    assert!(!d.holds_key(&key));
+    assert_eq!(
+        d.list(&b"key-".to_vec()),
+        vec![b"key-keep".to_vec(), b"key-live".to_vec()]
+    );
+    assert_eq!(
+        d.put_if_version(&key, &b"new".to_vec(), 0).unwrap(),
+        CasResult::Applied { version: 14 }
+    );
+    // The plain put removed the expiry time
+    assert_eq!(*d.get(key.clone()).unwrap(), b"new".to_vec());
+
+    // The sweep is bounded, and frees all memory used by the expired entries
+    assert_eq!(d.sweep_expired(5), 5);
+    assert_eq!(d.sweep_expired(100), 4);
+    assert_eq!(d.sweep_expired(100), 0);
+    assert_eq!(d.entries.len(), 3);
+    d.delete(key).unwrap();
+    assert_eq!(d.used_bytes(), used_bytes_base);
+
+    // Expiry times are preserved across upgrades
+    let r = DataBucket::from_stable_payload(STABLE_SCHEMA_VERSION, &d.to_stable_payload()).unwrap();
+    assert_eq!(r.used_bytes(), d.used_bytes());
+    assert_eq!(
+        r.entries[&calc_sha256(b"key-live")].expires_at,
+        Some(now + hour)
+    );
+}
+
+#[test]
+fn bm_data_compression() {
+    // Values are stored compressed, relocated without decoding, and returned as the original
+    let mut d = DataBucket::new(CanisterId::from(42));
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    d.set_default_codec(Codec::Deflate);
+
+    let doc = std::fs::read("tests/movies.json").unwrap();
+    let key = b"key-doc".to_vec();
+    assert_eq!(d.put(&key, &doc, false).unwrap(), doc.len() as u64);
+    assert_eq!(*d.get(key.clone()).unwrap(), doc);
+    let entry = &d.entries[&calc_sha256(&key)];
+    assert_eq!(entry.codec, Codec::Deflate);
+    assert!(entry.value.len() < doc.len() / 2);
+    assert!(d.used_bytes() < doc.len() / 2);
+
+    // Values which don't compress are stored Raw, and the codec can be chosen per put
+    let key_short = b"key-short".to_vec();
+    d.put_with_codec(&key_short, &b"abc".to_vec(), false, Codec::Deflate)
+        .unwrap();
+    assert_eq!(d.entries[&calc_sha256(&key_short)].codec, Codec::Raw);
+    let key_raw = b"key-raw".to_vec();
+    d.put_with_codec(&key_raw, &doc, false, Codec::Raw).unwrap();
+    assert_eq!(d.entries[&calc_sha256(&key_raw)].value.len(), doc.len());
+
+    // Appending and chunked reads work on the original value
+    d.put(&key, &b"appended".to_vec(), true).unwrap();
+    let (value_len, chunk) = d.get_chunk(&key, doc.len() as u64, 100).unwrap();
+    assert_eq!(value_len, doc.len() as u64 + 8);
+    assert_eq!(chunk, b"appended".to_vec());
+
+    // Relocation moves the compressed bytes
+    let mut d2 = DataBucket::new(CanisterId::from(43));
+    d2.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MIN, 0);
+    let batch = d.get_relocation_batch(&None, u64::MAX);
+    let e = batch.iter().find(|e| e.key == key).unwrap();
+    assert_eq!(e.codec, Codec::Deflate);
+    assert!(e.value.len() < doc.len() / 2);
+    assert_eq!(d2.put_relocation_batch(&batch), 3);
+    assert_eq!(d2.used_bytes(), d.used_bytes());
+    assert_eq!(d2.get(key.clone()).unwrap().len(), doc.len() + 8);
+    assert_eq!(*d2.get(key_raw.clone()).unwrap(), doc);
+
+    // The codecs are preserved across upgrades
+    d2.set_default_codec(Codec::Deflate);
+    let mut r =
+        DataBucket::from_stable_payload(STABLE_SCHEMA_VERSION, &d2.to_stable_payload()).unwrap();
+    assert_eq!(r.default_codec(), Codec::Deflate);
+    assert_eq!(r.get(key.clone()).unwrap(), d2.get(key.clone()).unwrap());
+    assert_eq!(r.get(key_raw.clone()).unwrap(), d2.get(key_raw).unwrap());
+
+    // A delete reports the length of the value as stored, without decoding it
+    let stored_len = r.entries[&calc_sha256(&key)].value.len() as u64;
+    assert!(stored_len < doc.len() as u64 / 2);
+    assert_eq!(r.delete(key).unwrap(), stored_len);
+
+    // Uploaded values are stored Raw, and read in chunks without decompressing
+    let key_upload = b"key-upload".to_vec();
+    let upload_id = d2.begin_upload(&key_upload, doc.len() as u64).unwrap();
+    d2.put_chunk(upload_id, 0, &doc).unwrap();
+    d2.commit_upload(upload_id).unwrap();
+    assert_eq!(d2.entries[&calc_sha256(&key_upload)].codec, Codec::Raw);
+
+    // A compressed value is inflated up to the size of a put, a larger one is rejected
+    let zeros = vec![0u8; PUT_VALUE_SIZE_MAX as usize + 1];
+    let compressed = miniz_oxide::deflate::compress_to_vec(&zeros, 6);
+    assert!(Codec::Deflate.decode(&compressed).is_err());
+    assert_eq!(Codec::Deflate.encode(&zeros).0, Codec::Raw);
+
+    // A value which fails to decode can still be deleted, and the indexes stay consistent
+    let key_sha2 = calc_sha256(&key_upload);
+    let entry = d2.entries.get_mut(&key_sha2).unwrap();
+    entry.codec = Codec::Deflate;
+    entry.value = compressed.clone();
+    assert!(matches!(
+        d2.get(key_upload.clone()),
+        Err(BigMapError::DataCorrupted(_))
+    ));
+    assert_eq!(
+        d2.delete(key_upload.clone()).unwrap(),
+        compressed.len() as u64
+    );
+    assert!(!d2.holds_key(&key_upload));
+    assert!(d2.list(&key_upload).is_empty());
+}
+
+#[test]
+fn bm_data_certified_get() {
+    // Every value can be verified against the root hash, through all changes of the entries
+    let mut d = DataBucket::new(CanisterId::from(42));
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    d.set_default_codec(Codec::Deflate);
+    let root_empty = d.root_hash();
+    for i in 0..100 {
+        let key = format!("key-{}", i).into_bytes();
+        d.put(&key, &vec![b'a'; 1000 + i], false).unwrap();
+    }
+    d.put(&b"key-0".to_vec(), &b"appended".to_vec(), true)
+        .unwrap();
+    d.delete(b"key-1".to_vec()).unwrap();
+
+    let root = d.root_hash();
+    for i in 2..100 {
+        let key = format!("key-{}", i).into_bytes();
+        let certified = d.get_certified(&key).unwrap();
+        assert_eq!(certified.value, vec![b'a'; 1000 + i]);
+        assert!(certified.witness.verify(&root, &key, &certified.value));
+        assert!(!certified.witness.verify(&root, &key, b"forged"));
+        assert_eq!(certified.certificate, None);
+    }
+    let certified = d.get_certified(&b"key-0".to_vec()).unwrap();
+    assert!(certified.witness.verify(&root, b"key-0", &certified.value));
+    assert!(d.get_certified(&b"key-1".to_vec()).is_err());
+
+    // The root hash is rebuilt after an upgrade
+    let r = DataBucket::from_stable_payload(STABLE_SCHEMA_VERSION, &d.to_stable_payload()).unwrap();
+    assert_eq!(r.root_hash(), root);
+    assert_eq!(r.used_bytes(), d.used_bytes());
+
+    // After relocating the upper half, both buckets certify exactly their own entries
+    let range_half = sha256_range_half(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX);
+    let mut d2 = DataBucket::new(CanisterId::from(43));
+    d2.set_range(&range_half, &SHA256_DIGEST_MAX, 0);
+    d.set_range(&SHA256_DIGEST_MIN, &range_half, 0);
+    let batch = d.get_relocation_batch(&None, u64::MAX);
+    d2.put_relocation_batch(&batch);
+    d.delete_entries(&batch.iter().map(|e| e.key_sha2.clone()).collect());
+    for i in 2..100 {
+        let key = format!("key-{}", i).into_bytes();
+        let d = if d.holds_key(&key) { &d } else { &d2 };
+        let certified = d.get_certified(&key).unwrap();
+        assert!(certified
+            .witness
+            .verify(&d.root_hash(), &key, &certified.value));
+    }
+
+    // Deleting all entries returns to the empty root hash, and frees the memory
+    for i in 0..100 {
+        let key = format!("key-{}", i).into_bytes();
+        let d = if d.is_in_range(&calc_sha256(&key)) {
+            &mut d
+        } else {
+            &mut d2
+        };
+        d.delete(key).unwrap();
+    }
+    assert_eq!(d.root_hash(), root_empty);
+    assert_eq!(d2.root_hash(), root_empty);
+    assert_eq!(d.used_bytes() + d2.used_bytes(), 0);
+}
+
+#[test]
+fn bm_data_snapshot_export_import() {
+    // A snapshot exported in pages imports into an identical DataBucket
+    let mut d = DataBucket::new(CanisterId::from(42));
+    let range_half = sha256_range_half(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX);
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    for i in 0..50 {
+        let key = format!("key-{}", i).into_bytes();
+        d.put(&key, &vec![b'a'; 100 + i], false).unwrap();
+    }
+    d.put_with_codec(
+        &b"key-doc".to_vec(),
+        &vec![b'd'; 20_000],
+        false,
+        Codec::Deflate,
+    )
+    .unwrap();
+    d.put_with_expiry(&b"key-expiring".to_vec(), &b"soon".to_vec(), u64::MAX / 2)
+        .unwrap();
+    d.put(&b"key-large".to_vec(), &vec![b'l'; 5000], false)
+        .unwrap();
+    // Entries outside of the range, e.g. during rebalancing, are in the snapshot as well
+    d.set_range(&SHA256_DIGEST_MIN, &range_half, 0);
+
+    let mut bytes = Vec::new();
+    let mut cursor = None;
+    let mut pages = 0;
+    loop {
+        let page = d.export_snapshot(&cursor, 1000);
+        assert!(page.bytes.len() <= 1000);
+        bytes.extend_from_slice(&page.bytes);
+        pages += 1;
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert!(pages > 5);
+
+    let (header, entries) = snapshot::decode_snapshot(&bytes).unwrap();
+    assert_eq!(header.ranges, vec![(*SHA256_DIGEST_MIN, range_half)]);
+    assert_eq!(header.id, CanisterId::from(42));
+    assert_eq!(header.checksum, d.root_hash());
+    assert_eq!(entries.len(), 53);
+
+    // Import in pieces of different sizes than the exported pages
+    let mut r = DataBucket::new(CanisterId::from(43));
+    let mut imported = 0;
+    for chunk in bytes.chunks(777) {
+        imported = r.import_snapshot(chunk).unwrap();
+    }
+    assert_eq!(imported, 53);
+    assert_eq!(r.root_hash(), d.root_hash());
+    assert_eq!(r.used_bytes(), d.used_bytes());
+    assert_eq!(r.get_key_hash_range(), d.get_key_hash_range());
+    assert!(!r.is_in_range(&SHA256_DIGEST_MAX));
+    for key in [&b"key-doc"[..], b"key-expiring", b"key-large", b"key-7"] {
+        let key = key.to_vec();
+        assert_eq!(r.get(key.clone()).unwrap(), d.get(key.clone()).unwrap());
+        let key_sha2 = calc_sha256(&key);
+        assert_eq!(r.entries[&key_sha2].codec, d.entries[&key_sha2].codec);
+        assert_eq!(r.entries[&key_sha2].version, d.entries[&key_sha2].version);
+        assert_eq!(
+            r.entries[&key_sha2].expires_at,
+            d.entries[&key_sha2].expires_at
+        );
+    }
+
+    // Snapshots are only imported into an empty DataBucket
+    assert!(r.import_snapshot(&bytes).is_err());
+
+    // A corrupted value fails the checksum
+    let mut corrupted = bytes.clone();
+    let pos = corrupted
+        .windows(100)
+        .position(|w| w == [b'l'; 100])
+        .unwrap();
+    corrupted[pos] = b'x';
+    assert!(snapshot::decode_snapshot(&corrupted).is_err());
+    assert!(DataBucket::new(CanisterId::from(44))
+        .import_snapshot(&corrupted)
+        .is_err());
+    assert!(snapshot::decode_snapshot(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn bm_data_snapshot_import_corrupted() {
+    // A failed import leaves the DataBucket empty, with its ranges, and can start over
+    let mut d = DataBucket::new(CanisterId::from(42));
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    for i in 0..50 {
+        let key = format!("key-{}", i).into_bytes();
+        d.put_with_expiry(&key, &vec![b'a'; 100 + i], u64::MAX / 2)
+            .unwrap();
+    }
+    d.put(&b"key-last".to_vec(), &vec![b'l'; 5000], false)
+        .unwrap();
+    let bytes = d.export_snapshot(&None, u64::MAX).bytes;
+    let mut corrupted = bytes.clone();
+    let pos = corrupted
+        .windows(100)
+        .rposition(|w| w == [b'l'; 100])
+        .unwrap();
+    corrupted[pos] = b'x';
+
+    let mut r = DataBucket::new(CanisterId::from(43));
+    let range_half = sha256_range_half(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX);
+    r.set_range(&range_half, &SHA256_DIGEST_MAX, 0);
+    let empty_hash = r.root_hash();
+    let mut result = Ok(0);
+    for chunk in corrupted.chunks(1000) {
+        result = r.import_snapshot(chunk);
+        if result.is_err() {
+            break;
+        }
+        // The ranges are set only once the checksum is verified
+        assert!(!r.is_in_range(&SHA256_DIGEST_MIN));
+    }
+    assert_eq!(
+        result,
+        Err(BigMapError::DataCorrupted(
+            "Snapshot checksum mismatch".to_string()
+        ))
+    );
+    assert!(r.entries.is_empty());
+    assert!(r.list(&b"key-".to_vec()).is_empty());
+    assert_eq!(r.sweep_expired(100), 0);
+    assert_eq!(r.used_bytes(), 0);
+    assert_eq!(r.root_hash(), empty_hash);
+    assert!(!r.is_in_range(&SHA256_DIGEST_MIN));
+
+    // An invalid entry fails the import, also after the entries of the previous pages
+    let mut page = Vec::new();
+    snapshot::decode_snapshot(&bytes)
+        .unwrap()
+        .0
+        .encode(&mut page);
+    let mut entries = d.entries.iter();
+    for (key_sha2, entry) in entries.by_ref().take(10) {
+        snapshot::encode_entry(key_sha2, entry, &mut page);
+    }
+    assert_eq!(r.import_snapshot(&page).unwrap(), 10);
+    assert_eq!(r.entries.len(), 10);
+    let (key_sha2, entry) = entries.next().unwrap();
+    let mut page = Vec::new();
+    snapshot::encode_entry(key_sha2, entry, &mut page);
+    page[32 + 4 + entry.key.len()] = 9; // The codec
+    assert!(r.import_snapshot(&page).is_err());
+    assert!(r.entries.is_empty());
+    assert_eq!(r.used_bytes(), 0);
+
+    // The import starts over
+    assert_eq!(r.import_snapshot(&bytes).unwrap(), 51);
+    assert_eq!(r.root_hash(), d.root_hash());
+    assert_eq!(r.used_bytes(), d.used_bytes());
+    assert!(r.is_in_range(&SHA256_DIGEST_MIN));
+}
+
+#[test]
+fn bm_data_memory_usage() {
+    // The memory usage breakdown is kept in sync with the structures through all updates
+    let mut d = DataBucket::new(CanisterId::from(42));
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    let now = crate::time_now();
+    let hour = 3_600_000_000_000;
+
+    for i in 0..100u32 {
+        let key = format!("key-{}", i).into_bytes();
+        d.put(&key, &vec![(i % 256) as u8; 100], false).unwrap();
+    }
+    d.put_with_expiry(&b"key-1".to_vec(), &b"value".to_vec(), now + hour)
+        .unwrap();
+    d.put_with_expiry(&b"key-2".to_vec(), &b"value".to_vec(), now - 1)
+        .unwrap();
+    d.put(&b"key-3".to_vec(), &b"-appended".to_vec(), true)
+        .unwrap();
+    d.put(&b"key-1".to_vec(), &b"-appended".to_vec(), true)
+        .unwrap();
+    d.delete(b"key-4".to_vec()).unwrap();
+    d.sweep_expired(100);
+    let upload_id = d.begin_upload(&b"key-5".to_vec(), 300).unwrap();
+    d.put_chunk(upload_id, 0, &[1u8; 300]).unwrap();
+    d.commit_upload(upload_id).unwrap();
+    let upload_id = d.begin_upload(&b"key-upload".to_vec(), 300).unwrap();
+    d.put_chunk(upload_id, 100, &[1u8; 200]).unwrap();
+    assert_eq!(d.memory_usage(), d.memory_usage_calc());
+    assert_eq!(d.memory_usage().uploads, 300);
+    assert_eq!(d.memory_usage().heap, None);
+    // used_bytes counts the keys and the values of the entries as stored, and the key hashes
+    let entries_bytes: usize = d
+        .entries
+        .values()
+        .map(|e| e.key.len() + e.value.len() + 32)
+        .sum();
+    assert_eq!(d.used_bytes(), entries_bytes);
+
+    // Appending to an existing entry uses the same memory as a put of the combined value
+    let mut d2 = DataBucket::new(CanisterId::from(43));
+    d2.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    d2.put(&b"key".to_vec(), &b"value".to_vec(), false).unwrap();
+    d2.put(&b"key".to_vec(), &b"-appended".to_vec(), true)
+        .unwrap();
+    let mut d3 = DataBucket::new(CanisterId::from(44));
+    d3.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    d3.put(&b"key".to_vec(), &b"value-appended".to_vec(), false)
+        .unwrap();
+    assert_eq!(d2.memory_usage(), d3.memory_usage());
+
+    // Relocating the entries out moves their memory usage as well
+    let range_end = sha256_range_half(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX);
+    d.set_range(&SHA256_DIGEST_MIN, &range_end, 0);
+    let batch = d.get_relocation_batch(&None, u64::MAX);
+    d2.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    d2.put_relocation_batch(&batch);
+    d.delete_entries(&batch.iter().map(|e| e.key_sha2.clone()).collect());
+    assert_eq!(d.memory_usage(), d.memory_usage_calc());
+    assert_eq!(d2.memory_usage(), d2.memory_usage_calc());
+    d.abort_upload(upload_id).unwrap();
+    assert_eq!(d.memory_usage().uploads, 0);
+
+    // The memory usage is recalculated on restore, the uploads are not kept
+    let r = DataBucket::from_stable_payload(STABLE_SCHEMA_VERSION, &d.to_stable_payload()).unwrap();
+    assert_eq!(r.memory_usage(), d.memory_usage());
+}
+
+#[test]
+fn bm_data_capacity_upgrade_headroom() {
+    // A DataBucket filled up to the default capacity of a canister can still be upgraded:
+    // the payload fits in the stable memory, and the restore fits in the heap
+    let capacity_bytes = capacity_bytes_for_heap(WASM_HEAP_BYTES) as u128;
+    assert!(capacity_bytes > WASM_HEAP_BYTES as u128 / 2);
+    for value_len in &[0usize, 10, 1000, 100_000] {
+        let mut d = DataBucket::new(CanisterId::from(42));
+        d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+        let num_entries = 200;
+        for i in 0..num_entries {
+            let key = format!("k{}", i).into_bytes();
+            d.put(&key, &vec![(i % 256) as u8; *value_len], false)
+                .unwrap();
+        }
+        let used_bytes = d.memory_usage().total() as u128;
+        let payload_len = d.to_stable_payload().len() as u128;
+        let restore_bytes = used_bytes + (num_entries * RESTORE_ENTRY_BYTES) as u128;
+
+        // Scaled to a DataBucket of the same entries at the capacity
+        assert!(payload_len * capacity_bytes / used_bytes < u32::MAX as u128);
+        assert!(
+            restore_bytes * capacity_bytes / used_bytes + UPGRADE_HEADROOM_BYTES as u128
+                <= WASM_HEAP_BYTES as u128
+        );
+    }
+}
+
+#[test]
+fn bm_data_capacity() {
+    // Writes over the capacity are rejected, deletes and relocations make room
+    let mut d = DataBucket::new(CanisterId::from(42));
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    assert_eq!(d.capacity_bytes(), CAPACITY_BYTES_DEFAULT);
+    assert!(d.set_capacity_bytes(0).is_err());
+    d.set_capacity_bytes(100_000).unwrap();
+
+    let mut i = 0;
+    let full = loop {
+        let key = format!("key-{}", i).into_bytes();
+        match d.put(&key, &vec![7u8; 1000], false) {
+            Ok(_) => i += 1,
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(
+        full,
+        BigMapError::BucketFull {
+            capacity_bytes: 100_000
+        }
+    );
+    assert!(d.used_bytes() <= 100_000);
+    let key = b"key-0".to_vec();
+    assert!(d.put(&key, &vec![7u8; 1000], true).is_err());
+    let upload_id = d.begin_upload(&b"key-upload".to_vec(), 2000).unwrap();
+    assert!(d.put_chunk(upload_id, 0, &[7u8; 2000]).is_err());
+
+    // A delete makes room for the next put
+    d.delete(key.clone()).unwrap();
+    d.put(&key, &vec![7u8; 1000], false).unwrap();
+
+    // Relocations are accepted over the capacity
+    let mut d2 = DataBucket::new(CanisterId::from(43));
+    d2.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    d2.set_capacity_bytes(1000).unwrap();
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MIN, 0);
+    let batch = d.get_relocation_batch(&None, u64::MAX);
+    assert_eq!(d2.put_relocation_batch(&batch), batch.len() as u64);
+
+    // The capacity is kept across upgrades
+    let r =
+        DataBucket::from_stable_payload(STABLE_SCHEMA_VERSION, &d2.to_stable_payload()).unwrap();
+    assert_eq!(r.capacity_bytes(), 1000);
+}
+
+#[test]
+fn bm_data_access_policy() {
+    let mut d = DataBucket::new(CanisterId::from(42));
+    let index = CanisterId::from(1);
+    let admin = CanisterId::from(2);
+    let app = CanisterId::from(3);
+    d.record_index_canister(index.clone());
+    // The first recorded index canister is kept, e.g. on upgrades
+    d.record_index_canister(app.clone());
+    assert_eq!(d.access_policy().index_canister, Some(index.clone()));
+
+    // By default, apps may write entries directly, but not administer the DataBucket
+    assert!(d.authorize(&index, Access::Admin).is_ok());
+    assert!(d.authorize(&app, Access::Write).is_ok());
+    assert_eq!(
+        d.authorize(&app, Access::Admin),
+        Err(BigMapError::Unauthorized)
+    );
+
+    // Apps access directly only the keys of their default namespace
+    let own_key = scoped_key(&app.to_string(), b"key");
+    let foreign_key = scoped_key(&admin.to_string(), b"key");
+    for access in &[Access::Read, Access::Write] {
+        assert!(d.authorize_key(&app, *access, &own_key).is_ok());
+        assert_eq!(
+            d.authorize_key(&app, *access, &foreign_key),
+            Err(BigMapError::Unauthorized)
+        );
+        assert_eq!(
+            d.authorize_key(&app, *access, b"key"),
+            Err(BigMapError::Unauthorized)
+        );
+        assert!(d.authorize_key(&index, *access, &foreign_key).is_ok());
+    }
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    let upload_id = d.begin_upload(&foreign_key, 10).unwrap();
+    assert_eq!(
+        d.authorize_upload(&app, upload_id),
+        Err(BigMapError::Unauthorized)
+    );
+    let upload_id = d.begin_upload(&own_key, 10).unwrap();
+    assert!(d.authorize_upload(&app, upload_id).is_ok());
+
+    assert!(d.set_access_policy(AccessPolicy::default()).is_err());
+    d.set_access_policy(AccessPolicy {
+        index_canister: Some(index.clone()),
+        admins: vec![admin.clone()],
+        allow_direct_writes: false,
+    })
+    .unwrap();
+    assert!(d.authorize(&admin, Access::Admin).is_ok());
+    assert!(d.authorize(&index, Access::Write).is_ok());
+    assert_eq!(
+        d.authorize(&app, Access::Write),
+        Err(BigMapError::Unauthorized)
+    );
+    assert!(d.authorize_key(&app, Access::Read, &own_key).is_ok());
+    assert_eq!(
+        d.authorize_key(&app, Access::Write, &own_key),
+        Err(BigMapError::Unauthorized)
+    );
+
+    // The access policy is kept across upgrades
+    let r = DataBucket::from_stable_payload(STABLE_SCHEMA_VERSION, &d.to_stable_payload()).unwrap();
+    assert_eq!(r.access_policy(), d.access_policy());
+}
+
+#[test]
+fn bm_data_tombstones() {
+    // A delete through the BigMap Index keeps a tombstone with a new version, which is
+    // copied to the other replicas like an entry
+    let mut d = DataBucket::new(CanisterId::from(42));
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    let key = b"key".to_vec();
+    d.put(&key, &vec![1u8; 100], false).unwrap();
+    let version = d.get_with_version(&key).unwrap().1;
+    let entry = d.get_replica_entries(&[key.clone()]).pop().unwrap();
+    assert!(!entry.deleted);
+
+    assert_eq!(d.delete_with_tombstone(&key).unwrap(), 100);
+    assert_eq!(d.get(key.clone()), Err(BigMapError::NotFound));
+    let tombstone = d.get_replica_entries(&[key.clone()]).pop().unwrap();
+    assert!(tombstone.deleted);
+    assert!(tombstone.value.is_empty());
+    assert!(tombstone.version > version);
+    assert_eq!(d.memory_usage(), d.memory_usage_calc());
+    assert!(d.memory_usage().total() > 0);
+
+    // A replica which holds the entry deletes it and keeps the tombstone
+    let mut r = DataBucket::new(CanisterId::from(43));
+    r.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    r.put_replica_batch(&[entry]);
+    assert!(r.holds_key(&key));
+    assert_eq!(
+        r.put_replica_batch(&[tombstone.clone()]),
+        vec![Ok(tombstone.version)]
+    );
+    assert!(!r.holds_key(&key));
+    assert_eq!(
+        r.get_replica_entries(&[key.clone()]).pop().unwrap().version,
+        tombstone.version
+    );
+    assert_eq!(r.memory_usage(), r.memory_usage_calc());
+    // A later write gets a higher version than the tombstone, and supersedes it
+    r.put(&key, &vec![2u8; 100], false).unwrap();
+    assert!(r.get_with_version(&key).unwrap().1 > tombstone.version);
+    assert!(!r.get_replica_entries(&[key.clone()]).pop().unwrap().deleted);
+
+    // The tombstones are kept across upgrades, and reclaimed after TOMBSTONE_TTL_NS
+    let restored =
+        DataBucket::from_stable_payload(STABLE_SCHEMA_VERSION, &d.to_stable_payload()).unwrap();
+    assert_eq!(restored.used_bytes(), d.used_bytes());
+    assert!(restored.get_replica_entries(&[key.clone()])[0].deleted);
+    d.sweep_tombstones(crate::time_now(), 10);
+    assert_eq!(d.get_replica_entries(&[key.clone()]).len(), 1);
+    d.sweep_tombstones(crate::time_now() + TOMBSTONE_TTL_NS, 10);
+    assert!(d.get_replica_entries(&[key.clone()]).is_empty());
+    assert_eq!(d.used_bytes(), 0);
+
+    // A delete by the apps leaves no tombstone
+    d.put(&key, &vec![1u8; 100], false).unwrap();
+    d.delete(key.clone()).unwrap();
+    assert!(d.get_replica_entries(&[key]).is_empty());
+}
+
+#[test]
+fn bm_data_reset() {
+    // A DataBucket taken out of the hash ring starts over, and keeps its configuration
+    let mut d = DataBucket::new(CanisterId::from(42));
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 3);
+    d.set_capacity_bytes(1_000_000).unwrap();
+    d.record_index_canister(CanisterId::from(1));
+    let key = b"key".to_vec();
+    d.put(&key, &b"value".to_vec(), false).unwrap();
+
+    // Refused while an entry is left
+    assert!(matches!(d.reset(), Err(BigMapError::InvalidArgument(_))));
+    assert!(d.holds_key(&key));
+
+    d.delete_with_tombstone(&key).unwrap();
+    d.begin_upload(&b"upload".to_vec(), 100).unwrap();
+    assert!(!d.tombstones.is_empty());
+    d.reset().unwrap();
+    assert!(d.ranges().is_empty());
+    assert_eq!(d.range_epoch(), 0);
+    assert!(d.tombstones.is_empty());
+    assert_eq!(d.version_counter, 0);
+    assert!(d.uploads.is_empty());
+    assert_eq!(d.memory_usage(), MemoryUsage::default());
+    assert_eq!(d.capacity_bytes(), 1_000_000);
+    assert!(d.authorize(&CanisterId::from(1), Access::Admin).is_ok());
+}
+
+#[test]
+fn bm_data_relocation_counter_ahead() {
+    // Entries relocated into a DataBucket whose version counter is ahead of the source keep
+    // their versions, and the counter is not moved back
+    let mut src = DataBucket::new(CanisterId::from(42));
+    src.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    let mut dst = DataBucket::new(CanisterId::from(43));
+    dst.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    for i in 0..10 {
+        src.put(&format!("key-{}", i).into_bytes(), &b"src".to_vec(), false)
+            .unwrap();
+    }
+    for i in 0..100 {
+        dst.put(&format!("dst-{}", i).into_bytes(), &b"dst".to_vec(), false)
+            .unwrap();
+    }
+    assert_eq!(src.raise_version_counter(0), 10);
+    assert_eq!(dst.raise_version_counter(src.raise_version_counter(0)), 100);
+
+    src.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MIN, 1);
+    let batch = src.get_relocation_batch(&None, u64::MAX);
+    assert_eq!(dst.put_relocation_batch(&batch), 10);
+    assert_eq!(dst.put_relocation_batch(&batch), 10);
+    for e in batch.iter() {
+        let (value, version) = dst.get_with_version(&e.key).unwrap();
+        assert_eq!(*value, b"src".to_vec());
+        assert_eq!(version, e.version);
+    }
+    assert_eq!(dst.raise_version_counter(0), 100);
+
+    // A write after the relocation is newer than the relocated entry, and wins over it
+    let key = batch[0].key.clone();
+    assert_eq!(dst.put(&key, &b"new".to_vec(), false).unwrap(), 3);
+    assert_eq!(dst.get_with_version(&key).unwrap().1, 101);
+    assert_eq!(dst.put_relocation_batch(&batch), 10);
+    assert_eq!(*dst.get(key).unwrap(), b"new".to_vec());
+}
+
+#[test]
+fn bm_data_replica_range() {
+    // A range which wraps around the end of the key space, as held by a replica
+    let mut d = DataBucket::new(CanisterId::from(42));
+    let half = sha256_range_half(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX);
+    let quarter = sha256_range_half(&SHA256_DIGEST_MIN, &half);
+    let three_quarters = sha256_range_half(&half, &SHA256_DIGEST_MAX);
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    let keys: Vec<Vec<u8>> = (0..200)
+        .map(|i| format!("key-{}", i).into_bytes())
+        .collect();
+    for key in keys.iter() {
+        d.put(key, &key.repeat(10), false).unwrap();
+    }
+    d.set_range(&three_quarters, &quarter, 0);
+    let (keys_in, keys_out): (Vec<&Vec<u8>>, Vec<&Vec<u8>>) = keys
+        .iter()
+        .partition(|key| d.is_in_range(&calc_sha256(key)));
+    assert!(!keys_in.is_empty() && !keys_out.is_empty());
+    for key in keys_in.iter() {
+        let key_sha2 = calc_sha256(key);
+        assert!(key_sha2 >= three_quarters || key_sha2 < quarter);
+    }
+
+    // The entries in the range are copied in batches, continuing after the cursor
+    let mut copied = Vec::new();
+    let mut cursor = None;
+    loop {
+        let batch = d.get_range_batch(&[(three_quarters, quarter)], &cursor, 500);
+        if batch.is_empty() {
+            break;
+        }
+        cursor = batch.last().map(|e| e.key_sha2.clone());
+        copied.extend(batch);
+    }
+    assert_eq!(copied.len(), keys_in.len());
+    let mut d2 = DataBucket::new(CanisterId::from(43));
+    d2.set_range(&three_quarters, &quarter, 0);
+    assert_eq!(d2.put_relocation_batch(&copied), copied.len() as u64);
+
+    // The entries outside of the range are relocated
+    let batch = d.get_relocation_batch(&None, u64::MAX);
+    assert_eq!(batch.len(), keys_out.len());
+    let batch_sha2 = batch.iter().map(|e| e.key_sha2.clone()).collect();
+    d.delete_entries(&batch_sha2);
+    assert_eq!(d.list(&Vec::new()).len(), keys_in.len());
+
+    // A replica takes over the entries sent from the owner, with their version
+    let key = keys_in[0];
+    d.put(key, &b"new".to_vec(), false).unwrap();
+    let entries = d.get_replica_entries(&[key.clone(), keys_out[0].clone()]);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        d2.put_replica_batch(&entries),
+        vec![Ok(d.get_with_version(key).unwrap().1)]
+    );
+    assert_eq!(d2.get_with_version(key), d.get_with_version(key));
+    let mut entry_out = entries[0].clone();
+    entry_out.key_sha2 = calc_sha256(keys_out[0]).to_vec();
+    assert_eq!(
+        d2.put_replica_batch(&[entry_out]),
+        vec![Err(BigMapError::KeyOutOfRange)]
+    );
+}
+
+#[test]
+fn bm_data_multiple_ranges() {
+    // The ranges of the virtual nodes of a data bucket, one of which wraps around the end of
+    // the key space, and one which is set twice
+    let mut d = DataBucket::new(CanisterId::from(42));
+    let half = sha256_range_half(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX);
+    let quarter = sha256_range_half(&SHA256_DIGEST_MIN, &half);
+    let eighth = sha256_range_half(&SHA256_DIGEST_MIN, &quarter);
+    let three_quarters = sha256_range_half(&half, &SHA256_DIGEST_MAX);
+    d.set_range(&SHA256_DIGEST_MIN, &SHA256_DIGEST_MAX, 0);
+    let keys: Vec<Vec<u8>> = (0..300)
+        .map(|i| format!("key-{}", i).into_bytes())
+        .collect();
+    for key in keys.iter() {
+        d.put(key, &key.repeat(10), false).unwrap();
+    }
+    let ranges = vec![(three_quarters, eighth), (quarter, half), (quarter, half)];
+    d.set_ranges(&ranges, 5);
+    assert_eq!(d.ranges(), &ranges[..]);
+    assert_eq!(d.range_epoch(), 5);
+
+    let (keys_in, keys_out): (Vec<&Vec<u8>>, Vec<&Vec<u8>>) = keys
+        .iter()
+        .partition(|key| d.is_in_range(&calc_sha256(key)));
+    assert!(!keys_in.is_empty() && !keys_out.is_empty());
+    for key in keys.iter() {
+        let key_sha2 = calc_sha256(key);
+        let expected = key_sha2 >= three_quarters
+            || key_sha2 < eighth
+            || (key_sha2 >= quarter && key_sha2 < half);
+        assert_eq!(d.is_in_range(&key_sha2), expected);
+    }
+
+    // The batches are in the order of the key hashes, each entry is in one batch only
+    let batches = |get_batch: &dyn Fn(&Option<Vec<u8>>) -> Vec<RelocationEntry>| {
+        let mut result: Vec<RelocationEntry> = Vec::new();
+        let mut cursor = None;
+        loop {
+            let batch = get_batch(&cursor);
+            if batch.is_empty() {
+                break;
+            }
+            cursor = batch.last().map(|e| e.key_sha2.clone());
+            result.extend(batch);
+        }
+        assert!(result.windows(2).all(|w| w[0].key_sha2 < w[1].key_sha2));
+        result
+    };
+    let copied = batches(&|cursor| d.get_range_batch(&ranges, cursor, 500));
+    assert_eq!(copied.len(), keys_in.len());
+    let relocated = batches(&|cursor| d.get_relocation_batch(cursor, 500));
+    assert_eq!(relocated.len(), keys_out.len());
+    for entry in relocated.iter() {
+        assert!(!d.is_in_range(&sha256_digest_from_vec(&entry.key_sha2)));
+    }
+
+    // The ranges are kept across upgrades and in the snapshots
+    let r = DataBucket::from_stable_payload(STABLE_SCHEMA_VERSION, &d.to_stable_payload()).unwrap();
+    assert_eq!(r.ranges(), d.ranges());
+    assert_eq!(r.range_epoch(), 5);
+    let (header, _) = snapshot::decode_snapshot(&d.export_snapshot(&None, u64::MAX).bytes).unwrap();
+    assert_eq!(header.ranges, ranges);
+
+    // Without ranges, the data bucket is not in the hash ring and all entries are relocated
+    d.set_ranges(&[], 6);
+    assert!(!d.is_in_range(&calc_sha256(keys_in[0])));
+    assert_eq!(d.get_relocation_batch(&None, u64::MAX).len(), keys.len());
+}
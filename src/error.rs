@@ -0,0 +1,86 @@
+///////////////////////////////////////////////////////////////
+// Errors returned by the BigMap canisters
+//
+// All endpoints of the BigMap Index, Data Bucket and Search canisters return
+// Result<T, BigMapError>, so that clients can tell apart e.g. a missing entry
+// from a failed call, and retry only where it makes sense.
+///////////////////////////////////////////////////////////////
+use candid::CandidType;
+use ic_cdk::context::RejectionCode;
+use serde::Deserialize;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum BigMapError {
+    // The key is not in the range of the data bucket, e.g. since the range
+    // changed with rebalancing. The BigMap Index routes the key correctly.
+    KeyOutOfRange,
+    NotFound,
+    UploadNotFound { upload_id: u64 },
+    // No data bucket or search canister is available yet, retry later
+    BucketUnavailable,
+    // The operation can't be done while rebalancing, retry later
+    Rebalancing,
+    // The request would exceed a storage limit
+    QuotaExceeded { limit_bytes: u64 },
+    // The data bucket is at its capacity. The BigMap Index splits it and retries the write.
+    BucketFull { capacity_bytes: u64 },
+    // The value is too large for a put, larger values are sent with a chunked upload
+    // and read with get_chunk. Uploads are limited as well.
+    ValueTooLarge { limit_bytes: u64 },
+    InvalidArgument(String),
+    // The caller may not call the method, see the access policy of the data bucket
+    Unauthorized,
+    // A stored value or a snapshot can't be decoded or fails its checksum
+    DataCorrupted(String),
+    // A call to another canister was rejected, code is the IC rejection code
+    CallFailed { code: i32, msg: String },
+    // The operation was routed with an outdated routing table, refresh it from the
+    // BigMap Index with get_routing_table
+    StaleEpoch { current_epoch: u64 },
+}
+
+impl fmt::Display for BigMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BigMapError::KeyOutOfRange => {
+                write!(f, "Key is not in the range assigned to this DataBucket")
+            }
+            BigMapError::NotFound => write!(f, "Entry not found"),
+            BigMapError::UploadNotFound { upload_id } => {
+                write!(f, "Upload id {} not found", upload_id)
+            }
+            BigMapError::BucketUnavailable => write!(f, "No data bucket available"),
+            BigMapError::Rebalancing => write!(f, "Rebalancing in progress"),
+            BigMapError::QuotaExceeded { limit_bytes } => {
+                write!(f, "Quota of {} bytes exceeded", limit_bytes)
+            }
+            BigMapError::BucketFull { capacity_bytes } => {
+                write!(f, "Data bucket is full at {} bytes", capacity_bytes)
+            }
+            BigMapError::ValueTooLarge { limit_bytes } => {
+                write!(f, "Value is larger than {} bytes", limit_bytes)
+            }
+            BigMapError::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            BigMapError::Unauthorized => write!(f, "The caller is not authorized"),
+            BigMapError::DataCorrupted(msg) => write!(f, "Data corrupted: {}", msg),
+            BigMapError::CallFailed { code, msg } => {
+                write!(f, "Call failed with rejection code {}: {}", code, msg)
+            }
+            BigMapError::StaleEpoch { current_epoch } => {
+                write!(f, "Routing table is older than epoch {}", current_epoch)
+            }
+        }
+    }
+}
+
+// Allows using `?` on the result of ic_cdk::call
+impl From<(RejectionCode, String)> for BigMapError {
+    fn from(err: (RejectionCode, String)) -> Self {
+        let (code, msg) = err;
+        BigMapError::CallFailed {
+            code: code as i32,
+            msg,
+        }
+    }
+}
@@ -42,11 +42,16 @@ impl<T: Clone> Ord for Node<T> {
     }
 }
 
+// A node may have multiple virtual nodes in the ring, each of which holds the keys up to
+// its key. The weight of a node is its share of the keys, relative to the other nodes.
 #[derive(Debug, Default, Clone)]
 pub struct HashRing<T: Clone> {
     pub(crate) ring: Vec<Node<T>>,
+    weights: Vec<(T, u32)>, // Only the nodes with a weight other than WEIGHT_DEFAULT
 }
 
+pub const WEIGHT_DEFAULT: u32 = 1;
+
 /// Hash Ring
 ///
 /// A hash ring that provides consistent hashing for nodes that are added to it.
@@ -60,7 +65,7 @@ impl<T: Clone + Default> HashRing<T> {
 
 #[allow(dead_code)]
 impl<T: Clone> HashRing<T> {
-    /// Get the number of nodes in the hash ring.
+    /// Get the number of virtual nodes in the hash ring.
     pub fn len(&self) -> usize {
         self.ring.len()
     }
@@ -104,8 +109,58 @@ pub(crate) fn sha256_range_half(
     biguint_to_sha256_digest(&bigint_diff)
 }
 
+// The key at the share numerator / denominator of the range from sha256_lower to sha256_upper
+pub(crate) fn sha256_range_share(
+    sha256_lower: &Sha256Digest,
+    sha256_upper: &Sha256Digest,
+    numerator: u32,
+    denominator: u32,
+) -> Sha256Digest {
+    let i_prev = biguint_from_slice256(sha256_lower.as_slice());
+    let i = biguint_from_slice256(sha256_upper.as_slice());
+    let bigint_share = i_prev.clone() + (i - i_prev) * numerator / denominator;
+    biguint_to_sha256_digest(&bigint_share)
+}
+
 #[allow(dead_code)]
 impl<T: Clone + PartialEq + std::fmt::Debug> HashRing<T> {
+    /// Add `node` to the hash ring, with `vnodes` virtual nodes, each of which bisects
+    /// the largest gap between the keys of the ring.
+    /// Returns the index of the last virtual node added
+    pub fn add_vnodes(&mut self, node: T, vnodes: u32) -> usize {
+        let mut result = 0;
+        for _ in 0..vnodes.max(1) {
+            result = self.add(node.clone());
+        }
+        result
+    }
+
+    /// Add `node` to the hash ring, with a virtual node in the range of each virtual node
+    /// of `existing`. The new virtual node takes the share of the range given by the
+    /// weights of the two nodes, from the start of the range.
+    /// Returns the number of virtual nodes added
+    pub fn add_before(&mut self, existing: &T, node: T, weight: u32) -> usize {
+        let weight_existing = self.weight(existing);
+        let mut keys = Vec::new();
+        for (hr_idx, n) in self.ring.iter().enumerate() {
+            if n.node == *existing {
+                let (range_start, range_end) = self.get_key_range_for_idx(hr_idx);
+                keys.push(sha256_range_share(
+                    &range_start,
+                    &range_end,
+                    weight,
+                    weight + weight_existing,
+                ));
+            }
+        }
+        for key in keys.iter() {
+            self.ring.push(Node::new(*key, node.clone()));
+        }
+        self.ring.sort();
+        self.set_weight(&node, weight);
+        keys.len()
+    }
+
     /// Add `node` to the hash ring.
     /// Returns the index at which the node was added
     pub fn add(&mut self, node: T) -> usize {
@@ -135,7 +190,7 @@ impl<T: Clone + PartialEq + std::fmt::Debug> HashRing<T> {
         // let key = get_key(&self.hash_builder, &node);
         self.ring.push(Node::new(key_hash, node.clone()));
         self.ring.sort();
-        self.get_idx_key_node_for_node(&node).unwrap().0
+        self.ring.iter().position(|n| n.key == key_hash).unwrap()
     }
 
     /// Add `node` to the hash ring, with the provided key
@@ -146,16 +201,80 @@ impl<T: Clone + PartialEq + std::fmt::Debug> HashRing<T> {
         self.get_idx_key_node_for_node(&node).unwrap().0
     }
 
-    /// Remove `node` from the hash ring. Requires searching the entire ring.
+    /// Remove `node` with all of its virtual nodes from the hash ring. Requires searching
+    /// the entire ring.
     /// Returns an `Option` that will contain the `node` if it was in the hash
     /// ring or `None` if it was not present.
     pub fn remove_node(&mut self, node: &T) -> Option<T> {
-        match self.ring.iter().position(|n| n.node == *node) {
-            Some(index) => Some(self.ring.remove(index).node),
-            None => None,
+        let index = self.ring.iter().position(|n| n.node == *node)?;
+        let result = self.ring[index].node.clone();
+        self.ring.retain(|n| n.node != *node);
+        self.weights.retain(|(n, _)| n != node);
+        Some(result)
+    }
+
+    /// Replace `node` with `node_new` in all of its virtual nodes, keeping its weight
+    pub fn replace_node(&mut self, node: &T, node_new: T) {
+        for n in self.ring.iter_mut().filter(|n| n.node == *node) {
+            n.node = node_new.clone();
+        }
+        for (n, _) in self.weights.iter_mut().filter(|(n, _)| n == node) {
+            *n = node_new.clone();
+        }
+    }
+
+    /// The distinct nodes in the hash ring, in the order of their first virtual node
+    pub fn nodes(&self) -> Vec<T> {
+        let mut result: Vec<T> = Vec::new();
+        for n in self.ring.iter() {
+            if !result.contains(&n.node) {
+                result.push(n.node.clone());
+            }
+        }
+        result
+    }
+
+    pub fn weight(&self, node: &T) -> u32 {
+        match self.weights.iter().find(|(n, _)| n == node) {
+            Some((_, weight)) => *weight,
+            None => WEIGHT_DEFAULT,
+        }
+    }
+
+    /// Set the weight of `node`, which is used for placing the virtual nodes of the nodes
+    /// added before it with `add_before`
+    pub fn set_weight(&mut self, node: &T, weight: u32) {
+        self.weights.retain(|(n, _)| n != node);
+        if weight != WEIGHT_DEFAULT {
+            self.weights.push((node.clone(), weight));
         }
     }
 
+    /// The nodes with a weight other than WEIGHT_DEFAULT
+    pub fn weights(&self) -> &[(T, u32)] {
+        &self.weights
+    }
+
+    /// The node which takes over all virtual nodes of `node` if it's removed, None if the
+    /// virtual nodes have different successors, or `node` holds the end of the key space
+    pub fn successor_node(&self, node: &T) -> Option<&T> {
+        let mut result = None;
+        for (hr_idx, n) in self.ring.iter().enumerate() {
+            if n.node != *node {
+                continue;
+            }
+            let successor = &self.ring.get(hr_idx + 1)?.node;
+            if successor == node {
+                continue;
+            }
+            match result {
+                Some(result) if result != successor => return None,
+                _ => result = Some(successor),
+            }
+        }
+        result
+    }
+
     /// Get the Option<(idx,node)> responsible for `key`.
     /// Returns `None` if the ring is empty
     pub fn get_idx_node_for_key(&self, key: &Sha256Digest) -> Option<(usize, &T)> {
@@ -204,6 +323,14 @@ impl<T: Clone + PartialEq + std::fmt::Debug> HashRing<T> {
         (self.ring[idx - 1].key, self.ring[idx].key)
     }
 
+    /// The key ranges of the virtual nodes of `node`, in the order of the ring
+    pub fn get_key_ranges_for_node(&self, node: &T) -> Vec<(Sha256Digest, Sha256Digest)> {
+        (0..self.ring.len())
+            .filter(|idx| self.ring[*idx].node == *node)
+            .map(|idx| self.get_key_range_for_idx(idx))
+            .collect()
+    }
+
     /// Get the Option<(key,node)> responsible for `key`.
     /// Returns `None` if the ring is empty
     pub fn get_key_node(&self, key: &Sha256Digest) -> Option<(Sha256Digest, &T)> {
@@ -259,7 +386,8 @@ impl<T: Clone + PartialEq + std::fmt::Debug> HashRing<T> {
 mod tests {
     use super::biguint_to_sha256_digest;
     use super::BigUint;
-    use super::HashRing;
+    use super::{HashRing, WEIGHT_DEFAULT};
+    use crate::calc_sha256;
 
     #[test]
     fn hashring_sha256_add_key() {
@@ -294,4 +422,57 @@ mod tests {
         assert_eq!(r.get_key_node_at_idx(3).unwrap().0, max_hash);
         assert_eq!(r.ring.len(), 4);
     }
+
+    #[test]
+    fn hashring_sha256_vnodes_weights() {
+        // A node with virtual nodes is split with the weights, and merged into its successor
+        let max_hash_uint = BigUint::parse_bytes(b"f".repeat(64).as_slice(), 16).unwrap();
+        let at = |numerator: u32, denominator: u32| {
+            biguint_to_sha256_digest(&(&max_hash_uint * numerator / denominator))
+        };
+
+        let mut r = HashRing::new();
+        r.add_vnodes(1u32, 4);
+        assert_eq!(r.len(), 4);
+        assert_eq!(r.nodes(), vec![1]);
+        assert_eq!(
+            r.get_key_ranges_for_node(&1),
+            vec![
+                (at(0, 4), at(1, 4)),
+                (at(1, 4), at(2, 4)),
+                (at(2, 4), at(3, 4)),
+                (at(3, 4), at(4, 4))
+            ]
+        );
+        assert_eq!(r.successor_node(&1), None);
+
+        // Node 2 with weight 3 takes 3/4 of the start of each range of node 1
+        assert_eq!(r.add_before(&1, 2, 3), 4);
+        assert_eq!(r.weight(&1), WEIGHT_DEFAULT);
+        assert_eq!(r.weight(&2), 3);
+        assert_eq!(r.nodes(), vec![2, 1]);
+        assert_eq!(r.get_key_ranges_for_node(&2)[1], (at(4, 16), at(7, 16)));
+        assert_eq!(r.get_key_ranges_for_node(&1)[1], (at(7, 16), at(8, 16)));
+        for i in 0..100u32 {
+            let key = calc_sha256(i.to_be_bytes());
+            let owner = r.get(&key).unwrap();
+            let in_ranges = r
+                .get_key_ranges_for_node(owner)
+                .iter()
+                .any(|(start, end)| *start <= key && key < *end);
+            assert!(in_ranges);
+        }
+        assert_eq!(r.successor_node(&2), Some(&1));
+
+        // Renaming keeps the virtual nodes and the weight
+        r.replace_node(&2, 3);
+        assert_eq!(r.nodes(), vec![3, 1]);
+        assert_eq!(r.weight(&3), 3);
+        assert_eq!(r.weight(&2), WEIGHT_DEFAULT);
+
+        assert_eq!(r.remove_node(&3), Some(3));
+        assert_eq!(r.len(), 4);
+        assert_eq!(r.nodes(), vec![1]);
+        assert!(r.weights().is_empty());
+    }
 }
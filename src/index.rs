@@ -1,17 +1,38 @@
+use crate::data::{RelocationEntry, PUT_VALUE_SIZE_MAX, UPLOAD_IDLE_NS};
+#[cfg(target_arch = "wasm32")]
+use crate::subnet_upgrade_canister_code;
+use crate::upgrade::{CodeVersion, StableMagic};
 use crate::{
-    calc_sha256, hashring_sha256, subnet_create_new_canister, subnet_install_canister_code,
-    CanisterId, Key, Sha256Digest, Sha2Vec, Val,
+    calc_sha256, data, data_certificate, hashring_sha256, set_certified_data,
+    sha256_digest_from_vec, subnet_create_new_canister, subnet_install_canister_code, time_now,
+    BigMapError, CanisterId, Key, Sha256Digest, Sha2Vec, Val,
 };
 use bytesize::ByteSize;
+use candid::CandidType;
 #[cfg(target_arch = "wasm32")]
 use ic_cdk::println;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::hash::BuildHasherDefault;
+use std::io::{Read, Write};
 use wyhash::WyHash;
 
+pub mod call;
+pub mod code_upgrade;
+pub mod namespace;
+pub mod schedule;
+use call::CallPolicy;
+use code_upgrade::{CanisterKind, UpgradeFailure, UpgradeJob, UpgradeTarget};
+use namespace::{Namespace, Namespaces};
+use schedule::{MaintenanceAction, MaintenanceReport, MaintenanceSchedule, MaintenanceStatus};
+
 // CanisterPtr allows us to have u64 instead of a full CanisterId
 // in various parts of the BigMap Index
-#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(
+    Copy, Clone, Default, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
 struct CanisterPtr(u32);
 pub type DetHashSet<K> = HashSet<K, BuildHasherDefault<WyHash>>;
 pub type DetHashMap<K, V> = HashMap<K, V, BuildHasherDefault<WyHash>>;
@@ -22,7 +43,7 @@ type HashRingRange = (Sha256Digest, Sha256Digest);
 #[cfg(not(target_arch = "wasm32"))]
 type FnPtrAddToSearchIndex = Box<dyn Fn(CanisterId, &Key, &String)>;
 #[cfg(not(target_arch = "wasm32"))]
-type FnPtrBatchAddToSearchIndex = Box<dyn Fn(CanisterId, &Vec<(Key, String)>) -> u64>;
+type FnPtrBatchAddToSearchIndex = Box<dyn Fn(CanisterId, &[(Key, String)]) -> u64>;
 #[cfg(not(target_arch = "wasm32"))]
 type FnPtrSearchKeysByQuery = Box<dyn Fn(CanisterId, &String) -> Vec<Key>>;
 #[cfg(not(target_arch = "wasm32"))]
@@ -30,24 +51,76 @@ type FnPtrRemoveFromSearchIndex = Box<dyn Fn(CanisterId, &Key)>;
 #[cfg(not(target_arch = "wasm32"))]
 type FnPtrList = Box<dyn Fn(CanisterId, &Key) -> Vec<Key>>;
 #[cfg(not(target_arch = "wasm32"))]
+type FnPtrListPage = Box<dyn Fn(CanisterId, &Key, &Option<Key>, u32) -> Vec<Key>>;
+#[cfg(not(target_arch = "wasm32"))]
 type FnPtrUsedBytes = Box<dyn Fn(CanisterId) -> usize>;
 #[cfg(not(target_arch = "wasm32"))]
 type FnPtrHoldsKey = Box<dyn Fn(CanisterId, &Key) -> bool>;
 #[cfg(not(target_arch = "wasm32"))]
-type FnPtrSetRange = Box<dyn Fn(CanisterId, Sha256Digest, Sha256Digest)>;
+type FnPtrSetRange = Box<dyn Fn(CanisterId, &[HashRingRange], u64)>;
+#[cfg(not(target_arch = "wasm32"))]
+type FnPtrGetRelocationBatch =
+    Box<dyn Fn(CanisterId, &Option<Sha2Vec>, u64) -> Vec<RelocationEntry>>;
 #[cfg(not(target_arch = "wasm32"))]
-type FnPtrGetRelocationBatch = Box<dyn Fn(CanisterId, u64) -> Vec<(Sha2Vec, Key, Val)>>;
+type FnPtrGetRangeBatch =
+    Box<dyn Fn(CanisterId, &[HashRingRange], &Option<Sha2Vec>, u64) -> Vec<RelocationEntry>>;
 #[cfg(not(target_arch = "wasm32"))]
-type FnPtrPutRelocationBatch = Box<dyn Fn(CanisterId, &Vec<(Sha2Vec, Key, Val)>) -> u64>;
+type FnPtrGetReplicaEntries = Box<dyn Fn(CanisterId, &[Key]) -> Vec<RelocationEntry>>;
+#[cfg(not(target_arch = "wasm32"))]
+type FnPtrGetEntryPart = Box<dyn Fn(CanisterId, &Key, u64) -> Option<RelocationEntry>>;
+#[cfg(not(target_arch = "wasm32"))]
+type FnPtrPutReplicaBatch =
+    Box<dyn Fn(CanisterId, &[RelocationEntry]) -> Vec<Result<u64, BigMapError>>>;
+#[cfg(not(target_arch = "wasm32"))]
+type FnPtrPutRelocationBatch = Box<dyn Fn(CanisterId, &[RelocationEntry]) -> u64>;
 #[cfg(not(target_arch = "wasm32"))]
 type FnPtrDeleteEntries = Box<dyn Fn(CanisterId, &Vec<Vec<u8>>)>;
+#[cfg(not(target_arch = "wasm32"))]
+type FnPtrSweepExpired = Box<dyn Fn(CanisterId, u32) -> u64>;
+#[cfg(not(target_arch = "wasm32"))]
+type FnPtrReset = Box<dyn Fn(CanisterId) -> Result<(), BigMapError>>;
+#[cfg(not(target_arch = "wasm32"))]
+type FnPtrRaiseVersionCounter = Box<dyn Fn(CanisterId, u64) -> u64>;
+#[cfg(not(target_arch = "wasm32"))]
+type FnPtrDelete = Box<dyn Fn(CanisterId, &Key) -> Result<u64, BigMapError>>;
+#[cfg(not(target_arch = "wasm32"))]
+type FnPtrUpgradeCode = Box<dyn Fn(CanisterId, &[u8]) -> Result<(), String>>;
+#[cfg(not(target_arch = "wasm32"))]
+type FnPtrCodeVersion = Box<dyn Fn(CanisterId) -> Result<CodeVersion, BigMapError>>;
 
 #[derive(Default)]
 pub struct BigmapIdx {
     idx: Vec<CanisterId>, // indirection for CanisterId, to avoid many copies of CanisterIds
     hash_ring: hashring_sha256::HashRing<CanisterPtr>,
-    now_rebalancing_src_dst: Option<(CanisterPtr, CanisterPtr)>,
-    is_maintenance_active: bool,
+    // Increases on every change of the hash ring, the data buckets get it with their range
+    ring_epoch: u64,
+    relocation: Option<RelocationJob>,
+    // Sha256 of the keys deleted while the relocation runs. A batch read from the source
+    // before the delete must not bring them back. Not persisted, since no batch is in
+    // flight across an upgrade, and the source doesn't hold the deleted keys anymore.
+    relocation_deleted: BTreeSet<Sha2Vec>,
+    // Number of data buckets which hold each key: its owner in the hash ring and the successors
+    replication_factor: u32,
+    // Nodes of the first data bucket in the hash ring, the data buckets created by the
+    // splits get as many, one in each range of the split data bucket
+    virtual_nodes: u32,
+    // Retries and failover of the calls to the data buckets
+    call_policy: CallPolicy,
+    // Position of the repair of the replicas: the data bucket in idx and the last key repaired.
+    // Not persisted, the repair starts over after an upgrade.
+    repair_cursor: (usize, Option<Key>),
+    maintenance_run_id: u64,
+    // Time of the last progress of the running maintenance, None if no maintenance is running
+    maintenance_heartbeat: Option<u64>,
+    // Maintenance run by the heartbeat, see index/schedule.rs
+    maintenance_schedule: MaintenanceSchedule,
+    maintenance_next_tick: u64,
+    maintenance_last_tick: Option<MaintenanceReport>,
+    // Rolling upgrade of the code of the canisters, see index/code_upgrade.rs
+    code_upgrade: Option<UpgradeJob>,
+    // Principals which may call the admin operations: the wasm binaries, the upgrade of the
+    // canisters and the configuration. The canister which installed the BigmapIdx is the first.
+    admins: Vec<CanisterId>,
     creating_data_canister: bool,
     creating_search_canister: bool,
     batch_limit_bytes: u64,
@@ -58,6 +131,9 @@ pub struct BigmapIdx {
     data_bucket_canister_wasm_binary: Vec<u8>,
     search_canister_wasm_binary: Vec<u8>,
     id: CanisterId,
+    uploads: DetHashMap<u64, Upload>,
+    next_upload_id: u64,
+    namespaces: Namespaces,
     // Testing functions
     #[cfg(not(target_arch = "wasm32"))]
     fn_ptr_add_to_search_index: Option<FnPtrAddToSearchIndex>,
@@ -70,6 +146,8 @@ pub struct BigmapIdx {
     #[cfg(not(target_arch = "wasm32"))]
     fn_ptr_list: Option<FnPtrList>,
     #[cfg(not(target_arch = "wasm32"))]
+    fn_ptr_list_page: Option<FnPtrListPage>,
+    #[cfg(not(target_arch = "wasm32"))]
     fn_ptr_used_bytes: Option<FnPtrUsedBytes>,
     #[cfg(not(target_arch = "wasm32"))]
     fn_ptr_holds_key: Option<Box<dyn Fn(CanisterId, &Key) -> bool>>,
@@ -78,9 +156,327 @@ pub struct BigmapIdx {
     #[cfg(not(target_arch = "wasm32"))]
     fn_ptr_get_relocation_batch: Option<FnPtrGetRelocationBatch>,
     #[cfg(not(target_arch = "wasm32"))]
+    fn_ptr_get_range_batch: Option<FnPtrGetRangeBatch>,
+    #[cfg(not(target_arch = "wasm32"))]
+    fn_ptr_get_replica_entries: Option<FnPtrGetReplicaEntries>,
+    #[cfg(not(target_arch = "wasm32"))]
+    fn_ptr_get_entry_part: Option<FnPtrGetEntryPart>,
+    #[cfg(not(target_arch = "wasm32"))]
+    fn_ptr_put_replica_batch: Option<FnPtrPutReplicaBatch>,
+    #[cfg(not(target_arch = "wasm32"))]
     fn_ptr_put_relocation_batch: Option<FnPtrPutRelocationBatch>,
     #[cfg(not(target_arch = "wasm32"))]
     fn_ptr_delete_entries: Option<FnPtrDeleteEntries>,
+    #[cfg(not(target_arch = "wasm32"))]
+    fn_ptr_sweep_expired: Option<FnPtrSweepExpired>,
+    #[cfg(not(target_arch = "wasm32"))]
+    fn_ptr_reset: Option<FnPtrReset>,
+    #[cfg(not(target_arch = "wasm32"))]
+    fn_ptr_raise_version_counter: Option<FnPtrRaiseVersionCounter>,
+    #[cfg(not(target_arch = "wasm32"))]
+    fn_ptr_delete: Option<FnPtrDelete>,
+    #[cfg(not(target_arch = "wasm32"))]
+    fn_ptr_upgrade_code: Option<FnPtrUpgradeCode>,
+    #[cfg(not(target_arch = "wasm32"))]
+    fn_ptr_code_version: Option<FnPtrCodeVersion>,
+}
+
+// Upper limit for the number of expired entries reclaimed in a data bucket per maintenance run
+const EXPIRY_SWEEP_LIMIT: u32 = 10000;
+
+// Number of keys of which the replicas are compared, and repaired, per maintenance tick
+const REPAIR_KEYS_PER_STEP: u32 = 100;
+
+// Two neighbouring data buckets are merged if together they use less than this percentage
+// of the used_bytes_threshold, so that the merged data bucket is far from the next split
+const MERGE_UTILIZATION_PERCENT: u64 = 25;
+
+// Upper limit for the replication factor, every write is sent to this many data buckets
+pub const REPLICATION_FACTOR_MAX: u32 = 5;
+
+// Upper limit for the virtual nodes of a data bucket, each of which is a range to relocate
+pub const VIRTUAL_NODES_MAX: u32 = 64;
+
+// A maintenance run without progress for this long (in nanoseconds) was interrupted,
+// e.g. by a trap, and the next maintenance run takes over
+const MAINTENANCE_STALE_NS: u64 = 10 * 60 * 1_000_000_000;
+
+// A data bucket split: the entries outside of the range of the source data bucket are
+// moved to the destination data bucket, in batches ordered by the Sha256 of the key.
+// The job is persisted and each of its steps can be repeated, so that a relocation
+// interrupted by a trap or an upgrade is resumed by the next maintenance run.
+// A merge runs as a rollback: the merged data bucket is the destination, which is
+// removed from the hash ring, and its successor in the hash ring is the source.
+// With replicas, the entries in the range of the receiving data bucket are copied to it,
+// and the successors of the source, whose ranges change as well, are updated afterwards.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct RelocationJob {
+    src: CanisterPtr,
+    dst: CanisterPtr,
+    phase: RelocationPhase,
+    // The destination is removed from the hash ring, and the entries go back to the source
+    rolling_back: bool,
+    // Sha256 of the key of the last relocated entry, and the offset of its next part if
+    // the entry is moved in parts, see RelocationEntry::cursor
+    cursor: Option<Sha2Vec>,
+    entries_moved: u64,
+}
+
+// A chunked upload started through the BigMap Index, not kept across upgrades
+#[derive(Clone, Debug)]
+struct Upload {
+    // The upload id in each replica of the key which received every chunk so far
+    bucket_uploads: Vec<(CanisterId, u64)>,
+    quorum: usize, // The majority of the replicas of the key
+    namespace: Namespace,
+    last_active: u64, // Time of begin_upload or of the last chunk
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum RelocationPhase {
+    SetRanges, // The ranges of the data buckets are set from the hash ring
+    Moving,
+    // With replicas, each of relocation_replicas in turn: on a rollback it gets the entries
+    // of its range from the source, then the entries outside of its range are deleted
+    Replicas { replica: u32, trimming: bool },
+}
+
+impl RelocationJob {
+    fn new(src: CanisterPtr, dst: CanisterPtr) -> Self {
+        Self {
+            src,
+            dst,
+            phase: RelocationPhase::SetRanges,
+            rolling_back: false,
+            cursor: None,
+            entries_moved: 0,
+        }
+    }
+
+    // The data buckets from which and to which the entries are moved
+    fn direction(&self) -> (CanisterPtr, CanisterPtr) {
+        if self.rolling_back {
+            (self.dst, self.src)
+        } else {
+            (self.src, self.dst)
+        }
+    }
+}
+
+// The ranges as sent to the data buckets
+#[cfg(target_arch = "wasm32")]
+fn ranges_to_vec(ranges: &[HashRingRange]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    ranges
+        .iter()
+        .map(|(range_start, range_end)| (range_start.to_vec(), range_end.to_vec()))
+        .collect()
+}
+
+// The list_page cursor is the last key of the returned page, prefixed with a format version
+const LIST_CURSOR_VERSION: u8 = 1;
+
+fn list_cursor_encode(last_key: &Key) -> Vec<u8> {
+    let mut result = vec![LIST_CURSOR_VERSION];
+    result.extend_from_slice(last_key);
+    result
+}
+
+fn list_cursor_decode(cursor: &[u8]) -> Option<Key> {
+    match cursor.split_first() {
+        Some((&LIST_CURSOR_VERSION, last_key)) => Some(last_key.to_vec()),
+        _ => None,
+    }
+}
+
+// The entry of the key which wins among the entries held by its replicas, with the replica
+// which holds it. The owner in the hash ring holds the latest write, unless it doesn't have
+// the entry yet (e.g. during a split) or doesn't answer, and then the newest version wins.
+// A tombstone is an entry too, so a delete wins over the older versions of the entry.
+fn replica_winner<'a>(
+    owner: &CanisterId,
+    answers: &'a [(CanisterId, Option<RelocationEntry>)],
+) -> Option<(&'a CanisterId, &'a RelocationEntry)> {
+    match answers.first() {
+        Some((can_id, Some(entry))) if can_id == owner => Some((can_id, entry)),
+        _ => answers
+            .iter()
+            .filter_map(|(can_id, entry)| entry.as_ref().map(|entry| (can_id, entry)))
+            .max_by_key(|(_, entry)| entry.version),
+    }
+}
+
+// A chunked upload is assembled by every replica, so the versions may differ
+// while the values are the same
+fn replica_agrees(entry: &Option<RelocationEntry>, winner: &RelocationEntry) -> bool {
+    match entry {
+        Some(entry) => {
+            (
+                entry.deleted,
+                &entry.value,
+                entry.codec,
+                entry.expires_at,
+                &entry.part,
+            ) == (
+                winner.deleted,
+                &winner.value,
+                winner.codec,
+                winner.expires_at,
+                &winner.part,
+            )
+        }
+        None => false,
+    }
+}
+
+// The hash ring with the certificate of its hash, which allows clients to verify the
+// routing and then verify the values with the certified get of the data buckets
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CertifiedRing {
+    pub ring: Vec<(Sha2Vec, CanisterId)>, // (end of the key range, data bucket)
+    pub certificate: Option<Vec<u8>>,     // Only available in query calls
+}
+
+// The key ranges of the data buckets, for clients which send the operations to the data
+// buckets directly. The client tags the operations with the epoch, and a data bucket whose
+// range changed since rejects them with StaleEpoch, so the client refreshes the table.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct RoutingTable {
+    pub epoch: u64,
+    pub ranges: Vec<RoutingRange>, // In the order of the hash ring
+    pub replication_factor: u32,   // Each range is held by its owner and the successors
+    // The entries of a range may still be held by the previous owner, which the BigMap
+    // Index reads from as a fallback
+    pub relocating: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct RoutingRange {
+    pub range_start: Sha2Vec, // The Sha256 of the keys in [range_start..range_end)
+    pub range_end: Sha2Vec,
+    pub canister_id: CanisterId,
+}
+
+// Hash of the ring, as certified by the BigmapIdx
+pub fn ring_hash(ring: &[(Sha2Vec, CanisterId)]) -> Sha256Digest {
+    let mut digest = Sha256::new();
+    for (key, can_id) in ring {
+        digest.update(key);
+        digest.update((can_id.0.len() as u32).to_be_bytes());
+        digest.update(&can_id.0);
+    }
+    digest.finalize()
+}
+
+// Identifies the BigmapIdx state in the stable memory
+pub const STABLE_MAGIC: StableMagic = *b"BMIX";
+// Bump when the persisted state changes: add a new BigmapIdxStableVn struct
+// and keep decoding the older versions in read_stable_payload
+// Version 2: the relocation job replaces the pair of rebalancing canisters, and the epoch and
+// the weights of the hash ring, the virtual nodes, the namespaces, the replication factor,
+// the call policy, the maintenance schedule, the code upgrade job and the admins are persisted
+pub const STABLE_SCHEMA_VERSION: u32 = 2;
+
+// BigmapIdx state as persisted across upgrades, schema version 2
+// The transient flags (maintenance or canister creation in progress) are not
+// persisted, since no call can be in flight while the canister is upgraded.
+#[derive(Serialize)]
+struct BigmapIdxStableV2Ref<'a> {
+    idx: &'a Vec<CanisterId>,
+    hash_ring: Vec<(&'a [u8], CanisterPtr)>,
+    hash_ring_weights: &'a [(CanisterPtr, u32)],
+    virtual_nodes: u32,
+    ring_epoch: u64,
+    relocation: &'a Option<RelocationJob>,
+    batch_limit_bytes: u64,
+    canister_available_queue: &'a VecDeque<CanisterId>,
+    used_bytes_threshold: u32,
+    used_bytes_total: u64,
+    search_canisters: &'a Vec<CanisterId>,
+    data_bucket_canister_wasm_binary: &'a Vec<u8>,
+    search_canister_wasm_binary: &'a Vec<u8>,
+    id: &'a CanisterId,
+    namespaces: &'a Namespaces,
+    replication_factor: u32,
+    call_policy: &'a CallPolicy,
+    maintenance_schedule: &'a MaintenanceSchedule,
+    code_upgrade: &'a Option<UpgradeJob>,
+    admins: &'a Vec<CanisterId>,
+}
+
+// Owned counterpart of BigmapIdxStableV2Ref, with the same serialized layout
+#[derive(Deserialize)]
+struct BigmapIdxStableV2 {
+    idx: Vec<CanisterId>,
+    hash_ring: Vec<(Sha2Vec, CanisterPtr)>,
+    hash_ring_weights: Vec<(CanisterPtr, u32)>,
+    virtual_nodes: u32,
+    ring_epoch: u64,
+    relocation: Option<RelocationJob>,
+    batch_limit_bytes: u64,
+    canister_available_queue: VecDeque<CanisterId>,
+    used_bytes_threshold: u32,
+    used_bytes_total: u64,
+    search_canisters: Vec<CanisterId>,
+    data_bucket_canister_wasm_binary: Vec<u8>,
+    search_canister_wasm_binary: Vec<u8>,
+    id: CanisterId,
+    namespaces: Namespaces,
+    replication_factor: u32,
+    call_policy: CallPolicy,
+    maintenance_schedule: MaintenanceSchedule,
+    code_upgrade: Option<UpgradeJob>,
+    admins: Vec<CanisterId>,
+}
+
+// BigmapIdx state as persisted by schema version 1
+#[derive(Deserialize)]
+struct BigmapIdxStableV1 {
+    idx: Vec<CanisterId>,
+    hash_ring: Vec<(Sha2Vec, CanisterPtr)>,
+    now_rebalancing_src_dst: Option<(CanisterPtr, CanisterPtr)>,
+    batch_limit_bytes: u64,
+    canister_available_queue: VecDeque<CanisterId>,
+    used_bytes_threshold: u32,
+    used_bytes_total: u64,
+    search_canisters: Vec<CanisterId>,
+    data_bucket_canister_wasm_binary: Vec<u8>,
+    search_canister_wasm_binary: Vec<u8>,
+    id: CanisterId,
+}
+
+impl From<BigmapIdxStableV1> for BigmapIdxStableV2 {
+    fn from(state: BigmapIdxStableV1) -> Self {
+        BigmapIdxStableV2 {
+            idx: state.idx,
+            hash_ring: state.hash_ring,
+            // Each data bucket had a single node in the hash ring, with the default weight
+            hash_ring_weights: Vec::new(),
+            virtual_nodes: 1,
+            // The data buckets were not given an epoch, which leaves them at 0
+            ring_epoch: 0,
+            // The split was interrupted at an unknown step, repeat it from setting the ranges
+            relocation: state
+                .now_rebalancing_src_dst
+                .map(|(src, dst)| RelocationJob::new(src, dst)),
+            batch_limit_bytes: state.batch_limit_bytes,
+            canister_available_queue: state.canister_available_queue,
+            used_bytes_threshold: state.used_bytes_threshold,
+            used_bytes_total: state.used_bytes_total,
+            search_canisters: state.search_canisters,
+            data_bucket_canister_wasm_binary: state.data_bucket_canister_wasm_binary,
+            search_canister_wasm_binary: state.search_canister_wasm_binary,
+            id: state.id,
+            // The keys stored before were not scoped, and are not in any namespace
+            namespaces: Namespaces::default(),
+            // Every key was held by a single data bucket
+            replication_factor: 1,
+            call_policy: CallPolicy::default(),
+            maintenance_schedule: MaintenanceSchedule::default(),
+            code_upgrade: None,
+            // The first caller after the upgrade becomes the admin, see record_admin
+            admins: Vec::new(),
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -93,443 +489,1868 @@ impl BigmapIdx {
 
     pub fn reset(&mut self) {
         *self = Self {
-            used_bytes_threshold: 3 * 1024 * 1024 * 1024,
+            // Below data::CAPACITY_BYTES_DEFAULT, so the DataBuckets are split before they're full
+            used_bytes_threshold: 2 * 1024 * 1024 * 1024,
             batch_limit_bytes: 1024 * 1024,
+            replication_factor: 1,
+            virtual_nodes: 1,
             ..Default::default()
         }
     }
 
-    pub async fn get(&self, key: &Key) -> Option<Val> {
-        match self.lookup_get(&key).await {
-            Some(can_id) => {
-                let can_id: ic_cdk::CanisterId = can_id.0.into();
-                println!(
-                    "BigMap Index: get key {} @CanisterId {}",
-                    String::from_utf8_lossy(&key),
-                    can_id
-                );
-                ic_cdk::call(can_id, "get", Some(key)).await.unwrap()
-            }
-            None => {
-                println!(
-                    "BigMap Index: no data canister holds the key {}",
-                    String::from_utf8_lossy(&key)
-                );
-                None
-            }
+    pub async fn get(&self, key: &Key) -> Result<Val, BigMapError> {
+        if self.replication_factor > 1 {
+            return self.get_from_replicas(key).await?.into_decoded_value();
         }
+        let can_id = self.lookup_get(key).await?;
+        println!(
+            "BigMap Index: get key {} @CanisterId {}",
+            String::from_utf8_lossy(key),
+            can_id
+        );
+        self.call_bigmap_failover(&self.read_candidates(can_id, key), "get", key)
+            .await
     }
 
-    pub async fn put(&self, key: &Key, value: &Val) -> u64 {
-        match self.lookup_put(&key) {
-            Some(can_id) => {
-                let can_id: ic_cdk::CanisterId = can_id.0.into();
-                match ic_cdk::call(can_id.clone(), "batch_put", Some(vec![(key, value)]))
-                    .await
-                    .expect(&format!(
-                        "BigMap index: put call to CanisterId {} failed",
-                        can_id
-                    )) {
-                    1u64 => value.len() as u64,
-                    _ => 0,
-                }
-            }
-            None => {
-                println!(
-                    "BigMap Index: no data canister suitable for key {}",
-                    String::from_utf8_lossy(&key)
-                );
-                0
-            }
-        }
+    pub async fn put(&mut self, key: &Key, value: &Val) -> Result<u64, BigMapError> {
+        self.put_to_owner(key, "put_from_index", (key, value)).await
     }
 
-    pub async fn batch_put(&self, batch: &Vec<(Key, Val)>) -> u64 {
-        let mut result = 0;
-        let mut batches: DetHashMap<CanisterId, Vec<(Key, Val)>> = DetHashMap::default();
-        for (key, value) in batch.into_iter() {
-            match self.lookup_put(&key) {
-                Some(can_id) => {
-                    if batches.contains_key(&can_id) {
-                        batches
-                            .get_mut(&can_id)
-                            .unwrap()
-                            .push((key.clone(), value.clone()));
-                    } else {
-                        batches.insert(can_id, vec![(key.clone(), value.clone())]);
-                    };
-                }
-                None => {
-                    println!(
-                        "BigMap Index: no data canister suitable for key {}",
-                        String::from_utf8_lossy(&key)
-                    );
-                }
-            }
-        }
-        for (can_id, batch) in batches.into_iter() {
-            let can_id: ic_cdk::CanisterId = can_id.0.into();
-            let batch_result: u64 = ic_cdk::call(can_id.clone(), "batch_put", Some(batch))
-                .await
-                .expect(&format!(
-                    "BigMap index: put call to CanisterId {} failed",
-                    can_id
-                ));
-            result += batch_result;
+    // Send a write to the data bucket which owns the key. A full data bucket is split
+    // right away, and the write is sent again to the owner of the key after the split.
+    // With replicas, the entry is then copied from the owner to the other replicas.
+    async fn put_to_owner<T: CandidType + Clone, R: DeserializeOwned>(
+        &mut self,
+        key: &Key,
+        method: &str,
+        arg: T,
+    ) -> Result<R, BigMapError> {
+        self.relocation_take_keys(std::slice::from_ref(key)).await?;
+        let mut can_id = self.lookup_put(key)?;
+        let mut result = self.call_bigmap(&can_id, method, arg.clone()).await;
+        if let Err(BigMapError::BucketFull { .. }) = result {
+            self.split_full_bucket(&can_id).await?;
+            can_id = self.lookup_put(key)?;
+            result = self.call_bigmap(&can_id, method, arg).await;
         }
-        result
+        let result = result?;
+        self.replicate_key(&can_id, key).await?;
+        Ok(result)
     }
 
-    pub async fn append(&mut self, key: &Key, value: &Val) -> u64 {
-        if let Err(err) = self.ensure_at_least_one_data_canister().await {
-            println!(
-                "Error appending key {} => {}",
-                String::from_utf8_lossy(key),
-                err
-            );
-            return 0;
+    // Copy the entries of the keys from their owner, which accepted the writes, to the
+    // other replicas. The result of a key is an error if fewer than a quorum (the majority)
+    // of its replicas, counting the owner, hold the entry afterwards.
+    async fn replicate(&self, owner: &CanisterId, keys: &[Key]) -> Vec<Result<(), BigMapError>> {
+        let mut results = vec![Ok(()); keys.len()];
+        if self.replication_factor <= 1 || keys.is_empty() {
+            return results;
         }
+        let entries = match self.qcall_dcan_get_replica_entries(owner, keys).await {
+            Ok(entries) => entries,
+            Err(err) => return vec![Err(err); keys.len()],
+        };
 
-        match self.lookup_put(&key) {
-            Some(can_id) => {
-                let can_id: ic_cdk::CanisterId = can_id.0.into();
-                println!(
-                    "BigMap Index: append key {} @CanisterId {}",
-                    String::from_utf8_lossy(&key),
-                    can_id
-                );
-                ic_cdk::call(can_id.clone(), "append_from_index", Some((key, value)))
-                    .await
-                    .expect(&format!(
-                        "BigMap index: append call to CanisterId {} failed",
-                        can_id
-                    ))
-            }
-            None => {
-                println!(
-                    "BigMap Index: no data canister suitable for key {}",
-                    String::from_utf8_lossy(&key)
-                );
-                0
+        let positions: DetHashMap<&Key, usize> =
+            keys.iter().enumerate().map(|(i, key)| (key, i)).collect();
+        // The number of replicas which hold the entry, and which are needed, for each key
+        let mut acks = vec![(1, 1); keys.len()];
+        // The entries to send to each replica
+        let mut batches: DetHashMap<CanisterId, Vec<RelocationEntry>> = DetHashMap::default();
+        for entry in entries {
+            let i = match positions.get(&entry.key) {
+                Some(i) => *i,
+                None => continue,
+            };
+            let replicas = match self.replicas_for_key(&entry.key) {
+                Ok(replicas) => replicas,
+                Err(err) => {
+                    results[i] = Err(err);
+                    continue;
+                }
+            };
+            acks[i].1 = replicas.len() / 2 + 1;
+            for can_id in replicas.into_iter().filter(|can_id| can_id != owner) {
+                batches.entry(can_id).or_default().push(entry.clone());
             }
         }
-    }
-
-    fn can_ptr_to_canister_id(&self, can_ptr: &CanisterPtr) -> CanisterId {
-        self.idx[can_ptr.0 as usize].clone()
-    }
-
-    pub async fn add_canisters(&mut self, can_ids: Vec<CanisterId>) {
-        // let mut new_can_util_vec = Vec::new();
 
-        for can_id in can_ids {
-            for can_id_existing in &self.idx {
-                if &can_id == can_id_existing {
-                    println!(
-                        "BigMap Index: Skipping already existing Data CanisterId {}",
-                        can_id
-                    );
-                }
-            }
-            for can_id_existing in &self.canister_available_queue {
-                if &can_id == can_id_existing {
-                    println!(
-                        "BigMap Index: Skipping already existing Data CanisterId {}",
-                        can_id
-                    );
+        let mut errors = vec![None; keys.len()];
+        for (can_id, batch) in batches.into_iter() {
+            let can_results = match self.ucall_dcan_put_replica_batch(&can_id, &batch).await {
+                Ok(can_results) => can_results,
+                Err(err) => vec![Err(err); batch.len()],
+            };
+            for (entry, res) in batch.iter().zip(can_results) {
+                let i = positions[&entry.key];
+                let res = match res {
+                    Ok(_) if !entry.is_complete() => {
+                        self.send_entry_parts(owner, entry, &can_id, true).await
+                    }
+                    res => res.map(|_| ()),
+                };
+                match res {
+                    Ok(_) => acks[i].0 += 1,
+                    Err(err) => {
+                        println!(
+                            "BigMap Index: replicating key {} to CanisterId {} failed: {}",
+                            String::from_utf8_lossy(&entry.key),
+                            can_id,
+                            err
+                        );
+                        errors[i] = Some(err);
+                    }
                 }
             }
-
-            println!("BigMap Index: Created Data CanisterId {}", can_id);
-
-            // Add all canisters to the available queue
-            self.canister_available_queue.push_back(can_id);
         }
 
-        if let Err(err) = self.ensure_at_least_one_data_canister().await {
-            self.is_maintenance_active = false;
-            println!("Error adding canisters: {}", err);
+        for (i, (held, quorum)) in acks.into_iter().enumerate() {
+            if results[i].is_ok() && held < quorum {
+                results[i] = Err(errors[i].take().unwrap_or(BigMapError::BucketUnavailable));
+            }
         }
+        results
     }
 
-    pub async fn ensure_at_least_one_data_canister(&mut self) -> Result<(), String> {
-        if self.hash_ring.is_empty() {
-            if self.creating_data_canister {
-                return Err(
-                    "Already creating data canister, concurrent calls are not allowed".to_string(),
-                );
-            }
-            self.creating_data_canister = true;
-            println!("BigMap Index: No Data Canisters, creating one!");
-            match self.create_data_bucket_canister().await {
-                Ok(can_id) => {
-                    println!("BigMap Index: Activating Data CanisterId {}", can_id);
-
-                    let range = self.hash_ring_add_canister_id(&can_id);
-                    self.ucall_dcan_set_range(&can_id, range.0, range.1).await;
+    // Send the remaining parts of an entry, of which the data bucket `from` returned only the
+    // first part since the entry is too large for one message, see data::ValuePart. The
+    // parts are put with put_replica_batch if replace, and otherwise with put_relocation_batch.
+    async fn send_entry_parts(
+        &self,
+        from: &CanisterId,
+        first: &RelocationEntry,
+        to: &CanisterId,
+        replace: bool,
+    ) -> Result<(), BigMapError> {
+        let (mut offset, mut complete) = (first.next_offset(), first.is_complete());
+        while !complete {
+            let part = match self
+                .qcall_dcan_get_entry_part(from, &first.key, offset)
+                .await?
+            {
+                Some(part) if part.version == first.version => part,
+                _ => {
+                    return Err(BigMapError::DataCorrupted(format!(
+                        "Key {} changed @CanisterId {} while it was sent in parts",
+                        String::from_utf8_lossy(&first.key),
+                        from
+                    )))
                 }
-                Err(err) => {
-                    println!("BigMap Index: Error creating a new Data Canister {}", err);
+            };
+            offset = part.next_offset();
+            complete = part.is_complete();
+            let batch = vec![part];
+            if replace {
+                if let Some(Err(err)) = self.ucall_dcan_put_replica_batch(to, &batch).await?.pop() {
+                    return Err(err);
                 }
+            } else if self.ucall_dcan_put_relocation_batch(to, &batch).await? != 1 {
+                return Err(BigMapError::DataCorrupted(format!(
+                    "A part of key {} was not moved from {} to {}",
+                    String::from_utf8_lossy(&first.key),
+                    from,
+                    to
+                )));
             }
-            self.creating_data_canister = false;
-        };
+        }
         Ok(())
     }
 
-    pub async fn ensure_at_least_one_search_canister(&mut self) -> Result<(), String> {
-        if self.search_canisters.is_empty() {
-            if self.creating_search_canister {
-                return Err(
-                    "Already creating search canister, concurrent calls are not allowed"
-                        .to_string(),
-                );
-            }
-            self.creating_search_canister = true;
-            println!("BigMap Index: No Search Canisters, creating one!");
-            match self.create_search_canister().await {
-                Ok(can_id) => {
-                    println!("BigMap Index: Activating Search CanisterId {}", can_id);
-                    self.search_canisters.push(can_id);
-                }
-                Err(err) => {
-                    println!("BigMap Index: Error creating a new Search Canister {}", err);
-                }
-            }
-            self.creating_search_canister = false;
-        };
-        Ok(())
+    async fn replicate_key(&self, owner: &CanisterId, key: &Key) -> Result<(), BigMapError> {
+        match self.replicate(owner, std::slice::from_ref(key)).await.pop() {
+            Some(result) => result,
+            None => Ok(()),
+        }
     }
 
-    // Returns the data bucket canister which should hold the key
-    // If multiple canisters can hold the data due to rebalancing, we will
-    // query all candidates and return the correct CanisterId
-    pub async fn lookup_get(&self, key: &Key) -> Option<CanisterId> {
-        let key_sha256 = calc_sha256(key);
-        let (_, can_ptr) = match self.hash_ring.get_idx_node_for_key(&key_sha256) {
-            Some(v) => v,
-            None => return None,
-        };
-        // println!("BigMap Index: lookup_get @key {}", String::from_utf8_lossy(key));
-
-        let can_id = self.can_ptr_to_canister_id(can_ptr);
-        if self.qcall_dcan_holds_key(&can_id, key).await {
-            return Some(can_id);
+    // Read the entry from all replicas of the key, and succeed if any of them answers.
+    // The winning entry is returned, see replica_winner, and NotFound if it's a tombstone.
+    // The replicas which hold a different entry are repaired later by repair_step, since
+    // the changes made by a query are not kept.
+    async fn get_from_replicas(&self, key: &Key) -> Result<RelocationEntry, BigMapError> {
+        let replicas = self.replicas_for_key(key)?;
+        let mut candidates = replicas.clone();
+        if let Some(job) = &self.relocation {
+            // The relocation may not have moved the key from the source yet
+            let from_canister = self.can_ptr_to_canister_id(&job.direction().0);
+            if !candidates.contains(&from_canister) && !self.relocation_deleted_key(key) {
+                candidates.push(from_canister);
+            }
         }
 
-        if let Some(can_src_dst_ptr) = self.now_rebalancing_src_dst {
-            let (rebalance_src_ptr, rebalance_dst_ptr) = can_src_dst_ptr;
-            if can_ptr == &rebalance_src_ptr {
-                // The destination canister doesn't have the key but it's currently rebalancing.
-                // The key may not have been moved yet from the source canister
-
-                let can_id = self.can_ptr_to_canister_id(&rebalance_dst_ptr);
-                if self.qcall_dcan_holds_key(&can_id, key).await {
+        let keys = vec![key.clone()];
+        let mut answers: Vec<(CanisterId, Option<RelocationEntry>)> = Vec::new();
+        let mut last_err = BigMapError::BucketUnavailable;
+        for can_id in candidates {
+            match self.qcall_dcan_get_replica_entries(&can_id, &keys).await {
+                Ok(entries) => answers.push((can_id, entries.into_iter().next())),
+                Err(err) => {
                     println!(
-                        "BigMap Index: lookup_get @key {} from a relocation destination {}",
+                        "BigMap Index: get key {} @CanisterId {} failed: {}",
                         String::from_utf8_lossy(key),
-                        can_id
+                        can_id,
+                        err
                     );
-                    return Some(can_id);
+                    last_err = err;
                 }
             }
         }
+        if answers.is_empty() {
+            return Err(last_err);
+        }
 
-        None
+        match replica_winner(&replicas[0], &answers) {
+            Some((_, winner)) if !winner.deleted => Ok(winner.clone()),
+            _ => Err(BigMapError::NotFound),
+        }
     }
 
-    // Find the data bucket canister into which the object with the provided key should go
-    pub fn lookup_put(&self, key: &Key) -> Option<CanisterId> {
-        let key_sha256 = calc_sha256(key);
-        let (_, ring_node) = match self.hash_ring.get_idx_node_for_key(&key_sha256) {
-            Some(v) => v,
-            None => return None,
+    // Compare the entries held by the replicas of a page of keys, and repair the replicas
+    // which hold a different entry than the winning one, see replica_winner. Every tick of
+    // the maintenance continues with the next page of the keys of a data bucket, and then
+    // with the next data bucket. Returns the number of repaired replicas.
+    pub async fn repair_step(&mut self) -> Result<u64, BigMapError> {
+        if self.replication_factor <= 1 || self.relocation.is_some() || self.idx.is_empty() {
+            return Ok(0);
+        }
+        let (i, start_after) = match &self.repair_cursor {
+            (i, start_after) if *i < self.idx.len() => (*i, start_after.clone()),
+            _ => (0, None),
         };
-
-        // println!("BigMap Index: lookup_put @key {}", String::from_utf8_lossy(key));
-        Some(self.can_ptr_to_canister_id(ring_node))
+        let can_id = self.idx[i].clone();
+        let keys = self
+            .qcall_dcan_list_page(&can_id, &Vec::new(), &start_after, REPAIR_KEYS_PER_STEP)
+            .await?;
+        let repaired = self.repair_keys(&keys).await?;
+        self.repair_cursor = if keys.len() < REPAIR_KEYS_PER_STEP as usize {
+            (i + 1, None)
+        } else {
+            (i, keys.last().cloned())
+        };
+        Ok(repaired)
     }
 
-    // List keys starting with key_prefix
-    pub async fn list(&self, key_prefix: &Key) -> Vec<Key> {
-        let mut result = BTreeSet::new();
-
-        for can_id in self.idx.iter() {
-            let sub_list: Vec<Key> = self.qcall_dcan_list(can_id, key_prefix).await;
-            result.extend(sub_list);
-            if result.len() > 10000 {
-                // Safety brake, don't return too many entries
-                break;
+    async fn repair_keys(&self, keys: &[Key]) -> Result<u64, BigMapError> {
+        let mut replicas = Vec::with_capacity(keys.len());
+        let mut keys_by_replica: DetHashMap<CanisterId, Vec<Key>> = DetHashMap::default();
+        for key in keys {
+            let key_replicas = self.replicas_for_key(key)?;
+            for can_id in key_replicas.iter() {
+                keys_by_replica
+                    .entry(can_id.clone())
+                    .or_default()
+                    .push(key.clone());
             }
+            replicas.push(key_replicas);
         }
 
-        result.iter().cloned().collect()
-    }
+        // The entries held by each replica which answered, a replica which didn't is skipped
+        let mut answers: DetHashMap<&Key, Vec<(CanisterId, Option<RelocationEntry>)>> =
+            DetHashMap::default();
+        for (can_id, can_keys) in keys_by_replica.iter() {
+            let mut entries: DetHashMap<Key, RelocationEntry> =
+                match self.qcall_dcan_get_replica_entries(can_id, can_keys).await {
+                    Ok(entries) => entries.into_iter().map(|e| (e.key.clone(), e)).collect(),
+                    Err(err) => {
+                        println!(
+                            "BigMap Index: reading the replicas @CanisterId {} failed: {}",
+                            can_id, err
+                        );
+                        continue;
+                    }
+                };
+            for key in can_keys.iter() {
+                answers
+                    .entry(key)
+                    .or_default()
+                    .push((can_id.clone(), entries.remove(key)));
+            }
+        }
 
-    pub async fn maintenance(&mut self) -> String {
-        #[derive(serde::Serialize)]
+        let mut repaired = 0;
+        for (key, key_replicas) in keys.iter().zip(replicas) {
+            let key_answers = match answers.get(key) {
+                Some(key_answers) => key_answers,
+                None => continue,
+            };
+            let (winner_can_id, winner) = match replica_winner(&key_replicas[0], key_answers) {
+                Some(winner) => winner,
+                None => continue,
+            };
+            for (can_id, entry) in key_answers.iter() {
+                if replica_agrees(entry, winner) {
+                    continue;
+                }
+                println!(
+                    "BigMap Index: repairing key {} @CanisterId {}",
+                    String::from_utf8_lossy(key),
+                    can_id
+                );
+                match self.repair_replica(winner_can_id, winner, can_id).await {
+                    Ok(()) => repaired += 1,
+                    Err(err) => println!(
+                        "BigMap Index: repairing key {} @CanisterId {} failed: {}",
+                        String::from_utf8_lossy(key),
+                        can_id,
+                        err
+                    ),
+                }
+            }
+        }
+        Ok(repaired)
+    }
+
+    // Copy the winning entry, or its tombstone, from the replica `from` to the replica `to`
+    async fn repair_replica(
+        &self,
+        from: &CanisterId,
+        winner: &RelocationEntry,
+        to: &CanisterId,
+    ) -> Result<(), BigMapError> {
+        let batch = vec![winner.clone()];
+        match self.ucall_dcan_put_replica_batch(to, &batch).await?.pop() {
+            Some(Ok(_)) if !winner.is_complete() => {
+                self.send_entry_parts(from, winner, to, true).await
+            }
+            Some(result) => result.map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    // Returns the result of the put for each entry, in the order of the batch,
+    // so that the caller can retry only the entries which failed
+    pub async fn batch_put(&mut self, batch: &[(Key, Val)]) -> Vec<Result<u64, BigMapError>> {
+        let keys: Vec<Key> = batch.iter().map(|(key, _)| key.clone()).collect();
+        if let Err(err) = self.relocation_take_keys(&keys).await {
+            return vec![Err(err); batch.len()];
+        }
+        // Every entry gets its result below, from the lookup or from the data bucket
+        let mut results = vec![Err(BigMapError::BucketUnavailable); batch.len()];
+        let positions: Vec<usize> = (0..batch.len()).collect();
+        let full = self
+            .batch_put_positions(batch, &positions, &mut results)
+            .await;
+
+        // The entries rejected by a full data bucket are sent again once it's split
+        let mut positions_retry = Vec::new();
+        for (can_id, positions) in full.into_iter() {
+            match self.split_full_bucket(&can_id).await {
+                Ok(()) => positions_retry.extend(positions),
+                Err(err) => {
+                    for i in positions {
+                        results[i] = Err(err.clone());
+                    }
+                }
+            }
+        }
+        if !positions_retry.is_empty() {
+            self.batch_put_positions(batch, &positions_retry, &mut results)
+                .await;
+        }
+
+        // With replicas, copy the stored entries from their owners to the other replicas
+        if self.replication_factor > 1 {
+            let mut stored: DetHashMap<CanisterId, Vec<usize>> = DetHashMap::default();
+            for (i, (key, _)) in batch.iter().enumerate() {
+                if let (Ok(_), Ok(can_id)) = (&results[i], self.lookup_put(key)) {
+                    stored.entry(can_id).or_default().push(i);
+                }
+            }
+            for (can_id, positions) in stored.into_iter() {
+                let keys: Vec<Key> = positions.iter().map(|i| batch[*i].0.clone()).collect();
+                let replicated = self.replicate(&can_id, &keys).await;
+                for (i, res) in positions.into_iter().zip(replicated) {
+                    if let Err(err) = res {
+                        results[i] = Err(err);
+                    }
+                }
+            }
+        }
+        results
+    }
+
+    // Put the entries at the positions in the batch, and store their results
+    // Returns the positions rejected by full data buckets, for each data bucket
+    async fn batch_put_positions(
+        &self,
+        batch: &[(Key, Val)],
+        positions: &[usize],
+        results: &mut [Result<u64, BigMapError>],
+    ) -> DetHashMap<CanisterId, Vec<usize>> {
+        // Positions of the entries in the batch, for each data bucket
+        let mut batches: DetHashMap<CanisterId, Vec<usize>> = DetHashMap::default();
+        let mut full: DetHashMap<CanisterId, Vec<usize>> = DetHashMap::default();
+        for i in positions.iter().copied() {
+            let (key, value) = &batch[i];
+            if value.len() as u64 > PUT_VALUE_SIZE_MAX {
+                // Don't send it, the data bucket would refuse it anyway
+                results[i] = Err(BigMapError::ValueTooLarge {
+                    limit_bytes: PUT_VALUE_SIZE_MAX,
+                });
+                continue;
+            }
+            match self.lookup_put(key) {
+                Ok(can_id) => batches.entry(can_id).or_default().push(i),
+                Err(err) => results[i] = Err(err),
+            }
+        }
+        for (can_id, positions) in batches.into_iter() {
+            let can_batch: Vec<&(Key, Val)> = positions.iter().map(|i| &batch[*i]).collect();
+            let can_results: Result<Vec<Result<u64, BigMapError>>, BigMapError> =
+                self.call_bigmap(&can_id, "batch_put", can_batch).await;
+            match can_results {
+                Ok(can_results) => {
+                    for (i, res) in positions.into_iter().zip(can_results) {
+                        if let Err(BigMapError::BucketFull { .. }) = res {
+                            full.entry(can_id.clone()).or_default().push(i);
+                        }
+                        results[i] = res;
+                    }
+                }
+                Err(err) => {
+                    println!(
+                        "BigMap Index: batch_put @CanisterId {} failed: {}",
+                        can_id, err
+                    );
+                    for i in positions {
+                        results[i] = Err(err.clone());
+                    }
+                }
+            }
+        }
+        full
+    }
+
+    pub async fn append(&mut self, key: &Key, value: &Val) -> Result<u64, BigMapError> {
+        self.ensure_at_least_one_data_canister().await?;
+
+        let can_id = self.lookup_put(key)?;
+        println!(
+            "BigMap Index: append key {} @CanisterId {}",
+            String::from_utf8_lossy(key),
+            can_id
+        );
+        self.put_to_owner(key, "append_from_index", (key, value))
+            .await
+    }
+
+    // Put the value, stored with the provided codec instead of the default codec of the data bucket
+    pub async fn put_with_codec(
+        &mut self,
+        key: &Key,
+        value: &Val,
+        codec: data::Codec,
+    ) -> Result<u64, BigMapError> {
+        self.ensure_at_least_one_data_canister().await?;
+
+        let can_id = self.lookup_put(key)?;
+        println!(
+            "BigMap Index: put_with_codec key {} @CanisterId {}",
+            String::from_utf8_lossy(key),
+            can_id
+        );
+        self.put_to_owner(key, "put_with_codec_from_index", (key, value, codec))
+            .await
+    }
+
+    // Put the value, which will be treated as absent from the expires_at time on
+    // expires_at is in nanoseconds since 1970-01-01, as returned by ic_cdk::time()
+    pub async fn put_with_expiry(
+        &mut self,
+        key: &Key,
+        value: &Val,
+        expires_at: u64,
+    ) -> Result<u64, BigMapError> {
+        self.ensure_at_least_one_data_canister().await?;
+
+        let can_id = self.lookup_put(key)?;
+        println!(
+            "BigMap Index: put_with_expiry key {} @CanisterId {}",
+            String::from_utf8_lossy(key),
+            can_id
+        );
+        self.put_to_owner(key, "put_with_expiry_from_index", (key, value, expires_at))
+            .await
+    }
+
+    // Returns the value and its version, to be used with put_if_version and delete_if_version
+    pub async fn get_with_version(&self, key: &Key) -> Result<(Val, u64), BigMapError> {
+        if self.replication_factor > 1 {
+            let entry = self.get_from_replicas(key).await?;
+            let version = entry.version;
+            return Ok((entry.into_decoded_value()?, version));
+        }
+        let can_id = self.lookup_get(key).await?;
+        println!(
+            "BigMap Index: get_with_version key {} @CanisterId {}",
+            String::from_utf8_lossy(key),
+            can_id
+        );
+        self.call_bigmap_failover(
+            &self.read_candidates(can_id, key),
+            "get_with_version_from_index",
+            key,
+        )
+        .await
+    }
+
+    // Put the value only if the entry currently has the expected version (0 = entry must not exist)
+    pub async fn put_if_version(
+        &mut self,
+        key: &Key,
+        value: &Val,
+        expected_version: u64,
+    ) -> Result<data::CasResult, BigMapError> {
+        self.ensure_at_least_one_data_canister().await?;
+
+        let can_id = self.lookup_put(key)?;
+        println!(
+            "BigMap Index: put_if_version key {} @CanisterId {}",
+            String::from_utf8_lossy(key),
+            can_id
+        );
+        self.put_to_owner(
+            key,
+            "put_if_version_from_index",
+            (key, value, expected_version),
+        )
+        .await
+    }
+
+    // Delete the entry only if it currently has the expected version
+    pub async fn delete_if_version(
+        &mut self,
+        key: &Key,
+        expected_version: u64,
+    ) -> Result<data::CasResult, BigMapError> {
+        self.relocation_take_keys(std::slice::from_ref(key)).await?;
+        self.relocation_mark_deleted(key);
+        let can_id = self.lookup_put(key)?;
+        println!(
+            "BigMap Index: delete_if_version key {} @CanisterId {}",
+            String::from_utf8_lossy(key),
+            can_id
+        );
+        let result = self
+            .call_bigmap(
+                &can_id,
+                "delete_if_version_from_index",
+                (key, expected_version),
+            )
+            .await?;
+        if let data::CasResult::Applied { .. } = result {
+            // The tombstone is copied to the other replicas
+            self.replicate_key(&can_id, key).await?;
+        }
+        Ok(result)
+    }
+
+    // Returns the length of the deleted value, as stored with its codec
+    pub async fn delete(&mut self, key: &Key) -> Result<u64, BigMapError> {
+        self.relocation_take_keys(std::slice::from_ref(key)).await?;
+        self.relocation_mark_deleted(key);
+        let can_id = self.lookup_put(key)?;
+        println!(
+            "BigMap Index: delete key {} @CanisterId {}",
+            String::from_utf8_lossy(key),
+            can_id
+        );
+        let result = self.ucall_dcan_delete(&can_id, key).await?;
+        // The owner keeps a tombstone, also if it doesn't hold the key, since the other
+        // replicas may hold it. The tombstone is copied to them.
+        self.replicate_key(&can_id, key).await?;
+        Ok(result)
+    }
+
+    // Start a chunked upload, returns the upload id to use with put_chunk and commit_upload
+    // The upload is pinned to the data buckets which hold the key at this moment, every
+    // replica assembles the value, since it may be too large to be copied in one message
+    // The key is scoped to the namespace, and only calls in the namespace see the upload
+    pub async fn begin_upload(
+        &mut self,
+        namespace: &str,
+        key: &Key,
+        total_len: u64,
+    ) -> Result<u64, BigMapError> {
+        self.ensure_at_least_one_data_canister().await?;
+        self.relocation_take_keys(std::slice::from_ref(key)).await?;
+
+        let mut bucket_uploads = Vec::new();
+        for can_id in self.replicas_for_key(key)? {
+            println!(
+                "BigMap Index: begin_upload key {} ({} bytes) @CanisterId {}",
+                String::from_utf8_lossy(key),
+                total_len,
+                can_id
+            );
+            match self
+                .call_bigmap(&can_id, "begin_upload_from_index", (key, total_len))
+                .await
+            {
+                Ok(bucket_upload_id) => bucket_uploads.push((can_id, bucket_upload_id)),
+                Err(err) => {
+                    for (can_id, bucket_upload_id) in bucket_uploads {
+                        let _: Result<(), BigMapError> = self
+                            .call_bigmap(&can_id, "abort_upload", bucket_upload_id)
+                            .await;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        self.next_upload_id += 1;
+        self.uploads.insert(
+            self.next_upload_id,
+            Upload {
+                quorum: bucket_uploads.len() / 2 + 1,
+                bucket_uploads,
+                namespace: namespace.to_string(),
+                last_active: time_now(),
+            },
+        );
+        Ok(self.next_upload_id)
+    }
+
+    // Returns the number of bytes stored
+    // The chunk is stored once a quorum of the replicas stored it, as with commit_upload.
+    // A replica which failed a chunk that others stored misses bytes, so it's left out of
+    // the upload and of the commit.
+    pub async fn put_chunk(
+        &mut self,
+        namespace: &str,
+        upload_id: u64,
+        offset: u64,
+        bytes: &Vec<u8>,
+    ) -> Result<u64, BigMapError> {
+        let (bucket_uploads, quorum) = match self.uploads.get_mut(&upload_id) {
+            Some(upload) if upload.namespace == namespace => {
+                upload.last_active = time_now();
+                (upload.bucket_uploads.clone(), upload.quorum)
+            }
+            _ => return Err(BigMapError::UploadNotFound { upload_id }),
+        };
+
+        let mut stored = Vec::new();
+        let mut failed = Vec::new();
+        let mut last_err = BigMapError::BucketUnavailable;
+        for (can_id, bucket_upload_id) in bucket_uploads.iter() {
+            match self
+                .call_bigmap(
+                    can_id,
+                    "put_chunk_from_index",
+                    (bucket_upload_id, offset, bytes),
+                )
+                .await
+            {
+                Ok(bytes_stored) => stored.push(bytes_stored),
+                Err(err) => {
+                    println!(
+                        "BigMap Index: put_chunk of upload id {} @CanisterId {} failed: {}",
+                        upload_id, can_id, err
+                    );
+                    failed.push((can_id.clone(), *bucket_upload_id));
+                    last_err = err;
+                }
+            }
+        }
+        // If no replica stored the chunk, they all still hold the same bytes
+        if !stored.is_empty() && !failed.is_empty() {
+            if let Some(upload) = self.uploads.get_mut(&upload_id) {
+                upload
+                    .bucket_uploads
+                    .retain(|bucket| !failed.contains(bucket));
+            }
+            for (can_id, bucket_upload_id) in failed {
+                let _: Result<(), BigMapError> = self
+                    .call_bigmap(&can_id, "abort_upload", bucket_upload_id)
+                    .await;
+            }
+        }
+        if stored.len() < quorum {
+            return Err(last_err);
+        }
+        Ok(stored[0])
+    }
+
+    // Returns the length of the stored value
+    // The upload is committed once a quorum of the replicas committed it, only the replicas
+    // which received every chunk are asked to commit
+    pub async fn commit_upload(
+        &mut self,
+        namespace: &str,
+        upload_id: u64,
+    ) -> Result<u64, BigMapError> {
+        match self.uploads.get(&upload_id).cloned() {
+            Some(upload) if upload.namespace == namespace => {
+                let quorum = upload.quorum;
+                let mut committed = Vec::new();
+                let mut last_err = BigMapError::BucketUnavailable;
+                for (can_id, bucket_upload_id) in upload.bucket_uploads.iter() {
+                    match self
+                        .call_bigmap(can_id, "commit_upload", bucket_upload_id)
+                        .await
+                    {
+                        Ok(value_len) => committed.push(value_len),
+                        Err(err) => last_err = err,
+                    }
+                }
+                if committed.len() < quorum {
+                    return Err(last_err);
+                }
+                self.uploads.remove(&upload_id);
+                Ok(committed[0])
+            }
+            _ => Err(BigMapError::UploadNotFound { upload_id }),
+        }
+    }
+
+    pub async fn abort_upload(
+        &mut self,
+        namespace: &str,
+        upload_id: u64,
+    ) -> Result<(), BigMapError> {
+        match self.uploads.get(&upload_id) {
+            Some(upload) if upload.namespace == namespace => {
+                let upload = self.uploads.remove(&upload_id).unwrap();
+                let mut result = Ok(());
+                for (can_id, bucket_upload_id) in upload.bucket_uploads {
+                    let aborted: Result<(), BigMapError> = self
+                        .call_bigmap(&can_id, "abort_upload", bucket_upload_id)
+                        .await;
+                    if let Err(err) = aborted {
+                        result = Err(err);
+                    }
+                }
+                result
+            }
+            _ => Err(BigMapError::UploadNotFound { upload_id }),
+        }
+    }
+
+    // Returns the total length of the value and up to len bytes of the value, starting at offset
+    pub async fn get_chunk(
+        &self,
+        key: &Key,
+        offset: u64,
+        len: u64,
+    ) -> Result<(u64, Val), BigMapError> {
+        let can_id = self.lookup_get(key).await?;
+        self.call_bigmap_failover(
+            &self.read_candidates(can_id, key),
+            "get_chunk_from_index",
+            (key, offset, len),
+        )
+        .await
+    }
+
+    fn can_ptr_to_canister_id(&self, can_ptr: &CanisterPtr) -> CanisterId {
+        self.idx[can_ptr.0 as usize].clone()
+    }
+
+    pub async fn add_canisters(&mut self, can_ids: Vec<CanisterId>) -> Result<(), BigMapError> {
+        // let mut new_can_util_vec = Vec::new();
+
+        for can_id in can_ids {
+            for can_id_existing in &self.idx {
+                if &can_id == can_id_existing {
+                    println!(
+                        "BigMap Index: Skipping already existing Data CanisterId {}",
+                        can_id
+                    );
+                }
+            }
+            for can_id_existing in &self.canister_available_queue {
+                if &can_id == can_id_existing {
+                    println!(
+                        "BigMap Index: Skipping already existing Data CanisterId {}",
+                        can_id
+                    );
+                }
+            }
+
+            println!("BigMap Index: Created Data CanisterId {}", can_id);
+
+            // Add all canisters to the available queue
+            self.canister_available_queue.push_back(can_id);
+        }
+
+        self.ensure_at_least_one_data_canister().await
+    }
+
+    pub async fn ensure_at_least_one_data_canister(&mut self) -> Result<(), BigMapError> {
+        if self.hash_ring.is_empty() {
+            if self.creating_data_canister {
+                // Concurrent calls are not allowed while the data canister is being created
+                return Err(BigMapError::BucketUnavailable);
+            }
+            self.creating_data_canister = true;
+            println!("BigMap Index: No Data Canisters, creating one!");
+            let result = match self.create_data_bucket_canister().await {
+                Ok(can_id) => {
+                    println!("BigMap Index: Activating Data CanisterId {}", can_id);
+
+                    let ranges = self.hash_ring_add_canister_id(&can_id);
+                    self.ucall_dcan_set_range(&can_id, &ranges).await
+                }
+                Err(err) => {
+                    println!("BigMap Index: Error creating a new Data Canister {}", err);
+                    Err(BigMapError::BucketUnavailable)
+                }
+            };
+            self.creating_data_canister = false;
+            return result;
+        };
+        Ok(())
+    }
+
+    pub async fn ensure_at_least_one_search_canister(&mut self) -> Result<(), BigMapError> {
+        if self.search_canisters.is_empty() {
+            if self.creating_search_canister {
+                // Concurrent calls are not allowed while the search canister is being created
+                return Err(BigMapError::BucketUnavailable);
+            }
+            self.creating_search_canister = true;
+            println!("BigMap Index: No Search Canisters, creating one!");
+            let result = match self.create_search_canister().await {
+                Ok(can_id) => {
+                    println!("BigMap Index: Activating Search CanisterId {}", can_id);
+                    self.search_canisters.push(can_id);
+                    Ok(())
+                }
+                Err(err) => {
+                    println!("BigMap Index: Error creating a new Search Canister {}", err);
+                    Err(BigMapError::BucketUnavailable)
+                }
+            };
+            self.creating_search_canister = false;
+            return result;
+        };
+        Ok(())
+    }
+
+    // Returns the data bucket canister which should hold the key
+    // With replicas, this is the first healthy replica which holds the key. If multiple
+    // canisters can hold the data due to rebalancing, we will query all candidates and
+    // return the correct CanisterId
+    pub async fn lookup_get(&self, key: &Key) -> Result<CanisterId, BigMapError> {
+        let key_sha256 = calc_sha256(key);
+        let hr_idx = match self.hash_ring.get_idx_node_for_key(&key_sha256) {
+            Some((hr_idx, _)) => hr_idx,
+            None => return Err(BigMapError::BucketUnavailable),
+        };
+        // println!("BigMap Index: lookup_get @key {}", String::from_utf8_lossy(key));
+
+        let replicas = self.replica_ptrs(hr_idx);
+        let mut result = Err(BigMapError::NotFound);
+        for can_ptr in replicas.iter() {
+            let can_id = self.can_ptr_to_canister_id(can_ptr);
+            match self.qcall_dcan_holds_key(&can_id, key).await {
+                Ok(true) => return Ok(can_id),
+                Ok(false) => {}
+                Err(err) => result = Err(err),
+            }
+        }
+
+        if let Some(job) = &self.relocation {
+            let (from_ptr, to_ptr) = job.direction();
+            if replicas.contains(&to_ptr)
+                && !replicas.contains(&from_ptr)
+                && !self.relocation_deleted_key(key)
+            {
+                // The data bucket which now holds the key range doesn't have the key, but the
+                // relocation is in progress and the key may not have been moved yet
+                let can_id = self.can_ptr_to_canister_id(&from_ptr);
+                if self.qcall_dcan_holds_key(&can_id, key).await? {
+                    println!(
+                        "BigMap Index: lookup_get @key {} from a relocation source {}",
+                        String::from_utf8_lossy(key),
+                        can_id
+                    );
+                    return Ok(can_id);
+                }
+            }
+        }
+
+        println!(
+            "BigMap Index: no data canister holds the key {}",
+            String::from_utf8_lossy(key)
+        );
+        result
+    }
+
+    // The data buckets to read the key from: the one found by lookup_get, and as fallbacks
+    // the other replicas of the key and the data buckets of the relocation in progress
+    fn read_candidates(&self, can_id: CanisterId, key: &Key) -> Vec<CanisterId> {
+        let mut result = vec![can_id];
+        let mut fallbacks = self.replicas_for_key(key).unwrap_or_default();
+        if let Some(job) = &self.relocation {
+            let (from_ptr, to_ptr) = job.direction();
+            fallbacks.push(self.can_ptr_to_canister_id(&to_ptr));
+            fallbacks.push(self.can_ptr_to_canister_id(&from_ptr));
+        }
+        for can_id in fallbacks {
+            if !result.contains(&can_id) {
+                result.push(can_id);
+            }
+        }
+        result
+    }
+
+    // Find the data bucket canister into which the object with the provided key should go
+    pub fn lookup_put(&self, key: &Key) -> Result<CanisterId, BigMapError> {
+        let key_sha256 = calc_sha256(key);
+        let (_, ring_node) = match self.hash_ring.get_idx_node_for_key(&key_sha256) {
+            Some(v) => v,
+            None => {
+                println!(
+                    "BigMap Index: no data canister suitable for key {}",
+                    String::from_utf8_lossy(key)
+                );
+                return Err(BigMapError::BucketUnavailable);
+            }
+        };
+
+        // println!("BigMap Index: lookup_put @key {}", String::from_utf8_lossy(key));
+        Ok(self.can_ptr_to_canister_id(ring_node))
+    }
+
+    // List keys starting with key_prefix
+    pub async fn list(&self, key_prefix: &Key) -> Result<Vec<Key>, BigMapError> {
+        let mut result = BTreeSet::new();
+
+        for can_id in self.idx.iter() {
+            let sub_list: Vec<Key> = self.qcall_dcan_list(can_id, key_prefix).await?;
+            result.extend(sub_list);
+            if result.len() > 10000 {
+                // Safety brake, don't return too many entries
+                break;
+            }
+        }
+
+        Ok(result.iter().cloned().collect())
+    }
+
+    // List up to `limit` keys starting with key_prefix, merged from all data buckets in key order
+    // Returns the keys and an opaque cursor for fetching the next page, or None after the last page
+    pub async fn list_page(
+        &self,
+        key_prefix: &Key,
+        cursor: &Option<Vec<u8>>,
+        limit: u32,
+    ) -> Result<(Vec<Key>, Option<Vec<u8>>), BigMapError> {
+        let start_after = match cursor {
+            Some(cursor) => match list_cursor_decode(cursor) {
+                Some(key) => Some(key),
+                None => {
+                    return Err(BigMapError::InvalidArgument(
+                        "Invalid list_page cursor".to_string(),
+                    ))
+                }
+            },
+            None => None,
+        };
+        let limit = limit.clamp(1, data::LIST_PAGE_LIMIT_MAX);
+
+        let mut result = BTreeSet::new();
+        let mut more_available = false;
+        for can_id in self.idx.iter() {
+            let sub_list = self
+                .qcall_dcan_list_page(can_id, key_prefix, &start_after, limit)
+                .await?;
+            // A full page means that the data bucket may hold more keys
+            more_available |= sub_list.len() >= limit as usize;
+            result.extend(sub_list);
+        }
+
+        more_available |= result.len() > limit as usize;
+        let result: Vec<Key> = result.into_iter().take(limit as usize).collect();
+        let cursor = match (more_available, result.last()) {
+            (true, Some(last_key)) => Some(list_cursor_encode(last_key)),
+            _ => None,
+        };
+        Ok((result, cursor))
+    }
+
+    // Returns the maintenance status as JSON
+    pub async fn maintenance(&mut self) -> Result<String, BigMapError> {
+        #[derive(serde::Serialize)]
         struct Status {
             status: &'static str,
             message: &'static str,
         };
 
-        if self.is_maintenance_active {
-            return serde_json_wasm::to_string(&Status {
-                status: "Good",
-                message: "Already rebalancing",
-            })
-            .unwrap();
+        let run_id = match self.maintenance_begin() {
+            Some(run_id) => run_id,
+            None => {
+                return Ok(serde_json_wasm::to_string(&Status {
+                    status: "Good",
+                    message: "Already rebalancing",
+                })
+                .unwrap())
+            }
+        };
+        let result = self.maintenance_run(run_id).await;
+        self.maintenance_end(run_id);
+        result?;
+
+        Ok(serde_json_wasm::to_string(&Status {
+            status: "Good",
+            message: "Finished maintenance",
+        })
+        .unwrap())
+    }
+
+    // Called by the heartbeat in every round. Once the interval of the schedule has passed,
+    // run a bounded part of the maintenance, see index/schedule.rs
+    pub async fn maintenance_tick(&mut self) {
+        let now = time_now();
+        if !self.maintenance_schedule.enabled
+            || now < self.maintenance_next_tick
+            || self.hash_ring.is_empty()
+        {
+            return;
+        }
+        self.maintenance_next_tick = now.saturating_add(self.maintenance_schedule.interval_ns());
+
+        let mut report = MaintenanceReport::new(now);
+        match self.maintenance_begin() {
+            Some(run_id) => {
+                if let Err(err) = self.maintenance_tick_run(run_id, &mut report).await {
+                    println!("BigMap Index: scheduled maintenance failed: {}", err);
+                    report.error = Some(err);
+                }
+                self.maintenance_end(run_id);
+            }
+            None => report.action = MaintenanceAction::Skipped,
+        }
+        report.finished_at = time_now();
+        report.relocation_running = self.relocation.is_some();
+        self.maintenance_last_tick = Some(report);
+    }
+
+    async fn maintenance_tick_run(
+        &mut self,
+        run_id: u64,
+        report: &mut MaintenanceReport,
+    ) -> Result<(), BigMapError> {
+        if self.relocation.is_some() {
+            report.action = MaintenanceAction::Relocation;
+        } else {
+            let used_bytes = self.maintenance_check_buckets().await?;
+            let over_threshold = (0..used_bytes.len())
+                .find(|&i| self.is_over_threshold(&CanisterPtr(i as u32), used_bytes[i]));
+            if let Some(i) = over_threshold {
+                report.action = MaintenanceAction::Split;
+                self.relocation_start(CanisterPtr(i as u32)).await?;
+            } else if let Some((merged_ptr, successor_ptr)) = self.merge_candidate(&used_bytes) {
+                println!(
+                    "BigMap Index: merging CanisterId {} into CanisterId {}",
+                    self.can_ptr_to_canister_id(&merged_ptr),
+                    self.can_ptr_to_canister_id(&successor_ptr)
+                );
+                report.action = MaintenanceAction::Merge;
+                self.relocation_merge_start(merged_ptr, successor_ptr)
+                    .await?;
+            } else {
+                if self.replication_factor > 1 {
+                    report.action = MaintenanceAction::Repair;
+                    let repaired = self.repair_step().await?;
+                    if repaired > 0 {
+                        println!("BigMap Index: repaired {} replicas", repaired);
+                    }
+                }
+                return Ok(());
+            }
+        }
+        let steps = self.maintenance_schedule.relocation_steps;
+        report.relocation_steps = self.relocation_run_steps(run_id, steps).await?;
+        Ok(())
+    }
+
+    // Start a maintenance run, returns its run id, or None if another run is making progress
+    fn maintenance_begin(&mut self) -> Option<u64> {
+        if let Some(heartbeat) = self.maintenance_heartbeat {
+            if time_now().saturating_sub(heartbeat) < MAINTENANCE_STALE_NS {
+                return None;
+            }
+            println!("BigMap Index: the previous maintenance made no progress, taking over");
+        }
+        // A run which was taken over stops at its next step, see relocation_run
+        self.maintenance_run_id += 1;
+        self.maintenance_heartbeat = Some(time_now());
+        Some(self.maintenance_run_id)
+    }
+
+    fn maintenance_end(&mut self, run_id: u64) {
+        if self.maintenance_run_id == run_id {
+            self.maintenance_heartbeat = None;
+        }
+    }
+
+    async fn maintenance_run(&mut self, run_id: u64) -> Result<(), BigMapError> {
+        self.ensure_at_least_one_data_canister().await?;
+
+        println!("BigMap Index: starting maintenance");
+
+        if self.relocation.is_some() {
+            // A previous maintenance run was interrupted during a relocation
+            println!("BigMap Index: resuming the relocation");
+            self.relocation_run(run_id).await?;
+        }
+
+        let used_bytes = self.maintenance_check_buckets().await?;
+        for (i, used_bytes) in used_bytes.into_iter().enumerate() {
+            if self.is_over_threshold(&CanisterPtr(i as u32), used_bytes) {
+                // This canister should be rebalanced. We'll do these steps:
+                // - Create destination canister, to which half of the data from the source canister will go
+                // - Move batches of objects from source canister to the destination canister
+                self.relocation_start(CanisterPtr(i as u32)).await?;
+                self.relocation_run(run_id).await?;
+            }
+        }
+
+        self.merge_underutilized(run_id).await?;
+
+        println!("Total capacity used {}", ByteSize(self.used_bytes_total));
+        Ok(())
+    }
+
+    // Reclaim the expired entries and update used_bytes_total
+    // Returns the used bytes of the data buckets, in the order of idx
+    async fn maintenance_check_buckets(&mut self) -> Result<Vec<u64>, BigMapError> {
+        let mut result = Vec::with_capacity(self.idx.len());
+        self.used_bytes_total = 0;
+        self.sweep_idle_uploads();
+
+        for i in 0..self.idx.len() {
+            let can_id = self.idx[i].clone();
+            let expired_count = self
+                .ucall_dcan_sweep_expired(&can_id, EXPIRY_SWEEP_LIMIT)
+                .await?;
+            if expired_count > 0 {
+                println!(
+                    "BigMap Index: CanisterId {} reclaimed {} expired entries",
+                    can_id, expired_count
+                );
+            }
+            let used_bytes = self.qcall_canister_used_bytes(&can_id).await? as u64;
+            self.used_bytes_total += used_bytes;
+
+            self.print_canister_utilization(&can_id, used_bytes);
+            let can_ptr = CanisterPtr(i as u32);
+            if self.is_over_threshold(&can_ptr, used_bytes) {
+                println!(
+                    "BigMap Index: CanisterId {} used bytes {} is over threshold {}",
+                    can_id,
+                    used_bytes,
+                    self.used_bytes_threshold as u64 * self.hash_ring.weight(&can_ptr) as u64
+                );
+            }
+            result.push(used_bytes);
+        }
+
+        // FIXME: Check the utilization of the Search canisters, split if necessary
+        // FIXME: Remove and/or update the indexes in the Search canisters
+
+        for can_id in self.search_canisters.iter() {
+            let used_bytes = self.qcall_canister_used_bytes(can_id).await? as u32;
+            self.used_bytes_total += used_bytes as u64;
+        }
+        Ok(result)
+    }
+
+    // Forget the uploads which got no chunk for UPLOAD_IDLE_NS, the data buckets drop
+    // their part of the upload in sweep_expired
+    fn sweep_idle_uploads(&mut self) {
+        let now = time_now();
+        self.uploads.retain(|upload_id, upload| {
+            let idle = now.saturating_sub(upload.last_active) >= UPLOAD_IDLE_NS;
+            if idle {
+                println!("BigMap Index: dropping the idle upload id {}", upload_id);
+            }
+            !idle
+        });
+    }
+
+    // The threshold grows with the weight of the data bucket, which holds more keys
+    fn is_over_threshold(&self, can_ptr: &CanisterPtr, used_bytes: u64) -> bool {
+        used_bytes > self.used_bytes_threshold as u64 * self.hash_ring.weight(can_ptr) as u64
+    }
+
+    // The first data bucket, in the order of the hash ring, which is merged into its
+    // successor, given the used bytes of the data buckets in the order of idx. A data bucket
+    // is merged only if all of its nodes in the hash ring have the same successor.
+    fn merge_candidate(&self, used_bytes: &[u64]) -> Option<(CanisterPtr, CanisterPtr)> {
+        self.hash_ring.nodes().into_iter().find_map(|merged_ptr| {
+            let successor_ptr = *self.hash_ring.successor_node(&merged_ptr)?;
+            let merge_limit = self.used_bytes_threshold as u64
+                * self.hash_ring.weight(&successor_ptr) as u64
+                * MERGE_UTILIZATION_PERCENT
+                / 100;
+            let used = used_bytes[merged_ptr.0 as usize] + used_bytes[successor_ptr.0 as usize];
+            if used < merge_limit {
+                Some((merged_ptr, successor_ptr))
+            } else {
+                None
+            }
+        })
+    }
+
+    // Merge the data buckets with low utilization into their successor in the hash ring,
+    // and make the merged data buckets available for the next splits. The data bucket
+    // which holds the end of the key space is never merged.
+    async fn merge_underutilized(&mut self, run_id: u64) -> Result<(), BigMapError> {
+        // Used bytes of the data buckets, in the order of idx
+        let mut used_bytes = Vec::with_capacity(self.idx.len());
+        for i in 0..self.idx.len() {
+            let can_id = self.idx[i].clone();
+            used_bytes.push(self.qcall_canister_used_bytes(&can_id).await? as u64);
+        }
+
+        while let Some((merged_ptr, successor_ptr)) = self.merge_candidate(&used_bytes) {
+            let (merged, successor) = (merged_ptr.0 as usize, successor_ptr.0 as usize);
+            println!(
+                "BigMap Index: merging CanisterId {} ({} bytes) into CanisterId {} ({} bytes)",
+                self.can_ptr_to_canister_id(&merged_ptr),
+                used_bytes[merged],
+                self.can_ptr_to_canister_id(&successor_ptr),
+                used_bytes[successor]
+            );
+            self.relocation_merge_start(merged_ptr, successor_ptr)
+                .await?;
+            self.relocation_run(run_id).await?;
+            // The successor took over the ranges, and the last data bucket in idx took the
+            // place of the merged one, see retire_data_bucket
+            used_bytes[successor] += used_bytes[merged];
+            used_bytes.swap_remove(merged);
+        }
+        Ok(())
+    }
+
+    // Remove the merged data bucket from the hash ring, and start the relocation job
+    // which moves its entries to the successor, which now holds its range
+    async fn relocation_merge_start(
+        &mut self,
+        merged_ptr: CanisterPtr,
+        successor_ptr: CanisterPtr,
+    ) -> Result<(), BigMapError> {
+        if self.relocation.is_some() {
+            return Err(BigMapError::Rebalancing);
+        }
+        self.raise_version_counter(
+            &self.can_ptr_to_canister_id(&merged_ptr),
+            &self.can_ptr_to_canister_id(&successor_ptr),
+        )
+        .await?;
+        if self.relocation.is_some() {
+            return Err(BigMapError::Rebalancing);
+        }
+        self.relocation = Some(RelocationJob {
+            rolling_back: true,
+            ..RelocationJob::new(successor_ptr, merged_ptr)
+        });
+        self.relocation_deleted.clear();
+        self.hash_ring.remove_node(&merged_ptr);
+        self.ring_changed();
+        Ok(())
+    }
+
+    // Split the data bucket which rejected a write since it's full, without waiting for
+    // the next maintenance. If a maintenance is running, it splits the data bucket.
+    async fn split_full_bucket(&mut self, can_id: &CanisterId) -> Result<(), BigMapError> {
+        let can_ptr = match self.idx.iter().position(|id| id == can_id) {
+            Some(i) => CanisterPtr(i as u32),
+            None => return Err(BigMapError::BucketUnavailable),
+        };
+        let run_id = match self.maintenance_begin() {
+            Some(run_id) => run_id,
+            None => return Err(BigMapError::Rebalancing),
+        };
+        println!("BigMap Index: CanisterId {} is full, splitting it", can_id);
+        let result = self.split_full_bucket_run(run_id, can_ptr).await;
+        self.maintenance_end(run_id);
+        result
+    }
+
+    async fn split_full_bucket_run(
+        &mut self,
+        run_id: u64,
+        can_ptr: CanisterPtr,
+    ) -> Result<(), BigMapError> {
+        if self.relocation.is_some() {
+            // Finish the interrupted relocation first, it may have split this data bucket
+            self.relocation_run(run_id).await?;
+            return Ok(());
+        }
+        self.relocation_start(can_ptr).await?;
+        self.relocation_run(run_id).await
+    }
+
+    // Split the source data bucket: create the destination data bucket, add it to
+    // the hash ring before the source, and start the relocation job
+    async fn relocation_start(&mut self, src_canister_ptr: CanisterPtr) -> Result<(), BigMapError> {
+        if self.relocation.is_some() {
+            return Err(BigMapError::Rebalancing);
+        }
+        let dst_canister = match self.create_data_bucket_canister().await {
+            Ok(can_id) => can_id,
+            Err(err) => {
+                println!("BigMap Index: Error creating a new Data Canister {}", err);
+                return Err(BigMapError::BucketUnavailable);
+            }
+        };
+        let src_canister = self.can_ptr_to_canister_id(&src_canister_ptr);
+        if let Err(err) = self
+            .raise_version_counter(&src_canister, &dst_canister)
+            .await
+        {
+            self.canister_available_queue.push_front(dst_canister);
+            return Err(err);
+        }
+        if self.relocation.is_some() {
+            // Another relocation started while the canister was prepared
+            self.canister_available_queue.push_front(dst_canister);
+            return Err(BigMapError::Rebalancing);
+        }
+
+        let dst_canister_ptr = self.hash_ring_add_before_this(&src_canister_ptr, &dst_canister);
+        self.relocation = Some(RelocationJob::new(src_canister_ptr, dst_canister_ptr));
+        self.relocation_deleted.clear();
+        Ok(())
+    }
+
+    // The data bucket `to` takes over keys from the data bucket `from`, and its version
+    // counter is raised to the one of `from` before the hash ring changes. The entries are
+    // compared by version when they're relocated, so the writes in `to` have to get newer
+    // versions than the entries which are still to be relocated from `from`.
+    async fn raise_version_counter(
+        &self,
+        from: &CanisterId,
+        to: &CanisterId,
+    ) -> Result<(), BigMapError> {
+        let version = self.ucall_dcan_raise_version_counter(from, 0).await?;
+        self.ucall_dcan_raise_version_counter(to, version).await?;
+        Ok(())
+    }
+
+    // Run the relocation job until it's finished
+    async fn relocation_run(&mut self, run_id: u64) -> Result<(), BigMapError> {
+        self.relocation_run_steps(run_id, u32::MAX).await?;
+        Ok(())
+    }
+
+    // Run up to steps_max steps of the relocation job, returns the number of steps run
+    async fn relocation_run_steps(
+        &mut self,
+        run_id: u64,
+        steps_max: u32,
+    ) -> Result<u32, BigMapError> {
+        let mut steps = 0;
+        while steps < steps_max {
+            if self.maintenance_run_id != run_id {
+                // Another maintenance run took over, and continues the relocation
+                return Err(BigMapError::Rebalancing);
+            }
+            self.maintenance_heartbeat = Some(time_now());
+            steps += 1;
+            if self.relocation_step().await? {
+                break;
+            }
+        }
+        Ok(steps)
+    }
+
+    // The ranges of keys held by the data bucket, one for each of its nodes in the hash
+    // ring, with the neighbouring ranges joined. Empty if it's not in the hash ring.
+    // With replicas, the data bucket also holds the ranges of its predecessors in the
+    // hash ring, and a range wraps around the end of the key space for the first ones
+    fn hash_ring_ranges_for_node(&self, can_ptr: &CanisterPtr) -> Vec<HashRingRange> {
+        let nodes = self.hash_ring.nodes();
+        if !nodes.contains(can_ptr) {
+            return Vec::new();
+        }
+        if self.replication_factor as usize >= nodes.len() {
+            return vec![(
+                *hashring_sha256::SHA256_DIGEST_MIN,
+                *hashring_sha256::SHA256_DIGEST_MAX,
+            )];
+        }
+
+        let mut result: Vec<HashRingRange> = Vec::new();
+        for hr_idx in 0..self.hash_ring.len() {
+            if !self.replica_ptrs(hr_idx).contains(can_ptr) {
+                continue;
+            }
+            let (range_start, range_end) = self.hash_ring.get_key_range_for_idx(hr_idx);
+            match result.last_mut() {
+                Some(last) if last.1 == range_start => last.1 = range_end,
+                _ => result.push((range_start, range_end)),
+            }
+        }
+        // The range at the end of the key space continues with the range at its start
+        if result.len() > 1
+            && result[0].0 == *hashring_sha256::SHA256_DIGEST_MIN
+            && result[result.len() - 1].1 == self.hash_ring.ring[self.hash_ring.len() - 1].key
+        {
+            let (range_start, _) = result.pop().unwrap();
+            result[0].0 = range_start;
+        }
+        result
+    }
+
+    // The data buckets which hold the keys of the hash ring node hr_idx: the node itself
+    // and the next distinct data buckets in the hash ring, up to the replication factor
+    fn replica_ptrs(&self, hr_idx: usize) -> Vec<CanisterPtr> {
+        let ring_len = self.hash_ring.len();
+        let mut result = Vec::new();
+        for i in 0..ring_len {
+            if result.len() >= self.replication_factor as usize {
+                break;
+            }
+            let can_ptr = self.hash_ring.ring[(hr_idx + i) % ring_len].node;
+            if !result.contains(&can_ptr) {
+                result.push(can_ptr);
+            }
+        }
+        result
+    }
+
+    // The data buckets which hold the key, starting with its owner in the hash ring
+    pub fn replicas_for_key(&self, key: &Key) -> Result<Vec<CanisterId>, BigMapError> {
+        let key_sha256 = calc_sha256(key);
+        match self.hash_ring.get_idx_node_for_key(&key_sha256) {
+            Some((hr_idx, _)) => Ok(self
+                .replica_ptrs(hr_idx)
+                .iter()
+                .map(|can_ptr| self.can_ptr_to_canister_id(can_ptr))
+                .collect()),
+            None => Err(BigMapError::BucketUnavailable),
+        }
+    }
+
+    // The data buckets other than the source and the destination whose ranges change with
+    // the relocation: the successors of the nodes of the source which hold their replicas
+    fn relocation_replicas(&self, job: &RelocationJob) -> Vec<CanisterPtr> {
+        let mut result = Vec::new();
+        for hr_idx in 0..self.hash_ring.len() {
+            if self.hash_ring.ring[hr_idx].node != job.src {
+                continue;
+            }
+            for can_ptr in self.replica_ptrs(hr_idx).into_iter().skip(1) {
+                if can_ptr != job.src && can_ptr != job.dst && !result.contains(&can_ptr) {
+                    result.push(can_ptr);
+                }
+            }
         }
-        self.is_maintenance_active = true;
+        result
+    }
 
-        if let Err(_) = self.ensure_at_least_one_data_canister().await {
-            self.is_maintenance_active = false;
-            return serde_json_wasm::to_string(&Status {
-                status: "Unknown",
-                message: "Error trying to ensure at least one data canister",
-            })
-            .unwrap();
+    // The data bucket which receives the key from the running relocation, if any
+    fn relocation_receiver_for_key(&self, key: &Key) -> Option<(&RelocationJob, CanisterId)> {
+        let job = self.relocation.as_ref()?;
+        let to_canister = self.can_ptr_to_canister_id(&job.direction().1);
+        match self.lookup_put(key) {
+            Ok(can_id) if can_id == to_canister => Some((job, to_canister)),
+            _ => None,
         }
+    }
 
-        println!("BigMap Index: starting maintenance");
+    fn relocation_deleted_key(&self, key: &Key) -> bool {
+        self.relocation.is_some() && self.relocation_deleted.contains(&calc_sha256(key).to_vec())
+    }
 
-        self.used_bytes_total = 0;
+    // Move the keys which the running relocation didn't move yet to their new data bucket,
+    // before they are written there. So the write applies to the latest entry: an append
+    // extends the current value, a conditional write compares the current version, and the
+    // version of the new entry continues from it. The keys are refused while the ranges of
+    // the data buckets are being set, since the source may still accept them.
+    async fn relocation_take_keys(&self, keys: &[Key]) -> Result<(), BigMapError> {
+        let mut receiving = Vec::new();
+        let mut job_receiver = None;
+        for key in keys {
+            if let Some((job, to_canister)) = self.relocation_receiver_for_key(key) {
+                if !self.relocation_deleted_key(key) {
+                    receiving.push(key.clone());
+                    job_receiver = Some((job, to_canister));
+                }
+            }
+        }
+        let (job, to_canister) = match job_receiver {
+            Some(job_receiver) => job_receiver,
+            None => return Ok(()),
+        };
+        if job.phase == RelocationPhase::SetRanges {
+            return Err(BigMapError::Rebalancing);
+        }
+        let from_canister = self.can_ptr_to_canister_id(&job.direction().0);
 
-        for i in 0..self.idx.len() {
-            let can_id = self.idx[i].clone();
-            let can_ptr = CanisterPtr { 0: i as u32 };
-            let used_bytes = self.qcall_canister_used_bytes(&can_id).await as u64;
-            self.used_bytes_total += used_bytes;
+        let entries = self
+            .qcall_dcan_get_replica_entries(&from_canister, &receiving)
+            .await?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        // An entry which was moved meanwhile is kept, and the newer version wins
+        let put_count = self
+            .ucall_dcan_put_relocation_batch(&to_canister, &entries)
+            .await?;
+        if entries.len() as u64 != put_count {
+            return Err(BigMapError::DataCorrupted(format!(
+                "Only {} of {} entries were moved from {} to {}",
+                put_count,
+                entries.len(),
+                from_canister,
+                to_canister
+            )));
+        }
+        for entry in entries.iter().filter(|e| !e.is_complete()) {
+            self.send_entry_parts(&from_canister, entry, &to_canister, false)
+                .await?;
+        }
+        let keys_sha2: Vec<Vec<u8>> = entries.iter().map(|e| e.key_sha2.clone()).collect();
+        self.ucall_dcan_delete_entries(&from_canister, &keys_sha2)
+            .await
+    }
 
-            self.print_canister_utilization(&can_id, used_bytes);
+    // Record the delete of a key which the running relocation moves, before it's sent to the
+    // data bucket. The key was taken from the source, and a batch read from the source
+    // earlier skips it. If the delete fails, the key is still held by the receiving data
+    // bucket, and the record has no effect.
+    fn relocation_mark_deleted(&mut self, key: &Key) {
+        if self.relocation_receiver_for_key(key).is_some() {
+            self.relocation_deleted.insert(calc_sha256(key).to_vec());
+        }
+    }
+
+    // Make one step of the relocation job, returns true once there is no job left
+    // Every step can be repeated: set_range sets the same ranges again, a batch which
+    // is put again doesn't overwrite the entries, and deleting the entries again is a no-op.
+    // If the job changed while a call was in flight (e.g. a rollback was requested),
+    // the step is abandoned and the next step continues with the changed job.
+    async fn relocation_step(&mut self) -> Result<bool, BigMapError> {
+        let job = match &self.relocation {
+            Some(job) => job.clone(),
+            None => return Ok(true),
+        };
+        let (from_ptr, to_ptr) = job.direction();
+        let from_canister = self.can_ptr_to_canister_id(&from_ptr);
+        let to_canister = self.can_ptr_to_canister_id(&to_ptr);
+
+        match job.phase {
+            RelocationPhase::SetRanges => {
+                // The receiving data bucket accepts its new ranges before the other one shrinks
+                let ranges_to = self.hash_ring_ranges_for_node(&to_ptr);
+                let ranges_from = self.hash_ring_ranges_for_node(&from_ptr);
+                self.ucall_dcan_set_range(&to_canister, &ranges_to).await?;
+                for can_ptr in self.relocation_replicas(&job) {
+                    let ranges = self.hash_ring_ranges_for_node(&can_ptr);
+                    let can_id = self.can_ptr_to_canister_id(&can_ptr);
+                    self.ucall_dcan_set_range(&can_id, &ranges).await?;
+                }
+                self.ucall_dcan_set_range(&from_canister, &ranges_from)
+                    .await?;
+                if let Some(current) = self.relocation.as_mut().filter(|current| **current == job) {
+                    current.phase = RelocationPhase::Moving;
+                }
+            }
+            RelocationPhase::Moving => {
+                // The entries in the ranges of the receiving data bucket, which without
+                // replicas are the entries outside of the ranges of the other one
+                let ranges_to = self.hash_ring_ranges_for_node(&to_ptr);
+                let batch = self
+                    .ucall_dcan_get_range_batch(
+                        &from_canister,
+                        &ranges_to,
+                        &job.cursor,
+                        self.batch_limit_bytes,
+                    )
+                    .await?;
+                if self.relocation.as_ref() != Some(&job) {
+                    return Ok(false);
+                }
 
-            if used_bytes as u32 > self.used_bytes_threshold {
                 println!(
-                    "BigMap Index: CanisterId {} used bytes {} is over threshold {}",
-                    can_id, used_bytes, self.used_bytes_threshold
+                    "BigMap Index: Got relocation batch of {} entries",
+                    batch.len()
                 );
 
-                // This canister should be rebalanced. We'll do these steps:
-                // - Create destination canister, to which half of the data from the source canister will go
-                // - Move batches of objects from source canister to the destination canister
-                // We're just starting to rebalance, create the destination canister
-                let src_canister_ptr = can_ptr.clone();
-                let src_canister = self.can_ptr_to_canister_id(&src_canister_ptr);
-                let dst_canister = self
-                    .create_data_bucket_canister()
-                    .await
-                    .expect("create_data_bucket_canister failed");
-
-                let (dst_canister_ptr, range_dst, range_src) =
-                    self.hash_ring_add_before_this(&src_canister_ptr, &dst_canister.clone());
-
-                // The new canister has been created and added to the hash ring
-                // Remember the canisters we're currently rebalancing
-                self.now_rebalancing_src_dst = Some((src_canister_ptr, dst_canister_ptr));
+                if batch.is_empty() {
+                    // Finished rebalancing this canister
+                    if self.relocation_replicas(&job).is_empty() {
+                        self.relocation_finish(&job).await;
+                        return Ok(true);
+                    }
+                    if let Some(current) = self.relocation.as_mut() {
+                        current.phase = RelocationPhase::Replicas {
+                            replica: 0,
+                            trimming: !job.rolling_back,
+                        };
+                        current.cursor = None;
+                    }
+                    return Ok(false);
+                }
 
-                // Update the range covered by Source and Destination canister
-                self.ucall_dcan_set_range(&dst_canister, range_dst.0, range_dst.1)
-                    .await;
-                self.ucall_dcan_set_range(&src_canister, range_src.0, range_src.1)
-                    .await;
+                // An entry which is moved in parts is deleted from the source after its last part
+                let batch_sha2: Vec<Vec<u8>> = batch
+                    .iter()
+                    .filter(|e| e.is_complete())
+                    .map(|e| e.key_sha2.clone())
+                    .collect();
+                let cursor = batch.last().map(|e| e.cursor());
+                // The keys deleted since the batch was read are only deleted from the source
+                let batch: Vec<RelocationEntry> = batch
+                    .into_iter()
+                    .filter(|e| !self.relocation_deleted.contains(&e.key_sha2))
+                    .collect();
+                let put_count = if batch.is_empty() {
+                    0
+                } else {
+                    self.ucall_dcan_put_relocation_batch(&to_canister, &batch)
+                        .await?
+                };
+                if batch.len() as u64 != put_count {
+                    // Keep the entries in the source, the job stays until it's rolled back
+                    return Err(BigMapError::DataCorrupted(format!(
+                        "Only {} of {} entries were moved from {} to {}",
+                        put_count,
+                        batch.len(),
+                        from_canister,
+                        to_canister
+                    )));
+                }
+                println!(
+                    "BigMap Index: Moved {} elements from {} to {}",
+                    batch.len(),
+                    from_canister,
+                    to_canister
+                );
 
-                // Start moving data
-                let (rebalance_src_ptr, rebalance_dst_ptr) = self.now_rebalancing_src_dst.unwrap();
-                let src_canister = self.can_ptr_to_canister_id(&rebalance_src_ptr);
-                let dst_canister = self.can_ptr_to_canister_id(&rebalance_dst_ptr);
+                // The entries which are still in the range of the source are kept there
+                self.ucall_dcan_delete_entries(&from_canister, &batch_sha2)
+                    .await?;
+                if let Some(current) = self.relocation.as_mut().filter(|current| **current == job) {
+                    current.cursor = cursor;
+                    current.entries_moved += batch_sha2.len() as u64;
+                }
+            }
+            RelocationPhase::Replicas { replica, trimming } => {
+                let can_ptr = match self.relocation_replicas(&job).get(replica as usize) {
+                    Some(can_ptr) => *can_ptr,
+                    None => {
+                        self.relocation_finish(&job).await;
+                        return Ok(true);
+                    }
+                };
+                let can_id = self.can_ptr_to_canister_id(&can_ptr);
+                let batch = if trimming {
+                    self.ucall_dcan_get_relocation_batch(
+                        &can_id,
+                        &job.cursor,
+                        self.batch_limit_bytes,
+                    )
+                    .await?
+                } else {
+                    let ranges = self.hash_ring_ranges_for_node(&can_ptr);
+                    self.ucall_dcan_get_range_batch(
+                        &to_canister,
+                        &ranges,
+                        &job.cursor,
+                        self.batch_limit_bytes,
+                    )
+                    .await?
+                };
+                if self.relocation.as_ref() != Some(&job) {
+                    return Ok(false);
+                }
 
-                loop {
-                    let batch = self
-                        .ucall_dcan_get_relocation_batch(&src_canister, self.batch_limit_bytes)
-                        .await;
+                if batch.is_empty() {
+                    // Continue with deleting the entries outside of the range, or with the
+                    // next replica, which first gets the entries of its range on a rollback
+                    if let Some(current) = self.relocation.as_mut() {
+                        current.phase = if trimming {
+                            RelocationPhase::Replicas {
+                                replica: replica + 1,
+                                trimming: !job.rolling_back,
+                            }
+                        } else {
+                            RelocationPhase::Replicas {
+                                replica,
+                                trimming: true,
+                            }
+                        };
+                        current.cursor = None;
+                    }
+                    return Ok(false);
+                }
 
+                let batch_sha2: Vec<Vec<u8>> = batch.iter().map(|e| e.key_sha2.clone()).collect();
+                if trimming {
+                    self.ucall_dcan_delete_entries(&can_id, &batch_sha2).await?;
                     println!(
-                        "BigMap Index: Got relocation batch of {} entries",
-                        batch.len()
+                        "BigMap Index: Deleted {} elements outside of the range of {}",
+                        batch.len(),
+                        can_id
                     );
-
-                    if batch.is_empty() {
-                        // Finished rebalancing this canister
-                        self.now_rebalancing_src_dst = None;
-                        break;
-                    } else {
-                        let put_count = self
-                            .ucall_dcan_put_relocation_batch(&dst_canister, &batch)
-                            .await;
-                        if batch.len() as u64 != put_count {
-                            println!(
-                                "BigMap Index: Not all elements were moved from {} to {}",
-                                src_canister, dst_canister
-                            )
-                        } else {
-                            println!(
-                                "BigMap Index: Moved {} elements from {} to {}",
-                                batch.len(),
-                                src_canister,
-                                dst_canister
-                            )
-                        }
-                        let batch_sha2 = batch.iter().map(|e| e.0.clone()).collect();
-
-                        self.ucall_dcan_delete_entries(&src_canister, &batch_sha2)
-                            .await;
+                } else {
+                    let put_count = self
+                        .ucall_dcan_put_relocation_batch(&can_id, &batch)
+                        .await?;
+                    if batch.len() as u64 != put_count {
+                        return Err(BigMapError::DataCorrupted(format!(
+                            "Only {} of {} entries were copied from {} to {}",
+                            put_count,
+                            batch.len(),
+                            to_canister,
+                            can_id
+                        )));
                     }
+                    println!(
+                        "BigMap Index: Copied {} elements from {} to {}",
+                        batch.len(),
+                        to_canister,
+                        can_id
+                    );
+                }
+                if let Some(current) = self.relocation.as_mut().filter(|current| **current == job) {
+                    current.cursor = batch.last().map(|e| e.cursor());
                 }
             }
         }
+        Ok(false)
+    }
 
-        // FIXME: Check the utilization of the Search canisters, split if necessary
-        // FIXME: Remove and/or update the indexes in the Search canisters
-
-        for can_id in self.search_canisters.iter() {
-            let used_bytes = self.qcall_canister_used_bytes(can_id).await as u32;
-            self.used_bytes_total += used_bytes as u64;
+    async fn relocation_finish(&mut self, job: &RelocationJob) {
+        if job.rolling_back {
+            // The destination is empty and no longer in the hash ring, it can be used again
+            self.retire_data_bucket(job.dst).await;
         }
+        println!(
+            "BigMap Index: Finished the relocation of {} entries",
+            job.entries_moved
+        );
+        self.relocation = None;
+        self.relocation_deleted.clear();
+    }
 
-        println!("Total capacity used {}", ByteSize(self.used_bytes_total));
-
-        self.is_maintenance_active = false;
+    // Remove the data bucket, which is no longer in the hash ring, from idx and make it
+    // available for the next split. The last data bucket in idx takes its place, so the
+    // hash ring nodes of the last data bucket are pointed to the new place.
+    // The data bucket is reset first, so it doesn't keep its ranges, tombstones, versions and
+    // uploads. If the reset fails, the data bucket is not used again.
+    async fn retire_data_bucket(&mut self, can_ptr: CanisterPtr) {
+        let last_ptr = CanisterPtr(self.idx.len() as u32 - 1);
+        if can_ptr != last_ptr {
+            self.hash_ring.replace_node(&last_ptr, can_ptr);
+        }
+        let can_id = self.idx.swap_remove(can_ptr.0 as usize);
+        match self.ucall_dcan_reset(&can_id).await {
+            Ok(()) => {
+                println!("BigMap Index: CanisterId {} is available again", can_id);
+                self.canister_available_queue.push_front(can_id);
+            }
+            Err(err) => println!(
+                "BigMap Index: CanisterId {} is not used again, the reset failed: {}",
+                can_id, err
+            ),
+        }
+    }
 
-        serde_json_wasm::to_string(&Status {
-            status: "Good",
-            message: "Finished maintenance",
-        })
-        .unwrap()
+    // Abandon the split in progress: the destination data bucket is removed from the
+    // hash ring, and the next maintenance run moves the entries back to the source
+    pub async fn relocation_rollback(&mut self) -> Result<(), BigMapError> {
+        let (src, dst) = match self.relocation.as_ref() {
+            Some(job) if !job.rolling_back => (job.src, job.dst),
+            _ => {
+                return Err(BigMapError::InvalidArgument(
+                    "No relocation to roll back".to_string(),
+                ))
+            }
+        };
+        // The source takes the keys back from the destination
+        self.raise_version_counter(
+            &self.can_ptr_to_canister_id(&dst),
+            &self.can_ptr_to_canister_id(&src),
+        )
+        .await?;
+        let job = match self.relocation.as_mut() {
+            Some(job) if !job.rolling_back && job.src == src && job.dst == dst => job,
+            _ => return Err(BigMapError::Rebalancing),
+        };
+        println!(
+            "BigMap Index: Rolling back the relocation from {} to {}",
+            self.idx[job.src.0 as usize], self.idx[job.dst.0 as usize]
+        );
+        *job = RelocationJob {
+            rolling_back: true,
+            ..RelocationJob::new(job.src, job.dst)
+        };
+        let dst = job.dst;
+        self.hash_ring.remove_node(&dst);
+        self.ring_changed();
+        Ok(())
     }
 
-    pub async fn status(&self) -> String {
+    // The namespaces in the status are the ones the caller has access to
+    pub async fn status(&self, caller: &CanisterId) -> Result<String, BigMapError> {
         #[derive(serde::Serialize, Default)]
         struct DataBucketStatus {
             canister_id: String,
             used_bytes: u32,
-        };
+            weight: u32,
+        }
 
         #[derive(serde::Serialize, Default)]
         struct SearchCanisterStatus {
             canister_id: String,
             used_bytes: u32,
-        };
+        }
+
+        #[derive(serde::Serialize)]
+        struct RelocationStatus {
+            src: String,
+            dst: String,
+            rolling_back: bool,
+            entries_moved: u64,
+        }
 
         #[derive(serde::Serialize, Default)]
         struct Status {
+            namespace: String,
+            namespaces: Vec<String>,
             data_buckets: Vec<DataBucketStatus>,
             search_canisters: Vec<SearchCanisterStatus>,
             used_bytes_total: u64,
+            replication_factor: u32,
+            call_policy: CallPolicy,
+            relocation: Option<RelocationStatus>,
         };
 
-        let mut status = Status::default();
+        let mut status = Status {
+            namespace: self.namespaces.current(caller),
+            namespaces: self.namespaces.visible(caller),
+            replication_factor: self.replication_factor,
+            call_policy: self.call_policy.clone(),
+            relocation: self.relocation.as_ref().map(|job| RelocationStatus {
+                src: self.can_ptr_to_canister_id(&job.src).to_string(),
+                dst: self.can_ptr_to_canister_id(&job.dst).to_string(),
+                rolling_back: job.rolling_back,
+                entries_moved: job.entries_moved,
+            }),
+            ..Default::default()
+        };
 
-        for can_id in self.idx.iter() {
-            let used_bytes = self.qcall_canister_used_bytes(can_id).await as u32;
+        for (i, can_id) in self.idx.iter().enumerate() {
+            let used_bytes = self.qcall_canister_used_bytes(can_id).await? as u32;
             status.data_buckets.push(DataBucketStatus {
                 canister_id: can_id.to_string(),
                 used_bytes,
+                weight: self.hash_ring.weight(&CanisterPtr(i as u32)),
             });
             status.used_bytes_total += used_bytes as u64;
         }
 
         for can_id in self.search_canisters.iter() {
-            let used_bytes = self.qcall_canister_used_bytes(can_id).await as u32;
+            let used_bytes = self.qcall_canister_used_bytes(can_id).await? as u32;
             status.search_canisters.push(SearchCanisterStatus {
                 canister_id: can_id.to_string(),
                 used_bytes,
@@ -537,62 +2358,256 @@ impl BigmapIdx {
             status.used_bytes_total += used_bytes as u64;
         }
 
-        serde_json_wasm::to_string(&status).unwrap()
+        Ok(serde_json_wasm::to_string(&status).unwrap())
     }
 
+    // Add the new data bucket to the hash ring, with a node before each node of the
+    // existing data bucket, which takes the share of its range given by the weights
     fn hash_ring_add_before_this(
         &mut self,
         can_ptr: &CanisterPtr,
         can_id_new: &CanisterId,
-    ) -> (CanisterPtr, HashRingRange, HashRingRange) {
+    ) -> CanisterPtr {
         let can_ptr_new = CanisterPtr {
             0: self.idx.len() as u32,
         };
-        // Query the Hash Ring for the information on the provided (existing) Canister
-        let (hr_i, hr_key, _) = self.hash_ring.get_idx_key_node_for_node(can_ptr).unwrap();
-        // Get the value of the previous key in the hash ring
-        let hr_key_prev = if hr_i == 0 {
-            // There is no previous key
-            *hashring_sha256::SHA256_DIGEST_MIN
-        } else {
-            self.hash_ring.get_prev_key_node_at_idx(hr_i).unwrap().0
-        };
-        // Now calculate the middle point between hr_key_prev and hr_key
-        let hr_key_new = hashring_sha256::sha256_range_half(&hr_key_prev, &hr_key);
-        self.hash_ring.add_with_key(&hr_key_new, can_ptr_new);
+        self.hash_ring
+            .add_before(can_ptr, can_ptr_new, hashring_sha256::WEIGHT_DEFAULT);
 
         self.idx.push(can_id_new.clone());
-
-        // The entries in the hash ring are now updated
-        let hr_idx_new_canister = hr_i; // == self.hash_ring.get_idx_key_node_for_node(can_ptr_new).unwrap().0;
-        let hr_idx_old_canister = hr_i + 1; // == self.hash_ring.get_idx_key_node_for_node(can_ptr).unwrap().0;
-        (
-            can_ptr_new,
-            self.hash_ring.get_key_range_for_idx(hr_idx_new_canister),
-            self.hash_ring.get_key_range_for_idx(hr_idx_old_canister),
-        )
+        self.ring_changed();
+        can_ptr_new
     }
 
-    fn hash_ring_add_canister_id(&mut self, can_id: &CanisterId) -> HashRingRange {
+    // Add the first data bucket to the hash ring, returns its ranges
+    fn hash_ring_add_canister_id(&mut self, can_id: &CanisterId) -> Vec<HashRingRange> {
         let ptr_new = CanisterPtr {
             0: self.idx.len() as u32,
         };
-        self.hash_ring.add(ptr_new.clone());
+        self.hash_ring.add_vnodes(ptr_new, self.virtual_nodes);
 
         self.idx.push(can_id.clone());
+        self.ring_changed();
+
+        self.hash_ring_ranges_for_node(&ptr_new)
+    }
+
+    // The hash ring entries, with the canister ids of the data buckets
+    pub fn ring(&self) -> Vec<(Sha2Vec, CanisterId)> {
+        self.hash_ring
+            .ring
+            .iter()
+            .map(|n| (n.key.to_vec(), self.can_ptr_to_canister_id(&n.node)))
+            .collect()
+    }
+
+    pub fn get_ring_certified(&self) -> CertifiedRing {
+        CertifiedRing {
+            ring: self.ring(),
+            certificate: data_certificate(),
+        }
+    }
+
+    // Set the hash of the ring as the certified data of the canister
+    pub fn certify_ring(&self) {
+        set_certified_data(&ring_hash(&self.ring()));
+    }
+
+    // The routing tables of the clients are outdated, and the data buckets whose range
+    // changes get the new epoch with the range
+    fn ring_changed(&mut self) {
+        self.ring_epoch += 1;
+        self.certify_ring();
+    }
+
+    pub fn ring_epoch(&self) -> u64 {
+        self.ring_epoch
+    }
+
+    pub fn get_routing_table(&self) -> RoutingTable {
+        RoutingTable {
+            epoch: self.ring_epoch,
+            ranges: (0..self.hash_ring.len())
+                .map(|hr_idx| {
+                    let (range_start, range_end) = self.hash_ring.get_key_range_for_idx(hr_idx);
+                    RoutingRange {
+                        range_start: range_start.to_vec(),
+                        range_end: range_end.to_vec(),
+                        canister_id: self.can_ptr_to_canister_id(&self.hash_ring.ring[hr_idx].node),
+                    }
+                })
+                .collect(),
+            replication_factor: self.replication_factor,
+            relocating: self.relocation.is_some(),
+        }
+    }
+
+    // Records the canister which installed the BigmapIdx as the admin, unless one is
+    // recorded already
+    pub fn record_admin(&mut self, can_id: CanisterId) {
+        if self.admins.is_empty() {
+            println!("BigMap Index: admin {}", can_id);
+            self.admins.push(can_id);
+        }
+    }
+
+    // Returns Unauthorized unless the caller is one of the admins
+    pub fn authorize_admin(&self, caller: &CanisterId) -> Result<(), BigMapError> {
+        if self.admins.contains(caller) {
+            Ok(())
+        } else {
+            Err(BigMapError::Unauthorized)
+        }
+    }
+
+    pub fn admins(&self) -> &Vec<CanisterId> {
+        &self.admins
+    }
+
+    pub fn set_admins(&mut self, admins: Vec<CanisterId>) -> Result<(), BigMapError> {
+        if admins.is_empty() {
+            return Err(BigMapError::InvalidArgument(
+                "The BigMap Index needs at least one admin".to_string(),
+            ));
+        }
+        self.admins = admins;
+        Ok(())
+    }
 
-        let hr_idx = self
-            .hash_ring
-            .get_idx_key_node_for_node(&ptr_new)
-            .unwrap()
-            .0;
-        self.hash_ring.get_key_range_for_idx(hr_idx)
+    pub fn namespaces(&self) -> &Namespaces {
+        &self.namespaces
+    }
+
+    pub fn namespaces_mut(&mut self) -> &mut Namespaces {
+        &mut self.namespaces
     }
 
     pub fn set_used_bytes_threshold(&mut self, used_bytes_threshold: u32) {
         self.used_bytes_threshold = used_bytes_threshold;
     }
 
+    // The ranges of the data buckets follow the replication factor as they are split and
+    // merged, so it can only be changed while there is at most one data bucket
+    pub fn set_replication_factor(&mut self, replication_factor: u32) -> Result<(), BigMapError> {
+        if !(1..=REPLICATION_FACTOR_MAX).contains(&replication_factor) {
+            return Err(BigMapError::InvalidArgument(format!(
+                "The replication factor must be 1 to {}",
+                REPLICATION_FACTOR_MAX
+            )));
+        }
+        if self.relocation.is_some() {
+            return Err(BigMapError::Rebalancing);
+        }
+        if self.hash_ring.nodes().len() > 1 {
+            return Err(BigMapError::InvalidArgument(
+                "The replication factor can only be changed while there is one data bucket"
+                    .to_string(),
+            ));
+        }
+        self.replication_factor = replication_factor;
+        Ok(())
+    }
+
+    pub fn replication_factor(&self) -> u32 {
+        self.replication_factor
+    }
+
+    // The splits keep the number of nodes of the data buckets, so it can only be changed
+    // while there is at most one data bucket. The nodes of the data bucket are replaced,
+    // its range stays the whole key space.
+    pub fn set_virtual_nodes(&mut self, virtual_nodes: u32) -> Result<(), BigMapError> {
+        if !(1..=VIRTUAL_NODES_MAX).contains(&virtual_nodes) {
+            return Err(BigMapError::InvalidArgument(format!(
+                "The virtual nodes must be 1 to {}",
+                VIRTUAL_NODES_MAX
+            )));
+        }
+        if self.relocation.is_some() {
+            return Err(BigMapError::Rebalancing);
+        }
+        let nodes = self.hash_ring.nodes();
+        if nodes.len() > 1 {
+            return Err(BigMapError::InvalidArgument(
+                "The virtual nodes can only be changed while there is one data bucket".to_string(),
+            ));
+        }
+        self.virtual_nodes = virtual_nodes;
+        if let Some(can_ptr) = nodes.first() {
+            let weight = self.hash_ring.weight(can_ptr);
+            self.hash_ring.remove_node(can_ptr);
+            self.hash_ring.add_vnodes(*can_ptr, virtual_nodes);
+            self.hash_ring.set_weight(can_ptr, weight);
+            self.ring_changed();
+        }
+        Ok(())
+    }
+
+    pub fn virtual_nodes(&self) -> u32 {
+        self.virtual_nodes
+    }
+
+    // The weight is the share of the keys of the data bucket relative to the others, e.g.
+    // for a data bucket with more memory. It takes effect with the next split of the data
+    // bucket, which leaves it the share weight / (weight + 1) of its ranges, and with its
+    // thresholds for the split and for merging a predecessor into it.
+    pub fn set_data_bucket_weight(
+        &mut self,
+        can_id: &CanisterId,
+        weight: u32,
+    ) -> Result<(), BigMapError> {
+        if weight == 0 {
+            return Err(BigMapError::InvalidArgument(
+                "The weight must be at least 1".to_string(),
+            ));
+        }
+        let can_ptr = match self.idx.iter().position(|id| id == can_id) {
+            Some(i) => CanisterPtr(i as u32),
+            None => {
+                return Err(BigMapError::InvalidArgument(format!(
+                    "CanisterId {} is not a data bucket",
+                    can_id
+                )))
+            }
+        };
+        if !self.hash_ring.nodes().contains(&can_ptr) {
+            return Err(BigMapError::InvalidArgument(format!(
+                "CanisterId {} is not in the hash ring",
+                can_id
+            )));
+        }
+        self.hash_ring.set_weight(&can_ptr, weight);
+        Ok(())
+    }
+
+    pub fn set_call_policy(&mut self, call_policy: CallPolicy) -> Result<(), BigMapError> {
+        call_policy.validate()?;
+        self.call_policy = call_policy;
+        Ok(())
+    }
+
+    pub fn call_policy(&self) -> &CallPolicy {
+        &self.call_policy
+    }
+
+    // The next tick runs right away, so that the new schedule takes effect
+    pub fn set_maintenance_schedule(
+        &mut self,
+        schedule: MaintenanceSchedule,
+    ) -> Result<(), BigMapError> {
+        schedule.validate()?;
+        self.maintenance_schedule = schedule;
+        self.maintenance_next_tick = 0;
+        Ok(())
+    }
+
+    pub fn maintenance_status(&self) -> MaintenanceStatus {
+        MaintenanceStatus {
+            schedule: self.maintenance_schedule.clone(),
+            next_tick_at: self.maintenance_next_tick,
+            last_tick: self.maintenance_last_tick.clone(),
+        }
+    }
+
     pub fn set_canister_id(&mut self, can_id: CanisterId) {
         self.id = can_id
     }
@@ -601,6 +2616,98 @@ impl BigmapIdx {
         self.id.clone()
     }
 
+    // Serialize the persistent BigmapIdx state, to be saved in the stable memory before an upgrade
+    pub fn write_stable_payload<W: Write>(&self, writer: W) -> Result<(), String> {
+        let state = BigmapIdxStableV2Ref {
+            idx: &self.idx,
+            hash_ring: self
+                .hash_ring
+                .ring
+                .iter()
+                .map(|n| (n.key.as_slice(), n.node))
+                .collect(),
+            hash_ring_weights: self.hash_ring.weights(),
+            virtual_nodes: self.virtual_nodes,
+            ring_epoch: self.ring_epoch,
+            relocation: &self.relocation,
+            batch_limit_bytes: self.batch_limit_bytes,
+            canister_available_queue: &self.canister_available_queue,
+            used_bytes_threshold: self.used_bytes_threshold,
+            used_bytes_total: self.used_bytes_total,
+            search_canisters: &self.search_canisters,
+            data_bucket_canister_wasm_binary: &self.data_bucket_canister_wasm_binary,
+            search_canister_wasm_binary: &self.search_canister_wasm_binary,
+            id: &self.id,
+            namespaces: &self.namespaces,
+            replication_factor: self.replication_factor,
+            call_policy: &self.call_policy,
+            maintenance_schedule: &self.maintenance_schedule,
+            code_upgrade: &self.code_upgrade,
+            admins: &self.admins,
+        };
+        bincode::serialize_into(writer, &state)
+            .map_err(|err| format!("BigmapIdx serialization failed: {}", err))
+    }
+
+    // As write_stable_payload, into a Vec
+    pub fn to_stable_payload(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        self.write_stable_payload(&mut result)
+            .expect("BigmapIdx serialization failed");
+        result
+    }
+
+    // As read_stable_payload, from a slice
+    pub fn from_stable_payload(schema_version: u32, payload: &[u8]) -> Result<Self, String> {
+        Self::read_stable_payload(schema_version, payload)
+    }
+
+    // Restore the BigmapIdx state saved with write_stable_payload, by this or an older release
+    pub fn read_stable_payload<R: Read>(schema_version: u32, reader: R) -> Result<Self, String> {
+        let deserialize_err = |err| format!("BigmapIdx deserialization failed: {}", err);
+        let state: BigmapIdxStableV2 = match schema_version {
+            1 => bincode::deserialize_from::<_, BigmapIdxStableV1>(reader)
+                .map_err(deserialize_err)?
+                .into(),
+            2 => bincode::deserialize_from(reader).map_err(deserialize_err)?,
+            _ => {
+                return Err(format!(
+                    "Unsupported BigmapIdx schema version {}",
+                    schema_version
+                ))
+            }
+        };
+
+        let mut result = BigmapIdx::new();
+        for (key, can_ptr) in state.hash_ring {
+            result
+                .hash_ring
+                .add_with_key(&sha256_digest_from_vec(&key), can_ptr);
+        }
+        for (can_ptr, weight) in state.hash_ring_weights {
+            result.hash_ring.set_weight(&can_ptr, weight);
+        }
+        result.virtual_nodes = state.virtual_nodes;
+        result.ring_epoch = state.ring_epoch;
+        result.idx = state.idx;
+        result.relocation = state.relocation;
+        result.batch_limit_bytes = state.batch_limit_bytes;
+        result.canister_available_queue = state.canister_available_queue;
+        result.used_bytes_threshold = state.used_bytes_threshold;
+        result.used_bytes_total = state.used_bytes_total;
+        result.search_canisters = state.search_canisters;
+        result.data_bucket_canister_wasm_binary = state.data_bucket_canister_wasm_binary;
+        result.search_canister_wasm_binary = state.search_canister_wasm_binary;
+        result.id = state.id;
+        result.namespaces = state.namespaces;
+        result.replication_factor = state.replication_factor;
+        result.call_policy = state.call_policy;
+        result.maintenance_schedule = state.maintenance_schedule;
+        result.code_upgrade = state.code_upgrade;
+        result.admins = state.admins;
+        Ok(result)
+    }
+
     async fn create_data_bucket_canister(&mut self) -> Result<CanisterId, String> {
         match self.canister_available_queue.pop_front() {
             Some(can_id) => Ok(can_id),
@@ -623,44 +2730,217 @@ impl BigmapIdx {
                     };
                     Ok(new_can_id)
                 }
-                Err(err) => Err(err),
-            },
+                Err(err) => Err(err),
+            },
+        }
+    }
+
+    async fn create_search_canister(&mut self) -> Result<CanisterId, String> {
+        match subnet_create_new_canister().await {
+            Ok(new_can_id) => {
+                println!("BigMap Index: Created new CanisterId {}", new_can_id);
+                match subnet_install_canister_code(
+                    new_can_id.clone(),
+                    self.search_canister_wasm_binary.clone(),
+                )
+                .await
+                {
+                    Ok(_) => println!("BigMap Index: Code install successful to {}", new_can_id),
+                    Err(err) => println!(
+                        "CanisterId {}: code install failed with error {}",
+                        new_can_id, err
+                    ),
+                };
+                Ok(new_can_id)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn set_data_bucket_canister_wasm_binary(
+        &mut self,
+        wasm_binary: Vec<u8>,
+    ) -> Result<(), BigMapError> {
+        self.code_upgrade_discard_halted()?;
+        self.data_bucket_canister_wasm_binary = wasm_binary;
+        self.ensure_at_least_one_data_canister().await
+    }
+
+    pub async fn set_search_canister_wasm_binary(
+        &mut self,
+        wasm_binary: Vec<u8>,
+    ) -> Result<(), BigMapError> {
+        self.code_upgrade_discard_halted()?;
+        self.search_canister_wasm_binary = wasm_binary;
+        self.ensure_at_least_one_search_canister().await
+    }
+
+    // The code can't change while an upgrade job installs it, and a halted job is replaced,
+    // so that the next upgrade_canisters installs the new code in all canisters
+    fn code_upgrade_discard_halted(&mut self) -> Result<(), BigMapError> {
+        match &self.code_upgrade {
+            Some(job) if !job.is_finished() && job.failure.is_none() => {
+                Err(BigMapError::Rebalancing)
+            }
+            Some(job) if !job.is_finished() => {
+                self.code_upgrade = None;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Install the stored code in all data buckets and search canisters, one after the other,
+    // see index/code_upgrade.rs. Returns the report of the job, which halts on the first
+    // failure. An unfinished job is resumed, with the new expected version.
+    pub async fn upgrade_canisters(
+        &mut self,
+        expected_version: Option<String>,
+    ) -> Result<UpgradeJob, BigMapError> {
+        let run_id = match self.maintenance_begin() {
+            Some(run_id) => run_id,
+            None => return Err(BigMapError::Rebalancing),
+        };
+        let result = self.upgrade_canisters_run(run_id, expected_version).await;
+        self.maintenance_end(run_id);
+        result
+    }
+
+    async fn upgrade_canisters_run(
+        &mut self,
+        run_id: u64,
+        expected_version: Option<String>,
+    ) -> Result<UpgradeJob, BigMapError> {
+        if self.relocation.is_some() {
+            // The relocation calls both data buckets, the maintenance finishes it first
+            return Err(BigMapError::Rebalancing);
+        }
+        match &mut self.code_upgrade {
+            Some(job) if !job.is_finished() => {
+                println!("BigMap Index: resuming the upgrade of the canisters");
+                job.expected_version = expected_version;
+                job.failure = None;
+            }
+            _ => self.code_upgrade = Some(self.code_upgrade_new_job(expected_version)?),
+        }
+
+        loop {
+            if self.maintenance_run_id != run_id {
+                // Another maintenance run took over, and continues the upgrade
+                return Err(BigMapError::Rebalancing);
+            }
+            self.maintenance_heartbeat = Some(time_now());
+            let job = self.code_upgrade.as_mut().expect("No code upgrade job");
+            let target = match job.pending.first() {
+                Some(target) => target.clone(),
+                None => break,
+            };
+            job.upgrading = Some(target.canister_id.clone());
+            let expected_version = job.expected_version.clone();
+
+            let result = self.upgrade_canister(&target, &expected_version).await;
+            let job = self.code_upgrade.as_mut().expect("No code upgrade job");
+            match result {
+                Ok(()) => job.advance(),
+                Err(failure) => {
+                    println!(
+                        "BigMap Index: upgrade of CanisterId {} failed: {}",
+                        failure.canister_id, failure.error
+                    );
+                    job.halt(failure);
+                    return Ok(job.clone());
+                }
+            }
         }
+
+        let job = self.code_upgrade.as_mut().expect("No code upgrade job");
+        job.finished_at = Some(time_now());
+        println!(
+            "BigMap Index: upgraded the code of {} canisters",
+            job.upgraded.len()
+        );
+        Ok(job.clone())
     }
 
-    async fn create_search_canister(&mut self) -> Result<CanisterId, String> {
-        match subnet_create_new_canister().await {
-            Ok(new_can_id) => {
-                println!("BigMap Index: Created new CanisterId {}", new_can_id);
-                match subnet_install_canister_code(
-                    new_can_id.clone(),
-                    self.search_canister_wasm_binary.clone(),
-                )
-                .await
-                {
-                    Ok(_) => println!("BigMap Index: Code install successful to {}", new_can_id),
-                    Err(err) => println!(
-                        "CanisterId {}: code install failed with error {}",
-                        new_can_id, err
-                    ),
-                };
-                Ok(new_can_id)
+    // The data buckets in the hash ring come first, then the available data buckets and the
+    // search canisters
+    fn code_upgrade_new_job(
+        &self,
+        expected_version: Option<String>,
+    ) -> Result<UpgradeJob, BigMapError> {
+        let data_buckets = self.idx.iter().chain(self.canister_available_queue.iter());
+        let mut pending: Vec<UpgradeTarget> = data_buckets
+            .map(|can_id| UpgradeTarget {
+                canister_id: can_id.clone(),
+                kind: CanisterKind::DataBucket,
+            })
+            .collect();
+        pending.extend(self.search_canisters.iter().map(|can_id| UpgradeTarget {
+            canister_id: can_id.clone(),
+            kind: CanisterKind::Search,
+        }));
+        for target in pending.iter() {
+            if self.wasm_binary_for(target.kind).is_empty() {
+                return Err(BigMapError::InvalidArgument(format!(
+                    "The wasm binary of the {:?} canisters is not set",
+                    target.kind
+                )));
             }
-            Err(err) => Err(err),
         }
+        Ok(UpgradeJob::new(pending, expected_version, time_now()))
     }
 
-    pub async fn set_data_bucket_canister_wasm_binary(&mut self, wasm_binary: Vec<u8>) {
-        self.data_bucket_canister_wasm_binary = wasm_binary;
-        if let Err(err) = self.ensure_at_least_one_data_canister().await {
-            println!("Error adding canisters: {}", err);
+    fn wasm_binary_for(&self, kind: CanisterKind) -> &Vec<u8> {
+        match kind {
+            CanisterKind::DataBucket => &self.data_bucket_canister_wasm_binary,
+            CanisterKind::Search => &self.search_canister_wasm_binary,
         }
     }
 
-    pub async fn set_search_canister_wasm_binary(&mut self, wasm_binary: Vec<u8>) {
-        self.search_canister_wasm_binary = wasm_binary;
-        if let Err(err) = self.ensure_at_least_one_search_canister().await {
-            println!("Error adding canisters: {}", err);
+    async fn upgrade_canister(
+        &self,
+        target: &UpgradeTarget,
+        expected_version: &Option<String>,
+    ) -> Result<(), UpgradeFailure> {
+        let can_id = &target.canister_id;
+        let failure = |error: String, code_replaced: bool| UpgradeFailure {
+            canister_id: can_id.clone(),
+            error,
+            code_replaced,
+        };
+        println!("BigMap Index: upgrading the code of CanisterId {}", can_id);
+        let wasm_binary = self.wasm_binary_for(target.kind).clone();
+        // A failed install leaves the canister running the old code with its state
+        self.ucall_mgmt_upgrade_code(can_id, wasm_binary)
+            .await
+            .map_err(|err| failure(format!("install_code failed: {}", err), false))?;
+
+        let version = self
+            .qcall_canister_code_version(can_id)
+            .await
+            .map_err(|err| failure(format!("version query failed: {}", err), true))?;
+        match expected_version {
+            Some(expected) if &version.crate_version != expected => Err(failure(
+                format!(
+                    "runs version {}, expected {}",
+                    version.crate_version, expected
+                ),
+                true,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // The last upgrade job, running, halted or finished
+    pub fn upgrade_status(&self) -> Option<&UpgradeJob> {
+        self.code_upgrade.as_ref()
+    }
+
+    // The calls to the canister are paused while its code is upgraded
+    fn is_upgrading(&self, can_id: &CanisterId) -> bool {
+        match &self.code_upgrade {
+            Some(job) => job.upgrading.as_ref() == Some(can_id),
+            None => false,
         }
     }
 
@@ -673,22 +2953,22 @@ impl BigmapIdx {
     }
 
     // Returns a randomly generated and unused key
-    pub async fn get_random_key(&self) -> String {
+    // The key is probed in the data buckets scoped to the namespace
+    pub async fn get_random_key(&self, namespace: &str) -> Result<String, BigMapError> {
         let time_bytes = ic_cdk::time().to_be_bytes();
         let mut rand_key = calc_sha256(&time_bytes.to_vec());
         for i in 0..100u32 {
             // Only try this a limited number of times
             let rand_key_hash = calc_sha256(&rand_key);
-            let (_, can_ptr) = match self.hash_ring.get_idx_node_for_key(&rand_key_hash) {
+            let key = namespace::scoped_key(namespace, &rand_key);
+            let (_, can_ptr) = match self.hash_ring.get_idx_node_for_key(&calc_sha256(&key)) {
                 Some(v) => v,
-                None => return hex::encode(rand_key),
+                None => return Ok(hex::encode(rand_key)),
             };
 
             let can_id = self.can_ptr_to_canister_id(can_ptr);
 
-            let key_is_used = self
-                .qcall_dcan_holds_key(&can_id, &Vec::from(rand_key.as_slice()))
-                .await;
+            let key_is_used = self.qcall_dcan_holds_key(&can_id, &key).await?;
 
             if !key_is_used {
                 let result = hex::encode(rand_key);
@@ -696,93 +2976,106 @@ impl BigmapIdx {
                     "get_random_key: after {} attempts found {} which maps to {}",
                     i, result, can_id
                 );
-                return result;
+                return Ok(result);
             }
 
             rand_key = rand_key_hash;
         }
         println!("get_random_key: failed to find an unused key in the range");
-        "".to_string()
+        Err(BigMapError::NotFound)
     }
 
     //
     // Search functions
     //
 
-    pub async fn put_and_fts_index(&mut self, key: &Key, document: &String) -> u64 {
-        if let Err(err) = self.ensure_at_least_one_search_canister().await {
-            println!(
-                "Error putting key {} => {}",
-                String::from_utf8_lossy(key),
-                err
-            );
-            return 0;
+    // FIXME: Ensure the search canister has enough space and allocate a new one if necessary
+    fn search_canister_for_put(&self) -> Result<CanisterId, BigMapError> {
+        match self.search_canisters.first() {
+            Some(can_id) => Ok(can_id.clone()),
+            None => Err(BigMapError::BucketUnavailable),
         }
+    }
+
+    pub async fn put_and_fts_index(
+        &mut self,
+        key: &Key,
+        document: &String,
+    ) -> Result<u64, BigMapError> {
+        self.ensure_at_least_one_search_canister().await?;
 
         let value_vec = Vec::from(document.as_bytes());
 
-        let result = self.put(&key, &value_vec).await;
+        let result = self.put(key, &value_vec).await?;
 
-        // FIXME: Ensure the search canister has enough space and allocate a new one if necessary
-        let search_can_id = &self.search_canisters[0].clone();
+        let search_can_id = self.search_canister_for_put()?;
         self.ucall_s_can_add_to_search_index(&search_can_id, key, document)
-            .await;
+            .await?;
 
-        result
+        Ok(result)
     }
 
-    pub async fn batch_put_and_fts_index(&mut self, batch: &Vec<(Key, String)>) -> u64 {
-        if let Err(err) = self.ensure_at_least_one_search_canister().await {
-            println!("Error putting a batch of length {} => {}", batch.len(), err);
-            return 0;
-        }
+    pub async fn batch_put_and_fts_index(
+        &mut self,
+        batch: &[(Key, String)],
+    ) -> Result<u64, BigMapError> {
+        self.ensure_at_least_one_search_canister().await?;
 
         let batch_as_bytes: Vec<_> = batch
             .iter()
             .map(|(k, v)| (k.clone(), Vec::from(v.as_bytes())))
             .collect();
 
-        self.batch_put(&batch_as_bytes).await;
+        // Only the stored documents are added to the search index
+        let results = self.batch_put(&batch_as_bytes).await;
+        let stored: Vec<(Key, String)> = batch
+            .iter()
+            .zip(results)
+            .filter(|(_, res)| res.is_ok())
+            .map(|(doc, _)| doc.clone())
+            .collect();
 
-        // FIXME: Ensure the search canister has enough space and allocate a new one if necessary
-        let search_can_id = &self.search_canisters[0].clone();
-        self.ucall_s_can_batch_add_to_search_index(&search_can_id, batch)
-            .await;
+        let search_can_id = self.search_canister_for_put()?;
+        self.ucall_s_can_batch_add_to_search_index(&search_can_id, &stored)
+            .await?;
 
-        batch.len() as u64
+        Ok(stored.len() as u64)
     }
 
-    pub async fn remove_from_fts_index(&mut self, key: &Key) {
-        if let Err(err) = self.ensure_at_least_one_search_canister().await {
-            println!(
-                "Error removing key {} => {}",
-                String::from_utf8_lossy(key),
-                err
-            );
-            return;
-        }
+    pub async fn remove_from_fts_index(&mut self, key: &Key) -> Result<(), BigMapError> {
+        self.ensure_at_least_one_search_canister().await?;
 
         for can_id in self.search_canisters.iter() {
-            self.ucall_s_can_remove_from_search_index(can_id, key).await
+            self.ucall_s_can_remove_from_search_index(can_id, key)
+                .await?
         }
+        Ok(())
     }
 
-    pub async fn search(&self, search_query: &String) -> (u64, Vec<(Key, Val)>) {
+    // Returns the number of matching keys starting with key_prefix, and up to 20 of the entries
+    pub async fn search(
+        &self,
+        search_query: &String,
+        key_prefix: &Key,
+    ) -> Result<(u64, Vec<(Key, Val)>), BigMapError> {
         if self.search_canisters.is_empty() {
-            return (0, Vec::new());
+            return Ok((0, Vec::new()));
         }
 
         let mut results = Vec::new();
         let mut results_len = 0;
 
         for can_id in self.search_canisters.iter() {
-            let results_per_canister = self
+            let results_per_canister: Vec<Key> = self
                 .qcall_s_can_search_keys_by_query(can_id, search_query)
-                .await;
+                .await?
+                .into_iter()
+                .filter(|key| key.starts_with(key_prefix))
+                .collect();
             results_len += results_per_canister.len() as u64;
             for key in results_per_canister {
                 match self.get(&key).await {
-                    Some(value) => {
+                    Ok(value) => {
                         println!(
                             "search {} => key {} value {}",
                             search_query,
@@ -791,15 +3084,60 @@ impl BigmapIdx {
                         );
                         results.push((key, value));
                         if results.len() >= 20 {
-                            return (results_len, results);
+                            return Ok((results_len, results));
                         }
                     }
-                    None => continue,
+                    // The search index may still hold deleted keys
+                    Err(BigMapError::NotFound) => continue,
+                    Err(err) => return Err(err),
                 }
             }
         }
 
-        (results_len, results)
+        Ok((results_len, results))
+    }
+}
+
+// Call an endpoint of a BigMap Data or Search canister, which all return Result<R, BigMapError>
+// A rejected call is returned as BigMapError::CallFailed
+async fn call_bigmap_once<T: CandidType, R: DeserializeOwned>(
+    can_id: &CanisterId,
+    method: &str,
+    arg: T,
+) -> Result<R, BigMapError> {
+    ic_cdk::call::<T, Result<R, BigMapError>>(can_id.clone().0.into(), method, Some(arg)).await?
+}
+
+impl BigmapIdx {
+    // Call the canister, and retry as allowed by the call policy
+    async fn call_bigmap<T: CandidType + Clone, R: DeserializeOwned>(
+        &self,
+        can_id: &CanisterId,
+        method: &str,
+        arg: T,
+    ) -> Result<R, BigMapError> {
+        self.call_bigmap_failover(std::slice::from_ref(can_id), method, arg)
+            .await
+    }
+
+    // Call the first candidate, and fall back to the next ones if it doesn't answer
+    async fn call_bigmap_failover<T: CandidType + Clone, R: DeserializeOwned>(
+        &self,
+        candidates: &[CanisterId],
+        method: &str,
+        arg: T,
+    ) -> Result<R, BigMapError> {
+        call::call_with_retries(&self.call_policy, candidates, |can_id| {
+            let arg = arg.clone();
+            let upgrading = self.is_upgrading(&can_id);
+            async move {
+                if upgrading {
+                    return Err(BigMapError::BucketUnavailable);
+                }
+                call_bigmap_once(&can_id, method, arg).await
+            }
+        })
+        .await
     }
 }
 
@@ -808,15 +3146,10 @@ impl BigmapIdx {
     async fn ucall_s_can_batch_add_to_search_index(
         &self,
         can_id: &CanisterId,
-        doc_vec: &Vec<(Key, String)>,
-    ) -> u64 {
-        ic_cdk::call(
-            can_id.clone().0.into(),
-            "batch_add_to_search_index",
-            Some(doc_vec),
-        )
-        .await
-        .expect("batch_add_to_search_index call failed")
+        doc_vec: &[(Key, String)],
+    ) -> Result<u64, BigMapError> {
+        self.call_bigmap(can_id, "batch_add_to_search_index", doc_vec)
+            .await
     }
 
     async fn ucall_s_can_add_to_search_index(
@@ -824,101 +3157,190 @@ impl BigmapIdx {
         can_id: &CanisterId,
         key: &Key,
         document: &String,
-    ) {
-        ic_cdk::call_no_return(
-            can_id.clone().0.into(),
-            "add_to_search_index",
-            Some((key, document)),
-        )
-        .await
-        .expect("add_to_search_index call failed")
+    ) -> Result<(), BigMapError> {
+        self.call_bigmap(can_id, "add_to_search_index", (key, document))
+            .await
     }
 
-    async fn ucall_s_can_remove_from_search_index(&self, can_id: &CanisterId, key: &Key) {
-        ic_cdk::call_no_return(
-            can_id.clone().0.into(),
-            "remove_from_search_index",
-            Some(key),
-        )
-        .await
-        .expect("remove_from_search_index call failed")
+    async fn ucall_s_can_remove_from_search_index(
+        &self,
+        can_id: &CanisterId,
+        key: &Key,
+    ) -> Result<(), BigMapError> {
+        self.call_bigmap(can_id, "remove_from_search_index", key)
+            .await
     }
 
     async fn qcall_s_can_search_keys_by_query(
         &self,
         can_id: &CanisterId,
         search_query: &String,
-    ) -> Vec<Key> {
-        ic_cdk::call(
-            can_id.clone().0.into(),
-            "search_keys_by_query",
-            Some(search_query),
-        )
-        .await
-        .expect("search_keys_by_query call failed")
+    ) -> Result<Vec<Key>, BigMapError> {
+        self.call_bigmap(can_id, "search_keys_by_query", search_query)
+            .await
     }
 
-    async fn qcall_dcan_list(&self, can_id: &CanisterId, key_prefix: &Vec<u8>) -> Vec<Key> {
-        ic_cdk::call(can_id.clone().0.into(), "list", Some(key_prefix))
-            .await
-            .expect("list call failed")
+    async fn qcall_dcan_list(
+        &self,
+        can_id: &CanisterId,
+        key_prefix: &Vec<u8>,
+    ) -> Result<Vec<Key>, BigMapError> {
+        self.call_bigmap(can_id, "list", key_prefix).await
     }
 
-    async fn qcall_canister_used_bytes(&self, can_id: &CanisterId) -> usize {
-        ic_cdk::call(can_id.clone().0.into(), "used_bytes", Some(()))
+    async fn qcall_dcan_list_page(
+        &self,
+        can_id: &CanisterId,
+        key_prefix: &Key,
+        start_after: &Option<Key>,
+        limit: u32,
+    ) -> Result<Vec<Key>, BigMapError> {
+        self.call_bigmap(can_id, "list_page", (key_prefix, start_after, limit))
             .await
-            .expect("used_bytes call failed")
     }
 
-    async fn qcall_dcan_holds_key(&self, can_id: &CanisterId, key: &Key) -> bool {
-        ic_cdk::call(can_id.clone().0.into(), "holds_key", Some(key))
-            .await
-            .expect("holds_key call failed")
+    async fn qcall_canister_used_bytes(&self, can_id: &CanisterId) -> Result<usize, BigMapError> {
+        let used_bytes: u64 = self.call_bigmap(can_id, "used_bytes", ()).await?;
+        Ok(used_bytes as usize)
+    }
+
+    async fn qcall_dcan_holds_key(
+        &self,
+        can_id: &CanisterId,
+        key: &Key,
+    ) -> Result<bool, BigMapError> {
+        self.call_bigmap(can_id, "holds_key", key).await
     }
 
     async fn ucall_dcan_set_range(
         &self,
         can_id: &CanisterId,
-        range_start: Sha256Digest,
-        range_end: Sha256Digest,
-    ) {
-        ic_cdk::call_no_return(
-            can_id.clone().0.into(),
+        ranges: &[HashRingRange],
+    ) -> Result<(), BigMapError> {
+        self.call_bigmap(
+            can_id,
             "set_range",
-            Some((range_start.to_vec(), range_end.to_vec())),
+            (ranges_to_vec(ranges), self.ring_epoch),
         )
         .await
-        .expect("set_range call failed")
     }
 
     async fn ucall_dcan_get_relocation_batch(
         &self,
         can_id: &CanisterId,
+        start_after: &Option<Sha2Vec>,
         batch_size_bytes: u64,
-    ) -> Vec<(Sha2Vec, Key, Val)> {
-        ic_cdk::call(
-            can_id.clone().0.into(),
+    ) -> Result<Vec<RelocationEntry>, BigMapError> {
+        self.call_bigmap(
+            can_id,
             "get_relocation_batch",
-            Some(batch_size_bytes),
+            (start_after, batch_size_bytes),
+        )
+        .await
+    }
+
+    async fn ucall_dcan_get_range_batch(
+        &self,
+        can_id: &CanisterId,
+        ranges: &[HashRingRange],
+        start_after: &Option<Sha2Vec>,
+        batch_size_bytes: u64,
+    ) -> Result<Vec<RelocationEntry>, BigMapError> {
+        self.call_bigmap(
+            can_id,
+            "get_range_batch",
+            (ranges_to_vec(ranges), start_after, batch_size_bytes),
         )
         .await
-        .expect("get_relocation_batch call failed")
+    }
+
+    async fn qcall_dcan_get_replica_entries(
+        &self,
+        can_id: &CanisterId,
+        keys: &[Key],
+    ) -> Result<Vec<RelocationEntry>, BigMapError> {
+        self.call_bigmap(can_id, "get_replica_entries", keys).await
+    }
+
+    async fn qcall_dcan_get_entry_part(
+        &self,
+        can_id: &CanisterId,
+        key: &Key,
+        offset: u64,
+    ) -> Result<Option<RelocationEntry>, BigMapError> {
+        self.call_bigmap(can_id, "get_entry_part", (key, offset))
+            .await
+    }
+
+    async fn ucall_dcan_put_replica_batch(
+        &self,
+        can_id: &CanisterId,
+        batch: &[RelocationEntry],
+    ) -> Result<Vec<Result<u64, BigMapError>>, BigMapError> {
+        self.call_bigmap(can_id, "put_replica_batch", batch).await
     }
 
     async fn ucall_dcan_put_relocation_batch(
         &self,
         can_id: &CanisterId,
-        batch: &Vec<(Sha2Vec, Key, Val)>,
-    ) -> u64 {
-        ic_cdk::call(can_id.clone().0.into(), "put_relocation_batch", Some(batch))
+        batch: &[RelocationEntry],
+    ) -> Result<u64, BigMapError> {
+        self.call_bigmap(can_id, "put_relocation_batch", batch)
             .await
-            .expect("put_relocation_batch call failed")
     }
 
-    async fn ucall_dcan_delete_entries(&self, can_id: &CanisterId, keys_sha2: &Vec<Vec<u8>>) {
-        ic_cdk::call_no_return(can_id.clone().0.into(), "delete_entries", Some(keys_sha2))
+    async fn ucall_dcan_delete_entries(
+        &self,
+        can_id: &CanisterId,
+        keys_sha2: &Vec<Vec<u8>>,
+    ) -> Result<(), BigMapError> {
+        self.call_bigmap(can_id, "delete_entries", keys_sha2).await
+    }
+
+    async fn ucall_dcan_sweep_expired(
+        &self,
+        can_id: &CanisterId,
+        limit: u32,
+    ) -> Result<u64, BigMapError> {
+        self.call_bigmap(can_id, "sweep_expired", limit).await
+    }
+
+    async fn ucall_dcan_reset(&self, can_id: &CanisterId) -> Result<(), BigMapError> {
+        self.call_bigmap(can_id, "reset", ()).await
+    }
+
+    async fn ucall_dcan_raise_version_counter(
+        &self,
+        can_id: &CanisterId,
+        version: u64,
+    ) -> Result<u64, BigMapError> {
+        self.call_bigmap(can_id, "raise_version_counter", version)
             .await
-            .expect("delete_entries call failed")
+    }
+
+    async fn ucall_dcan_delete(&self, can_id: &CanisterId, key: &Key) -> Result<u64, BigMapError> {
+        self.call_bigmap(can_id, "delete_from_index", key).await
+    }
+
+    async fn ucall_mgmt_upgrade_code(
+        &self,
+        can_id: &CanisterId,
+        wasm_binary: Vec<u8>,
+    ) -> Result<(), String> {
+        subnet_upgrade_canister_code(can_id.clone(), wasm_binary).await
+    }
+
+    // Bypasses the pause of the calls to the canister which is upgraded
+    async fn qcall_canister_code_version(
+        &self,
+        can_id: &CanisterId,
+    ) -> Result<CodeVersion, BigMapError> {
+        call::call_with_retries(
+            &self.call_policy,
+            std::slice::from_ref(can_id),
+            |can_id| async move { call_bigmap_once(&can_id, "version", ()).await },
+        )
+        .await
     }
 }
 
@@ -954,6 +3376,10 @@ impl BigmapIdx {
         self.fn_ptr_list = Some(fn_ptr);
     }
 
+    pub fn set_fn_ptr_list_page(&mut self, fn_ptr: FnPtrListPage) {
+        self.fn_ptr_list_page = Some(fn_ptr);
+    }
+
     pub fn set_fn_ptr_holds_key(&mut self, fn_ptr: FnPtrHoldsKey) {
         self.fn_ptr_holds_key = Some(fn_ptr);
     }
@@ -962,6 +3388,22 @@ impl BigmapIdx {
         self.fn_ptr_get_relocation_batch = Some(fn_ptr);
     }
 
+    pub fn set_fn_ptr_get_range_batch(&mut self, fn_ptr: FnPtrGetRangeBatch) {
+        self.fn_ptr_get_range_batch = Some(fn_ptr);
+    }
+
+    pub fn set_fn_ptr_get_replica_entries(&mut self, fn_ptr: FnPtrGetReplicaEntries) {
+        self.fn_ptr_get_replica_entries = Some(fn_ptr);
+    }
+
+    pub fn set_fn_ptr_get_entry_part(&mut self, fn_ptr: FnPtrGetEntryPart) {
+        self.fn_ptr_get_entry_part = Some(fn_ptr);
+    }
+
+    pub fn set_fn_ptr_put_replica_batch(&mut self, fn_ptr: FnPtrPutReplicaBatch) {
+        self.fn_ptr_put_replica_batch = Some(fn_ptr);
+    }
+
     pub fn set_fn_ptr_put_relocation_batch(&mut self, fn_ptr: FnPtrPutRelocationBatch) {
         self.fn_ptr_put_relocation_batch = Some(fn_ptr);
     }
@@ -970,16 +3412,40 @@ impl BigmapIdx {
         self.fn_ptr_delete_entries = Some(fn_ptr);
     }
 
+    pub fn set_fn_ptr_sweep_expired(&mut self, fn_ptr: FnPtrSweepExpired) {
+        self.fn_ptr_sweep_expired = Some(fn_ptr);
+    }
+
+    pub fn set_fn_ptr_reset(&mut self, fn_ptr: FnPtrReset) {
+        self.fn_ptr_reset = Some(fn_ptr);
+    }
+
+    pub fn set_fn_ptr_raise_version_counter(&mut self, fn_ptr: FnPtrRaiseVersionCounter) {
+        self.fn_ptr_raise_version_counter = Some(fn_ptr);
+    }
+
+    pub fn set_fn_ptr_delete(&mut self, fn_ptr: FnPtrDelete) {
+        self.fn_ptr_delete = Some(fn_ptr);
+    }
+
+    pub fn set_fn_ptr_upgrade_code(&mut self, fn_ptr: FnPtrUpgradeCode) {
+        self.fn_ptr_upgrade_code = Some(fn_ptr);
+    }
+
+    pub fn set_fn_ptr_code_version(&mut self, fn_ptr: FnPtrCodeVersion) {
+        self.fn_ptr_code_version = Some(fn_ptr);
+    }
+
     async fn ucall_s_can_batch_add_to_search_index(
         &self,
         can_id: &CanisterId,
-        doc_vec: &Vec<(Key, String)>,
-    ) -> u64 {
+        doc_vec: &[(Key, String)],
+    ) -> Result<u64, BigMapError> {
         let fn_ptr = self
             .fn_ptr_batch_add_to_search_index
             .as_ref()
             .expect("fn_ptr_batch_add_to_search_index is not set");
-        fn_ptr(can_id.clone(), doc_vec)
+        Ok(fn_ptr(can_id.clone(), doc_vec))
     }
 
     async fn ucall_s_can_add_to_search_index(
@@ -987,98 +3453,248 @@ impl BigmapIdx {
         can_id: &CanisterId,
         key: &Vec<u8>,
         doc: &String,
-    ) {
+    ) -> Result<(), BigMapError> {
         let fn_ptr = self
             .fn_ptr_add_to_search_index
             .as_ref()
             .expect("fn_ptr_add_to_search_index is not set");
-        fn_ptr(can_id.clone(), key, doc)
+        fn_ptr(can_id.clone(), key, doc);
+        Ok(())
     }
 
-    async fn ucall_s_can_remove_from_search_index(&self, can_id: &CanisterId, key: &Vec<u8>) {
+    async fn ucall_s_can_remove_from_search_index(
+        &self,
+        can_id: &CanisterId,
+        key: &Vec<u8>,
+    ) -> Result<(), BigMapError> {
         let fn_ptr = self
             .fn_ptr_remove_from_search_index
             .as_ref()
             .expect("fn_ptr_remove_from_search_index is not set");
-        fn_ptr(can_id.clone(), key)
+        fn_ptr(can_id.clone(), key);
+        Ok(())
     }
 
     async fn qcall_s_can_search_keys_by_query(
         &self,
         can_id: &CanisterId,
         search_query: &String,
-    ) -> Vec<Key> {
+    ) -> Result<Vec<Key>, BigMapError> {
         let fn_ptr = self
             .fn_ptr_search_keys_by_query
             .as_ref()
             .expect("fn_ptr_search_keys_by_query is not set");
-        fn_ptr(can_id.clone(), search_query)
+        Ok(fn_ptr(can_id.clone(), search_query))
     }
 
-    async fn qcall_canister_used_bytes(&self, can_id: &CanisterId) -> usize {
+    async fn qcall_canister_used_bytes(&self, can_id: &CanisterId) -> Result<usize, BigMapError> {
         let fn_ptr = self
             .fn_ptr_used_bytes
             .as_ref()
             .expect("fn_ptr_used_bytes is not set");
-        fn_ptr(can_id.clone())
+        Ok(fn_ptr(can_id.clone()))
     }
 
-    async fn qcall_dcan_list(&self, can_id: &CanisterId, key_prefix: &Vec<u8>) -> Vec<Key> {
+    async fn qcall_dcan_list(
+        &self,
+        can_id: &CanisterId,
+        key_prefix: &Vec<u8>,
+    ) -> Result<Vec<Key>, BigMapError> {
         let fn_ptr = self.fn_ptr_list.as_ref().expect("fn_ptr_list is not set");
-        fn_ptr(can_id.clone(), key_prefix)
+        Ok(fn_ptr(can_id.clone(), key_prefix))
+    }
+
+    async fn qcall_dcan_list_page(
+        &self,
+        can_id: &CanisterId,
+        key_prefix: &Key,
+        start_after: &Option<Key>,
+        limit: u32,
+    ) -> Result<Vec<Key>, BigMapError> {
+        let fn_ptr = self
+            .fn_ptr_list_page
+            .as_ref()
+            .expect("fn_ptr_list_page is not set");
+        Ok(fn_ptr(can_id.clone(), key_prefix, start_after, limit))
     }
 
-    async fn qcall_dcan_holds_key(&self, can_id: &CanisterId, key: &Key) -> bool {
+    async fn qcall_dcan_holds_key(
+        &self,
+        can_id: &CanisterId,
+        key: &Key,
+    ) -> Result<bool, BigMapError> {
         let fn_ptr = self
             .fn_ptr_holds_key
             .as_ref()
             .expect("fn_ptr_used_bytes is not set");
-        fn_ptr(can_id.clone(), key)
+        Ok(fn_ptr(can_id.clone(), key))
     }
 
     async fn ucall_dcan_set_range(
         &self,
         can_id: &CanisterId,
-        range_start: Sha256Digest,
-        range_end: Sha256Digest,
-    ) {
+        ranges: &[HashRingRange],
+    ) -> Result<(), BigMapError> {
         let fn_ptr = self
             .fn_ptr_set_range
             .as_ref()
             .expect("fn_ptr_set_range is not set");
-        fn_ptr(can_id.clone(), range_start, range_end)
+        fn_ptr(can_id.clone(), ranges, self.ring_epoch);
+        Ok(())
     }
 
     async fn ucall_dcan_get_relocation_batch(
         &self,
         can_id: &CanisterId,
+        start_after: &Option<Sha2Vec>,
         batch_limit_bytes: u64,
-    ) -> Vec<(Sha2Vec, Key, Val)> {
+    ) -> Result<Vec<RelocationEntry>, BigMapError> {
         let fn_ptr = self
             .fn_ptr_get_relocation_batch
             .as_ref()
             .expect("fn_ptr_get_relocation_batch is not set");
-        fn_ptr(can_id.clone(), batch_limit_bytes)
+        Ok(fn_ptr(can_id.clone(), start_after, batch_limit_bytes))
+    }
+
+    async fn ucall_dcan_get_range_batch(
+        &self,
+        can_id: &CanisterId,
+        ranges: &[HashRingRange],
+        start_after: &Option<Sha2Vec>,
+        batch_limit_bytes: u64,
+    ) -> Result<Vec<RelocationEntry>, BigMapError> {
+        let fn_ptr = self
+            .fn_ptr_get_range_batch
+            .as_ref()
+            .expect("fn_ptr_get_range_batch is not set");
+        Ok(fn_ptr(
+            can_id.clone(),
+            ranges,
+            start_after,
+            batch_limit_bytes,
+        ))
+    }
+
+    async fn qcall_dcan_get_replica_entries(
+        &self,
+        can_id: &CanisterId,
+        keys: &[Key],
+    ) -> Result<Vec<RelocationEntry>, BigMapError> {
+        let fn_ptr = self
+            .fn_ptr_get_replica_entries
+            .as_ref()
+            .expect("fn_ptr_get_replica_entries is not set");
+        Ok(fn_ptr(can_id.clone(), keys))
+    }
+
+    async fn qcall_dcan_get_entry_part(
+        &self,
+        can_id: &CanisterId,
+        key: &Key,
+        offset: u64,
+    ) -> Result<Option<RelocationEntry>, BigMapError> {
+        let fn_ptr = self
+            .fn_ptr_get_entry_part
+            .as_ref()
+            .expect("fn_ptr_get_entry_part is not set");
+        Ok(fn_ptr(can_id.clone(), key, offset))
+    }
+
+    async fn ucall_dcan_put_replica_batch(
+        &self,
+        can_id: &CanisterId,
+        batch: &[RelocationEntry],
+    ) -> Result<Vec<Result<u64, BigMapError>>, BigMapError> {
+        let fn_ptr = self
+            .fn_ptr_put_replica_batch
+            .as_ref()
+            .expect("fn_ptr_put_replica_batch is not set");
+        Ok(fn_ptr(can_id.clone(), batch))
     }
 
     async fn ucall_dcan_put_relocation_batch(
         &self,
         can_id: &CanisterId,
-        batch: &Vec<(Sha2Vec, Key, Val)>,
-    ) -> u64 {
+        batch: &[RelocationEntry],
+    ) -> Result<u64, BigMapError> {
         let fn_ptr = self
             .fn_ptr_put_relocation_batch
             .as_ref()
             .expect("fn_ptr_put_relocation_batch is not set");
-        fn_ptr(can_id.clone(), batch)
+        Ok(fn_ptr(can_id.clone(), batch))
     }
 
-    async fn ucall_dcan_delete_entries(&self, can_id: &CanisterId, keys_sha2: &Vec<Vec<u8>>) {
+    async fn ucall_dcan_delete_entries(
+        &self,
+        can_id: &CanisterId,
+        keys_sha2: &Vec<Vec<u8>>,
+    ) -> Result<(), BigMapError> {
         let fn_ptr = self
             .fn_ptr_delete_entries
             .as_ref()
             .expect("fn_ptr_delete_entries is not set");
-        fn_ptr(can_id.clone(), keys_sha2)
+        fn_ptr(can_id.clone(), keys_sha2);
+        Ok(())
+    }
+
+    async fn ucall_dcan_sweep_expired(
+        &self,
+        can_id: &CanisterId,
+        limit: u32,
+    ) -> Result<u64, BigMapError> {
+        let fn_ptr = self
+            .fn_ptr_sweep_expired
+            .as_ref()
+            .expect("fn_ptr_sweep_expired is not set");
+        Ok(fn_ptr(can_id.clone(), limit))
+    }
+
+    async fn ucall_dcan_reset(&self, can_id: &CanisterId) -> Result<(), BigMapError> {
+        let fn_ptr = self.fn_ptr_reset.as_ref().expect("fn_ptr_reset is not set");
+        fn_ptr(can_id.clone())
+    }
+
+    async fn ucall_dcan_raise_version_counter(
+        &self,
+        can_id: &CanisterId,
+        version: u64,
+    ) -> Result<u64, BigMapError> {
+        let fn_ptr = self
+            .fn_ptr_raise_version_counter
+            .as_ref()
+            .expect("fn_ptr_raise_version_counter is not set");
+        Ok(fn_ptr(can_id.clone(), version))
+    }
+
+    async fn ucall_dcan_delete(&self, can_id: &CanisterId, key: &Key) -> Result<u64, BigMapError> {
+        let fn_ptr = self
+            .fn_ptr_delete
+            .as_ref()
+            .expect("fn_ptr_delete is not set");
+        fn_ptr(can_id.clone(), key)
+    }
+
+    async fn ucall_mgmt_upgrade_code(
+        &self,
+        can_id: &CanisterId,
+        wasm_binary: Vec<u8>,
+    ) -> Result<(), String> {
+        let fn_ptr = self
+            .fn_ptr_upgrade_code
+            .as_ref()
+            .expect("fn_ptr_upgrade_code is not set");
+        fn_ptr(can_id.clone(), &wasm_binary)
+    }
+
+    async fn qcall_canister_code_version(
+        &self,
+        can_id: &CanisterId,
+    ) -> Result<CodeVersion, BigMapError> {
+        let fn_ptr = self
+            .fn_ptr_code_version
+            .as_ref()
+            .expect("fn_ptr_code_version is not set");
+        fn_ptr(can_id.clone())
     }
 }
 
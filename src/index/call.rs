@@ -0,0 +1,147 @@
+// Retries and failover of the calls from the BigMap Index to the data buckets
+//
+// A call which fails is handled according to its error:
+// - Retry: the data bucket may answer later, e.g. the call was not delivered, or the range
+//   of the data bucket is being changed by a relocation. The call is sent again after a
+//   backoff, up to max_attempts times per data bucket.
+// - Failover: the data bucket can't answer, e.g. it trapped or is stopped. The call goes
+//   to the next candidate right away.
+// - Fail: the answer is final, e.g. NotFound or BucketFull, and is returned as it is.
+// The candidates after the first one are fallbacks for reads (the relocation source or
+// destination, the other replicas), writes only have the data bucket which owns the key.
+use crate::{BigMapError, CanisterId};
+use candid::CandidType;
+use ic_cdk::context::RejectionCode;
+#[cfg(target_arch = "wasm32")]
+use ic_cdk::println;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct CallPolicy {
+    pub max_attempts: u32,       // Calls to each candidate, including the first one
+    pub backoff_rounds: u32,     // Rounds to wait before the first retry, doubled for the next
+    pub backoff_rounds_max: u32, // Upper limit for the rounds to wait before a retry
+}
+
+impl Default for CallPolicy {
+    fn default() -> Self {
+        CallPolicy {
+            max_attempts: 3,
+            backoff_rounds: 1,
+            backoff_rounds_max: 8,
+        }
+    }
+}
+
+// Upper limits, so that a call with retries completes in a reasonable number of rounds
+pub const CALL_ATTEMPTS_MAX: u32 = 10;
+pub const BACKOFF_ROUNDS_MAX: u32 = 64;
+
+impl CallPolicy {
+    pub fn validate(&self) -> Result<(), BigMapError> {
+        if !(1..=CALL_ATTEMPTS_MAX).contains(&self.max_attempts) {
+            return Err(BigMapError::InvalidArgument(format!(
+                "max_attempts must be between 1 and {}",
+                CALL_ATTEMPTS_MAX
+            )));
+        }
+        if self.backoff_rounds > self.backoff_rounds_max
+            || self.backoff_rounds_max > BACKOFF_ROUNDS_MAX
+        {
+            return Err(BigMapError::InvalidArgument(format!(
+                "backoff_rounds must not exceed backoff_rounds_max, which must not exceed {}",
+                BACKOFF_ROUNDS_MAX
+            )));
+        }
+        Ok(())
+    }
+
+    // Rounds to wait before the retry which follows the failed attempt (1 for the first)
+    pub fn backoff_rounds_after(&self, attempt: u32) -> u32 {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.backoff_rounds
+            .saturating_mul(factor)
+            .min(self.backoff_rounds_max)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    Retry,
+    Failover,
+    Fail,
+}
+
+pub fn recovery(err: &BigMapError) -> Recovery {
+    match err {
+        BigMapError::CallFailed { code, .. } => {
+            if *code == RejectionCode::SysTransient as i32 {
+                Recovery::Retry
+            } else {
+                Recovery::Failover
+            }
+        }
+        BigMapError::BucketUnavailable | BigMapError::Rebalancing | BigMapError::KeyOutOfRange => {
+            Recovery::Retry
+        }
+        _ => Recovery::Fail,
+    }
+}
+
+// Returns the result of the first candidate which answers, see the top of the file
+// If none does, the error of the first candidate is returned, since the others are fallbacks
+pub async fn call_with_retries<R, F, Fut>(
+    policy: &CallPolicy,
+    candidates: &[CanisterId],
+    mut call: F,
+) -> Result<R, BigMapError>
+where
+    F: FnMut(CanisterId) -> Fut,
+    Fut: Future<Output = Result<R, BigMapError>>,
+{
+    let mut first_err = None;
+    for (i, can_id) in candidates.iter().enumerate() {
+        let mut attempt = 1;
+        let err = loop {
+            let err = match call(can_id.clone()).await {
+                Ok(result) => return Ok(result),
+                Err(err) => err,
+            };
+            match recovery(&err) {
+                Recovery::Retry if attempt < policy.max_attempts => {
+                    wait_rounds(policy.backoff_rounds_after(attempt)).await;
+                    attempt += 1;
+                }
+                Recovery::Fail if i == 0 => return Err(err),
+                _ => break err,
+            }
+        };
+        println!(
+            "BigMap Index: call @CanisterId {} failed after {} attempts: {}",
+            can_id, attempt, err
+        );
+        // A fallback which doesn't hold the key answers NotFound, which doesn't tell
+        // anything about the key, so the error of the first candidate is kept
+        if recovery(&err) != Recovery::Fail {
+            first_err.get_or_insert(err);
+        }
+    }
+    Err(first_err.unwrap_or(BigMapError::BucketUnavailable))
+}
+
+// Let the rounds pass before a retry. A canister can't sleep, but every call to the
+// management canister takes at least a round.
+#[cfg(target_arch = "wasm32")]
+async fn wait_rounds(rounds: u32) {
+    for _ in 0..rounds {
+        if crate::subnet_raw_rand().await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn wait_rounds(_rounds: u32) {}
@@ -0,0 +1,86 @@
+// Rolling upgrade of the code of the data buckets and the search canisters
+//
+// set_data_bucket_canister_wasm_binary and set_search_canister_wasm_binary only change the
+// code installed in the new canisters. The upgrade job installs the stored code in the
+// existing canisters, one after the other:
+// - The calls from the BigMap Index to the canister are paused, and answered with
+//   BucketUnavailable, which is retried according to the call policy.
+// - The code is installed in upgrade mode, so that the canister keeps its state.
+// - The canister must answer the version query, with the expected crate version if any.
+// - The calls to the canister resume, and the job continues with the next canister.
+// The job halts on the first failure. If the canister kept running the old code, the calls
+// to it resume, otherwise they stay paused until the job is resumed or replaced.
+// The job is persisted, so that it's reported and can be resumed after an upgrade of the
+// BigMap Index.
+use crate::CanisterId;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum CanisterKind {
+    DataBucket,
+    Search,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct UpgradeTarget {
+    pub canister_id: CanisterId,
+    pub kind: CanisterKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct UpgradeFailure {
+    pub canister_id: CanisterId,
+    pub error: String,
+    pub code_replaced: bool, // The canister runs the new code, but failed the version check
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct UpgradeJob {
+    pub expected_version: Option<String>, // Crate version of the new code, any if None
+    pub started_at: u64,                  // Nanoseconds since the UNIX epoch
+    pub finished_at: Option<u64>,         // Nanoseconds since the UNIX epoch
+    pub pending: Vec<UpgradeTarget>,      // In the order of the upgrade
+    pub upgraded: Vec<CanisterId>,
+    pub upgrading: Option<CanisterId>, // The calls to this canister are paused
+    pub failure: Option<UpgradeFailure>,
+}
+
+impl UpgradeJob {
+    pub fn new(
+        pending: Vec<UpgradeTarget>,
+        expected_version: Option<String>,
+        started_at: u64,
+    ) -> Self {
+        UpgradeJob {
+            expected_version,
+            started_at,
+            finished_at: None,
+            pending,
+            upgraded: Vec::new(),
+            upgrading: None,
+            failure: None,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished_at.is_some()
+    }
+
+    // The job stops at the front of the pending canisters, until it's resumed
+    pub fn halt(&mut self, failure: UpgradeFailure) {
+        if !failure.code_replaced {
+            self.upgrading = None;
+        }
+        self.failure = Some(failure);
+    }
+
+    // The front of the pending canisters was upgraded
+    pub fn advance(&mut self) {
+        if !self.pending.is_empty() {
+            let target = self.pending.remove(0);
+            self.upgraded.push(target.canister_id);
+        }
+        self.upgrading = None;
+    }
+}
@@ -0,0 +1,176 @@
+// Namespaces of the BigMap Index, for several apps sharing one BigMap
+//
+// Every key is scoped to a namespace, so that an app sees only the keys of the namespaces
+// it has access to. The default namespace of a caller is named after its principal, and
+// only the caller has access to it. Other namespaces are created with create, and their
+// owner grants the access to other principals with an ACL.
+//
+// The keys are scoped with the functions of crate::namespace, which the data buckets use too.
+use crate::index::DetHashMap;
+pub use crate::namespace::{scope, scoped_key, unscoped_key, Namespace};
+use crate::{BigMapError, CanisterId};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Upper limit for the length of a namespace name, enough for the principals
+pub const NAMESPACE_LEN_MAX: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Read,  // Get the values of known keys
+    Write, // Put and delete entries
+    List,  // Enumerate the keys, with list and search
+}
+
+// Access to a namespace, the owner has all permissions and manages the ACL
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct NamespaceAcl {
+    pub owner: CanisterId,
+    pub readers: Vec<CanisterId>,
+    pub writers: Vec<CanisterId>,
+    pub listers: Vec<CanisterId>,
+}
+
+impl NamespaceAcl {
+    pub fn allows(&self, caller: &CanisterId, permission: Permission) -> bool {
+        if &self.owner == caller {
+            return true;
+        }
+        match permission {
+            Permission::Read => self.readers.contains(caller),
+            Permission::Write => self.writers.contains(caller),
+            Permission::List => self.listers.contains(caller),
+        }
+    }
+
+    fn allows_any(&self, caller: &CanisterId) -> bool {
+        [Permission::Read, Permission::Write, Permission::List]
+            .iter()
+            .any(|permission| self.allows(caller, *permission))
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Namespaces {
+    acls: BTreeMap<Namespace, NamespaceAcl>, // The created namespaces
+    selected: DetHashMap<CanisterId, Namespace>, // Callers which don't use their default namespace
+}
+
+impl Namespaces {
+    // The namespace of the caller, unless it selects another one
+    pub fn default_namespace(caller: &CanisterId) -> Namespace {
+        crate::namespace::default_namespace(caller)
+    }
+
+    // The namespace the caller currently uses
+    pub fn current(&self, caller: &CanisterId) -> Namespace {
+        match self.selected.get(caller) {
+            Some(namespace) => namespace.clone(),
+            None => Self::default_namespace(caller),
+        }
+    }
+
+    // Returns the current namespace of the caller, if the caller has the permission there
+    // A namespace which doesn't exist is reported as Unauthorized too, not to reveal its name
+    pub fn authorize(
+        &self,
+        caller: &CanisterId,
+        permission: Permission,
+    ) -> Result<Namespace, BigMapError> {
+        self.authorize_all(caller, &[permission])
+    }
+
+    // Returns the current namespace of the caller, if the caller has all of the permissions there
+    pub fn authorize_all(
+        &self,
+        caller: &CanisterId,
+        permissions: &[Permission],
+    ) -> Result<Namespace, BigMapError> {
+        let namespace = self.current(caller);
+        if namespace == Self::default_namespace(caller) {
+            return Ok(namespace);
+        }
+        match self.acls.get(&namespace) {
+            Some(acl) if permissions.iter().all(|p| acl.allows(caller, *p)) => Ok(namespace),
+            _ => Err(BigMapError::Unauthorized),
+        }
+    }
+
+    // Create a namespace owned by the caller
+    pub fn create(&mut self, caller: &CanisterId, name: &str) -> Result<(), BigMapError> {
+        if name.is_empty() || name.len() > NAMESPACE_LEN_MAX {
+            return Err(BigMapError::InvalidArgument(format!(
+                "The namespace name must have 1 to {} bytes",
+                NAMESPACE_LEN_MAX
+            )));
+        }
+        // The names of the principals are reserved for their default namespaces
+        if ic_cdk::CanisterId::from_str(name).is_ok() {
+            return Err(BigMapError::InvalidArgument(format!(
+                "The namespace name {} is reserved",
+                name
+            )));
+        }
+        if self.acls.contains_key(name) {
+            return Err(BigMapError::InvalidArgument(format!(
+                "The namespace {} already exists",
+                name
+            )));
+        }
+        let acl = NamespaceAcl {
+            owner: caller.clone(),
+            ..Default::default()
+        };
+        self.acls.insert(name.to_string(), acl);
+        Ok(())
+    }
+
+    // Only the owner may read and replace the ACL, and may transfer the ownership with it
+    pub fn acl(&self, caller: &CanisterId, name: &str) -> Result<&NamespaceAcl, BigMapError> {
+        match self.acls.get(name) {
+            Some(acl) if &acl.owner == caller => Ok(acl),
+            _ => Err(BigMapError::Unauthorized),
+        }
+    }
+
+    pub fn set_acl(
+        &mut self,
+        caller: &CanisterId,
+        name: &str,
+        acl: NamespaceAcl,
+    ) -> Result<(), BigMapError> {
+        self.acl(caller, name)?;
+        self.acls.insert(name.to_string(), acl);
+        Ok(())
+    }
+
+    // Use the namespace for the following calls of the caller, or the default namespace if None
+    pub fn select(&mut self, caller: &CanisterId, name: Option<&str>) -> Result<(), BigMapError> {
+        match name {
+            Some(name) if name != Self::default_namespace(caller) => {
+                match self.acls.get(name) {
+                    Some(acl) if acl.allows_any(caller) => {}
+                    _ => return Err(BigMapError::Unauthorized),
+                }
+                self.selected.insert(caller.clone(), name.to_string());
+            }
+            _ => {
+                self.selected.remove(caller);
+            }
+        }
+        Ok(())
+    }
+
+    // The namespaces the caller has access to, starting with its default namespace
+    pub fn visible(&self, caller: &CanisterId) -> Vec<Namespace> {
+        let mut result = vec![Self::default_namespace(caller)];
+        result.extend(
+            self.acls
+                .iter()
+                .filter(|(_, acl)| acl.allows_any(caller))
+                .map(|(name, _)| name.clone()),
+        );
+        result
+    }
+}
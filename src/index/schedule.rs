@@ -0,0 +1,95 @@
+// Scheduled maintenance of the BigMap Index, so that the data buckets stay balanced
+// without an operator calling maintenance
+//
+// The heartbeat of the BigMap Index calls maintenance_tick in every round. Once the interval
+// of the schedule has passed, a tick either continues the running relocation, or checks the
+// utilization of the data buckets and starts at most one split or merge. A tick runs at most
+// relocation_steps steps of the relocation, each of which moves one batch of entries, and
+// the following ticks continue the relocation until it's finished. With replicas, a tick
+// with nothing to split or merge repairs the replicas of the next page of keys.
+use crate::BigMapError;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct MaintenanceSchedule {
+    pub enabled: bool,
+    pub interval_secs: u64, // Time from the start of a tick to the start of the next one
+    pub relocation_steps: u32, // Upper limit for the relocation steps per tick
+}
+
+impl Default for MaintenanceSchedule {
+    fn default() -> Self {
+        MaintenanceSchedule {
+            enabled: true,
+            interval_secs: 60,
+            relocation_steps: 16,
+        }
+    }
+}
+
+// Upper limit for the relocation steps per tick, so that a tick completes in a reasonable
+// number of rounds
+pub const RELOCATION_STEPS_MAX: u32 = 1000;
+
+impl MaintenanceSchedule {
+    pub fn validate(&self) -> Result<(), BigMapError> {
+        if self.interval_secs == 0 {
+            return Err(BigMapError::InvalidArgument(
+                "interval_secs must be at least 1".to_string(),
+            ));
+        }
+        if !(1..=RELOCATION_STEPS_MAX).contains(&self.relocation_steps) {
+            return Err(BigMapError::InvalidArgument(format!(
+                "relocation_steps must be between 1 and {}",
+                RELOCATION_STEPS_MAX
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn interval_ns(&self) -> u64 {
+        self.interval_secs.saturating_mul(1_000_000_000)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum MaintenanceAction {
+    Idle,       // The utilization of all data buckets is within the limits
+    Split,      // Started the split of a data bucket over the used_bytes_threshold
+    Merge,      // Started the merge of two data buckets with low utilization
+    Relocation, // Continued the running relocation
+    Repair,     // Compared the replicas of a page of keys, and repaired the differing ones
+    Skipped,    // Another maintenance run is making progress
+}
+
+// Result of the last tick, not persisted
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct MaintenanceReport {
+    pub started_at: u64,  // Nanoseconds since the UNIX epoch
+    pub finished_at: u64, // Nanoseconds since the UNIX epoch
+    pub action: MaintenanceAction,
+    pub relocation_steps: u32,    // Relocation steps run by the tick
+    pub relocation_running: bool, // The relocation continues in the next ticks
+    pub error: Option<BigMapError>,
+}
+
+impl MaintenanceReport {
+    pub fn new(started_at: u64) -> Self {
+        MaintenanceReport {
+            started_at,
+            finished_at: started_at,
+            action: MaintenanceAction::Idle,
+            relocation_steps: 0,
+            relocation_running: false,
+            error: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct MaintenanceStatus {
+    pub schedule: MaintenanceSchedule,
+    pub next_tick_at: u64, // Nanoseconds since the UNIX epoch
+    pub last_tick: Option<MaintenanceReport>,
+}
@@ -1,12 +1,26 @@
-use crate::data::DataBucket;
-use crate::index::BigmapIdx;
-use crate::{CanisterId, Key, Sha256Digest, Sha2Vec, Val};
+use crate::data::{CasResult, DataBucket, RelocationEntry, PUT_VALUE_SIZE_MAX};
+use crate::hashring_sha256::{
+    sha256_digest_to_biguint, SHA256_DIGEST_MAX, SHA256_DIGEST_MIN, WEIGHT_DEFAULT,
+};
+use crate::index::call::{self, CallPolicy, Recovery};
+use crate::index::code_upgrade::UpgradeJob;
+use crate::index::namespace::{self, NamespaceAcl, Namespaces, Permission};
+use crate::index::schedule::{MaintenanceAction, MaintenanceSchedule, RELOCATION_STEPS_MAX};
+use crate::index::{
+    ring_hash, BigmapIdx, CanisterPtr, REPLICATION_FACTOR_MAX, STABLE_MAGIC, STABLE_SCHEMA_VERSION,
+    VIRTUAL_NODES_MAX,
+};
+use crate::upgrade::{decode_stable_blob, encode_stable_blob, CodeVersion};
+use crate::{calc_sha256, time_now, BigMapError, CanisterId, Key, Sha256Digest, Sha2Vec};
 use indexmap::IndexMap;
+use std::cell::RefCell;
 use std::collections::BTreeSet;
+use std::rc::Rc;
 use std::sync::{Arc, RwLock};
 // use std::time::Instant;
 
 type DataBucketMap = Arc<RwLock<IndexMap<CanisterId, DataBucket>>>;
+type InstalledCode = Rc<RefCell<Vec<(CanisterId, Vec<u8>)>>>;
 
 #[actix_rt::test]
 async fn bigmap_put_get() {
@@ -16,7 +30,7 @@ async fn bigmap_put_get() {
 
     let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(num_data_canisters_initial).await;
 
-    bm_idx.maintenance().await;
+    bm_idx.maintenance().await.unwrap();
 
     for i in 0..1001 {
         let key = format!("key-{}", i).into_bytes();
@@ -42,7 +56,7 @@ async fn bigmap_put_get() {
         );
     }
 
-    bm_idx.maintenance().await;
+    bm_idx.maintenance().await.unwrap();
 
     for i in 0..1001 {
         let key = format!("key-{}", i).into_bytes();
@@ -65,7 +79,7 @@ async fn bigmap_list() {
 
     let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(num_data_canisters_initial).await;
 
-    bm_idx.maintenance().await;
+    bm_idx.maintenance().await.unwrap();
 
     let mut keys_expected_no_prefix = BTreeSet::new();
     let mut keys_expected_with_prefix = BTreeSet::new();
@@ -103,22 +117,118 @@ async fn bigmap_list() {
     bm_idx.set_used_bytes_threshold(5000);
 
     for _ in 0..5u32 {
-        bm_idx.maintenance().await;
+        bm_idx.maintenance().await.unwrap();
     }
 
-    let list_keys = bm_idx.list(&Vec::new()).await;
+    let list_keys = bm_idx.list(&Vec::new()).await.unwrap();
     for (key, key_expected) in list_keys.iter().zip(keys_expected_no_prefix) {
         let key = String::from_utf8_lossy(key);
         assert_eq!(key, key_expected);
     }
 
-    let list_keys = bm_idx.list(&key_prefix.into_bytes()).await;
+    let list_keys = bm_idx.list(&key_prefix.into_bytes()).await.unwrap();
     for (key, key_expected) in list_keys.iter().zip(keys_expected_with_prefix) {
         let key = String::from_utf8_lossy(key);
         assert_eq!(key, key_expected);
     }
 }
 
+#[actix_rt::test]
+async fn bigmap_list_page() {
+    // Iterate over all keys with the list_page cursor and verify that every key is returned once, in order
+
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(20).await;
+
+    let mut keys_expected = BTreeSet::new();
+    for i in 0..1001 {
+        let key = format!("key-{}", i);
+        keys_expected.insert(key.clone());
+
+        let key = key.into_bytes();
+        let can_data_id = bm_idx.lookup_put(&key).unwrap();
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&can_data_id)
+            .unwrap()
+            .put(&key, &vec![(i % 256) as u8; 20], false)
+            .expect("DataBucket put failed");
+    }
+
+    bm_idx.set_used_bytes_threshold(5000);
+    for _ in 0..5u32 {
+        bm_idx.maintenance().await.unwrap();
+    }
+
+    for (key_prefix, page_size) in &[("", 100), ("", 1), ("key-1", 7), ("key-9", 5000)] {
+        let key_prefix = key_prefix.as_bytes().to_vec();
+        let mut list_keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next_cursor) = bm_idx
+                .list_page(&key_prefix, &cursor, *page_size)
+                .await
+                .unwrap();
+            assert!(page.len() <= *page_size as usize);
+            list_keys.extend(page);
+            if next_cursor.is_none() {
+                break;
+            }
+            cursor = next_cursor;
+        }
+
+        let keys_expected: Vec<Key> = keys_expected
+            .iter()
+            .filter(|k| k.as_bytes().starts_with(&key_prefix))
+            .map(|k| k.as_bytes().to_vec())
+            .collect();
+        assert_eq!(list_keys, keys_expected);
+    }
+}
+
+#[actix_rt::test]
+async fn bigmap_maintenance_sweeps_expired() {
+    // Entries past their expiry time are reclaimed by maintenance, the other entries are kept
+
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(3).await;
+
+    let now = crate::time_now();
+    for i in 0..400 {
+        let key = format!("key-{}", i).into_bytes();
+        let can_data_id = bm_idx.lookup_put(&key).unwrap();
+        let mut db_map = db_map.write().unwrap();
+        let d = db_map.get_mut(&can_data_id).unwrap();
+        match i % 3 {
+            0 => d.put_with_expiry(&key, &vec![1; 100], now - 1),
+            1 => d.put_with_expiry(&key, &vec![2; 100], now + 3_600_000_000_000),
+            _ => d.put(&key, &vec![3; 100], false),
+        }
+        .expect("DataBucket put failed");
+    }
+    let used_bytes_before: usize = db_map
+        .read()
+        .unwrap()
+        .values()
+        .map(|d| d.used_bytes())
+        .sum();
+
+    bm_idx.maintenance().await.unwrap();
+
+    let db_map = db_map.read().unwrap();
+    let entries_count: usize = db_map.values().map(|d| d.entries.len()).sum();
+    assert_eq!(entries_count, 400 - 134);
+    let used_bytes_after: usize = db_map.values().map(|d| d.used_bytes()).sum();
+    assert!(used_bytes_after < used_bytes_before);
+    for i in 0..400 {
+        let key = format!("key-{}", i).into_bytes();
+        let can_data_id = bm_idx.lookup_put(&key).unwrap();
+        assert_eq!(
+            db_map.get(&can_data_id).unwrap().holds_key(&key),
+            i % 3 != 0
+        );
+    }
+}
+
 #[actix_rt::test]
 async fn bigmap_put_rebalance_get() {
     let num_data_canisters_initial = 10;
@@ -142,7 +252,7 @@ async fn bigmap_put_rebalance_get() {
         assert!(can_data.used_bytes() > 0);
     }
 
-    bm_idx.maintenance().await;
+    bm_idx.maintenance().await.unwrap();
 
     // Check that all values are still retrievable from the BigMap
     for i in 0..num_entries {
@@ -158,6 +268,1454 @@ async fn bigmap_put_rebalance_get() {
     }
 }
 
+#[actix_rt::test]
+async fn bigmap_stable_save_restore() {
+    // Serialize the BigmapIdx as before an upgrade, restore it, and verify the routing is preserved
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(10).await;
+
+    for i in 0..1000 {
+        let key = format!("key-{}", i).into_bytes();
+        let can_data_id = bm_idx.lookup_put(&key).unwrap();
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&can_data_id)
+            .unwrap()
+            .put(&key, &vec![(i % 256) as u8; 2000], false)
+            .expect("DataBucket put failed");
+    }
+
+    // Split the data buckets a few times, so that the hash ring is not trivial
+    bm_idx.set_used_bytes_threshold(200_000);
+    for _ in 0..3u32 {
+        bm_idx.maintenance().await.unwrap();
+    }
+
+    let blob = encode_stable_blob(
+        &STABLE_MAGIC,
+        STABLE_SCHEMA_VERSION,
+        &bm_idx.to_stable_payload(),
+    );
+    let (schema_version, payload) = decode_stable_blob(&STABLE_MAGIC, &blob).unwrap();
+    let restored = BigmapIdx::from_stable_payload(schema_version, payload).unwrap();
+
+    assert_eq!(restored.canister_id(), bm_idx.canister_id());
+    for i in 0..1000 {
+        let key = format!("key-{}", i).into_bytes();
+        assert_eq!(restored.lookup_put(&key), bm_idx.lookup_put(&key));
+    }
+    assert_eq!(restored.to_stable_payload(), bm_idx.to_stable_payload());
+
+    // The certified routing is preserved as well
+    let ring = bm_idx.get_ring_certified().ring;
+    assert!(ring.len() > 1);
+    assert_eq!(restored.ring(), ring);
+    assert_eq!(ring_hash(&restored.ring()), ring_hash(&ring));
+
+    assert!(BigmapIdx::from_stable_payload(STABLE_SCHEMA_VERSION + 1, payload).is_err());
+}
+
+#[actix_rt::test]
+async fn bigmap_routing_table() {
+    // The routing table follows the splits, and the data buckets reject the operations
+    // routed with an outdated table
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(10).await;
+    bm_idx.maintenance().await.unwrap();
+
+    let table = bm_idx.get_routing_table();
+    assert_eq!(table.ranges.len(), 1);
+    assert_eq!(table.ranges[0].range_start, SHA256_DIGEST_MIN.to_vec());
+    assert_eq!(table.ranges[0].range_end, SHA256_DIGEST_MAX.to_vec());
+    assert!(!table.relocating);
+    let first_can_id = table.ranges[0].canister_id.clone();
+    assert_eq!(
+        db_map.read().unwrap()[&first_can_id].range_epoch(),
+        table.epoch
+    );
+
+    let keys: Vec<Key> = (0..1000)
+        .map(|i| format!("key-{}", i).into_bytes())
+        .collect();
+    for key in keys.iter() {
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&first_can_id)
+            .unwrap()
+            .put(key, &vec![0u8; 2000], false)
+            .expect("DataBucket put failed");
+    }
+    bm_idx.set_used_bytes_threshold(200_000);
+    for _ in 0..3u32 {
+        bm_idx.maintenance().await.unwrap();
+    }
+
+    let table_new = bm_idx.get_routing_table();
+    assert!(table_new.epoch > table.epoch);
+    assert!(table_new.ranges.len() > 1);
+    assert!(!table_new.relocating);
+    // The ranges cover the key space without gaps, in the order of the hash ring
+    assert_eq!(table_new.ranges[0].range_start, SHA256_DIGEST_MIN.to_vec());
+    for pair in table_new.ranges.windows(2) {
+        assert_eq!(pair[0].range_end, pair[1].range_start);
+    }
+    assert_eq!(
+        table_new.ranges.last().unwrap().range_end,
+        SHA256_DIGEST_MAX.to_vec()
+    );
+    for key in keys.iter() {
+        let key_sha2 = calc_sha256(key).to_vec();
+        let range = table_new
+            .ranges
+            .iter()
+            .find(|r| r.range_start <= key_sha2 && key_sha2 < r.range_end)
+            .unwrap();
+        assert_eq!(range.canister_id, bm_idx.lookup_put(key).unwrap());
+    }
+
+    // The split data bucket rejects the old table, all data buckets accept the new one
+    let db_map = db_map.read().unwrap();
+    assert_eq!(
+        db_map[&first_can_id].check_epoch(table.epoch),
+        Err(BigMapError::StaleEpoch {
+            current_epoch: db_map[&first_can_id].range_epoch()
+        })
+    );
+    for range in table_new.ranges.iter() {
+        assert_eq!(
+            db_map[&range.canister_id].check_epoch(table_new.epoch),
+            Ok(())
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn bigmap_relocation_resume_rollback() {
+    // Interrupt a split and resume it after an upgrade, then interrupt another split and roll it back
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(4).await;
+    bm_idx.batch_limit_bytes = 20_000;
+    // Low enough that the halves are not merged again, high enough that they are not split
+    bm_idx.set_used_bytes_threshold(2_000_000);
+
+    let keys: Vec<Key> = (0..1000)
+        .map(|i| format!("key-{}", i).into_bytes())
+        .collect();
+    for key in keys.iter() {
+        let can_data_id = bm_idx.lookup_put(key).unwrap();
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&can_data_id)
+            .unwrap()
+            .put(key, &vec![7u8; 1000], false)
+            .expect("DataBucket put failed");
+    }
+
+    // Every key is retrievable, and held by the data bucket which owns it in the hash ring
+    async fn check_keys(bm_idx: &BigmapIdx, db_map: &DataBucketMap, keys: &[Key], settled: bool) {
+        for key in keys {
+            let can_data_id = bm_idx.lookup_get(key).await.unwrap();
+            assert_eq!(
+                *db_map.read().unwrap()[&can_data_id]
+                    .get(key.clone())
+                    .unwrap(),
+                vec![7u8; 1000]
+            );
+            if settled {
+                assert_eq!(can_data_id, bm_idx.lookup_put(key).unwrap());
+            }
+        }
+    }
+
+    bm_idx.relocation_start(CanisterPtr(0)).await.unwrap();
+    for _ in 0..3 {
+        assert!(!bm_idx.relocation_step().await.unwrap());
+    }
+    assert!(bm_idx.relocation.as_ref().unwrap().entries_moved > 0);
+    check_keys(&bm_idx, &db_map, &keys, false).await;
+
+    // A maintenance run with recent progress is still active, one without progress was interrupted
+    bm_idx.maintenance_heartbeat = Some(time_now());
+    assert!(bm_idx
+        .maintenance()
+        .await
+        .unwrap()
+        .contains("Already rebalancing"));
+    bm_idx.maintenance_heartbeat = Some(0);
+
+    // Upgrade the index in the middle of the relocation, the next maintenance finishes it
+    let payload = bm_idx.to_stable_payload();
+    let mut bm_idx = BigmapIdx::from_stable_payload(STABLE_SCHEMA_VERSION, &payload).unwrap();
+    set_fn_ptrs(&mut bm_idx, &db_map);
+    bm_idx.maintenance().await.unwrap();
+    assert!(bm_idx.relocation.is_none());
+    assert_eq!(bm_idx.ring().len(), 2);
+    check_keys(&bm_idx, &db_map, &keys, true).await;
+
+    // Interrupt the next split and roll it back
+    let ring = bm_idx.ring();
+    let idx_len = bm_idx.idx.len();
+    bm_idx.relocation_start(CanisterPtr(0)).await.unwrap();
+    let dst = bm_idx.idx[idx_len].clone();
+    for _ in 0..3 {
+        assert!(!bm_idx.relocation_step().await.unwrap());
+    }
+    bm_idx.relocation_rollback().await.unwrap();
+    assert!(bm_idx.relocation_rollback().await.is_err());
+    assert_eq!(bm_idx.ring(), ring);
+    check_keys(&bm_idx, &db_map, &keys, false).await;
+
+    bm_idx.maintenance().await.unwrap();
+    assert!(bm_idx.relocation.is_none());
+    assert_eq!(bm_idx.ring(), ring);
+    check_keys(&bm_idx, &db_map, &keys, true).await;
+    // The destination is empty, reset and available for the next split
+    assert_eq!(bm_idx.idx.len(), idx_len);
+    assert_eq!(bm_idx.canister_available_queue.front(), Some(&dst));
+    assert_eq!(db_map.read().unwrap()[&dst].entries.len(), 0);
+    assert!(db_map.read().unwrap()[&dst].ranges().is_empty());
+    assert_eq!(db_map.read().unwrap()[&dst].range_epoch(), 0);
+}
+
+#[actix_rt::test]
+async fn bigmap_relocation_version_counter() {
+    // The destination of a split continues from the version counter of the source, so a write
+    // there before the key is relocated is newer than the relocated entry
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(2).await;
+    bm_idx.batch_limit_bytes = 1000;
+
+    let keys: Vec<Key> = (0..100)
+        .map(|i| format!("key-{}", i).into_bytes())
+        .collect();
+    for _ in 0..3 {
+        for key in keys.iter() {
+            let can_data_id = bm_idx.lookup_put(key).unwrap();
+            db_map
+                .write()
+                .unwrap()
+                .get_mut(&can_data_id)
+                .unwrap()
+                .put(key, &vec![1u8; 100], false)
+                .expect("DataBucket put failed");
+        }
+    }
+    let src = bm_idx.idx[0].clone();
+    let src_counter = db_map
+        .write()
+        .unwrap()
+        .get_mut(&src)
+        .unwrap()
+        .raise_version_counter(0);
+    assert_eq!(src_counter, 300);
+
+    bm_idx.relocation_start(CanisterPtr(0)).await.unwrap();
+    let dst = bm_idx.idx[1].clone();
+    // Set the ranges, the keys of the destination are still in the source
+    assert!(!bm_idx.relocation_step().await.unwrap());
+    let moved_later: Vec<&Key> = keys
+        .iter()
+        .filter(|key| bm_idx.lookup_put(key).unwrap() == dst)
+        .collect();
+    assert!(!moved_later.is_empty());
+    for key in moved_later.iter() {
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&dst)
+            .unwrap()
+            .put(key, &vec![2u8; 100], false)
+            .expect("DataBucket put failed");
+    }
+
+    while !bm_idx.relocation_step().await.unwrap() {}
+    for key in moved_later {
+        let db_map = db_map.read().unwrap();
+        let (value, version) = db_map[&dst].get_with_version(key).unwrap();
+        assert_eq!(*value, vec![2u8; 100]);
+        assert!(version > src_counter);
+    }
+}
+
+#[actix_rt::test]
+async fn bigmap_relocation_keys_in_flight() {
+    // Writes of keys which a split didn't move yet take the keys from the source first,
+    // deletes are not undone by the batches read before, and a rollback keeps the writes
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(3).await;
+    bm_idx.batch_limit_bytes = 100;
+    let keys: Vec<Key> = (0..200)
+        .map(|i| format!("key-{}", i).into_bytes())
+        .collect();
+    let src = bm_idx.lookup_put(&keys[0]).unwrap();
+    for key in keys.iter() {
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&src)
+            .unwrap()
+            .put(key, &b"old".to_vec(), false)
+            .unwrap();
+    }
+    let entry = |can_id: &CanisterId, key: &Key| {
+        db_map.read().unwrap()[can_id]
+            .get_replica_entries(std::slice::from_ref(key))
+            .pop()
+    };
+
+    // A batch read from the source before a delete, which the data bucket still returns
+    let stale: Arc<RwLock<Vec<RelocationEntry>>> = Arc::new(RwLock::new(Vec::new()));
+    let db_map_ref = db_map.clone();
+    let stale_ref = stale.clone();
+    let fn_ptr = move |can_id: CanisterId,
+                       ranges: &[(Sha256Digest, Sha256Digest)],
+                       start_after: &Option<Sha2Vec>,
+                       batch_limit_bytes: u64| {
+        let mut batch: Vec<RelocationEntry> = stale_ref.write().unwrap().drain(..).collect();
+        batch.extend(db_map_ref.read().unwrap()[&can_id].get_range_batch(
+            ranges,
+            start_after,
+            batch_limit_bytes,
+        ));
+        batch
+    };
+    bm_idx.set_fn_ptr_get_range_batch(Box::new(fn_ptr));
+
+    bm_idx.relocation_start(CanisterPtr(0)).await.unwrap();
+    let dst = bm_idx.idx[1].clone();
+    let moving: Vec<Key> = keys
+        .iter()
+        .filter(|key| bm_idx.lookup_put(key).unwrap() == dst)
+        .cloned()
+        .collect();
+    assert!(moving.len() > 10);
+    // The batches are in the order of the key hashes, the last keys are moved last
+    let mut moving = moving;
+    moving.sort_by_key(|key| calc_sha256(key));
+    let (k_put, k_append) = (&moving[0], &moving[1]);
+    let (k_cas, k_del) = (&moving[moving.len() - 1], &moving[moving.len() - 2]);
+
+    // The keys are refused until the ranges are set
+    assert_eq!(
+        bm_idx.relocation_take_keys(&moving[..1]).await,
+        Err(BigMapError::Rebalancing)
+    );
+    assert!(!bm_idx.relocation_step().await.unwrap());
+
+    // A put continues from the version in the source, and an append extends its value
+    let version_old = entry(&src, k_put).unwrap().version;
+    bm_idx
+        .relocation_take_keys(&[k_put.clone(), k_append.clone()])
+        .await
+        .unwrap();
+    assert!(entry(&src, k_put).is_none());
+    {
+        let mut db_map_w = db_map.write().unwrap();
+        let dst_data = db_map_w.get_mut(&dst).unwrap();
+        dst_data.put(k_put, &b"new".to_vec(), false).unwrap();
+        dst_data.put(k_append, &b"+".to_vec(), true).unwrap();
+    }
+    assert!(entry(&dst, k_put).unwrap().version > version_old);
+    assert!(!bm_idx.relocation_step().await.unwrap());
+
+    // A conditional put compares the version in the source
+    let version_cas = entry(&src, k_cas).unwrap().version;
+    bm_idx
+        .relocation_take_keys(std::slice::from_ref(k_cas))
+        .await
+        .unwrap();
+    assert!(matches!(
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&dst)
+            .unwrap()
+            .put_if_version(k_cas, &b"cas".to_vec(), version_cas),
+        Ok(CasResult::Applied { .. })
+    ));
+
+    // A deleted key is not read from the source, and not brought back by a batch
+    stale.write().unwrap().push(entry(&src, k_del).unwrap());
+    bm_idx
+        .relocation_take_keys(std::slice::from_ref(k_del))
+        .await
+        .unwrap();
+    bm_idx.relocation_mark_deleted(k_del);
+    db_map
+        .write()
+        .unwrap()
+        .get_mut(&dst)
+        .unwrap()
+        .delete(k_del.clone())
+        .unwrap();
+    assert_eq!(bm_idx.lookup_get(k_del).await, Err(BigMapError::NotFound));
+    while !bm_idx.relocation_step().await.unwrap() {}
+    assert!(bm_idx.relocation_deleted.is_empty());
+
+    let expected = |key: &Key| -> Option<Vec<u8>> {
+        match key {
+            k if k == k_put => Some(b"new".to_vec()),
+            k if k == k_append => Some(b"old+".to_vec()),
+            k if k == k_cas => Some(b"cas".to_vec()),
+            k if k == k_del => None,
+            _ => Some(b"old".to_vec()),
+        }
+    };
+    for key in keys.iter() {
+        let owner = bm_idx.lookup_put(key).unwrap();
+        for can_id in [&src, &dst].iter() {
+            let value = entry(can_id, key).map(|e| e.into_decoded_value().unwrap());
+            if **can_id == owner {
+                assert_eq!(value, expected(key));
+            } else {
+                assert_eq!(value, None);
+            }
+        }
+    }
+
+    // The writes during a split which is rolled back are moved back to the source
+    bm_idx.relocation_start(CanisterPtr(0)).await.unwrap();
+    let dst = bm_idx.idx[2].clone();
+    assert!(!bm_idx.relocation_step().await.unwrap());
+    let moving: Vec<Key> = keys
+        .iter()
+        .filter(|key| bm_idx.lookup_put(key).unwrap() == dst && expected(key).is_some())
+        .cloned()
+        .collect();
+    let (k_put, k_del) = (&moving[0], &moving[1]);
+    bm_idx
+        .relocation_take_keys(&[k_put.clone(), k_del.clone()])
+        .await
+        .unwrap();
+    bm_idx.relocation_mark_deleted(k_del);
+    {
+        let mut db_map_w = db_map.write().unwrap();
+        let dst_data = db_map_w.get_mut(&dst).unwrap();
+        dst_data
+            .put(k_put, &b"rolled back".to_vec(), false)
+            .unwrap();
+        dst_data.delete(k_del.clone()).unwrap();
+    }
+    bm_idx.relocation_rollback().await.unwrap();
+    while !bm_idx.relocation_step().await.unwrap() {}
+    assert_eq!(bm_idx.lookup_put(k_put).unwrap(), src);
+    assert_eq!(
+        entry(&src, k_put).unwrap().into_decoded_value().unwrap(),
+        b"rolled back".to_vec()
+    );
+    assert!(entry(&src, k_del).is_none());
+    assert_eq!(bm_idx.lookup_get(k_del).await, Err(BigMapError::NotFound));
+}
+
+#[actix_rt::test]
+async fn bigmap_split_full_bucket() {
+    // A data bucket which rejects writes since it's full is split right away
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(4).await;
+
+    let mut keys = Vec::new();
+    let mut key_full = None;
+    for i in 0..1000 {
+        let key = format!("key-{}", i).into_bytes();
+        let can_data_id = bm_idx.lookup_put(&key).unwrap();
+        let mut db_map_w = db_map.write().unwrap();
+        let can_data = db_map_w.get_mut(&can_data_id).unwrap();
+        can_data.set_capacity_bytes(500_000).unwrap();
+        match can_data.put(&key, &vec![7u8; 1000], false) {
+            Ok(_) => keys.push(key),
+            Err(err) => {
+                assert_eq!(
+                    err,
+                    BigMapError::BucketFull {
+                        capacity_bytes: 500_000
+                    }
+                );
+                key_full = Some((key, can_data_id));
+                break;
+            }
+        }
+    }
+    let (key, can_data_id) = key_full.expect("The data bucket never got full");
+
+    // Not while another maintenance is making progress
+    bm_idx.maintenance_heartbeat = Some(time_now());
+    assert_eq!(
+        bm_idx.split_full_bucket(&can_data_id).await,
+        Err(BigMapError::Rebalancing)
+    );
+    bm_idx.maintenance_heartbeat = None;
+
+    bm_idx.split_full_bucket(&can_data_id).await.unwrap();
+    assert!(bm_idx.relocation.is_none());
+    assert!(bm_idx.maintenance_heartbeat.is_none());
+    assert_eq!(bm_idx.ring().len(), 2);
+
+    // The write succeeds on the data bucket which owns the key after the split
+    let can_data_id = bm_idx.lookup_put(&key).unwrap();
+    db_map
+        .write()
+        .unwrap()
+        .get_mut(&can_data_id)
+        .unwrap()
+        .put(&key, &vec![7u8; 1000], false)
+        .unwrap();
+    keys.push(key);
+    for key in keys.iter() {
+        let can_data_id = bm_idx.lookup_get(key).await.unwrap();
+        assert!(db_map.read().unwrap()[&can_data_id].holds_key(key));
+    }
+}
+
+#[actix_rt::test]
+async fn bigmap_merge_underutilized() {
+    // After most entries are deleted, the data buckets are merged and become available again
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(8).await;
+    bm_idx.batch_limit_bytes = 20_000;
+    bm_idx.set_used_bytes_threshold(200_000);
+
+    let keys: Vec<Key> = (0..1000)
+        .map(|i| format!("key-{}", i).into_bytes())
+        .collect();
+    for key in keys.iter() {
+        let can_data_id = bm_idx.lookup_put(key).unwrap();
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&can_data_id)
+            .unwrap()
+            .put(key, &vec![7u8; 1000], false)
+            .expect("DataBucket put failed");
+        if key.ends_with(b"99") {
+            bm_idx.maintenance().await.unwrap();
+        }
+    }
+    bm_idx.maintenance().await.unwrap();
+    let ring_len_split = bm_idx.ring().len();
+    assert!(ring_len_split > 2);
+    let available_before = bm_idx.canister_available_queue.len();
+
+    // Delete all but a few entries, which then fit into a single data bucket
+    let (keys_deleted, keys_kept) = keys.split_at(990);
+    for key in keys_deleted {
+        let can_data_id = bm_idx.lookup_get(key).await.unwrap();
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&can_data_id)
+            .unwrap()
+            .delete(key.clone())
+            .unwrap();
+    }
+    bm_idx.maintenance().await.unwrap();
+    assert!(bm_idx.relocation.is_none());
+    assert_eq!(bm_idx.ring().len(), 1);
+    assert_eq!(bm_idx.idx.len(), 1);
+    assert_eq!(
+        bm_idx.canister_available_queue.len(),
+        available_before + ring_len_split - 1
+    );
+    for key in keys_kept {
+        let can_data_id = bm_idx.lookup_get(key).await.unwrap();
+        assert_eq!(can_data_id, bm_idx.lookup_put(key).unwrap());
+        assert_eq!(
+            *db_map.read().unwrap()[&can_data_id]
+                .get(key.clone())
+                .unwrap(),
+            vec![7u8; 1000]
+        );
+    }
+    // The merged data buckets are empty
+    let db_map = db_map.read().unwrap();
+    for can_id in bm_idx.canister_available_queue.iter() {
+        assert_eq!(db_map[can_id].entries.len(), 0);
+    }
+}
+
+#[actix_rt::test]
+async fn bigmap_maintenance_schedule() {
+    // The heartbeat splits and merges the data buckets, with a bounded number of steps per tick
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(8).await;
+    bm_idx.batch_limit_bytes = 20_000;
+    bm_idx.set_used_bytes_threshold(200_000);
+
+    let keys: Vec<Key> = (0..600)
+        .map(|i| format!("key-{}", i).into_bytes())
+        .collect();
+    for key in keys.iter() {
+        let can_data_id = bm_idx.lookup_put(key).unwrap();
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&can_data_id)
+            .unwrap()
+            .put(key, &vec![7u8; 1000], false)
+            .expect("DataBucket put failed");
+    }
+
+    let invalid_schedules = [
+        MaintenanceSchedule {
+            interval_secs: 0,
+            ..Default::default()
+        },
+        MaintenanceSchedule {
+            relocation_steps: 0,
+            ..Default::default()
+        },
+        MaintenanceSchedule {
+            relocation_steps: RELOCATION_STEPS_MAX + 1,
+            ..Default::default()
+        },
+    ];
+    for schedule in invalid_schedules.iter() {
+        assert!(matches!(
+            bm_idx.set_maintenance_schedule(schedule.clone()),
+            Err(BigMapError::InvalidArgument(_))
+        ));
+    }
+    let schedule = MaintenanceSchedule {
+        enabled: true,
+        interval_secs: 3600,
+        relocation_steps: 2,
+    };
+    bm_idx.set_maintenance_schedule(schedule.clone()).unwrap();
+
+    // The first tick starts a split, and stops after the steps of the schedule
+    bm_idx.maintenance_tick().await;
+    let status = bm_idx.maintenance_status();
+    let report = status.last_tick.unwrap();
+    assert_eq!(report.action, MaintenanceAction::Split);
+    assert_eq!(report.relocation_steps, 2);
+    assert!(report.relocation_running);
+    assert_eq!(report.error, None);
+    assert!(bm_idx.relocation.is_some());
+    assert!(bm_idx.maintenance_heartbeat.is_none());
+    assert_eq!(
+        status.next_tick_at,
+        report.started_at + schedule.interval_secs * 1_000_000_000
+    );
+
+    // Nothing happens before the interval has passed
+    bm_idx.maintenance_tick().await;
+    assert_eq!(bm_idx.maintenance_status().last_tick, Some(report));
+
+    // Not while another maintenance is making progress
+    bm_idx.maintenance_heartbeat = Some(time_now());
+    bm_idx.maintenance_next_tick = 0;
+    bm_idx.maintenance_tick().await;
+    let report = bm_idx.maintenance_status().last_tick.unwrap();
+    assert_eq!(report.action, MaintenanceAction::Skipped);
+    assert_eq!(report.relocation_steps, 0);
+    bm_idx.maintenance_heartbeat = None;
+
+    // Tick until the map is balanced, returns the actions of the ticks
+    async fn tick_until_idle(bm_idx: &mut BigmapIdx) -> Vec<MaintenanceAction> {
+        let mut actions = Vec::new();
+        for _ in 0..1000 {
+            bm_idx.maintenance_next_tick = 0;
+            bm_idx.maintenance_tick().await;
+            let report = bm_idx.maintenance_status().last_tick.unwrap();
+            assert_eq!(report.error, None);
+            assert!(report.relocation_steps <= 2);
+            if report.action == MaintenanceAction::Idle {
+                assert!(!report.relocation_running);
+                return actions;
+            }
+            actions.push(report.action);
+        }
+        panic!("The scheduled maintenance never finished");
+    }
+
+    let actions = tick_until_idle(&mut bm_idx).await;
+    assert!(actions.contains(&MaintenanceAction::Relocation));
+    assert!(!actions.contains(&MaintenanceAction::Merge));
+    assert!(bm_idx.relocation.is_none());
+    let ring_len_split = bm_idx.ring().len();
+    assert!(ring_len_split > 2);
+    for can_id in bm_idx.idx.iter() {
+        assert!(db_map.read().unwrap()[can_id].used_bytes() <= 200_000);
+    }
+    for key in keys.iter() {
+        let can_data_id = bm_idx.lookup_get(key).await.unwrap();
+        assert_eq!(can_data_id, bm_idx.lookup_put(key).unwrap());
+        assert!(db_map.read().unwrap()[&can_data_id].holds_key(key));
+    }
+
+    // The schedule is kept across upgrades
+    let payload = bm_idx.to_stable_payload();
+    let mut bm_idx = BigmapIdx::from_stable_payload(STABLE_SCHEMA_VERSION, &payload).unwrap();
+    set_fn_ptrs(&mut bm_idx, &db_map);
+    assert_eq!(bm_idx.maintenance_status().schedule, schedule);
+
+    // After most entries are deleted, the ticks merge the data buckets
+    let (keys_deleted, keys_kept) = keys.split_at(590);
+    for key in keys_deleted {
+        let can_data_id = bm_idx.lookup_get(key).await.unwrap();
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&can_data_id)
+            .unwrap()
+            .delete(key.clone())
+            .unwrap();
+    }
+    let actions = tick_until_idle(&mut bm_idx).await;
+    assert!(actions.contains(&MaintenanceAction::Merge));
+    assert!(!actions.contains(&MaintenanceAction::Split));
+    assert_eq!(bm_idx.ring().len(), 1);
+    for key in keys_kept {
+        let can_data_id = bm_idx.lookup_get(key).await.unwrap();
+        assert!(db_map.read().unwrap()[&can_data_id].holds_key(key));
+    }
+
+    // A disabled schedule runs no ticks
+    let report = bm_idx.maintenance_status().last_tick;
+    bm_idx
+        .set_maintenance_schedule(MaintenanceSchedule {
+            enabled: false,
+            ..schedule
+        })
+        .unwrap();
+    bm_idx.maintenance_tick().await;
+    assert_eq!(bm_idx.maintenance_status().last_tick, report);
+}
+
+#[actix_rt::test]
+async fn bigmap_upgrade_canisters() {
+    // The code of the canisters is upgraded one after the other, and the job halts on a failure
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(3).await;
+    let search_id = CanisterId::from(100u64);
+    let mut order: Vec<CanisterId> = db_map.read().unwrap().keys().cloned().collect();
+    order.push(search_id.clone());
+
+    // The installed code, the canister which rejects the install, and the version of the new code
+    let installed: InstalledCode = Rc::new(RefCell::new(Vec::new()));
+    let failing: Rc<RefCell<Option<CanisterId>>> = Rc::new(RefCell::new(None));
+    let version = Rc::new(RefCell::new("2.0.0".to_string()));
+    set_upgrade_fn_ptrs(&mut bm_idx, &installed, &failing, &version);
+
+    // Not without the code, and not while a relocation is running
+    assert!(matches!(
+        bm_idx.upgrade_canisters(None).await,
+        Err(BigMapError::InvalidArgument(_))
+    ));
+    bm_idx.data_bucket_canister_wasm_binary = vec![1; 10];
+    bm_idx.search_canister_wasm_binary = vec![2; 10];
+    bm_idx.relocation_start(CanisterPtr(0)).await.unwrap();
+    assert_eq!(
+        bm_idx.upgrade_canisters(None).await,
+        Err(BigMapError::Rebalancing)
+    );
+    bm_idx.maintenance().await.unwrap();
+    assert!(installed.borrow().is_empty());
+    // The used bytes of the search canister are not mocked, it's added after the maintenance
+    bm_idx.search_canisters.push(search_id.clone());
+
+    let job = bm_idx
+        .upgrade_canisters(Some("2.0.0".to_string()))
+        .await
+        .unwrap();
+    assert!(job.is_finished());
+    assert_eq!(job.failure, None);
+    assert_eq!(job.upgraded, order);
+    assert!(job.pending.is_empty());
+    let expected_installs: Vec<(CanisterId, Vec<u8>)> = order
+        .iter()
+        .map(|can_id| {
+            let wasm = if can_id == &search_id { 2 } else { 1 };
+            (can_id.clone(), vec![wasm; 10])
+        })
+        .collect();
+    assert_eq!(*installed.borrow(), expected_installs);
+    assert!(bm_idx.maintenance_heartbeat.is_none());
+
+    // The install fails on a canister, which keeps running the old code
+    installed.borrow_mut().clear();
+    *failing.borrow_mut() = Some(order[1].clone());
+    let job = bm_idx.upgrade_canisters(None).await.unwrap();
+    assert!(!job.is_finished());
+    assert_eq!(job.upgraded, order[..1].to_vec());
+    assert_eq!(job.pending[0].canister_id, order[1]);
+    let failure = job.failure.unwrap();
+    assert_eq!(failure.canister_id, order[1]);
+    assert!(!failure.code_replaced);
+    assert_eq!(job.upgrading, None);
+    assert!(!bm_idx.is_upgrading(&order[1]));
+
+    // The halted job is kept across upgrades of the index, and resumed from the failed canister
+    let payload = bm_idx.to_stable_payload();
+    let mut bm_idx = BigmapIdx::from_stable_payload(STABLE_SCHEMA_VERSION, &payload).unwrap();
+    set_fn_ptrs(&mut bm_idx, &db_map);
+    set_upgrade_fn_ptrs(&mut bm_idx, &installed, &failing, &version);
+    assert_eq!(
+        bm_idx.upgrade_status().unwrap().upgraded,
+        order[..1].to_vec()
+    );
+    *failing.borrow_mut() = None;
+
+    // The new code runs a different version, the calls to the canister stay paused
+    *version.borrow_mut() = "2.0.1".to_string();
+    let job = bm_idx
+        .upgrade_canisters(Some("2.0.0".to_string()))
+        .await
+        .unwrap();
+    let failure = job.failure.unwrap();
+    assert_eq!(failure.canister_id, order[1]);
+    assert!(failure.code_replaced);
+    assert!(failure.error.contains("2.0.1"));
+    assert_eq!(job.upgrading, Some(order[1].clone()));
+    assert!(bm_idx.is_upgrading(&order[1]));
+    assert!(!bm_idx.is_upgrading(&order[2]));
+
+    // Resumed with the right expected version, the job finishes and the calls resume
+    let job = bm_idx
+        .upgrade_canisters(Some("2.0.1".to_string()))
+        .await
+        .unwrap();
+    assert!(job.is_finished());
+    assert_eq!(job.upgraded, order);
+    assert!(!bm_idx.is_upgrading(&order[1]));
+    // The canisters before the failed one were upgraded once, the failed one again on resume
+    let installed_ids: Vec<CanisterId> = installed.borrow().iter().map(|i| i.0.clone()).collect();
+    let mut expected_ids = order.clone();
+    expected_ids.insert(1, order[1].clone());
+    assert_eq!(installed_ids, expected_ids);
+
+    // A new wasm binary replaces the halted job, but not the running one
+    *failing.borrow_mut() = Some(order[0].clone());
+    assert!(bm_idx
+        .upgrade_canisters(None)
+        .await
+        .unwrap()
+        .failure
+        .is_some());
+    bm_idx
+        .set_data_bucket_canister_wasm_binary(vec![3; 10])
+        .await
+        .unwrap();
+    assert_eq!(bm_idx.upgrade_status(), None);
+    bm_idx.code_upgrade = Some(UpgradeJob::new(Vec::new(), None, time_now()));
+    assert_eq!(
+        bm_idx
+            .set_data_bucket_canister_wasm_binary(vec![4; 10])
+            .await,
+        Err(BigMapError::Rebalancing)
+    );
+}
+
+#[actix_rt::test]
+async fn bigmap_namespaces() {
+    // Keys are scoped to the namespaces, and only the callers in the ACL have access
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(4).await;
+    let alice = CanisterId::from(1001);
+    let bob = CanisterId::from(1002);
+    let carol = CanisterId::from(1003);
+    let alice_ns = Namespaces::default_namespace(&alice);
+
+    let namespaces = bm_idx.namespaces_mut();
+    namespaces.create(&alice, "shared").unwrap();
+    assert!(namespaces.create(&bob, "shared").is_err());
+    assert!(namespaces.create(&bob, "").is_err());
+    // Nobody can take over the default namespace of a principal
+    assert!(namespaces.create(&bob, &alice_ns).is_err());
+
+    assert_eq!(
+        namespaces.select(&bob, Some("shared")),
+        Err(BigMapError::Unauthorized)
+    );
+    assert_eq!(
+        namespaces.select(&bob, Some(&alice_ns)),
+        Err(BigMapError::Unauthorized)
+    );
+    let acl = NamespaceAcl {
+        owner: alice.clone(),
+        readers: vec![bob.clone()],
+        listers: vec![bob.clone()],
+        ..Default::default()
+    };
+    assert!(namespaces.set_acl(&bob, "shared", acl.clone()).is_err());
+    namespaces.set_acl(&alice, "shared", acl).unwrap();
+    assert!(namespaces.acl(&bob, "shared").is_err());
+    namespaces.select(&bob, Some("shared")).unwrap();
+    assert_eq!(namespaces.current(&bob), "shared");
+    assert!(namespaces.authorize(&bob, Permission::Read).is_ok());
+    assert_eq!(
+        namespaces.authorize(&bob, Permission::Write),
+        Err(BigMapError::Unauthorized)
+    );
+    // The search needs both Read and List
+    assert_eq!(
+        namespaces.authorize_all(&bob, &[Permission::Read, Permission::List]),
+        Ok("shared".to_string())
+    );
+    assert_eq!(
+        namespaces.authorize_all(&bob, &[Permission::Read, Permission::Write]),
+        Err(BigMapError::Unauthorized)
+    );
+    assert_eq!(
+        namespaces.visible(&bob),
+        vec![Namespaces::default_namespace(&bob), "shared".to_string()]
+    );
+    assert_eq!(
+        namespaces.visible(&carol),
+        vec![Namespaces::default_namespace(&carol)]
+    );
+
+    // Alice writes the same keys to her default namespace and to the shared namespace
+    for ns in &[alice_ns.as_str(), "shared"] {
+        for i in 0..100 {
+            let key = namespace::scoped_key(ns, format!("key-{}", i).as_bytes());
+            let can_data_id = bm_idx.lookup_put(&key).unwrap();
+            db_map
+                .write()
+                .unwrap()
+                .get_mut(&can_data_id)
+                .unwrap()
+                .put(&key, &ns.as_bytes().to_vec(), false)
+                .expect("DataBucket put failed");
+        }
+    }
+
+    // Bob lists only the keys of the shared namespace, even with an empty prefix
+    let ns = bm_idx
+        .namespaces()
+        .authorize(&bob, Permission::List)
+        .unwrap();
+    let keys = bm_idx.list(&namespace::scoped_key(&ns, b"")).await.unwrap();
+    assert_eq!(keys.len(), 100);
+    for key in keys {
+        let key = namespace::unscoped_key(&ns, &key).unwrap();
+        assert!(key.starts_with(b"key-"));
+    }
+    assert_eq!(
+        namespace::unscoped_key(&ns, &namespace::scoped_key(&alice_ns, b"k")),
+        None
+    );
+
+    // Back in his default namespace, Bob sees no keys
+    bm_idx.namespaces_mut().select(&bob, None).unwrap();
+    let ns = bm_idx
+        .namespaces()
+        .authorize(&bob, Permission::List)
+        .unwrap();
+    let keys = bm_idx.list(&namespace::scope(&ns)).await.unwrap();
+    assert!(keys.is_empty());
+
+    // The namespaces are kept across upgrades
+    bm_idx
+        .namespaces_mut()
+        .select(&bob, Some("shared"))
+        .unwrap();
+    let restored =
+        BigmapIdx::from_stable_payload(STABLE_SCHEMA_VERSION, &bm_idx.to_stable_payload()).unwrap();
+    assert_eq!(restored.namespaces().current(&bob), "shared");
+    assert_eq!(
+        restored.namespaces().acl(&alice, "shared"),
+        bm_idx.namespaces().acl(&alice, "shared")
+    );
+}
+
+#[actix_rt::test]
+async fn bigmap_admin_access() {
+    // Only the admins may set the wasm binaries, upgrade the canisters and change the configuration
+    let (mut bm_idx, _db_map) = alloc_bigmap_index_and_data(1).await;
+    let controller = CanisterId::from(1001);
+    let alice = CanisterId::from(1002);
+
+    assert_eq!(
+        bm_idx.authorize_admin(&controller),
+        Err(BigMapError::Unauthorized)
+    );
+    bm_idx.record_admin(controller.clone());
+    // The first recorded admin is kept, a later caller doesn't become one
+    bm_idx.record_admin(alice.clone());
+    assert!(bm_idx.authorize_admin(&controller).is_ok());
+    assert_eq!(
+        bm_idx.authorize_admin(&alice),
+        Err(BigMapError::Unauthorized)
+    );
+
+    assert!(bm_idx.set_admins(Vec::new()).is_err());
+    bm_idx
+        .set_admins(vec![controller.clone(), alice.clone()])
+        .unwrap();
+    assert!(bm_idx.authorize_admin(&alice).is_ok());
+
+    // The admins are kept across upgrades
+    let restored =
+        BigmapIdx::from_stable_payload(STABLE_SCHEMA_VERSION, &bm_idx.to_stable_payload()).unwrap();
+    assert_eq!(restored.admins(), &vec![controller, alice]);
+    assert_eq!(
+        restored.authorize_admin(&CanisterId::from(1003)),
+        Err(BigMapError::Unauthorized)
+    );
+}
+
+#[actix_rt::test]
+async fn bigmap_replication() {
+    // Every key is held by its replicas as the data buckets are split and merged, and a read
+    // succeeds from any replica and repairs the others
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(12).await;
+    bm_idx.batch_limit_bytes = 20_000;
+    bm_idx.set_used_bytes_threshold(1_000_000);
+    assert!(bm_idx.set_replication_factor(0).is_err());
+    assert!(bm_idx
+        .set_replication_factor(REPLICATION_FACTOR_MAX + 1)
+        .is_err());
+    bm_idx.set_replication_factor(3).unwrap();
+
+    // The data buckets which hold the key are exactly its replicas
+    fn check_replicas(bm_idx: &BigmapIdx, db_map: &DataBucketMap, keys: &[Key]) {
+        let replicas_expected = bm_idx.ring().len().min(3);
+        for key in keys {
+            let replicas = bm_idx.replicas_for_key(key).unwrap();
+            assert_eq!(replicas.len(), replicas_expected);
+            let db_map = db_map.read().unwrap();
+            for (can_id, can_data) in db_map.iter() {
+                assert_eq!(can_data.holds_key(key), replicas.contains(can_id));
+            }
+        }
+    }
+
+    let keys: Vec<Key> = (0..1000)
+        .map(|i| format!("key-{}", i).into_bytes())
+        .collect();
+    for key in keys.iter() {
+        let owner = bm_idx.lookup_put(key).unwrap();
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&owner)
+            .unwrap()
+            .put(key, &vec![7u8; 1000], false)
+            .expect("DataBucket put failed");
+        bm_idx.replicate_key(&owner, key).await.unwrap();
+        if key.ends_with(b"99") {
+            bm_idx.maintenance().await.unwrap();
+        }
+    }
+    bm_idx.maintenance().await.unwrap();
+    assert!(bm_idx.relocation.is_none());
+    assert!(bm_idx.ring().len() > 3);
+    check_replicas(&bm_idx, &db_map, &keys);
+    assert!(bm_idx.set_replication_factor(1).is_err());
+
+    // A replica which lost the key, and one which holds an older value, are repaired by the
+    // maintenance, the read only returns the winning value
+    let key = &keys[0];
+    let replicas = bm_idx.replicas_for_key(key).unwrap();
+    db_map
+        .write()
+        .unwrap()
+        .get_mut(&replicas[0])
+        .unwrap()
+        .put(key, &vec![8u8; 1000], false)
+        .unwrap();
+    db_map
+        .write()
+        .unwrap()
+        .get_mut(&replicas[1])
+        .unwrap()
+        .delete(key.clone())
+        .unwrap();
+    assert_eq!(bm_idx.get(key).await.unwrap(), vec![8u8; 1000]);
+    assert!(!db_map.read().unwrap()[&replicas[1]].holds_key(key));
+    assert_eq!(repair_all(&mut bm_idx).await, 2);
+    for can_id in replicas.iter() {
+        assert_eq!(
+            *db_map.read().unwrap()[can_id].get(key.clone()).unwrap(),
+            vec![8u8; 1000]
+        );
+    }
+    // Without the owner, the newest version held by the other replicas wins
+    db_map
+        .write()
+        .unwrap()
+        .get_mut(&replicas[0])
+        .unwrap()
+        .delete(key.clone())
+        .unwrap();
+    let (value, version) = bm_idx.get_with_version(key).await.unwrap();
+    assert_eq!(value, vec![8u8; 1000]);
+    assert_eq!(repair_all(&mut bm_idx).await, 1);
+    assert_eq!(
+        db_map.read().unwrap()[&replicas[0]]
+            .get_with_version(key)
+            .unwrap()
+            .1,
+        version
+    );
+
+    // After most entries are deleted from all replicas, the data buckets are merged
+    let (keys_deleted, keys_kept) = keys.split_at(990);
+    for key in keys_deleted {
+        for can_id in bm_idx.replicas_for_key(key).unwrap() {
+            db_map
+                .write()
+                .unwrap()
+                .get_mut(&can_id)
+                .unwrap()
+                .delete(key.clone())
+                .unwrap();
+        }
+    }
+    bm_idx.maintenance().await.unwrap();
+    assert!(bm_idx.relocation.is_none());
+    assert_eq!(bm_idx.ring().len(), 1);
+    check_replicas(&bm_idx, &db_map, keys_kept);
+    for key in keys_kept {
+        assert!(bm_idx.get(key).await.is_ok());
+    }
+
+    // The replication factor is kept across upgrades
+    let payload = bm_idx.to_stable_payload();
+    let restored = BigmapIdx::from_stable_payload(STABLE_SCHEMA_VERSION, &payload).unwrap();
+    assert_eq!(restored.replication_factor(), 3);
+}
+
+#[actix_rt::test]
+async fn bigmap_replication_tombstones() {
+    // A delete leaves a tombstone on the replicas, which wins over a replica that missed
+    // the delete, and the repair deletes the entry there instead of restoring it
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(4).await;
+    bm_idx.set_replication_factor(3).unwrap();
+    for _ in 0..2 {
+        bm_idx.relocation_start(CanisterPtr(0)).await.unwrap();
+        while !bm_idx.relocation_step().await.unwrap() {}
+    }
+    assert_eq!(bm_idx.ring().len(), 3);
+
+    let key = b"key-1".to_vec();
+    let owner = bm_idx.lookup_put(&key).unwrap();
+    db_map
+        .write()
+        .unwrap()
+        .get_mut(&owner)
+        .unwrap()
+        .put(&key, &vec![7u8; 100], false)
+        .unwrap();
+    bm_idx.replicate_key(&owner, &key).await.unwrap();
+    let replicas = bm_idx.replicas_for_key(&key).unwrap();
+    assert_eq!(replicas.len(), 3);
+    let stale = db_map.read().unwrap()[&replicas[2]].get_replica_entries(&[key.clone()]);
+
+    assert_eq!(bm_idx.delete(&key).await.unwrap(), 100);
+    for can_id in replicas.iter() {
+        let entries = db_map.read().unwrap()[can_id].get_replica_entries(&[key.clone()]);
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].deleted);
+        assert!(entries[0].version > stale[0].version);
+    }
+    assert_eq!(bm_idx.get(&key).await, Err(BigMapError::NotFound));
+
+    // A replica which missed the delete still holds the entry
+    db_map
+        .write()
+        .unwrap()
+        .get_mut(&replicas[2])
+        .unwrap()
+        .put_replica_batch(&stale);
+    assert!(db_map.read().unwrap()[&replicas[2]].holds_key(&key));
+    assert_eq!(bm_idx.get(&key).await, Err(BigMapError::NotFound));
+    assert_eq!(
+        bm_idx.get_with_version(&key).await,
+        Err(BigMapError::NotFound)
+    );
+    assert_eq!(repair_all(&mut bm_idx).await, 1);
+    assert!(!db_map.read().unwrap()[&replicas[2]].holds_key(&key));
+    assert_eq!(repair_all(&mut bm_idx).await, 0);
+
+    // A write after the delete supersedes the tombstones
+    db_map
+        .write()
+        .unwrap()
+        .get_mut(&owner)
+        .unwrap()
+        .put(&key, &vec![8u8; 100], false)
+        .unwrap();
+    bm_idx.replicate_key(&owner, &key).await.unwrap();
+    assert_eq!(bm_idx.get(&key).await.unwrap(), vec![8u8; 100]);
+    for can_id in replicas.iter() {
+        assert!(db_map.read().unwrap()[can_id].holds_key(&key));
+    }
+}
+
+#[actix_rt::test]
+async fn bigmap_large_entries_in_parts() {
+    // Entries larger than a message are replicated, and moved by a split, in parts
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(4).await;
+    bm_idx.set_replication_factor(2).unwrap();
+    let value: Vec<u8> = (0..PUT_VALUE_SIZE_MAX as u32)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let keys: Vec<Key> = (0..10).map(|i| format!("key-{}", i).into_bytes()).collect();
+
+    bm_idx.relocation_start(CanisterPtr(0)).await.unwrap();
+    while !bm_idx.relocation_step().await.unwrap() {}
+    for key in keys.iter() {
+        let owner = bm_idx.lookup_put(key).unwrap();
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&owner)
+            .unwrap()
+            .put(key, &value, false)
+            .unwrap();
+        bm_idx.replicate_key(&owner, key).await.unwrap();
+    }
+    bm_idx.relocation_start(CanisterPtr(0)).await.unwrap();
+    while !bm_idx.relocation_step().await.unwrap() {}
+    assert_eq!(bm_idx.ring().len(), 3);
+
+    for key in keys.iter() {
+        let replicas = bm_idx.replicas_for_key(key).unwrap();
+        assert_eq!(replicas.len(), 2);
+        for (can_id, can_data) in db_map.read().unwrap().iter() {
+            if replicas.contains(can_id) {
+                assert_eq!(*can_data.get(key.clone()).unwrap(), value);
+            } else {
+                assert!(!can_data.holds_key(key));
+            }
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn bigmap_virtual_nodes_weights() {
+    // Every data bucket has as many nodes in the hash ring as the first one, and a data bucket
+    // with a larger weight keeps a larger share of its ranges when it's split
+    let (mut bm_idx, db_map) = alloc_bigmap_index_and_data(12).await;
+    bm_idx.batch_limit_bytes = 20_000;
+    bm_idx.set_used_bytes_threshold(600_000);
+    bm_idx.set_replication_factor(2).unwrap();
+    assert!(bm_idx.set_virtual_nodes(0).is_err());
+    assert!(bm_idx.set_virtual_nodes(VIRTUAL_NODES_MAX + 1).is_err());
+    let epoch = bm_idx.ring_epoch();
+    bm_idx.set_virtual_nodes(4).unwrap();
+    assert_eq!(bm_idx.ring().len(), 4);
+    assert!(bm_idx.ring_epoch() > epoch);
+
+    // The data buckets which hold the key are exactly its replicas, and the owner of the key
+    // holds it in one of its ranges
+    fn check_keys(bm_idx: &BigmapIdx, db_map: &DataBucketMap, keys: &[Key]) {
+        let db_map = db_map.read().unwrap();
+        for key in keys {
+            let replicas = bm_idx.replicas_for_key(key).unwrap();
+            assert_eq!(replicas.len(), bm_idx.idx.len().min(2));
+            assert_eq!(replicas[0], bm_idx.lookup_put(key).unwrap());
+            for (can_id, can_data) in db_map.iter() {
+                assert_eq!(can_data.holds_key(key), replicas.contains(can_id));
+            }
+            for can_id in replicas.iter() {
+                assert!(db_map[can_id].is_in_range(&calc_sha256(key)));
+            }
+        }
+    }
+
+    let keys: Vec<Key> = (0..1000)
+        .map(|i| format!("key-{}", i).into_bytes())
+        .collect();
+    for key in keys.iter() {
+        let owner = bm_idx.lookup_put(key).unwrap();
+        db_map
+            .write()
+            .unwrap()
+            .get_mut(&owner)
+            .unwrap()
+            .put(key, &vec![7u8; 1000], false)
+            .expect("DataBucket put failed");
+        bm_idx.replicate_key(&owner, key).await.unwrap();
+        if key.ends_with(b"99") {
+            bm_idx.maintenance().await.unwrap();
+        }
+    }
+    bm_idx.maintenance().await.unwrap();
+    assert!(bm_idx.relocation.is_none());
+    assert!(bm_idx.idx.len() > 2);
+    assert_eq!(bm_idx.ring().len(), 4 * bm_idx.idx.len());
+    assert!(bm_idx.set_virtual_nodes(2).is_err());
+    check_keys(&bm_idx, &db_map, &keys);
+
+    // The weight is only set for the data buckets in the hash ring
+    let can_ptr = CanisterPtr(1);
+    let can_id = bm_idx.idx[1].clone();
+    assert!(bm_idx.set_data_bucket_weight(&can_id, 0).is_err());
+    let available = bm_idx.canister_available_queue[0].clone();
+    assert!(bm_idx.set_data_bucket_weight(&available, 3).is_err());
+    bm_idx.set_data_bucket_weight(&can_id, 3).unwrap();
+    assert!(!bm_idx.is_over_threshold(&can_ptr, 1_500_000));
+    assert!(bm_idx.is_over_threshold(&CanisterPtr(0), 1_500_000));
+    assert!(bm_idx
+        .status(&CanisterId::from(100))
+        .await
+        .unwrap()
+        .contains("\"weight\":3"));
+
+    // The new data bucket takes a quarter of each range of the data bucket with weight 3
+    let ranges_before = bm_idx.hash_ring.get_key_ranges_for_node(&can_ptr);
+    bm_idx.relocation_start(can_ptr).await.unwrap();
+    let dst_ptr = bm_idx.relocation.as_ref().unwrap().dst;
+    let ranges_dst = bm_idx.hash_ring.get_key_ranges_for_node(&dst_ptr);
+    assert_eq!(ranges_dst.len(), 4);
+    for ((start, end), (start_dst, end_dst)) in ranges_before.iter().zip(ranges_dst.iter()) {
+        assert_eq!(start, start_dst);
+        let span = sha256_digest_to_biguint(*end) - sha256_digest_to_biguint(*start);
+        let span_dst = sha256_digest_to_biguint(*end_dst) - sha256_digest_to_biguint(*start_dst);
+        assert_eq!(span_dst, span / 4u32);
+    }
+    assert_eq!(bm_idx.hash_ring.weight(&can_ptr), 3);
+    assert_eq!(bm_idx.hash_ring.weight(&dst_ptr), WEIGHT_DEFAULT);
+    // The maintenance finishes the split, and may merge the data buckets below the limit
+    // for their weights
+    bm_idx.maintenance().await.unwrap();
+    assert!(bm_idx.relocation.is_none());
+    check_keys(&bm_idx, &db_map, &keys);
+
+    // The virtual nodes and the weights are kept across upgrades
+    let payload = bm_idx.to_stable_payload();
+    let restored = BigmapIdx::from_stable_payload(STABLE_SCHEMA_VERSION, &payload).unwrap();
+    assert_eq!(restored.virtual_nodes(), 4);
+    assert_eq!(restored.hash_ring.weights(), bm_idx.hash_ring.weights());
+    assert_eq!(restored.ring(), bm_idx.ring());
+
+    // After most entries are deleted from all replicas, the data buckets are merged into
+    // the one which holds the end of the key space, with its nodes
+    let (keys_deleted, keys_kept) = keys.split_at(990);
+    for key in keys_deleted {
+        for can_id in bm_idx.replicas_for_key(key).unwrap() {
+            db_map
+                .write()
+                .unwrap()
+                .get_mut(&can_id)
+                .unwrap()
+                .delete(key.clone())
+                .unwrap();
+        }
+    }
+    bm_idx.maintenance().await.unwrap();
+    assert!(bm_idx.relocation.is_none());
+    assert_eq!(bm_idx.idx.len(), 1);
+    assert_eq!(bm_idx.ring().len(), 4);
+    check_keys(&bm_idx, &db_map, keys_kept);
+}
+
+#[actix_rt::test]
+async fn bigmap_call_retries() {
+    // Transient failures are retried, unavailable data buckets are replaced by the
+    // fallbacks, and the final answers are returned right away
+    let transient = BigMapError::CallFailed {
+        code: 2,
+        msg: "queue full".to_string(),
+    };
+    let trapped = BigMapError::CallFailed {
+        code: 5,
+        msg: "canister trapped".to_string(),
+    };
+    assert_eq!(call::recovery(&transient), Recovery::Retry);
+    assert_eq!(call::recovery(&BigMapError::KeyOutOfRange), Recovery::Retry);
+    assert_eq!(call::recovery(&trapped), Recovery::Failover);
+    assert_eq!(call::recovery(&BigMapError::NotFound), Recovery::Fail);
+
+    let policy = CallPolicy::default();
+    assert_eq!(
+        (1..=5)
+            .map(|attempt| policy.backoff_rounds_after(attempt))
+            .collect::<Vec<u32>>(),
+        vec![1, 2, 4, 8, 8]
+    );
+    let can_ids: Vec<CanisterId> = (1..=3).map(CanisterId::from).collect();
+
+    // Calls to each data bucket, and the answers of the data bucket in turn
+    let call_log: RefCell<Vec<CanisterId>> = RefCell::new(Vec::new());
+    let call_with_answers = |answers: Vec<(u64, Vec<Result<u64, BigMapError>>)>| {
+        call_log.borrow_mut().clear();
+        let answers = RefCell::new(answers);
+        let call_log = &call_log;
+        move |can_id: CanisterId| {
+            call_log.borrow_mut().push(can_id.clone());
+            let mut answers = answers.borrow_mut();
+            let (_, can_answers) = answers
+                .iter_mut()
+                .find(|(n, _)| CanisterId::from(*n) == can_id)
+                .unwrap();
+            let answer = can_answers.remove(0);
+            async move { answer }
+        }
+    };
+
+    // A transient failure succeeds on the retry
+    let result = call::call_with_retries(
+        &policy,
+        &can_ids[..1],
+        call_with_answers(vec![(1, vec![Err(transient.clone()), Ok(7)])]),
+    )
+    .await;
+    assert_eq!(result, Ok(7));
+    assert_eq!(call_log.borrow().len(), 2);
+
+    // The attempts are limited, then the next candidate answers
+    let result = call::call_with_retries(
+        &policy,
+        &can_ids[..2],
+        call_with_answers(vec![(1, vec![Err(transient.clone()); 3]), (2, vec![Ok(8)])]),
+    )
+    .await;
+    assert_eq!(result, Ok(8));
+    assert_eq!(call_log.borrow().len(), 4);
+
+    // A trapped data bucket is not retried, and the first final answer is returned
+    let result = call::call_with_retries(
+        &policy,
+        &can_ids,
+        call_with_answers(vec![(1, vec![Err(trapped.clone())]), (2, vec![Ok(9)])]),
+    )
+    .await;
+    assert_eq!(result, Ok(9));
+    assert_eq!(*call_log.borrow(), can_ids[..2].to_vec());
+    let result = call::call_with_retries(
+        &policy,
+        &can_ids,
+        call_with_answers(vec![(1, vec![Err(BigMapError::NotFound)])]),
+    )
+    .await;
+    assert_eq!(result, Err(BigMapError::NotFound));
+    assert_eq!(call_log.borrow().len(), 1);
+
+    // The fallbacks which don't hold the key don't hide the failure of the first candidate
+    let result = call::call_with_retries(
+        &policy,
+        &can_ids,
+        call_with_answers(vec![
+            (1, vec![Err(trapped.clone())]),
+            (2, vec![Err(BigMapError::NotFound)]),
+            (3, vec![Err(trapped.clone())]),
+        ]),
+    )
+    .await;
+    assert_eq!(result, Err(trapped));
+    assert_eq!(call_log.borrow().len(), 3);
+
+    // The policy is validated, and kept across upgrades
+    let (mut bm_idx, _) = alloc_bigmap_index_and_data(1).await;
+    assert_eq!(bm_idx.call_policy(), &policy);
+    for (max_attempts, backoff_rounds, backoff_rounds_max) in
+        [(0, 1, 8), (11, 1, 8), (3, 9, 8), (3, 1, 65)]
+    {
+        assert!(bm_idx
+            .set_call_policy(CallPolicy {
+                max_attempts,
+                backoff_rounds,
+                backoff_rounds_max,
+            })
+            .is_err());
+    }
+    let policy = CallPolicy {
+        max_attempts: 5,
+        backoff_rounds: 0,
+        backoff_rounds_max: 0,
+    };
+    bm_idx.set_call_policy(policy.clone()).unwrap();
+    let payload = bm_idx.to_stable_payload();
+    let restored = BigmapIdx::from_stable_payload(STABLE_SCHEMA_VERSION, &payload).unwrap();
+    assert_eq!(restored.call_policy(), &policy);
+}
+
 async fn alloc_bigmap_index_and_data(num_data_canisters: u64) -> (BigmapIdx, DataBucketMap) {
     // Insert key&value pairs and then get the value, and verify the correctness
     let mut bm_idx = BigmapIdx::new();
@@ -173,6 +1731,41 @@ async fn alloc_bigmap_index_and_data(num_data_canisters: u64) -> (BigmapIdx, Dat
             .insert(can_id.clone(), DataBucket::new(can_id));
     }
 
+    set_fn_ptrs(&mut bm_idx, &db_map);
+
+    let can_ids = db_map.write().unwrap().keys().cloned().collect();
+    bm_idx.add_canisters(can_ids).await.unwrap();
+
+    (bm_idx, db_map)
+}
+
+// Record the installed code, reject the install on the failing canister, and answer the
+// version query with the version
+fn set_upgrade_fn_ptrs(
+    bm_idx: &mut BigmapIdx,
+    installed: &InstalledCode,
+    failing: &Rc<RefCell<Option<CanisterId>>>,
+    version: &Rc<RefCell<String>>,
+) {
+    let (installed_ref, failing_ref) = (installed.clone(), failing.clone());
+    bm_idx.set_fn_ptr_upgrade_code(Box::new(move |can_id: CanisterId, wasm: &[u8]| {
+        if failing_ref.borrow().as_ref() == Some(&can_id) {
+            return Err("Canister trapped in post_upgrade".to_string());
+        }
+        installed_ref.borrow_mut().push((can_id, wasm.to_vec()));
+        Ok(())
+    }));
+    let version_ref = version.clone();
+    bm_idx.set_fn_ptr_code_version(Box::new(move |_can_id: CanisterId| {
+        Ok(CodeVersion {
+            crate_version: version_ref.borrow().clone(),
+            stable_schema_version: 1,
+        })
+    }));
+}
+
+// Route the calls of the BigmapIdx to the DataBuckets in db_map
+fn set_fn_ptrs(bm_idx: &mut BigmapIdx, db_map: &DataBucketMap) {
     let db_map_ref = db_map.clone();
     let fn_ptr_used_bytes = move |can_id: CanisterId| {
         db_map_ref
@@ -206,39 +1799,98 @@ async fn alloc_bigmap_index_and_data(num_data_canisters: u64) -> (BigmapIdx, Dat
     };
     bm_idx.set_fn_ptr_list(Box::new(fn_ptr_list));
 
+    let db_map_ref = db_map.clone();
+    let fn_ptr_list_page =
+        move |can_id: CanisterId, key_prefix: &Key, start_after: &Option<Key>, limit: u32| {
+            db_map_ref.read().unwrap().get(&can_id).unwrap().list_page(
+                key_prefix,
+                start_after,
+                limit,
+            )
+        };
+    bm_idx.set_fn_ptr_list_page(Box::new(fn_ptr_list_page));
+
     let db_map_ref = db_map.clone();
     let fn_ptr_set_range =
-        move |can_id: CanisterId, range_start: Sha256Digest, range_end: Sha256Digest| {
+        move |can_id: CanisterId, ranges: &[(Sha256Digest, Sha256Digest)], epoch: u64| {
             db_map_ref
                 .write()
                 .unwrap()
                 .get_mut(&can_id)
                 .unwrap()
-                .set_range(&range_start, &range_end)
+                .set_ranges(ranges, epoch)
         };
     bm_idx.set_fn_ptr_set_range(Box::new(fn_ptr_set_range));
 
     let db_map_ref = db_map.clone();
-    let fn_ptr = move |can_id: CanisterId, batch_limit_bytes: u64| {
+    let fn_ptr =
+        move |can_id: CanisterId, start_after: &Option<Sha2Vec>, batch_limit_bytes: u64| {
+            db_map_ref
+                .write()
+                .unwrap()
+                .get(&can_id)
+                .unwrap()
+                .get_relocation_batch(start_after, batch_limit_bytes)
+        };
+    bm_idx.set_fn_ptr_get_relocation_batch(Box::new(fn_ptr));
+
+    let db_map_ref = db_map.clone();
+    let fn_ptr = move |can_id: CanisterId, batch: &[RelocationEntry]| {
         db_map_ref
             .write()
             .unwrap()
+            .get_mut(&can_id)
+            .unwrap()
+            .put_relocation_batch(batch)
+    };
+    bm_idx.set_fn_ptr_put_relocation_batch(Box::new(fn_ptr));
+
+    let db_map_ref = db_map.clone();
+    let fn_ptr = move |can_id: CanisterId,
+                       ranges: &[(Sha256Digest, Sha256Digest)],
+                       start_after: &Option<Sha2Vec>,
+                       batch_limit_bytes: u64| {
+        db_map_ref
+            .read()
+            .unwrap()
             .get(&can_id)
             .unwrap()
-            .get_relocation_batch(batch_limit_bytes)
+            .get_range_batch(ranges, start_after, batch_limit_bytes)
     };
-    bm_idx.set_fn_ptr_get_relocation_batch(Box::new(fn_ptr));
+    bm_idx.set_fn_ptr_get_range_batch(Box::new(fn_ptr));
 
     let db_map_ref = db_map.clone();
-    let fn_ptr = move |can_id: CanisterId, batch: &Vec<(Sha2Vec, Key, Val)>| {
+    let fn_ptr = move |can_id: CanisterId, keys: &[Key]| {
+        db_map_ref
+            .read()
+            .unwrap()
+            .get(&can_id)
+            .unwrap()
+            .get_replica_entries(keys)
+    };
+    bm_idx.set_fn_ptr_get_replica_entries(Box::new(fn_ptr));
+
+    let db_map_ref = db_map.clone();
+    let fn_ptr = move |can_id: CanisterId, key: &Key, offset: u64| {
+        db_map_ref
+            .read()
+            .unwrap()
+            .get(&can_id)
+            .unwrap()
+            .get_entry_part(key, offset)
+    };
+    bm_idx.set_fn_ptr_get_entry_part(Box::new(fn_ptr));
+
+    let db_map_ref = db_map.clone();
+    let fn_ptr = move |can_id: CanisterId, batch: &[RelocationEntry]| {
         db_map_ref
             .write()
             .unwrap()
             .get_mut(&can_id)
             .unwrap()
-            .put_relocation_batch(batch)
+            .put_replica_batch(batch)
     };
-    bm_idx.set_fn_ptr_put_relocation_batch(Box::new(fn_ptr));
+    bm_idx.set_fn_ptr_put_replica_batch(Box::new(fn_ptr));
 
     let db_map_ref = db_map.clone();
     let fn_ptr = move |can_id: CanisterId, keys_sha2: &Vec<Vec<u8>>| {
@@ -251,8 +1903,57 @@ async fn alloc_bigmap_index_and_data(num_data_canisters: u64) -> (BigmapIdx, Dat
     };
     bm_idx.set_fn_ptr_delete_entries(Box::new(fn_ptr));
 
-    let can_ids = db_map.write().unwrap().keys().cloned().collect();
-    bm_idx.add_canisters(can_ids).await;
+    let db_map_ref = db_map.clone();
+    let fn_ptr = move |can_id: CanisterId, limit: u32| {
+        db_map_ref
+            .write()
+            .unwrap()
+            .get_mut(&can_id)
+            .unwrap()
+            .sweep_expired(limit as usize)
+    };
+    bm_idx.set_fn_ptr_sweep_expired(Box::new(fn_ptr));
 
-    (bm_idx, db_map)
+    let db_map_ref = db_map.clone();
+    let fn_ptr = move |can_id: CanisterId| {
+        db_map_ref
+            .write()
+            .unwrap()
+            .get_mut(&can_id)
+            .unwrap()
+            .reset()
+    };
+    bm_idx.set_fn_ptr_reset(Box::new(fn_ptr));
+
+    let db_map_ref = db_map.clone();
+    let fn_ptr = move |can_id: CanisterId, version: u64| {
+        db_map_ref
+            .write()
+            .unwrap()
+            .get_mut(&can_id)
+            .unwrap()
+            .raise_version_counter(version)
+    };
+    bm_idx.set_fn_ptr_raise_version_counter(Box::new(fn_ptr));
+
+    let db_map_ref = db_map.clone();
+    let fn_ptr = move |can_id: CanisterId, key: &Key| {
+        db_map_ref
+            .write()
+            .unwrap()
+            .get_mut(&can_id)
+            .unwrap()
+            .delete_with_tombstone(key)
+    };
+    bm_idx.set_fn_ptr_delete(Box::new(fn_ptr));
+}
+
+// Run repair_step until the replicas of the keys of all data buckets were compared
+async fn repair_all(bm_idx: &mut BigmapIdx) -> u64 {
+    bm_idx.repair_cursor = (0, None);
+    let mut repaired = 0;
+    while bm_idx.repair_cursor.0 < bm_idx.idx.len() {
+        repaired += bm_idx.repair_step().await.unwrap();
+    }
+    repaired
 }
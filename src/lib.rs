@@ -1,11 +1,16 @@
 use digest::generic_array::GenericArray;
 use sha2::{Digest, Sha256};
 pub mod data;
+pub mod error;
 pub(crate) mod hashring;
 #[allow(dead_code)]
 pub(crate) mod hashring_sha256;
 pub mod index;
+pub mod memory;
+pub mod merkle;
+pub mod namespace;
 pub mod search;
+pub mod upgrade;
 
 /********************************************************************
      ____  _         __  __
@@ -41,6 +46,7 @@ pub use lib_wasm32::*;
 mod canister_management;
 pub use canister_management::{
     subnet_create_new_canister, subnet_install_canister_code, subnet_raw_rand,
+    subnet_upgrade_canister_code,
 };
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -48,6 +54,8 @@ mod lib_native;
 #[cfg(not(target_arch = "wasm32"))]
 pub use lib_native::*;
 
+pub use error::BigMapError;
+
 pub type Key = Vec<u8>;
 pub type Val = Vec<u8>;
 pub type Sha2Vec = Vec<u8>;
@@ -93,3 +93,27 @@ impl std::fmt::Debug for CanisterId {
         write!(f, "{}", self)
     }
 }
+
+// Current time in nanoseconds since 1970-01-01
+pub fn time_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("System time is before the UNIX epoch")
+        .as_nanos() as u64
+}
+
+// There is no certification outside of the IC
+pub fn set_certified_data(_data: &[u8]) {}
+
+pub fn data_certificate() -> Option<Vec<u8>> {
+    None
+}
+
+// The native tests run in the 64 bit address space of the host, which holds more than a
+// canister heap, e.g. for splitting a DataBucket with more entries than fit in a canister
+pub const HEAP_BYTES_MAX: u64 = 1 << 46;
+
+// The heap is shared by all canisters in the native tests, so it's not counted
+pub fn heap_allocated_bytes() -> Option<u64> {
+    None
+}
@@ -91,3 +91,26 @@ impl std::fmt::Debug for CanisterId {
         write!(f, "{}", self)
     }
 }
+
+// Current time in nanoseconds since 1970-01-01
+pub fn time_now() -> u64 {
+    ic_cdk::time()
+}
+
+// Certify up to 32 bytes, e.g. a root hash, for the following query calls
+pub fn set_certified_data(data: &[u8]) {
+    ic_cdk::set_certified_data(data)
+}
+
+// Certificate of the certified data, only available in query calls
+pub fn data_certificate() -> Option<Vec<u8>> {
+    ic_cdk::data_certificate()
+}
+
+// Size of the heap available to the canister
+pub const HEAP_BYTES_MAX: u64 = crate::memory::WASM_HEAP_BYTES;
+
+// Bytes currently allocated on the heap, as counted by the allocator
+pub fn heap_allocated_bytes() -> Option<u64> {
+    Some(ic_cdk::heap_allocated_bytes())
+}
@@ -0,0 +1,58 @@
+///////////////////////////////////////////////////////////////
+// Memory accounting for the BigMap canisters
+//
+// The allocator of the canisters counts the bytes currently allocated on the heap.
+// In addition, each canister keeps a breakdown of the memory used by its data
+// structures, so that it's visible where the memory goes.
+///////////////////////////////////////////////////////////////
+use candid::CandidType;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::mem::size_of;
+
+// Size of the heap of a canister, which is limited by the 32 bit address space of wasm32
+pub const WASM_HEAP_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+// Memory used by an element of a BTreeMap or BTreeSet, with key and value of kv_size
+// bytes, excluding the heap memory owned by the key and value. The nodes hold up to
+// 11 elements and are on average about 2/3 full, and internal nodes also hold the
+// pointers to their children.
+pub const fn btree_element_bytes(kv_size: usize) -> usize {
+    kv_size * 3 / 2 + size_of::<usize>()
+}
+
+// Memory used by the buckets of a HashMap, excluding the heap memory owned by the
+// keys and values. Each bucket has one control byte.
+pub fn hashmap_bytes<K, V, S>(map: &HashMap<K, V, S>) -> usize {
+    map.capacity() * (size_of::<(K, V)>() + 1)
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct MemoryUsage {
+    pub keys: u64,           // Keys, including their copies in the secondary indexes
+    pub values: u64,         // Values, as stored with their codec
+    pub index_overhead: u64, // Nodes of the maps and sets, and the key hashes in them
+    pub merkle: u64,         // Merkle tree over the entries
+    pub uploads: u64,        // Chunked uploads in progress
+    pub search_bitmaps: u64, // Inverted index of the full text search
+    // Bytes allocated on the heap, as counted by the allocator, or None in the native
+    // tests. This includes all of the above, and the memory not covered by the breakdown.
+    pub heap: Option<u64>,
+}
+
+impl MemoryUsage {
+    // Total of the breakdown
+    pub fn total(&self) -> u64 {
+        self.keys
+            + self.values
+            + self.index_overhead
+            + self.merkle
+            + self.uploads
+            + self.search_bitmaps
+    }
+
+    // The heap usage if known, and otherwise the total of the breakdown
+    pub fn used_bytes(&self) -> u64 {
+        self.heap.unwrap_or_else(|| self.total())
+    }
+}
@@ -0,0 +1,346 @@
+///////////////////////////////////////////////////////////////
+// Merkle tree over the entries of a DataBucket
+//
+// The tree is a crit-bit (compressed binary radix) tree over the Sha256 of the
+// keys. Every fork splits its leaves on the first bit in which they differ, so
+// the shape of the tree only depends on the set of keys, and a put or delete
+// only rehashes the path from the leaf to the root, in O(log n).
+//
+//   leaf hash = Sha256(0x00 | Sha256(key) | Sha256(value))
+//   fork hash = Sha256(0x01 | left hash | right hash)
+//   empty     = Sha256(0x02)
+//
+// The root hash is set as the certified data of the canister, and a Witness
+// (the sibling hashes on the path) allows clients to verify a single value.
+///////////////////////////////////////////////////////////////
+use crate::{calc_sha256, Sha256Digest, Sha2Vec};
+use candid::CandidType;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+const LEAF_TAG: u8 = 0;
+const FORK_TAG: u8 = 1;
+const EMPTY_TAG: u8 = 2;
+
+#[derive(Clone, Debug)]
+enum Node {
+    Leaf {
+        key_sha2: Sha256Digest,
+        hash: Sha256Digest,
+    },
+    Fork {
+        bit: usize, // The leaves on the left have this bit of key_sha2 clear
+        left: Box<Node>,
+        right: Box<Node>,
+        hash: Sha256Digest,
+    },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MerkleTree {
+    root: Option<Box<Node>>,
+    len: usize,
+}
+
+// Proof that a key and value are in the tree with a given root hash
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Witness {
+    // Sibling hashes from the leaf up to the root, with true if the sibling is on the left
+    pub siblings: Vec<(bool, Sha2Vec)>,
+}
+
+pub fn leaf_hash(key_sha2: &Sha256Digest, value: &[u8]) -> Sha256Digest {
+    let mut digest = Sha256::new();
+    digest.update([LEAF_TAG]);
+    digest.update(key_sha2);
+    digest.update(calc_sha256(value));
+    digest.finalize()
+}
+
+fn fork_hash(left: &[u8], right: &[u8]) -> Sha256Digest {
+    let mut digest = Sha256::new();
+    digest.update([FORK_TAG]);
+    digest.update(left);
+    digest.update(right);
+    digest.finalize()
+}
+
+fn get_bit(key_sha2: &Sha256Digest, bit: usize) -> bool {
+    key_sha2[bit / 8] & (0x80 >> (bit % 8)) != 0
+}
+
+// Index of the first bit in which the two digests differ, None if they are equal
+fn crit_bit(a: &Sha256Digest, b: &Sha256Digest) -> Option<usize> {
+    a.iter()
+        .zip(b.iter())
+        .position(|(a, b)| a != b)
+        .map(|i| i * 8 + (a[i] ^ b[i]).leading_zeros() as usize)
+}
+
+impl Node {
+    fn hash(&self) -> &Sha256Digest {
+        match self {
+            Node::Leaf { hash, .. } => hash,
+            Node::Fork { hash, .. } => hash,
+        }
+    }
+
+    fn fork(bit: usize, left: Box<Node>, right: Box<Node>) -> Box<Node> {
+        let hash = fork_hash(left.hash(), right.hash());
+        Box::new(Node::Fork {
+            bit,
+            left,
+            right,
+            hash,
+        })
+    }
+
+    // The leaf with the longest common prefix with key_sha2
+    fn closest_leaf(&self, key_sha2: &Sha256Digest) -> &Sha256Digest {
+        let mut node = self;
+        loop {
+            match node {
+                Node::Leaf { key_sha2, .. } => return key_sha2,
+                Node::Fork {
+                    bit, left, right, ..
+                } => node = if get_bit(key_sha2, *bit) { right } else { left },
+            }
+        }
+    }
+
+    // Insert the leaf at the first node which doesn't split on a bit before crit
+    // crit is None if the key is already in the tree, and the leaf is then replaced
+    fn insert(self, key_sha2: Sha256Digest, hash: Sha256Digest, crit: Option<usize>) -> Box<Node> {
+        match self {
+            Node::Fork {
+                bit, left, right, ..
+            } if crit.is_none_or(|crit| bit < crit) => {
+                if get_bit(&key_sha2, bit) {
+                    Node::fork(bit, left, (*right).insert(key_sha2, hash, crit))
+                } else {
+                    Node::fork(bit, (*left).insert(key_sha2, hash, crit), right)
+                }
+            }
+            node => {
+                let leaf = Box::new(Node::Leaf { key_sha2, hash });
+                match crit {
+                    None => leaf,
+                    Some(crit) if get_bit(&key_sha2, crit) => {
+                        Node::fork(crit, Box::new(node), leaf)
+                    }
+                    Some(crit) => Node::fork(crit, leaf, Box::new(node)),
+                }
+            }
+        }
+    }
+
+    // Returns the node without the leaf, or None if nothing remains
+    fn remove(self: Box<Node>, key_sha2: &Sha256Digest) -> Option<Box<Node>> {
+        match *self {
+            Node::Leaf {
+                key_sha2: ref leaf_key_sha2,
+                ..
+            } if leaf_key_sha2 == key_sha2 => None,
+            Node::Leaf { .. } => Some(self),
+            Node::Fork {
+                bit, left, right, ..
+            } => {
+                if get_bit(key_sha2, bit) {
+                    match right.remove(key_sha2) {
+                        Some(right) => Some(Node::fork(bit, left, right)),
+                        None => Some(left),
+                    }
+                } else {
+                    match left.remove(key_sha2) {
+                        Some(left) => Some(Node::fork(bit, left, right)),
+                        None => Some(right),
+                    }
+                }
+            }
+        }
+    }
+
+    // Build the subtree from a non-empty slice of leaves, sorted by key_sha2
+    fn from_sorted_leaves(leaves: &[(Sha256Digest, Sha256Digest)]) -> Box<Node> {
+        let (first, last) = (&leaves[0], &leaves[leaves.len() - 1]);
+        match crit_bit(&first.0, &last.0) {
+            None => Box::new(Node::Leaf {
+                key_sha2: first.0,
+                hash: first.1,
+            }),
+            Some(bit) => {
+                let split = leaves.partition_point(|(key_sha2, _)| !get_bit(key_sha2, bit));
+                Node::fork(
+                    bit,
+                    Node::from_sorted_leaves(&leaves[..split]),
+                    Node::from_sorted_leaves(&leaves[split..]),
+                )
+            }
+        }
+    }
+}
+
+impl MerkleTree {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Build the tree in O(n), from leaves sorted by key_sha2 and without duplicates
+    pub fn from_sorted_leaves(leaves: &[(Sha256Digest, Sha256Digest)]) -> Self {
+        Self {
+            root: if leaves.is_empty() {
+                None
+            } else {
+                Some(Node::from_sorted_leaves(leaves))
+            },
+            len: leaves.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn root_hash(&self) -> Sha256Digest {
+        match &self.root {
+            Some(root) => *root.hash(),
+            None => calc_sha256([EMPTY_TAG]),
+        }
+    }
+
+    // Insert or replace the leaf for key_sha2, returns true if the key was not in the tree
+    pub fn insert(&mut self, key_sha2: Sha256Digest, hash: Sha256Digest) -> bool {
+        match self.root.take() {
+            None => {
+                self.root = Some(Box::new(Node::Leaf { key_sha2, hash }));
+                self.len += 1;
+                true
+            }
+            Some(root) => {
+                let crit = crit_bit(root.closest_leaf(&key_sha2), &key_sha2);
+                self.root = Some((*root).insert(key_sha2, hash, crit));
+                if crit.is_some() {
+                    self.len += 1;
+                }
+                crit.is_some()
+            }
+        }
+    }
+
+    // Remove the leaf for key_sha2, returns true if the key was in the tree
+    pub fn remove(&mut self, key_sha2: &Sha256Digest) -> bool {
+        match self.root.take() {
+            Some(root) if root.closest_leaf(key_sha2) == key_sha2 => {
+                self.root = root.remove(key_sha2);
+                self.len -= 1;
+                true
+            }
+            root => {
+                self.root = root;
+                false
+            }
+        }
+    }
+
+    pub fn witness(&self, key_sha2: &Sha256Digest) -> Option<Witness> {
+        let mut siblings = Vec::new();
+        let mut node = self.root.as_ref()?;
+        loop {
+            match node.as_ref() {
+                Node::Leaf {
+                    key_sha2: leaf_key_sha2,
+                    ..
+                } if leaf_key_sha2 == key_sha2 => break,
+                Node::Leaf { .. } => return None,
+                Node::Fork {
+                    bit, left, right, ..
+                } => {
+                    if get_bit(key_sha2, *bit) {
+                        siblings.push((true, left.hash().to_vec()));
+                        node = right;
+                    } else {
+                        siblings.push((false, right.hash().to_vec()));
+                        node = left;
+                    }
+                }
+            }
+        }
+        siblings.reverse();
+        Some(Witness { siblings })
+    }
+}
+
+impl Witness {
+    // The root hash of a tree holding the key with the value, according to this witness
+    pub fn root_hash(&self, key: &[u8], value: &[u8]) -> Sha256Digest {
+        let mut hash = leaf_hash(&calc_sha256(key), value);
+        for (sibling_is_left, sibling) in self.siblings.iter() {
+            hash = if *sibling_is_left {
+                fork_hash(sibling, &hash)
+            } else {
+                fork_hash(&hash, sibling)
+            };
+        }
+        hash
+    }
+
+    // Check the key and value against a root hash, e.g. the certified data of a DataBucket
+    pub fn verify(&self, root_hash: &[u8], key: &[u8], value: &[u8]) -> bool {
+        self.root_hash(key, value).as_slice() == root_hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{leaf_hash, MerkleTree};
+    use crate::calc_sha256;
+
+    #[test]
+    fn merkle_insert_remove_witness() {
+        let mut t = MerkleTree::new();
+        let root_empty = t.root_hash();
+        let mut leaves = Vec::new();
+        for i in 0..500 {
+            let key = format!("key-{}", i).into_bytes();
+            let value = format!("value-{}", i).into_bytes();
+            let key_sha2 = calc_sha256(&key);
+            assert!(t.insert(key_sha2, leaf_hash(&key_sha2, &value)));
+            leaves.push((key_sha2, leaf_hash(&key_sha2, &value)));
+        }
+        assert_eq!(t.len(), 500);
+
+        // The shape of the tree doesn't depend on the insertion order
+        leaves.sort();
+        let root = t.root_hash();
+        assert_eq!(MerkleTree::from_sorted_leaves(&leaves).root_hash(), root);
+
+        // Every value can be verified, and a different value can't
+        for i in 0..500 {
+            let key = format!("key-{}", i).into_bytes();
+            let value = format!("value-{}", i).into_bytes();
+            let witness = t.witness(&calc_sha256(&key)).unwrap();
+            assert!(witness.verify(&root, &key, &value));
+            assert!(!witness.verify(&root, &key, b"forged"));
+        }
+        assert!(t.witness(&calc_sha256(b"missing")).is_none());
+
+        // Replacing a value changes the root hash, and restoring it changes it back
+        let key_sha2 = calc_sha256(b"key-7");
+        assert!(!t.insert(key_sha2, leaf_hash(&key_sha2, b"other")));
+        assert_ne!(t.root_hash(), root);
+        assert!(!t.insert(key_sha2, leaf_hash(&key_sha2, b"value-7")));
+        assert_eq!(t.root_hash(), root);
+
+        // Removing all keys returns to the empty tree
+        assert!(!t.remove(&calc_sha256(b"missing")));
+        for i in 0..500 {
+            assert!(t.remove(&calc_sha256(format!("key-{}", i))));
+        }
+        assert!(t.is_empty());
+        assert_eq!(t.root_hash(), root_empty);
+    }
+}
@@ -0,0 +1,40 @@
+// Keys scoped to a namespace, shared by the BigMap Index and the data buckets
+//
+// The data buckets and the search canisters hold the scoped keys:
+//   +---------+-----------+-----+
+//   | ns len  | namespace | key |
+//   | u8      | ns len    |     |
+//   +---------+-----------+-----+
+// The length prefix ensures that the keys of a namespace never start with the scope of
+// another namespace, so listing a prefix within a namespace never returns foreign keys.
+// The ACLs of the namespaces are held by the BigMap Index, see index::namespace.
+use crate::{CanisterId, Key};
+
+pub type Namespace = String;
+
+// The namespace of the caller, unless it selects another one
+pub fn default_namespace(caller: &CanisterId) -> Namespace {
+    caller.to_string()
+}
+
+// The prefix of all keys of the namespace
+pub fn scope(namespace: &str) -> Key {
+    let mut result = Vec::with_capacity(1 + namespace.len());
+    result.push(namespace.len() as u8);
+    result.extend_from_slice(namespace.as_bytes());
+    result
+}
+
+// The key as held by the data buckets
+pub fn scoped_key(namespace: &str, key: &[u8]) -> Key {
+    let mut result = scope(namespace);
+    result.extend_from_slice(key);
+    result
+}
+
+// The key as seen by the app, or None if the key is not in the namespace
+pub fn unscoped_key(namespace: &str, scoped_key: &[u8]) -> Option<Key> {
+    scoped_key
+        .strip_prefix(scope(namespace).as_slice())
+        .map(|key| key.to_vec())
+}
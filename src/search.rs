@@ -11,8 +11,10 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use roaring::RoaringBitmap;
 use rust_stemmers::{Algorithm, Stemmer};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::hash::BuildHasherDefault;
+use std::io::{Read, Write};
 use wyhash::WyHash;
 
 pub type DetHashMap<K, V> = HashMap<K, V, BuildHasherDefault<WyHash>>;
@@ -21,6 +23,8 @@ pub type DetHashSet<K> = HashSet<K, BuildHasherDefault<WyHash>>;
 // #[cfg(target_arch = "wasm32")]
 // use ic_cdk::println;
 
+use crate::memory::{self, MemoryUsage};
+use crate::upgrade::StableMagic;
 use crate::Key;
 
 // Roaring Bitmaps only support 32-bit integers
@@ -36,6 +40,20 @@ lazy_static! {
         .collect();
 }
 
+// Identifies the SearchIndexer state in the stable memory
+pub const STABLE_MAGIC: StableMagic = *b"BMSI";
+// Bump when the layout of SearchIndexerStable changes, and add a migration in read_stable_payload
+pub const STABLE_SCHEMA_VERSION: u32 = 1;
+
+// SearchIndexer state as persisted across upgrades. The inverted index of each term is
+// kept in the portable serialization format of the roaring bitmaps, and the map from the
+// document ids to the keys is rebuilt on restore.
+#[derive(Serialize, Deserialize)]
+struct SearchIndexerStable {
+    docs: Vec<(Key, DocumentId)>,
+    terms: Vec<(Term, u64, Vec<u8>)>, // Term, frequency, inverted index
+}
+
 #[derive(Default)]
 struct TermData {
     frequency: usize,
@@ -100,7 +118,7 @@ impl SearchIndexer {
         }
     }
 
-    pub fn batch_add_to_index(&mut self, doc_vec: &Vec<(Key, String)>) -> u64 {
+    pub fn batch_add_to_index(&mut self, doc_vec: &[(Key, String)]) -> u64 {
         let result = doc_vec.len() as u64;
         for (key, doc) in doc_vec.into_iter() {
             self.add_to_index(key, doc);
@@ -159,8 +177,99 @@ impl SearchIndexer {
         }
     }
 
+    // The heap usage as counted by the allocator, if installed, and otherwise the
+    // total of the memory usage breakdown
     pub fn used_bytes(&self) -> usize {
-        std::mem::size_of_val(self)
+        self.memory_usage().used_bytes() as usize
+    }
+
+    // Breakdown of the memory used by the keys, the terms and the inverted index
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage {
+            heap: crate::heap_allocated_bytes(),
+            ..Default::default()
+        };
+        // Each key is held by both maps between the keys and the document ids
+        usage.keys = 2 * self
+            .key_to_doc_id
+            .keys()
+            .map(|key| key.len() as u64)
+            .sum::<u64>();
+        usage.index_overhead = (memory::hashmap_bytes(&self.key_to_doc_id)
+            + memory::hashmap_bytes(&self.doc_id_to_key)
+            + memory::hashmap_bytes(&self.terms)) as u64;
+        for (term, term_data) in self.terms.iter() {
+            usage.index_overhead += term.capacity() as u64;
+            // The serialized size of a roaring bitmap is close to its size in memory
+            usage.search_bitmaps += term_data.inverted_index.serialized_size() as u64;
+        }
+        usage
+    }
+
+    // Serialize the SearchIndexer state, to be saved in the stable memory before an upgrade
+    pub fn write_stable_payload<W: Write>(&self, writer: W) -> Result<(), String> {
+        let mut state = SearchIndexerStable {
+            docs: self
+                .key_to_doc_id
+                .iter()
+                .map(|(key, doc_id)| (key.clone(), *doc_id))
+                .collect(),
+            terms: Vec::with_capacity(self.terms.len()),
+        };
+        for (term, term_data) in self.terms.iter() {
+            let mut inverted_index = Vec::with_capacity(term_data.inverted_index.serialized_size());
+            term_data
+                .inverted_index
+                .serialize_into(&mut inverted_index)
+                .expect("SearchIndexer serialization failed");
+            state
+                .terms
+                .push((term.clone(), term_data.frequency as u64, inverted_index));
+        }
+        bincode::serialize_into(writer, &state)
+            .map_err(|err| format!("SearchIndexer serialization failed: {}", err))
+    }
+
+    // As write_stable_payload, into a Vec
+    pub fn to_stable_payload(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        self.write_stable_payload(&mut result)
+            .expect("SearchIndexer serialization failed");
+        result
+    }
+
+    // As read_stable_payload, from a slice
+    pub fn from_stable_payload(schema_version: u32, payload: &[u8]) -> Result<Self, String> {
+        Self::read_stable_payload(schema_version, payload)
+    }
+
+    // Restore the SearchIndexer state saved with write_stable_payload
+    pub fn read_stable_payload<R: Read>(schema_version: u32, reader: R) -> Result<Self, String> {
+        if schema_version != STABLE_SCHEMA_VERSION {
+            return Err(format!(
+                "Unsupported SearchIndexer schema version {}",
+                schema_version
+            ));
+        }
+        let deserialize_err = |err| format!("SearchIndexer deserialization failed: {}", err);
+        let state: SearchIndexerStable =
+            bincode::deserialize_from(reader).map_err(deserialize_err)?;
+
+        let mut result = SearchIndexer::new();
+        for (key, doc_id) in state.docs {
+            result.doc_id_to_key.insert(doc_id, key.clone());
+            result.key_to_doc_id.insert(key, doc_id);
+        }
+        for (term, frequency, inverted_index) in state.terms {
+            let inverted_index = RoaringBitmap::deserialize_from(inverted_index.as_slice())
+                .map_err(|err| format!("SearchIndexer deserialization failed: {}", err))?;
+            let term_data = TermData {
+                frequency: frequency as usize,
+                inverted_index,
+            };
+            result.terms.insert(term, term_data);
+        }
+        Ok(result)
     }
 
     fn normalize_to_string(&self, input: &str) -> String {
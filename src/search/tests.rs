@@ -1,4 +1,4 @@
-use super::SearchIndexer;
+use super::{SearchIndexer, STABLE_SCHEMA_VERSION};
 
 #[test]
 fn search_basic_test() {
@@ -106,3 +106,57 @@ fn search_with_stemming() {
     let search_result = s.search_keys_by_query(&"stem".to_string());
     assert_eq!(search_result.len(), 2);
 }
+
+#[test]
+fn search_memory_usage() {
+    // The memory usage breakdown covers the keys, the terms and the inverted index
+    let mut s = SearchIndexer::new();
+    assert_eq!(s.memory_usage().total(), 0);
+
+    for i in 0..100 as u8 {
+        let key = format!("key-{:02}", i).into_bytes();
+        let value = format!("some text before value-{} some text after", i);
+
+        s.add_to_index(&key, &value);
+    }
+
+    let usage = s.memory_usage();
+    assert_eq!(usage.keys, 2 * 100 * 6);
+    assert!(usage.index_overhead > 0);
+    assert!(usage.search_bitmaps > 0);
+    assert_eq!(usage.heap, None);
+    assert_eq!(s.used_bytes() as u64, usage.total());
+}
+
+#[test]
+fn search_stable_save_restore() {
+    // The search index is the same after an upgrade
+    let mut s = SearchIndexer::new();
+
+    for i in 0..100u8 {
+        let key = format!("key-{}", i).into_bytes();
+        let value = format!("some text before value-{} some text after", i);
+
+        s.add_to_index(&key, &value);
+    }
+    s.remove_key(&b"key-7".to_vec());
+
+    let payload = s.to_stable_payload();
+    let r = SearchIndexer::from_stable_payload(STABLE_SCHEMA_VERSION, &payload).unwrap();
+    // The capacity of the maps may differ, but not the keys and the inverted indexes
+    assert_eq!(r.memory_usage().keys, s.memory_usage().keys);
+    assert_eq!(
+        r.memory_usage().search_bitmaps,
+        s.memory_usage().search_bitmaps
+    );
+    for query in &["value-42", "text", "value-7", "key-99"] {
+        let query = query.to_string();
+        assert_eq!(
+            r.search_keys_by_query(&query),
+            s.search_keys_by_query(&query)
+        );
+    }
+    assert!(r.search_keys_by_query(&"value-7".to_string()).is_empty());
+
+    assert!(SearchIndexer::from_stable_payload(STABLE_SCHEMA_VERSION + 1, &payload).is_err());
+}
@@ -0,0 +1,222 @@
+// Canister state persistence across code upgrades
+//
+// Before an upgrade, the canister state is serialized and written to the
+// stable memory, and after the upgrade it is read back and deserialized.
+// Stable memory layout:
+//
+//   +-------+----------------+----------------+-------------------+
+//   | magic | schema version | payload length | payload           |
+//   | 4 B   | u32 LE         | u64 LE         | payload length B  |
+//   +-------+----------------+----------------+-------------------+
+//
+// The magic identifies the canister type (data bucket, index, ...), so that
+// a canister never restores state written by a different canister type.
+// The schema version allows the state structures to change between releases,
+// with the old versions being migrated on restore.
+//
+// The payload is streamed between the state and the stable memory, so that the
+// heap doesn't need to hold a serialized copy of the whole state on either side.
+use candid::CandidType;
+use serde::Deserialize;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+pub const STABLE_HEADER_LEN: usize = 16;
+
+// Size of the buffers between the serializer and the stable memory
+const STABLE_IO_BUFFER_LEN: usize = 64 * 1024;
+
+// The code running in a canister, checked by the BigMap Index after it upgrades the canister
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct CodeVersion {
+    pub crate_version: String, // Version of the bigmap crate the canister was built from
+    pub stable_schema_version: u32, // Schema version of the state saved by the canister
+}
+
+impl CodeVersion {
+    pub fn new(stable_schema_version: u32) -> Self {
+        CodeVersion {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            stable_schema_version,
+        }
+    }
+}
+
+pub type StableMagic = [u8; 4];
+
+// The stable memory header for a payload of payload_len bytes
+pub fn encode_stable_header(
+    magic: &StableMagic,
+    schema_version: u32,
+    payload_len: u64,
+) -> [u8; STABLE_HEADER_LEN] {
+    let mut result = [0u8; STABLE_HEADER_LEN];
+    result[0..4].copy_from_slice(magic);
+    result[4..8].copy_from_slice(&schema_version.to_le_bytes());
+    result[8..16].copy_from_slice(&payload_len.to_le_bytes());
+    result
+}
+
+// Prepend the stable memory header to the serialized payload
+pub fn encode_stable_blob(magic: &StableMagic, schema_version: u32, payload: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(STABLE_HEADER_LEN + payload.len());
+    result.extend_from_slice(&encode_stable_header(
+        magic,
+        schema_version,
+        payload.len() as u64,
+    ));
+    result.extend_from_slice(payload);
+    result
+}
+
+// Parse the stable memory header and return the schema version and the length of the payload
+pub fn decode_stable_header(magic: &StableMagic, header: &[u8]) -> Result<(u32, usize), String> {
+    if header.len() < STABLE_HEADER_LEN {
+        return Err(format!(
+            "Stable memory header too short: {} bytes",
+            header.len()
+        ));
+    }
+    if &header[0..4] != magic {
+        return Err(format!(
+            "Stable memory magic mismatch: expected {} found {}",
+            hex::encode(magic),
+            hex::encode(&header[0..4])
+        ));
+    }
+    let mut schema_version = [0u8; 4];
+    schema_version.copy_from_slice(&header[4..8]);
+    let mut payload_len = [0u8; 8];
+    payload_len.copy_from_slice(&header[8..16]);
+    Ok((
+        u32::from_le_bytes(schema_version),
+        u64::from_le_bytes(payload_len) as usize,
+    ))
+}
+
+// Parse a complete blob and return the schema version and the payload
+pub fn decode_stable_blob<'a>(
+    magic: &StableMagic,
+    blob: &'a [u8],
+) -> Result<(u32, &'a [u8]), String> {
+    let (schema_version, payload_len) = decode_stable_header(magic, blob)?;
+    let payload = &blob[STABLE_HEADER_LEN..];
+    if payload.len() < payload_len {
+        return Err(format!(
+            "Stable memory payload truncated: expected {} bytes found {}",
+            payload_len,
+            payload.len()
+        ));
+    }
+    Ok((schema_version, &payload[..payload_len]))
+}
+
+// Writes to the stable memory from the offset on, growing the stable memory as needed
+struct StableWriter {
+    offset: u64,
+}
+
+impl Write for StableWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        use ic_cdk::stable::{stable_grow, stable_size, stable_write, WASM_PAGE_SIZE_IN_BYTES};
+
+        let end = self.offset + buf.len() as u64;
+        // The stable memory is addressed with 32 bit offsets
+        if end > u32::MAX as u64 {
+            return Err(io::Error::other("Stable memory is limited to 4 GiB"));
+        }
+        let pages_needed = end.div_ceil(WASM_PAGE_SIZE_IN_BYTES as u64) as u32;
+        let pages_current = stable_size();
+        if pages_needed > pages_current {
+            stable_grow(pages_needed - pages_current).map_err(|err| {
+                io::Error::other(format!(
+                    "Failed to grow stable memory to {} pages: {}",
+                    pages_needed, err
+                ))
+            })?;
+        }
+        stable_write(self.offset as u32, buf);
+        self.offset = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Reads the stable memory from the offset up to the end
+struct StableReader {
+    offset: u64,
+    end: u64,
+}
+
+impl Read for StableReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use ic_cdk::stable::stable_read;
+
+        let len = buf.len().min((self.end - self.offset) as usize);
+        stable_read(self.offset as u32, &mut buf[..len]);
+        self.offset += len as u64;
+        Ok(len)
+    }
+}
+
+// Write the header into the stable memory, followed by the payload streamed by write_payload
+// Returns the length of the payload
+pub fn stable_save<F>(
+    magic: &StableMagic,
+    schema_version: u32,
+    write_payload: F,
+) -> Result<u64, String>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), String>,
+{
+    let io_err = |err: io::Error| format!("Failed to write the stable memory: {}", err);
+    // The length of the payload is only known once it's written, it's filled in afterwards
+    let mut writer = StableWriter { offset: 0 };
+    writer
+        .write_all(&encode_stable_header(magic, schema_version, 0))
+        .map_err(io_err)?;
+    let mut buffered = BufWriter::with_capacity(STABLE_IO_BUFFER_LEN, writer);
+    write_payload(&mut buffered)?;
+    let mut writer = buffered
+        .into_inner()
+        .map_err(|err| io_err(err.into_error()))?;
+    let payload_len = writer.offset - STABLE_HEADER_LEN as u64;
+    writer.offset = 0;
+    writer
+        .write_all(&encode_stable_header(magic, schema_version, payload_len))
+        .map_err(io_err)?;
+    Ok(payload_len)
+}
+
+// Read the header from the stable memory, and pass the schema version and the payload to
+// read_payload. Returns Ok(None) if nothing was ever saved, e.g. on the first installation
+pub fn stable_restore<T, F>(magic: &StableMagic, read_payload: F) -> Result<Option<T>, String>
+where
+    F: FnOnce(u32, &mut dyn Read) -> Result<T, String>,
+{
+    use ic_cdk::stable::{stable_read, stable_size, WASM_PAGE_SIZE_IN_BYTES};
+
+    if stable_size() == 0 {
+        return Ok(None);
+    }
+    let mut header = [0u8; STABLE_HEADER_LEN];
+    stable_read(0, &mut header);
+    let (schema_version, payload_len) = decode_stable_header(magic, &header)?;
+    let end = STABLE_HEADER_LEN as u64 + payload_len as u64;
+    let stable_len = stable_size() as u64 * WASM_PAGE_SIZE_IN_BYTES as u64;
+    if end > stable_len {
+        return Err(format!(
+            "Stable memory payload truncated: expected {} bytes found {}",
+            payload_len,
+            stable_len - STABLE_HEADER_LEN as u64
+        ));
+    }
+    let reader = StableReader {
+        offset: STABLE_HEADER_LEN as u64,
+        end,
+    };
+    let mut buffered = BufReader::with_capacity(STABLE_IO_BUFFER_LEN, reader);
+    read_payload(schema_version, &mut buffered).map(Some)
+}